@@ -1,12 +1,29 @@
 #![allow(dead_code, unused_imports, unused_variables)]
 use std::{collections::{BTreeMap, BTreeSet, HashSet}, ffi::OsStr, fmt::format, path::PathBuf, str::FromStr, sync::{atomic::{AtomicBool, Ordering}, Arc}};
 
-use egui::{TextStyle, ScrollArea, mutex::Mutex, viewport, ViewportId};
+use egui::{TextStyle, ScrollArea, mutex::Mutex, viewport, ViewportId, Key, KeyboardShortcut, Modifiers};
 use egui_extras::{Column, TableBuilder};
 
-use crate::{format::{motion::{BoneKeyframe, MorphKeyframe, Motion}, pmm::read_pmm, pmx::Pmx}, misc::add_sphere};
+use glam::{Vec3, Vec4};
+
+use crate::{format::{motion::{BoneKeyframe, MorphKeyframe, Motion}, mvd_reader::read_mvd, pmm::PmmProject, pmx::*, pmx_merge::MergeOptions, vpd::Pose}, misc::add_sphere};
 use crate::dict::{bone_jap_to_eng, morph_jap_to_eng};
 use crate::custom3d::{Custom3d, self};
+use crate::history::{History, Snapshot};
+
+/// Part of the viewport an edit of the model has to update
+#[derive(Copy, Clone)]
+enum EditScope {
+    /// rigidbodies, joints and display frames are not drawn
+    None,
+    Mat(usize),
+    Bone(usize),
+    Morph(usize),
+    /// bones or morphs were added, removed or reordered
+    Model,
+    /// faces or textures changed, the viewport resources are built again
+    Mesh,
+}
 
 #[derive(PartialEq)]
 enum Page {
@@ -28,6 +45,9 @@ pub struct TemplateApp {
     pmx_mat_cur_value: BTreeSet<usize>,
     pmx_bone_cur_value: usize,
     pmx_morph_cur_value: usize,
+    pmx_rigidbody_cur_value: usize,
+    pmx_joint_cur_value: usize,
+    pmx_frame_cur_value: usize,
     page: Page,
 
     bone_cur_value: usize,
@@ -38,8 +58,19 @@ pub struct TemplateApp {
     show_model_view: Arc<Mutex<bool>>,
     custom3d: Arc<Mutex<Custom3d>>,
     model_viewport_id: ViewportId,
+    history: Arc<Mutex<History>>,
+    history_window_open: bool,
+    /// label of the property edit in progress, the changes of one interaction are undone together
+    pending_edit: Option<String>,
+    /// project opened to replace its models, with the path it was read from
+    pmm_project: Option<(PathBuf, PmmProject)>,
+    pmm_window_open: bool,
 }
 
+const UNDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+const REDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Y);
+const REDO_SHIFT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::Z);
+
 fn setup_custom_fonts(ctx: &egui::Context) {
     // Start with the default fonts (we will be adding to them rather than replacing them).
     let mut fonts = egui::FontDefinitions::default();
@@ -71,6 +102,521 @@ fn setup_custom_fonts(ctx: &egui::Context) {
     ctx.set_fonts(fonts);
 }
 
+const DRAW_FLAG_NAMES: [(&str, DrawFlags); 8] = [
+    ("No Cull", DrawFlags::NO_CULL),
+    ("Ground Shadow", DrawFlags::GROUND_SHADOW),
+    ("Cast Shadow", DrawFlags::CAST_SHADOW),
+    ("Receive Shadow", DrawFlags::RECEIVE_SHADOW),
+    ("Edge", DrawFlags::HAS_EDGE),
+    ("Vertex Color", DrawFlags::VERTEX_COLOR),
+    ("Point Drawing", DrawFlags::FILL_MODE_POINT),
+    ("Line Drawing", DrawFlags::FILL_MODE_EDGE),
+];
+
+const BONE_FLAG_NAMES: [(&str, BoneFlags); 12] = [
+    ("Rotatable", BoneFlags::ROTATABLE),
+    ("Translatable", BoneFlags::TRANSLATABLE),
+    ("Visible", BoneFlags::VISIBLE),
+    ("Enabled", BoneFlags::ENABLED),
+    ("IK", BoneFlags::IK),
+    ("Tail Bone", BoneFlags::INDEXED_TAIL_BONE),
+    ("Inherit Rotation", BoneFlags::INHERIT_ROTATION),
+    ("Inherit Translation", BoneFlags::INHERIT_TRANSLATION),
+    ("Fixed Axis", BoneFlags::FIXED_AXIS),
+    ("Local Axis", BoneFlags::LOCAL_AXIS),
+    ("Physics After Deform", BoneFlags::PHYSICS_AFTER_DEFORM),
+    ("External Parent", BoneFlags::EXTERNAL_PARENT),
+];
+
+const MORPH_PANEL_NAMES: [&str; 5] = ["System", "Eyebrow", "Eye", "Mouth", "Other"];
+
+fn edit_vec3(ui: &mut egui::Ui, v: &mut Vec3) -> bool {
+    ui.horizontal(|ui| {
+        let mut changed = false;
+        for c in v.as_mut() {
+            changed |= ui.add(egui::DragValue::new(c).speed(0.01)).changed();
+        }
+        changed
+    }).inner
+}
+
+/// Edits angles stored in radians as degrees
+fn edit_degrees(ui: &mut egui::Ui, v: &mut Vec3) -> bool {
+    let mut degrees = Vec3::from_array(v.to_array().map(f32::to_degrees));
+    let changed = ui.horizontal(|ui| {
+        let mut changed = false;
+        for c in degrees.as_mut() {
+            changed |= ui.add(egui::DragValue::new(c).speed(0.1).suffix("°")).changed();
+        }
+        changed
+    }).inner;
+    if changed {
+        *v = Vec3::from_array(degrees.to_array().map(f32::to_radians));
+    }
+    changed
+}
+
+fn edit_rgb(ui: &mut egui::Ui, v: &mut Vec3) -> bool {
+    let mut rgb = v.to_array();
+    let changed = ui.color_edit_button_rgb(&mut rgb).changed();
+    *v = Vec3::from_array(rgb);
+    changed
+}
+
+fn edit_rgba(ui: &mut egui::Ui, v: &mut Vec4) -> bool {
+    let mut rgba = v.to_array();
+    let changed = ui.color_edit_button_rgba_unmultiplied(&mut rgba).changed();
+    *v = Vec4::from_array(rgba);
+    changed
+}
+
+/// Texture selection, -1 is no texture
+fn texture_combo(ui: &mut egui::Ui, id_salt: &str, index: &mut i32, texs: &[String]) -> bool {
+    let before = *index;
+    let text = usize::try_from(*index).ok().and_then(|i| texs.get(i)).map_or("None", |t| t.as_str());
+    egui::ComboBox::from_id_salt(id_salt).selected_text(text).show_ui(ui, |ui| {
+        ui.selectable_value(index, -1, "None");
+        for (i, t) in texs.iter().enumerate() {
+            ui.selectable_value(index, i as i32, format!("{}: {}", i, t));
+        }
+    });
+    *index != before
+}
+
+/// Selection of an item by index, `None` is no item
+fn index_combo(ui: &mut egui::Ui, id_salt: &str, index: &mut Option<usize>, names: &[String]) -> bool {
+    let before = *index;
+    let text = index.and_then(|i| names.get(i)).map_or("None".to_string(), |n| format!("{}: {}", index.unwrap(), n));
+    egui::ComboBox::from_id_salt(id_salt).selected_text(text).show_ui(ui, |ui| {
+        ui.selectable_value(index, None, "None");
+        for (i, n) in names.iter().enumerate() {
+            ui.selectable_value(index, Some(i), format!("{}: {}", i, n));
+        }
+    });
+    *index != before
+}
+
+/// Editable fields of a material, returns whether anything changed
+fn material_form(ui: &mut egui::Ui, mat: &mut Mat, texs: &[String]) -> bool {
+    let mut changed = false;
+    egui::Grid::new("material_form").num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        changed |= ui.text_edit_singleline(&mut mat.name).changed();
+        ui.end_row();
+        ui.label("NameEn");
+        changed |= ui.text_edit_singleline(&mut mat.name_en).changed();
+        ui.end_row();
+        ui.label("Associated Face Count");
+        ui.label(mat.associated_face_count.to_string());
+        ui.end_row();
+        ui.label("Diffuse");
+        changed |= edit_rgba(ui, &mut mat.diffuse);
+        ui.end_row();
+        ui.label("Specular");
+        ui.horizontal(|ui| {
+            let mut specular = mat.specular.truncate();
+            changed |= edit_rgb(ui, &mut specular);
+            changed |= ui.add(egui::DragValue::new(&mut mat.specular.w).speed(0.1).range(0.0..=f32::MAX)).changed();
+            mat.specular = specular.extend(mat.specular.w);
+        });
+        ui.end_row();
+        ui.label("Ambient");
+        changed |= edit_rgb(ui, &mut mat.ambient);
+        ui.end_row();
+        ui.label("Edge Color");
+        changed |= edit_rgba(ui, &mut mat.edge_color);
+        ui.end_row();
+        ui.label("Edge Scale");
+        changed |= ui.add(egui::DragValue::new(&mut mat.edge_scale).speed(0.01).range(0.0..=f32::MAX)).changed();
+        ui.end_row();
+        ui.label("Tex");
+        changed |= texture_combo(ui, "mat_tex", &mut mat.tex_index, texs);
+        ui.end_row();
+        ui.label("MatCap Tex");
+        changed |= texture_combo(ui, "mat_env", &mut mat.env_index, texs);
+        ui.end_row();
+        ui.label("Matcap Blend Mode");
+        let before = mat.env_blend_mode;
+        egui::ComboBox::from_id_salt("mat_env_blend").selected_text(format!("{:?}", mat.env_blend_mode)).show_ui(ui, |ui| {
+            for mode in [BlendMode::Disable, BlendMode::Mul, BlendMode::Add, BlendMode::Other] {
+                ui.selectable_value(&mut mat.env_blend_mode, mode, format!("{:?}", mode));
+            }
+        });
+        changed |= mat.env_blend_mode != before;
+        ui.end_row();
+        ui.label("Toon Tex");
+        let before = mat.toon;
+        let text = match mat.toon {
+            Toon::Inner(i) => format!("toon{:02}.bmp", i as u32 + 1),
+            Toon::Tex(i) => usize::try_from(i).ok().and_then(|i| texs.get(i)).map_or("None".to_string(), |t| t.clone()),
+        };
+        egui::ComboBox::from_id_salt("mat_toon").selected_text(text).show_ui(ui, |ui| {
+            ui.selectable_value(&mut mat.toon, Toon::Tex(-1), "None");
+            for i in 0..10 {
+                ui.selectable_value(&mut mat.toon, Toon::Inner(i), format!("toon{:02}.bmp", i as u32 + 1));
+            }
+            for (i, t) in texs.iter().enumerate() {
+                ui.selectable_value(&mut mat.toon, Toon::Tex(i as i32), format!("{}: {}", i, t));
+            }
+        });
+        changed |= mat.toon != before;
+        ui.end_row();
+        ui.label("Draw Flags");
+        ui.vertical(|ui| {
+            for (name, flag) in DRAW_FLAG_NAMES {
+                let mut on = mat.draw_flag.contains(flag);
+                if ui.checkbox(&mut on, name).changed() {
+                    mat.draw_flag.set(flag, on);
+                    changed = true;
+                }
+            }
+        });
+        ui.end_row();
+        ui.label("Comment");
+        changed |= ui.text_edit_multiline(&mut mat.comment).changed();
+        ui.end_row();
+    });
+    changed
+}
+
+/// Editable fields of bone `index`, `names` are the names of all bones
+fn bone_form(ui: &mut egui::Ui, bone: &mut Bone, index: usize, names: &[String]) -> bool {
+    let mut changed = false;
+    egui::Grid::new("bone_form").num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        changed |= ui.text_edit_singleline(&mut bone.name).changed();
+        ui.end_row();
+        ui.label("NameEn");
+        changed |= ui.text_edit_singleline(&mut bone.name_en).changed();
+        ui.end_row();
+        ui.label("Position");
+        changed |= edit_vec3(ui, &mut bone.pos);
+        ui.end_row();
+        ui.label("Parent");
+        changed |= index_combo(ui, "bone_parent", &mut bone.parent_index, names);
+        if bone.parent_index == Some(index) {
+            bone.parent_index = None;
+        }
+        ui.end_row();
+        ui.label("Layer");
+        changed |= ui.add(egui::DragValue::new(&mut bone.layer)).changed();
+        ui.end_row();
+        ui.label("Flags");
+        ui.vertical(|ui| {
+            for (name, flag) in BONE_FLAG_NAMES {
+                let mut on = bone.bone_flags.contains(flag);
+                // the IK settings are stored with the IK list, which this form does not edit
+                let editable = !flag.contains(BoneFlags::IK);
+                if ui.add_enabled(editable, egui::Checkbox::new(&mut on, name)).changed() {
+                    bone.bone_flags.set(flag, on);
+                    bone.sync_flag_fields();
+                    changed = true;
+                }
+            }
+        });
+        ui.end_row();
+        ui.label("Tail");
+        match &mut bone.bone_tail_pos {
+            BoneTailPos::Bone(i) => {
+                let mut tail = usize::try_from(*i).ok();
+                if index_combo(ui, "bone_tail", &mut tail, names) {
+                    *i = tail.map_or(-1, |i| i as i32);
+                    changed = true;
+                }
+            },
+            BoneTailPos::Pos(pos) => changed |= edit_vec3(ui, pos),
+        }
+        ui.end_row();
+        if let Some((parent, ratio)) = &mut bone.inherit {
+            ui.label("Inherit Parent");
+            let mut p = usize::try_from(*parent).ok();
+            if index_combo(ui, "bone_inherit", &mut p, names) {
+                *parent = p.map_or(-1, |p| p as i32);
+                changed = true;
+            }
+            ui.end_row();
+            ui.label("Inherit Ratio");
+            changed |= ui.add(egui::DragValue::new(ratio).speed(0.01)).changed();
+            ui.end_row();
+        }
+        if let Some(axis) = &mut bone.fixed_axis {
+            ui.label("Fixed Axis");
+            changed |= edit_vec3(ui, axis);
+            ui.end_row();
+        }
+        if let Some((x, z)) = &mut bone.local_axis {
+            ui.label("Local Axis X");
+            changed |= edit_vec3(ui, x);
+            ui.end_row();
+            ui.label("Local Axis Z");
+            changed |= edit_vec3(ui, z);
+            ui.end_row();
+        }
+        if let Some(key) = &mut bone.external_parent {
+            ui.label("External Parent Key");
+            changed |= ui.add(egui::DragValue::new(key)).changed();
+            ui.end_row();
+        }
+    });
+    changed
+}
+
+/// Editable fields of a morph, its kind and items are fixed
+fn morph_form(ui: &mut egui::Ui, morph: &mut MorphInfo) -> bool {
+    let mut changed = false;
+    egui::Grid::new("morph_form").num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        changed |= ui.text_edit_singleline(&mut morph.name).changed();
+        ui.end_row();
+        ui.label("NameEn");
+        changed |= ui.text_edit_singleline(&mut morph.name_en).changed();
+        ui.end_row();
+        ui.label("Panel");
+        let before = morph.panel;
+        let text = usize::try_from(morph.panel).ok().and_then(|i| MORPH_PANEL_NAMES.get(i)).copied().unwrap_or("Unknown");
+        egui::ComboBox::from_id_salt("morph_panel").selected_text(text).show_ui(ui, |ui| {
+            for (i, name) in MORPH_PANEL_NAMES.iter().enumerate() {
+                ui.selectable_value(&mut morph.panel, i as i8, *name);
+            }
+        });
+        changed |= morph.panel != before;
+        ui.end_row();
+        let (kind, count) = match &morph.data {
+            Morph::MorphGroup(items) => ("Group", items.len()),
+            Morph::MorphFlip(items) => ("Flip", items.len()),
+            Morph::MorphVertex(items) => ("Vertex", items.len()),
+            Morph::MorphBone(items) => ("Bone", items.len()),
+            Morph::MorphUv(items) => ("UV", items.len()),
+            Morph::MorphRigidbody(items) => ("Impulse", items.len()),
+            Morph::MorphMat(items) => ("Material", items.len()),
+        };
+        ui.label("Kind");
+        ui.label(format!("{} (category {})", kind, morph.category));
+        ui.end_row();
+        ui.label("Items");
+        ui.label(count.to_string());
+        ui.end_row();
+    });
+    changed
+}
+
+const RIGIDBODY_MODE_NAMES: [(&str, RigidbodyMode); 3] = [
+    ("Static", RigidbodyMode::Kinematics),
+    ("Dynamic", RigidbodyMode::Dynamics),
+    ("Dynamic + Bone", RigidbodyMode::DynamicsPassRotation),
+];
+
+/// Editable fields of a rigidbody, `bones` are the names of all bones
+fn rigidbody_form(ui: &mut egui::Ui, rb: &mut Rigidbody, bones: &[String]) -> bool {
+    let mut changed = false;
+    egui::Grid::new("rigidbody_form").num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        changed |= ui.text_edit_singleline(&mut rb.name).changed();
+        ui.end_row();
+        ui.label("NameEn");
+        changed |= ui.text_edit_singleline(&mut rb.name_en).changed();
+        ui.end_row();
+        ui.label("Bone");
+        let mut bone = usize::try_from(rb.bone).ok();
+        if index_combo(ui, "rigidbody_bone", &mut bone, bones) {
+            rb.bone = bone.map_or(-1, |b| b as i32);
+            changed = true;
+        }
+        ui.end_row();
+        ui.label("Group");
+        // groups are numbered from 1 in MMD
+        let mut group = rb.group + 1;
+        if ui.add(egui::DragValue::new(&mut group).range(1..=16)).changed() {
+            rb.group = group - 1;
+            changed = true;
+        }
+        ui.end_row();
+        ui.label("Non-collision");
+        // a set bit of the mask collides with that group, checked groups are the cleared bits
+        egui::Grid::new("rigidbody_non_collision").spacing([2.0, 2.0]).show(ui, |ui| {
+            for g in 0..16 {
+                let mut off = rb.collision_group & (1 << g) == 0;
+                if ui.checkbox(&mut off, format!("{}", g + 1)).changed() {
+                    rb.collision_group ^= 1 << g;
+                    changed = true;
+                }
+                if g % 8 == 7 {
+                    ui.end_row();
+                }
+            }
+        });
+        ui.end_row();
+        ui.label("Shape");
+        let before = rb.shape;
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut rb.shape, RigidbodyShape::Shpere, "Sphere");
+            ui.selectable_value(&mut rb.shape, RigidbodyShape::Box, "Box");
+            ui.selectable_value(&mut rb.shape, RigidbodyShape::Capsule, "Capsule");
+        });
+        changed |= rb.shape != before;
+        ui.end_row();
+        ui.label("Size");
+        changed |= edit_vec3(ui, &mut rb.size);
+        ui.end_row();
+        ui.label("Position");
+        changed |= edit_vec3(ui, &mut rb.pos);
+        ui.end_row();
+        ui.label("Rotation");
+        changed |= edit_degrees(ui, &mut rb.rot);
+        ui.end_row();
+        for (name, v) in [
+            ("Mass", &mut rb.mass),
+            ("Linear Damping", &mut rb.linear_damping),
+            ("Angular Damping", &mut rb.angular_damping),
+            ("Restitution", &mut rb.restitution),
+            ("Friction", &mut rb.friction),
+        ] {
+            ui.label(name);
+            changed |= ui.add(egui::DragValue::new(v).speed(0.01).range(0.0..=f32::MAX)).changed();
+            ui.end_row();
+        }
+        ui.label("Mode");
+        let before = rb.mode;
+        ui.horizontal(|ui| {
+            for (name, mode) in RIGIDBODY_MODE_NAMES {
+                ui.selectable_value(&mut rb.mode, mode, name);
+            }
+        });
+        changed |= rb.mode != before;
+        ui.end_row();
+    });
+    changed
+}
+
+/// Editable fields of a joint, `rigidbodys` are the names of all rigidbodies
+fn joint_form(ui: &mut egui::Ui, joint: &mut Joint, rigidbodys: &[String]) -> bool {
+    let mut changed = false;
+    egui::Grid::new("joint_form").num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        changed |= ui.text_edit_singleline(&mut joint.name).changed();
+        ui.end_row();
+        ui.label("NameEn");
+        changed |= ui.text_edit_singleline(&mut joint.name_en).changed();
+        ui.end_row();
+        ui.label("Kind");
+        let before = joint.kind;
+        egui::ComboBox::from_id_salt("joint_kind").selected_text(format!("{:?}", joint.kind)).show_ui(ui, |ui| {
+            for kind in [JointKind::Spring6Dof, JointKind::SixDof, JointKind::P2p, JointKind::ConeTwist, JointKind::Slider, JointKind::Hinge] {
+                ui.selectable_value(&mut joint.kind, kind, format!("{:?}", kind));
+            }
+        });
+        changed |= joint.kind != before;
+        ui.end_row();
+        for (name, id_salt, index) in [
+            ("Rigidbody A", "joint_rigidbody_a", &mut joint.rigidbody_a),
+            ("Rigidbody B", "joint_rigidbody_b", &mut joint.rigidbody_b),
+        ] {
+            ui.label(name);
+            let mut rb = usize::try_from(*index).ok();
+            if index_combo(ui, id_salt, &mut rb, rigidbodys) {
+                *index = rb.map_or(-1, |r| r as i32);
+                changed = true;
+            }
+            ui.end_row();
+        }
+        ui.label("Position");
+        changed |= edit_vec3(ui, &mut joint.pos);
+        ui.end_row();
+        ui.label("Rotation");
+        changed |= edit_degrees(ui, &mut joint.rot);
+        ui.end_row();
+        ui.label("Position Min");
+        changed |= edit_vec3(ui, &mut joint.pos_min);
+        ui.end_row();
+        ui.label("Position Max");
+        changed |= edit_vec3(ui, &mut joint.pos_max);
+        ui.end_row();
+        ui.label("Rotation Min");
+        changed |= edit_degrees(ui, &mut joint.rot_min);
+        ui.end_row();
+        ui.label("Rotation Max");
+        changed |= edit_degrees(ui, &mut joint.rot_max);
+        ui.end_row();
+        ui.label("Position Spring");
+        changed |= edit_vec3(ui, &mut joint.pos_spring);
+        ui.end_row();
+        ui.label("Rotation Spring");
+        changed |= edit_vec3(ui, &mut joint.rot_spring);
+        ui.end_row();
+    });
+    changed
+}
+
+/// Editable display frame, entries are reordered by dragging them onto another entry
+fn display_frame_form(ui: &mut egui::Ui, frame: &mut DisplayFrame, bones: &[String], morphs: &[String]) -> bool {
+    let mut changed = false;
+    egui::Grid::new("display_frame_form").num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        changed |= ui.add_enabled(!frame.special, egui::TextEdit::singleline(&mut frame.name)).changed();
+        ui.end_row();
+        ui.label("NameEn");
+        changed |= ui.add_enabled(!frame.special, egui::TextEdit::singleline(&mut frame.name_en)).changed();
+        ui.end_row();
+        ui.label("Special");
+        ui.label(if frame.special { "Yes" } else { "No" });
+        ui.end_row();
+    });
+    ui.separator();
+    let mut moved = None;
+    let mut removed = None;
+    ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+        for (i, item) in frame.morph_items.iter().enumerate() {
+            let text = match *item {
+                DisplayFrameIndex::Bone(b) => format!("{:3}: Bone {}", i, bones.get(b as usize).map_or("?", |n| n.as_str())),
+                DisplayFrameIndex::Morph(m) => format!("{:3}: Morph {}", i, morphs.get(m as usize).map_or("?", |n| n.as_str())),
+            };
+            ui.horizontal(|ui| {
+                if ui.small_button("x").clicked() {
+                    removed = Some(i);
+                }
+                let response = ui.dnd_drag_source(egui::Id::new(("display_frame_item", i)), i, |ui| {
+                    ui.label(text);
+                }).response;
+                if let Some(from) = response.dnd_release_payload::<usize>() {
+                    moved = Some((*from, i));
+                }
+            });
+        }
+    });
+    if let Some((from, to)) = moved.filter(|(from, to)| from != to) {
+        let item = frame.morph_items.remove(from);
+        frame.morph_items.insert(to, item);
+        changed = true;
+    }
+    if let Some(i) = removed {
+        frame.morph_items.remove(i);
+        changed = true;
+    }
+    changed
+}
+
+/// Selectable list of names with their indexes
+fn name_list(ui: &mut egui::Ui, names: &[String], cur: &mut usize) {
+    ui.horizontal(|ui| {
+        let text = format!("Count: {}", names.len());
+        ui.heading(text);
+    });
+    ui.separator();
+    ScrollArea::vertical().auto_shrink([false; 2]).show_rows(
+        ui,
+        ui.text_style_height(&TextStyle::Body),
+        names.len(),
+        |ui, row_range| {
+            ui.with_layout(egui::Layout::top_down_justified(egui::Align::LEFT), |ui| {
+                for row in row_range {
+                    let text = format!("{:3}: {}", row, names[row]);
+                    ui.selectable_value(cur, row, text);
+                }
+            });
+        },
+    );
+}
+
 impl TemplateApp {
     /// Called once before the first frame.
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
@@ -87,27 +633,177 @@ impl TemplateApp {
             pmx_bone_cur_value: 0,
             pmx_mat_cur_value: BTreeSet::new(),
             pmx_morph_cur_value: 0,
+            pmx_rigidbody_cur_value: 0,
+            pmx_joint_cur_value: 0,
+            pmx_frame_cur_value: 0,
             show_model_view: Arc::new(Mutex::new(true)),
             custom3d: Arc::new(Mutex::new(Custom3d::new(cc))),
             model_viewport_id: egui::ViewportId::from_hash_of("model_viewport"),
+            history: Arc::new(Mutex::new(History::new())),
+            history_window_open: false,
+            pending_edit: None,
+            pmm_project: None,
+            pmm_window_open: false,
         };
         s.load_file(&PathBuf::from_str("./assets/ImagineGirls_Iris_v102_mmd/Iris_mmd/Iris.pmx").unwrap());
         s
     }
     fn load_file(&mut self, p: &PathBuf) {
+        if let Err(e) = self.try_load_file(p) {
+            self.show_error(&format!("Failed to load {}:\n{}", p.display(), e));
+        }
+    }
+    fn try_load_file(&mut self, p: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
         let ext = p.extension().unwrap_or_default().to_ascii_lowercase();
         if ext == OsStr::new("vmd") {
-            let content = std::fs::read(p).unwrap();
-            self.vmd_motion = Some(Motion::read(content, p.to_str().unwrap()));
+            let content = std::fs::read(p)?;
+            let motion = Motion::read(content, &p.to_string_lossy())?;
+            self.record(&format!("Open {}", p.display()), false, true);
+            self.vmd_motion = Some(motion);
             self.page = Page::VmdBone;
-        } else if ext == OsStr::new("pmx") {
-            let content = std::fs::read(p).unwrap();
-            let pmx_data = Arc::new(Mutex::new(Pmx::read(content, p.to_str().unwrap())));
+            self.custom3d.lock().set_motion(self.vmd_motion.clone());
+        } else if ext == OsStr::new("mvd") {
+            let content = std::fs::read(p)?;
+            let mut motions = read_mvd(content, &p.to_string_lossy())?;
+            if motions.is_empty() {
+                return Err("MVD file contains no objects".into());
+            }
+            // MVD keeps the camera as its own object, show it together with the first model
+            let camera_keyframes: Vec<_> = motions.iter().flat_map(|m| m.camera_keyframes.clone()).collect();
+            let model = motions.iter()
+                .position(|m| !m.bone_keyframes.is_empty() || !m.morph_keyframes.is_empty())
+                .unwrap_or(0);
+            let mut motion = motions.swap_remove(model);
+            motion.camera_keyframes = camera_keyframes;
+            self.record(&format!("Open {}", p.display()), false, true);
+            self.vmd_motion = Some(motion);
+            self.page = Page::VmdBone;
+            self.custom3d.lock().set_motion(self.vmd_motion.clone());
+        } else if ext == OsStr::new("vpd") {
+            let content = std::fs::read(p)?;
+            let mut motion = Pose::read(content)?.to_motion();
+            motion.path = p.to_string_lossy().to_string();
+            self.record(&format!("Open {}", p.display()), false, true);
+            self.vmd_motion = Some(motion);
+            self.page = Page::VmdBone;
+            self.custom3d.lock().set_motion(self.vmd_motion.clone());
+        } else if ext == OsStr::new("pmx") || ext == OsStr::new("pmd") {
+            let content = std::fs::read(p)?;
+            let pmx = if ext == OsStr::new("pmd") {
+                Pmx::read_pmd(content, &p.to_string_lossy())?
+            } else {
+                Pmx::read(content, &p.to_string_lossy())?
+            };
+            let pmx_data = Arc::new(Mutex::new(pmx));
             pmx_data.lock().right_hand();
             self.pmx_data = Some(pmx_data.clone());
+            // model snapshots belong to the previous model, motion edits stay undoable
+            self.history.lock().clear_models();
             self.page = Page::Material;
             self.custom3d.lock().load_mesh(pmx_data);
         }
+        Ok(())
+    }
+    /// Appends the model in `p` to the current model, bones and morphs are matched by name
+    fn merge_file(&mut self, p: &PathBuf) {
+        if let Err(e) = self.try_merge_file(p) {
+            self.show_error(&format!("Failed to merge {}:\n{}", p.display(), e));
+        }
+    }
+    fn try_merge_file(&mut self, p: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        let content = std::fs::read(p)?;
+        let ext = p.extension().unwrap_or_default().to_ascii_lowercase();
+        let mut other = if ext == OsStr::new("pmd") {
+            Pmx::read_pmd(content, &p.to_string_lossy())?
+        } else {
+            Pmx::read(content, &p.to_string_lossy())?
+        };
+        other.right_hand();
+        self.record(&format!("Merge {}", p.display()), true, false);
+        if let Some(m) = &self.pmx_data {
+            m.lock().merge(&other, &MergeOptions::new());
+            self.custom3d.lock().load_mesh(m.clone());
+        }
+        Ok(())
+    }
+    fn show_error(&mut self, text: &str) {
+        self.log_text += text;
+        self.log_text += "\n";
+        self.info_text = text.to_string();
+        self.info_window_open = true;
+    }
+    /// Saves the model and/or the motion before an edit so that it can be undone
+    fn record(&self, label: &str, model: bool, motion: bool) {
+        let mut states = Vec::new();
+        if model {
+            if let Some(m) = &self.pmx_data {
+                states.push(Snapshot::Model(Box::new(m.lock().clone())));
+            }
+        }
+        if motion {
+            states.push(Snapshot::Motion(self.vmd_motion.clone()));
+        }
+        if !states.is_empty() {
+            self.history.lock().push(label, states);
+        }
+    }
+    /// Puts back the given states and returns the ones they replaced
+    fn restore(&mut self, states: Vec<Snapshot>) -> Vec<Snapshot> {
+        let mut replaced = Vec::new();
+        for state in states {
+            match state {
+                Snapshot::Model(pmx) => {
+                    if let Some(m) = &self.pmx_data {
+                        let old = std::mem::replace(&mut *m.lock(), *pmx);
+                        replaced.push(Snapshot::Model(Box::new(old)));
+                        let mat_count = m.lock().mats.len();
+                        self.pmx_mat_cur_value.retain(|i| *i < mat_count);
+                        self.custom3d.lock().load_mesh(m.clone());
+                    }
+                },
+                Snapshot::Motion(motion) => {
+                    let old = std::mem::replace(&mut self.vmd_motion, motion);
+                    replaced.push(Snapshot::Motion(old));
+                    self.custom3d.lock().set_motion(self.vmd_motion.clone());
+                },
+            }
+        }
+        replaced
+    }
+    /// Applies a property edit to the model. The model is recorded for undo once per interaction,
+    /// `scope` tells which part of the viewport has to follow the edit.
+    fn apply_edit(&mut self, ctx: &egui::Context, label: &str, scope: EditScope, edit: impl FnOnce(&mut Pmx)) {
+        if self.pending_edit.as_deref() != Some(label) {
+            self.record(label, true, false);
+            self.pending_edit = Some(label.to_string());
+        }
+        if let Some(m) = &self.pmx_data {
+            edit(&mut m.lock());
+            let pmx = m.lock();
+            let mut custom3d = self.custom3d.lock();
+            match scope {
+                EditScope::None => {}
+                EditScope::Mat(i) => custom3d.refresh_mat(i, &pmx.mats[i]),
+                EditScope::Bone(i) => custom3d.refresh_bone(i, &pmx.bones[i]),
+                EditScope::Morph(i) => custom3d.refresh_morph(i, &pmx.morphs[i]),
+                EditScope::Model => custom3d.refresh_model(&pmx),
+                EditScope::Mesh => {
+                    drop(pmx);
+                    custom3d.load_mesh(m.clone());
+                }
+            }
+        }
+        ctx.request_repaint_of(self.model_viewport_id);
+    }
+    fn undo(&mut self) -> bool {
+        let history = self.history.clone();
+        let done = history.lock().undo(|states| self.restore(states));
+        done
+    }
+    fn redo(&mut self) -> bool {
+        let history = self.history.clone();
+        let done = history.lock().redo(|states| self.restore(states));
+        done
     }
 }
 
@@ -128,6 +824,27 @@ impl eframe::App for TemplateApp {
         if need_repaint_model_viewport {
             ctx.request_repaint_of(self.model_viewport_id);
         }
+        let captured = self.custom3d.lock().take_captured_camera_keyframes();
+        if !captured.is_empty() {
+            self.record("Capture Camera", false, true);
+            let motion = self.vmd_motion.get_or_insert_with(Motion::new);
+            for keyframe in captured {
+                motion.insert_camera_keyframe(keyframe);
+            }
+            ctx.request_repaint();
+        }
+        if !ctx.is_using_pointer() && ctx.memory(|m| m.focused().is_none()) {
+            self.pending_edit = None;
+        }
+        if !ctx.wants_keyboard_input() {
+            let (undo, redo) = ctx.input_mut(|i| {
+                let redo = i.consume_shortcut(&REDO_SHIFT_SHORTCUT) || i.consume_shortcut(&REDO_SHORTCUT);
+                (i.consume_shortcut(&UNDO_SHORTCUT), redo)
+            });
+            if (undo && self.undo()) || (redo && self.redo()) {
+                ctx.request_repaint_of(self.model_viewport_id);
+            }
+        }
 
         // Examples of how to create different panels and windows.
         // Pick whichever suits you.
@@ -145,6 +862,15 @@ impl eframe::App for TemplateApp {
                         }
                         ui.close_menu();
                     }
+                    if ui.add_enabled(self.pmx_data.is_some(), egui::Button::new("Merge PMX ...")).clicked() {
+                        let path = rfd::FileDialog::new()
+                            .add_filter("Poygon Mesh data eXtension", &["pmx", "pmd"])
+                            .pick_file();
+                        if let Some(p) = &path {
+                            self.merge_file(p);
+                        }
+                        ui.close_menu();
+                    }
                     if ui.button("Save PMX As ...").clicked() {
                         if let Some(m) = &self.pmx_data {
                             let path = rfd::FileDialog::new()
@@ -160,6 +886,44 @@ impl eframe::App for TemplateApp {
                         }
                         ui.close_menu();
                     }
+                    if ui.button("Save PMX As UTF-8 ...").clicked() {
+                        if let Some(m) = &self.pmx_data {
+                            let path = rfd::FileDialog::new()
+                                .add_filter("Poygon Mesh data eXtension", &["pmx"])
+                                .save_file();
+                            if let Some(p) = &path {
+                                let m = m.lock();
+                                let mut nm = m.clone();
+                                nm.header.utf8 = true;
+                                nm.right_hand();
+                                let contents = nm.write();
+                                std::fs::write(p, contents).unwrap();
+                            }
+                        }
+                        ui.close_menu();
+                    }
+                    if ui.button("Save PMD As ...").clicked() {
+                        if let Some(m) = &self.pmx_data {
+                            let path = rfd::FileDialog::new()
+                                .add_filter("Polygon Model Data", &["pmd"])
+                                .save_file();
+                            if let Some(p) = &path {
+                                let mut nm = m.lock().clone();
+                                nm.right_hand();
+                                let (contents, report) = nm.write_pmd();
+                                std::fs::write(p, contents).unwrap();
+                                self.info_text = if report.is_empty() {
+                                    "Saved as PMD without losses".to_string()
+                                } else {
+                                    format!("Saved as PMD, dropped or approximated:\n{}", report.join("\n"))
+                                };
+                                self.log_text += &self.info_text;
+                                self.log_text += "\n";
+                                self.info_window_open = true;
+                            }
+                        }
+                        ui.close_menu();
+                    }
                     if ui.button("Save VMD As ...").clicked() {
                         if let Some(m) = &self.vmd_motion {
                             let path = rfd::FileDialog::new()
@@ -177,7 +941,27 @@ impl eframe::App for TemplateApp {
                 });
 
                 ui.menu_button("Edit", |ui| {
+                    let (undo_label, redo_label) = {
+                        let history = self.history.lock();
+                        (history.undo.last().map(|c| c.label.clone()), history.redo.last().map(|c| c.label.clone()))
+                    };
+                    let undo_button = egui::Button::new(format!("Undo {}", undo_label.clone().unwrap_or_default()))
+                        .shortcut_text(ctx.format_shortcut(&UNDO_SHORTCUT));
+                    if ui.add_enabled(undo_label.is_some(), undo_button).clicked() {
+                        self.undo();
+                        ctx.request_repaint_of(self.model_viewport_id);
+                        ui.close_menu();
+                    }
+                    let redo_button = egui::Button::new(format!("Redo {}", redo_label.clone().unwrap_or_default()))
+                        .shortcut_text(ctx.format_shortcut(&REDO_SHORTCUT));
+                    if ui.add_enabled(redo_label.is_some(), redo_button).clicked() {
+                        self.redo();
+                        ctx.request_repaint_of(self.model_viewport_id);
+                        ui.close_menu();
+                    }
+                    ui.separator();
                     if ui.button("Calc Connected Normal to UV1").clicked() {
+                        self.record("Calc Connected Normal to UV1", true, false);
                         if let Some(m) = &mut self.pmx_data {
                             let mut m = m.lock();
                             m.calc_connected_nrms_to_uv1();
@@ -185,6 +969,7 @@ impl eframe::App for TemplateApp {
                         ui.close_menu();
                     }
                     if ui.button("Japanese to Engligh").clicked() {
+                        self.record("Japanese to English", true, true);
                         if let Some(m) = &mut self.pmx_data {
                             let mut m = m.lock();
                             for b in &mut m.bones {
@@ -212,26 +997,91 @@ impl eframe::App for TemplateApp {
                                 m.morph_keyframes = new_morph_keyframes;
                             }
                         }
+                        if let Some(m) = &self.pmx_data {
+                            self.custom3d.lock().load_mesh(m.clone());
+                        }
+                        self.custom3d.lock().set_motion(self.vmd_motion.clone());
                         ui.close_menu();
                     }
                     if ui.button("Clean Empty Keyframes").clicked() {
+                        self.record("Clean Empty Keyframes", false, true);
                         if let Some(m) = &mut self.vmd_motion {
                             *m = m.clear_empty_keyframe();
                         }
+                        self.custom3d.lock().set_motion(self.vmd_motion.clone());
                         ui.close_menu();
                     }
                     if ui.button("Extract PMM into VMDs").clicked() {
                         if let Some(p) = rfd::FileDialog::new().pick_file() {
                             let pmm_path = p.display().to_string();
-                            let motions = read_pmm(&p);
-                            let mut buf = String::new();
-                            for (i, m) in motions.iter().enumerate() {
-                                let new_m = m.clear_empty_keyframe();
-                                new_m.write_vmd(&format!("{}.{:0>2}.vmd", pmm_path, i));
-                                buf += &format!("Index: {}\n", i);
-                                buf += &new_m.summary();
+                            let project = std::fs::read(&p)
+                                .map_err(|e| e.to_string())
+                                .and_then(|content| PmmProject::read(content).map_err(|e| e.to_string()));
+                            match project {
+                                Ok(project) => {
+                                    let mut buf = project.summary();
+                                    for (i, m) in project.to_motions().iter().enumerate() {
+                                        let new_m = m.clear_empty_keyframe();
+                                        new_m.write_vmd(&format!("{}.{:0>2}.vmd", pmm_path, i));
+                                        buf += &format!("Index: {}\n", i);
+                                        buf += &new_m.summary();
+                                    }
+                                    self.log_text += &buf;
+                                },
+                                Err(e) => self.show_error(&format!("Failed to load {}:\n{}", pmm_path, e)),
+                            }
+                        }
+                        ui.close_menu();
+                    }
+                    if ui.button("Inject VMD into PMM ...").clicked() {
+                        let mut error = None;
+                        if let Some(vm) = &self.vmd_motion {
+                            if let Some(p) = rfd::FileDialog::new().add_filter("Polygon Movie Maker", &["pmm"]).pick_file() {
+                                let pmm_path = p.display().to_string();
+                                let project = std::fs::read(&p)
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|content| PmmProject::read(content).map_err(|e| e.to_string()));
+                                match project {
+                                    Ok(mut project) => {
+                                        // a camera motion replaces the camera, otherwise the models named like the motion
+                                        let mut injected = 0;
+                                        if !vm.camera_keyframes.is_empty() {
+                                            project.set_camera_motion(vm);
+                                            injected += 1;
+                                        }
+                                        for model in project.models.iter_mut().filter(|m| m.name == vm.model_name) {
+                                            model.set_motion(vm);
+                                            injected += 1;
+                                        }
+                                        if injected == 0 {
+                                            error = Some(format!("No model named {} in {}", vm.model_name, pmm_path));
+                                        } else if let Some(out) = rfd::FileDialog::new()
+                                            .add_filter("Polygon Movie Maker", &["pmm"])
+                                            .save_file() {
+                                            std::fs::write(out, project.write()).unwrap();
+                                        }
+                                    },
+                                    Err(e) => error = Some(format!("Failed to load {}:\n{}", pmm_path, e)),
+                                }
+                            }
+                        }
+                        if let Some(e) = error {
+                            self.show_error(&e);
+                        }
+                        ui.close_menu();
+                    }
+                    if ui.button("Replace Models in PMM ...").clicked() {
+                        if let Some(p) = rfd::FileDialog::new().add_filter("Polygon Movie Maker", &["pmm"]).pick_file() {
+                            let project = std::fs::read(&p)
+                                .map_err(|e| e.to_string())
+                                .and_then(|content| PmmProject::read(content).map_err(|e| e.to_string()));
+                            match project {
+                                Ok(project) => {
+                                    self.pmm_project = Some((p, project));
+                                    self.pmm_window_open = true;
+                                },
+                                Err(e) => self.show_error(&format!("Failed to load {}:\n{}", p.display(), e)),
                             }
-                            self.log_text += &buf;
                         }
                         ui.close_menu();
                     }
@@ -258,15 +1108,20 @@ impl eframe::App for TemplateApp {
                         ui.close_menu();
                     }
                     if ui.button("Add UV Sphere").clicked() {
+                        self.record("Add UV Sphere", true, false);
                         if let Some(m) = &mut self.pmx_data {
-                            let mut m = m.lock();
-                            add_sphere(&mut m, 32, 16, 1.0);
+                            {
+                                let mut m = m.lock();
+                                add_sphere(&mut m, 32, 16, 1.0);
+                            }
+                            self.custom3d.lock().load_mesh(m.clone());
                         }
                         ui.close_menu();
                     }
                     ui.separator();
                     ui.menu_button("Material", |ui| {
                         if ui.button("Merge").clicked() {
+                            self.record("Merge Materials", true, false);
                             if let Some(m) = &mut self.pmx_data {
                                 {
                                     let mut m = m.lock();
@@ -283,6 +1138,9 @@ impl eframe::App for TemplateApp {
                     if ui.checkbox(&mut self.show_model_view.lock(), "Show Model View").clicked() {
                         ui.close_menu();
                     }
+                    if ui.checkbox(&mut self.history_window_open, "History").clicked() {
+                        ui.close_menu();
+                    }
                 });
                 ui.menu_button("Help", |ui| {
                     if ui.button("Log").clicked() {
@@ -295,9 +1153,9 @@ impl eframe::App for TemplateApp {
                 ui.selectable_value(&mut self.page, Page::Material, "Material");
                 ui.selectable_value(&mut self.page, Page::Bone, "Bone");
                 ui.selectable_value(&mut self.page, Page::Morph, "Morph");
-                // ui.selectable_value(&mut self.page, Page::Frame, "Frame");
-                // ui.selectable_value(&mut self.page, Page::RigidBody, "RigidBody");
-                // ui.selectable_value(&mut self.page, Page::Joint, "Joint");
+                ui.selectable_value(&mut self.page, Page::Frame, "Frame");
+                ui.selectable_value(&mut self.page, Page::RigidBody, "RigidBody");
+                ui.selectable_value(&mut self.page, Page::Joint, "Joint");
                 ui.selectable_value(&mut self.page, Page::VmdBone, "VmdBone");
                 ui.selectable_value(&mut self.page, Page::VmdMorph, "VmdMorph");
                 ui.selectable_value(&mut self.page, Page::VmdCamera, "VmdCamera");
@@ -389,6 +1247,47 @@ impl eframe::App for TemplateApp {
                         ui.heading(text);
                     });
                     ui.separator();
+                    ui.horizontal(|ui| {
+                        let cur = self.pmx_bone_cur_value;
+                        let count = names.len();
+                        if ui.button("Add").clicked() {
+                            let index = (cur + 1).min(count);
+                            self.apply_edit(ctx, "Add Bone", EditScope::Model, |pmx| {
+                                let parent = pmx.bones.get(cur);
+                                let bone = Bone {
+                                    name: format!("Bone{}", count),
+                                    name_en: format!("Bone{}", count),
+                                    pos: parent.map_or(Vec3::ZERO, |p| p.pos),
+                                    parent_index: parent.map(|_| cur),
+                                    bone_flags: BoneFlags::ROTATABLE | BoneFlags::VISIBLE | BoneFlags::ENABLED,
+                                    ..Default::default()
+                                };
+                                pmx.insert_bone(index, bone);
+                            });
+                            self.pmx_bone_cur_value = index;
+                        }
+                        if cur < count {
+                            if ui.button("Duplicate").clicked() {
+                                self.apply_edit(ctx, "Duplicate Bone", EditScope::Model, |pmx| {
+                                    pmx.duplicate_bone(cur);
+                                });
+                                self.pmx_bone_cur_value = cur + 1;
+                            }
+                            if ui.button("Delete").clicked() {
+                                self.apply_edit(ctx, "Delete Bone", EditScope::Model, |pmx| pmx.delete_bones(&BTreeSet::from([cur])));
+                                self.pmx_bone_cur_value = cur.min(count.saturating_sub(2));
+                            }
+                            if ui.add_enabled(cur > 0, egui::Button::new("Up")).clicked() {
+                                self.apply_edit(ctx, "Move Bone", EditScope::Model, |pmx| pmx.move_bone(cur, cur - 1));
+                                self.pmx_bone_cur_value = cur - 1;
+                            }
+                            if ui.add_enabled(cur + 1 < count, egui::Button::new("Down")).clicked() {
+                                self.apply_edit(ctx, "Move Bone", EditScope::Model, |pmx| pmx.move_bone(cur, cur + 1));
+                                self.pmx_bone_cur_value = cur + 1;
+                            }
+                        }
+                    });
+                    ui.separator();
                     ScrollArea::vertical().auto_shrink([false; 2]).show_rows(
                         ui,
                         ui.text_style_height(&TextStyle::Body),
@@ -417,6 +1316,30 @@ impl eframe::App for TemplateApp {
                         let text = format!("Count: {}", names.len());
                         ui.heading(text);
                     });
+                    let count = names.len();
+                    if let Some(cur) = self.pmx_mat_cur_value.iter().next().copied() {
+                        ui.horizontal(|ui| {
+                            if ui.button("Duplicate").clicked() {
+                                self.apply_edit(ctx, "Duplicate Material", EditScope::Mesh, |pmx| {
+                                    pmx.duplicate_mat(cur);
+                                });
+                                self.pmx_mat_cur_value = BTreeSet::from([cur + 1]);
+                            }
+                            if ui.button("Split UV Islands").clicked() {
+                                self.apply_edit(ctx, "Split Material", EditScope::Mesh, |pmx| {
+                                    pmx.split_mat_by_uv_islands(cur);
+                                });
+                            }
+                            if ui.add_enabled(cur > 0, egui::Button::new("Up")).clicked() {
+                                self.apply_edit(ctx, "Move Material", EditScope::Mesh, |pmx| pmx.move_mat(cur, cur - 1));
+                                self.pmx_mat_cur_value = BTreeSet::from([cur - 1]);
+                            }
+                            if ui.add_enabled(cur + 1 < count, egui::Button::new("Down")).clicked() {
+                                self.apply_edit(ctx, "Move Material", EditScope::Mesh, |pmx| pmx.move_mat(cur, cur + 1));
+                                self.pmx_mat_cur_value = BTreeSet::from([cur + 1]);
+                            }
+                        });
+                    }
                     ui.separator();
                     ScrollArea::vertical().auto_shrink([false; 2]).show_rows(
                         ui,
@@ -457,6 +1380,45 @@ impl eframe::App for TemplateApp {
                         ui.heading(text);
                     });
                     ui.separator();
+                    ui.horizontal(|ui| {
+                        let cur = self.pmx_morph_cur_value;
+                        let count = names.len();
+                        if ui.button("Add").clicked() {
+                            let index = (cur + 1).min(count);
+                            self.apply_edit(ctx, "Add Morph", EditScope::Model, |pmx| {
+                                let morph = MorphInfo {
+                                    name: format!("Morph{}", count),
+                                    name_en: format!("Morph{}", count),
+                                    panel: 4,
+                                    category: 1,
+                                    data: Morph::MorphVertex(Vec::new()),
+                                };
+                                pmx.insert_morph(index, morph);
+                            });
+                            self.pmx_morph_cur_value = index;
+                        }
+                        if cur < count {
+                            if ui.button("Duplicate").clicked() {
+                                self.apply_edit(ctx, "Duplicate Morph", EditScope::Model, |pmx| {
+                                    pmx.duplicate_morph(cur);
+                                });
+                                self.pmx_morph_cur_value = cur + 1;
+                            }
+                            if ui.button("Delete").clicked() {
+                                self.apply_edit(ctx, "Delete Morph", EditScope::Model, |pmx| pmx.delete_morphs(&BTreeSet::from([cur])));
+                                self.pmx_morph_cur_value = cur.min(count.saturating_sub(2));
+                            }
+                            if ui.add_enabled(cur > 0, egui::Button::new("Up")).clicked() {
+                                self.apply_edit(ctx, "Move Morph", EditScope::Model, |pmx| pmx.move_morph(cur, cur - 1));
+                                self.pmx_morph_cur_value = cur - 1;
+                            }
+                            if ui.add_enabled(cur + 1 < count, egui::Button::new("Down")).clicked() {
+                                self.apply_edit(ctx, "Move Morph", EditScope::Model, |pmx| pmx.move_morph(cur, cur + 1));
+                                self.pmx_morph_cur_value = cur + 1;
+                            }
+                        }
+                    });
+                    ui.separator();
                     ScrollArea::vertical().auto_shrink([false; 2]).show_rows(
                         ui,
                         ui.text_style_height(&TextStyle::Body),
@@ -471,6 +1433,24 @@ impl eframe::App for TemplateApp {
                         },
                     );
                 },
+                Page::RigidBody => {
+                    let names: Vec<String> = self.pmx_data.as_ref()
+                        .map(|m| m.lock().rigidbodys.iter().map(|r| r.name.clone()).collect())
+                        .unwrap_or_default();
+                    name_list(ui, &names, &mut self.pmx_rigidbody_cur_value);
+                },
+                Page::Joint => {
+                    let names: Vec<String> = self.pmx_data.as_ref()
+                        .map(|m| m.lock().joints.iter().map(|j| j.name.clone()).collect())
+                        .unwrap_or_default();
+                    name_list(ui, &names, &mut self.pmx_joint_cur_value);
+                },
+                Page::Frame => {
+                    let names: Vec<String> = self.pmx_data.as_ref()
+                        .map(|m| m.lock().display_frames.iter().map(|f| format!("{} ({})", f.name, f.morph_items.len())).collect())
+                        .unwrap_or_default();
+                    name_list(ui, &names, &mut self.pmx_frame_cur_value);
+                },
                 _ => {},
             });
         }
@@ -484,42 +1464,90 @@ impl eframe::App for TemplateApp {
                 }
             },
             Page::Material => {
-                if let Some(m) = &self.pmx_data {
+                let cur = self.pmx_mat_cur_value.iter().next().copied();
+                let item = self.pmx_data.as_ref().zip(cur).and_then(|(m, i)| {
                     let m = m.lock();
-                    if m.mats.len() > 0 && self.pmx_mat_cur_value.len() > 0 {
-                        let mat = &m.mats[*self.pmx_mat_cur_value.first().unwrap()];
-                        ui.heading(format!("Name: {}", mat.name));
-                        ui.label(format!("Associated Face Count: {}", mat.associated_face_count));
-                        ui.label(format!("NameEn: {}", mat.name_en));
-                        ui.label(format!("Comment: {}", mat.comment));
-                        ui.label(format!("Diffuse: {}", mat.diffuse));
-                        ui.label(format!("Specular: {}", mat.specular));
-                        ui.label(format!("Ambient: {}", mat.ambient));
-                        ui.label(format!("Edge Color: {}", mat.edge_color));
-                        ui.label(format!("Edge Scale: {}", mat.edge_scale));
-                        if mat.tex_index >= 0 {
-                            ui.label(format!("Tex: {}", m.texs[mat.tex_index as usize]));
-                        } else {
-                            ui.label("Tex: None");
-                        }
-                        if mat.env_index >= 0 {
-                            ui.label(format!("MatCap Tex: {}", m.texs[mat.env_index as usize]));
-                        } else {
-                            ui.label("MatCap Tex: None");
-                        }
-                        ui.label(format!("Matcap Blend Mode: {:?}", mat.env_blend_mode));
-                        match mat.toon {
-                            crate::format::pmx::Toon::Tex(i) => {
-                                if i >= 0 {
-                                    ui.label(format!("Toon Tex: {}", m.texs[i as usize]));
-                                } else {
-                                    ui.label("Toon Tex: None");
-                                }
-                            },
-                            crate::format::pmx::Toon::Inner(i) => {
-                                ui.label(format!("Toon Tex: toon{:02}.bmp", i));
-                            },
+                    m.mats.get(i).map(|mat| (mat.clone(), m.texs.clone()))
+                });
+                if let (Some(i), Some((mut mat, texs))) = (cur, item) {
+                    let (tex_index, env_index, toon) = (mat.tex_index, mat.env_index, mat.toon);
+                    ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+                        if material_form(ui, &mut mat, &texs) {
+                            // textures are bound when the viewport resources are built
+                            let scope = if mat.tex_index != tex_index || mat.env_index != env_index || mat.toon != toon {
+                                EditScope::Mesh
+                            } else {
+                                EditScope::Mat(i)
+                            };
+                            self.apply_edit(ctx, &format!("Edit Material {}", i), scope, |pmx| pmx.mats[i] = mat);
+                        }
+                    });
+                }
+            },
+            Page::Bone => {
+                let cur = self.pmx_bone_cur_value;
+                let item = self.pmx_data.as_ref().and_then(|m| {
+                    let m = m.lock();
+                    m.bones.get(cur).map(|b| (b.clone(), m.bones.iter().map(|b| b.name.clone()).collect::<Vec<_>>()))
+                });
+                if let Some((mut bone, names)) = item {
+                    ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+                        if bone_form(ui, &mut bone, cur, &names) {
+                            self.apply_edit(ctx, &format!("Edit Bone {}", cur), EditScope::Bone(cur), |pmx| pmx.bones[cur] = bone);
+                        }
+                    });
+                }
+            },
+            Page::Morph => {
+                let cur = self.pmx_morph_cur_value;
+                let item = self.pmx_data.as_ref().and_then(|m| m.lock().morphs.get(cur).cloned());
+                if let Some(mut morph) = item {
+                    if morph_form(ui, &mut morph) {
+                        self.apply_edit(ctx, &format!("Edit Morph {}", cur), EditScope::Morph(cur), |pmx| pmx.morphs[cur] = morph);
+                    }
+                }
+            },
+            Page::RigidBody => {
+                let cur = self.pmx_rigidbody_cur_value;
+                let item = self.pmx_data.as_ref().and_then(|m| {
+                    let m = m.lock();
+                    m.rigidbodys.get(cur).map(|r| (r.clone(), m.bones.iter().map(|b| b.name.clone()).collect::<Vec<_>>()))
+                });
+                if let Some((mut rb, bones)) = item {
+                    ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+                        if rigidbody_form(ui, &mut rb, &bones) {
+                            self.apply_edit(ctx, &format!("Edit Rigidbody {}", cur), EditScope::None, |pmx| pmx.rigidbodys[cur] = rb);
                         }
+                    });
+                }
+            },
+            Page::Joint => {
+                let cur = self.pmx_joint_cur_value;
+                let item = self.pmx_data.as_ref().and_then(|m| {
+                    let m = m.lock();
+                    m.joints.get(cur).map(|j| (j.clone(), m.rigidbodys.iter().map(|r| r.name.clone()).collect::<Vec<_>>()))
+                });
+                if let Some((mut joint, rigidbodys)) = item {
+                    ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+                        if joint_form(ui, &mut joint, &rigidbodys) {
+                            self.apply_edit(ctx, &format!("Edit Joint {}", cur), EditScope::None, |pmx| pmx.joints[cur] = joint);
+                        }
+                    });
+                }
+            },
+            Page::Frame => {
+                let cur = self.pmx_frame_cur_value;
+                let item = self.pmx_data.as_ref().and_then(|m| {
+                    let m = m.lock();
+                    m.display_frames.get(cur).map(|f| (
+                        f.clone(),
+                        m.bones.iter().map(|b| b.name.clone()).collect::<Vec<_>>(),
+                        m.morphs.iter().map(|m| m.name.clone()).collect::<Vec<_>>(),
+                    ))
+                });
+                if let Some((mut frame, bones, morphs)) = item {
+                    if display_frame_form(ui, &mut frame, &bones, &morphs) {
+                        self.apply_edit(ctx, &format!("Edit Display Frame {}", cur), EditScope::None, |pmx| pmx.display_frames[cur] = frame);
                     }
                 }
             },
@@ -711,7 +1739,6 @@ impl eframe::App for TemplateApp {
                         });
                     });
             },
-            _ => {},
         });
 
         {
@@ -723,11 +1750,104 @@ impl eframe::App for TemplateApp {
                 ui.text_edit_multiline(&mut self.info_text);
             });
         }
+        {
+            // the undo stack ends with the current state, the redo stack lists the newest undone command last
+            let (undo_labels, redo_labels): (Vec<String>, Vec<String>) = {
+                let history = self.history.lock();
+                (history.undo.iter().map(|c| c.label.clone()).collect(), history.redo.iter().map(|c| c.label.clone()).collect())
+            };
+            let mut undo_steps = 0;
+            let mut redo_steps = 0;
+            egui::Window::new("History")
+                .scroll([false, true])
+                .open(&mut self.history_window_open)
+                .show(ctx, |ui| {
+                    ui.with_layout(egui::Layout::top_down_justified(egui::Align::LEFT), |ui| {
+                        if ui.selectable_label(undo_labels.is_empty(), "Initial State").clicked() {
+                            undo_steps = undo_labels.len();
+                        }
+                        for (i, label) in undo_labels.iter().enumerate() {
+                            if ui.selectable_label(i + 1 == undo_labels.len(), label).clicked() {
+                                undo_steps = undo_labels.len() - i - 1;
+                            }
+                        }
+                        for (i, label) in redo_labels.iter().enumerate().rev() {
+                            let text = egui::RichText::new(label).weak();
+                            if ui.selectable_label(false, text).clicked() {
+                                redo_steps = redo_labels.len() - i;
+                            }
+                        }
+                    });
+                });
+            // the target state is restored once, however many entries the jump skips
+            if undo_steps > 0 {
+                let history = self.history.clone();
+                history.lock().undo_steps(undo_steps, |states| self.restore(states));
+            }
+            if redo_steps > 0 {
+                let history = self.history.clone();
+                history.lock().redo_steps(redo_steps, |states| self.restore(states));
+            }
+            if undo_steps + redo_steps > 0 {
+                ctx.request_repaint_of(self.model_viewport_id);
+            }
+        }
+        if let Some((pmm_path, project)) = &mut self.pmm_project {
+            // every model can be pointed at another PMX, its tracks follow by bone and morph name
+            let mut error = None;
+            let mut saved = None;
+            egui::Window::new("PMM Models")
+                .scroll([false, true])
+                .open(&mut self.pmm_window_open)
+                .show(ctx, |ui| {
+                    ui.label(pmm_path.display().to_string());
+                    egui::Grid::new("pmm_models").striped(true).show(ui, |ui| {
+                        for i in 0..project.models.len() {
+                            ui.label(&project.models[i].name);
+                            ui.label(&project.models[i].path);
+                            if ui.button("Replace ...").clicked() {
+                                if let Some(p) = rfd::FileDialog::new().add_filter("Model", &["pmx", "pmd"]).pick_file() {
+                                    let ext = p.extension().unwrap_or_default().to_ascii_lowercase();
+                                    let pmx = std::fs::read(&p).map_err(|e| e.to_string()).and_then(|content| {
+                                        if ext == OsStr::new("pmd") {
+                                            Pmx::read_pmd(content, &p.to_string_lossy()).map_err(|e| e.to_string())
+                                        } else {
+                                            Pmx::read(content, &p.to_string_lossy()).map_err(|e| e.to_string())
+                                        }
+                                    });
+                                    let rebound = pmx.and_then(|pmx| {
+                                        project.rebind_model(i, &pmx, &p.display().to_string()).map_err(|e| e.to_string())
+                                    });
+                                    if let Err(e) = rebound {
+                                        error = Some(format!("Failed to load {}:\n{}", p.display(), e));
+                                    }
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+                    if ui.button("Save As ...").clicked() {
+                        if let Some(out) = rfd::FileDialog::new()
+                            .add_filter("Polygon Movie Maker", &["pmm"])
+                            .save_file() {
+                            std::fs::write(&out, project.write()).unwrap();
+                            saved = Some(out);
+                        }
+                    }
+                });
+            if let Some(out) = saved {
+                self.log_text += &format!("Saved {}\n", out.display());
+            }
+            if let Some(e) = error {
+                self.show_error(&e);
+            }
+        }
         {
             let show_model_view = self.show_model_view.clone();
             if *show_model_view.lock() {
                 let custom3d = self.custom3d.clone();
                 let pmx_data = self.pmx_data.clone();
+                let history = self.history.clone();
                 let model_viewport_id = self.model_viewport_id;
                 ctx.show_viewport_deferred(
                     model_viewport_id,
@@ -738,6 +1858,7 @@ impl eframe::App for TemplateApp {
                         if custom3d.lock().show_material_filter {
                             let custom3d = custom3d.clone();
                             let pmx_data = pmx_data.clone();
+                            let history = history.clone();
                             ctx.show_viewport_deferred(
                                 egui::ViewportId::from_hash_of("deferred_viewport"),
                                 egui::ViewportBuilder::default()
@@ -775,11 +1896,13 @@ impl eframe::App for TemplateApp {
                                                 if let Some(m) = &pmx_data {
                                                     {
                                                         let mut m = m.lock();
+                                                        history.lock().push("Delete Materials", vec![Snapshot::Model(Box::new(m.clone()))]);
                                                         m.delete_mats(&mats_need_delete);
                                                     }
                                                     custom3d.load_mesh(m.clone());
                                                 }
                                                 ctx.request_repaint_of(model_viewport_id);
+                                                ctx.request_repaint_of(ViewportId::ROOT);
                                             }
                                         });
                                         let text_style = TextStyle::Body;
@@ -816,7 +1939,30 @@ impl eframe::App for TemplateApp {
                                 ui.checkbox(&mut custom3d.draw_flag.gray, "gray");
                                 ui.checkbox(&mut custom3d.draw_flag.use_texture, "texture");
                                 ui.checkbox(&mut custom3d.show_material_filter, "filter");
+                                ui.checkbox(&mut custom3d.follow_motion_camera, "motion camera");
+                                if ui.button("capture camera").clicked() {
+                                    custom3d.capture_camera();
+                                    ctx.request_repaint_of(ViewportId::ROOT);
+                                }
                             });
+                            if custom3d.playback.motion.is_some() {
+                                ui.horizontal(|ui| {
+                                    let playback = &mut custom3d.playback;
+                                    if ui.button(if playback.playing { "Pause" } else { "Play" }).clicked() {
+                                        playback.playing = !playback.playing;
+                                    }
+                                    if ui.button("|<").clicked() {
+                                        playback.seek(0.0);
+                                    }
+                                    let mut frame = playback.frame;
+                                    let max_frame = playback.max_frame() as f32;
+                                    ui.spacing_mut().slider_width = ui.available_width() - 80.0;
+                                    if ui.add(egui::Slider::new(&mut frame, 0.0..=max_frame).fixed_decimals(0)).changed() {
+                                        playback.playing = false;
+                                        playback.seek(frame);
+                                    }
+                                });
+                            }
                             egui::Frame::canvas(ui.style()).show(ui, |ui| {
                                 custom3d.custom_painting(ui);
                             });
@@ -2,6 +2,7 @@
 use glam::*;
 
 use crate::custom3d::DrawFlag;
+use crate::format::motion::{CameraKeyframe, CameraPose, LINEAR_CAMERA_CURVE};
 
 pub struct Camera {
     pub pos: Vec3,
@@ -118,6 +119,47 @@ impl Camera {
         self.set_not_perspective();
         self.pitch = -90.0;
     }
+
+    /// Follows an MMD camera. MMD looks at the center from a negative distance in its left handed
+    /// space, rotating by yaw, pitch and roll in radians, while the viewport shows models with z flipped.
+    pub fn set_pose(&mut self, pose: &CameraPose) {
+        self.pos = vec3(pose.trans.x, pose.trans.y, -pose.trans.z);
+        self.yaw = -pose.rot.y.to_degrees();
+        self.pitch = -pose.rot.x.to_degrees();
+        self.roll = pose.rot.z.to_degrees();
+        self.dist = -pose.dist;
+        self.fov = pose.fov;
+        self.perspective = pose.perspective;
+    }
+
+    /// The MMD camera seeing what the viewport sees, the inverse of `set_pose`
+    pub fn pose(&self) -> CameraPose {
+        CameraPose {
+            dist: -self.dist,
+            trans: vec3(self.pos.x, self.pos.y, -self.pos.z),
+            rot: vec3(-self.pitch.to_radians(), -self.yaw.to_radians(), self.roll.to_radians()),
+            fov: self.fov,
+            perspective: self.perspective,
+        }
+    }
+
+    pub fn to_keyframe(&self, frame: u32) -> CameraKeyframe {
+        let pose = self.pose();
+        CameraKeyframe {
+            frame,
+            dist: pose.dist,
+            trans: pose.trans,
+            rot: pose.rot,
+            txc: LINEAR_CAMERA_CURVE,
+            tyc: LINEAR_CAMERA_CURVE,
+            tzc: LINEAR_CAMERA_CURVE,
+            rc: LINEAR_CAMERA_CURVE,
+            dc: LINEAR_CAMERA_CURVE,
+            vc: LINEAR_CAMERA_CURVE,
+            fov: pose.fov.round().max(1.0) as u32,
+            perspective: pose.perspective,
+        }
+    }
 }
 
 #[repr(C)]
@@ -152,3 +194,49 @@ impl CameraUniform {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::motion::interpolate_camera;
+
+    fn keyframe(dist: f32, trans: Vec3, rot: Vec3, fov: u32) -> CameraKeyframe {
+        CameraKeyframe {
+            frame: 0,
+            dist,
+            trans,
+            rot,
+            txc: LINEAR_CAMERA_CURVE,
+            tyc: LINEAR_CAMERA_CURVE,
+            tzc: LINEAR_CAMERA_CURVE,
+            rc: LINEAR_CAMERA_CURVE,
+            dc: LINEAR_CAMERA_CURVE,
+            vc: LINEAR_CAMERA_CURVE,
+            fov,
+            perspective: true,
+        }
+    }
+
+    #[test]
+    fn keyframe_round_trips_through_the_viewport() {
+        let kf = keyframe(-45.0, vec3(1.0, 10.0, 2.0), vec3(0.2, 0.5, -0.1), 30);
+        let mut camera = Camera::new();
+        camera.set_pose(&interpolate_camera(&[kf], 0.0).unwrap());
+        let back = camera.to_keyframe(0);
+        assert!((back.dist - kf.dist).abs() < 1e-4);
+        assert!(back.trans.abs_diff_eq(kf.trans, 1e-4));
+        assert!(back.rot.abs_diff_eq(kf.rot, 1e-4));
+        assert_eq!((back.fov, back.perspective), (30, true));
+    }
+
+    #[test]
+    fn viewport_mirrors_the_mmd_camera() {
+        // MMD's default camera sits 45 in front of the center at -z, the viewport flips z
+        let kf = keyframe(-45.0, vec3(0.0, 10.0, 2.0), Vec3::ZERO, 30);
+        let mut camera = Camera::new();
+        camera.set_pose(&interpolate_camera(&[kf], 0.0).unwrap());
+        assert_eq!(camera.pos, vec3(0.0, 10.0, -2.0));
+        assert!(camera.real_pos().abs_diff_eq(vec3(0.0, 10.0, 43.0), 1e-4));
+        assert!(camera.direction().abs_diff_eq(Vec3::NEG_Z, 1e-6));
+    }
+}
@@ -20,7 +20,9 @@ pub const IMAGE_TOONS: LazyLock<Vec<RgbaImage>> = LazyLock::new(|| {
     res
 });
 
-use crate::{camera::{Camera, CameraUniform}, grid::{ GridRenderResources, CustomGridCallback}, format::pmx::{Pmx, DrawFlags}, texture::TextureWrapper};
+use crate::{camera::{Camera, CameraUniform}, grid::{ GridRenderResources, CustomGridCallback}, format::{motion::{CameraKeyframe, Motion}, pmx::{Pmx, Mat, Bone, MorphInfo, DrawFlags}}, texture::TextureWrapper};
+use crate::playback::{Playback, PlaybackPose};
+use crate::morph::MatMorph;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -57,12 +59,29 @@ struct MatUniform {
 unsafe impl bytemuck::Pod for MatUniform {}
 unsafe impl bytemuck::Zeroable for MatUniform {}
 
+impl MatUniform {
+    fn from_mat(mat: &Mat) -> Self {
+        Self {
+            diffuse: mat.diffuse,
+            specular: mat.specular,
+            ambient: mat.ambient.extend(0.0),
+        }
+    }
+}
+
 pub struct Custom3d {
     camera: Camera,
     wgpu_render_state: RenderState,
     pub draw_flag: DrawFlag,
     pub filters: Vec<(String, bool)>,
     pub show_material_filter: bool,
+    pub playback: Playback,
+    /// view through the camera track of the motion being played
+    pub follow_motion_camera: bool,
+    /// camera keyframes captured from the viewport, for the app to add to its motion
+    captured_camera_keyframes: Vec<CameraKeyframe>,
+    /// edited materials and their indexes waiting to be uploaded with the next paint
+    pending_mats: Vec<(usize, Mat)>,
 }
 
 impl Custom3d {
@@ -85,6 +104,10 @@ impl Custom3d {
             draw_flag: Default::default(),
             filters: Vec::new(),
             show_material_filter: false,
+            playback: Playback::new(),
+            follow_motion_camera: false,
+            captured_camera_keyframes: Vec::new(),
+            pending_mats: Vec::new(),
         }
     }
     pub fn load_mesh(&mut self, pmx: Arc<Mutex<Pmx>>) {
@@ -93,6 +116,7 @@ impl Custom3d {
         for m in &pmx.mats {
             self.filters.push((m.name.clone(), true));
         }
+        self.playback.set_model(&pmx);
         self.wgpu_render_state
             .renderer
             .write()
@@ -102,6 +126,36 @@ impl Custom3d {
                 pmx.clone(),
             ));
     }
+    /// Updates the pose after edits that add, remove or reorder bones or morphs, cheaper than `load_mesh`
+    pub fn refresh_model(&mut self, pmx: &Pmx) {
+        self.playback.set_model(pmx);
+    }
+    /// Uploads an edited material that keeps its textures
+    pub fn refresh_mat(&mut self, index: usize, mat: &Mat) {
+        if let Some((name, _)) = self.filters.get_mut(index) {
+            *name = mat.name.clone();
+        }
+        self.pending_mats.retain(|(i, _)| *i != index);
+        self.pending_mats.push((index, mat.clone()));
+    }
+    pub fn refresh_bone(&mut self, index: usize, bone: &Bone) {
+        self.playback.set_bone(index, bone);
+    }
+    pub fn refresh_morph(&mut self, index: usize, morph: &MorphInfo) {
+        self.playback.set_morph(index, morph);
+    }
+    pub fn set_motion(&mut self, motion: Option<Motion>) {
+        self.playback.set_motion(motion);
+    }
+    /// Adds the current view as a camera keyframe at the current frame of the playback
+    pub fn capture_camera(&mut self) {
+        let keyframe = self.camera.to_keyframe(self.playback.frame.round() as u32);
+        self.playback.motion.get_or_insert_with(Motion::new).insert_camera_keyframe(keyframe);
+        self.captured_camera_keyframes.push(keyframe);
+    }
+    pub fn take_captured_camera_keyframes(&mut self) -> Vec<CameraKeyframe> {
+        std::mem::take(&mut self.captured_camera_keyframes)
+    }
 }
 
 // Callbacks in egui_wgpu have 3 stages:
@@ -128,6 +182,8 @@ struct CustomTriangleCallback {
     camera_uniform: CameraUniform,
     draw_wireframe: bool,
     filters: Vec<(String, bool)>,
+    pose: Option<PlaybackPose>,
+    mats: Vec<(usize, Mat)>,
 }
 
 impl egui_wgpu::CallbackTrait for CustomTriangleCallback {
@@ -140,7 +196,7 @@ impl egui_wgpu::CallbackTrait for CustomTriangleCallback {
         resources: &mut egui_wgpu::CallbackResources,
     ) -> Vec<wgpu::CommandBuffer> {
         if let Some(resources) = resources.get_mut::<TriangleRenderResources>() {
-            resources.prepare(device, queue, self.camera_uniform, self.draw_wireframe, self.filters.clone());
+            resources.prepare(device, queue, self.camera_uniform, self.draw_wireframe, self.filters.clone(), self.pose.as_ref(), self.mats.clone());
         }
         Vec::new()
     }
@@ -162,8 +218,18 @@ impl Custom3d {
         let (rect, response) =
             ui.allocate_exact_size(ui.available_size_before_wrap(), egui::Sense::drag());
 
-        // manipulate camera
-        {
+        self.playback.advance(ui.input(|i| i.stable_dt));
+        if self.playback.playing {
+            ui.ctx().request_repaint();
+        }
+        let motion_camera = self.playback.motion.as_ref()
+            .filter(|_| self.follow_motion_camera)
+            .and_then(|m| m.sample_camera(self.playback.frame));
+        if let Some(pose) = motion_camera {
+            self.camera.set_pose(&pose);
+            self.camera.aspect_ratio = rect.aspect_ratio();
+        } else {
+            // manipulate camera
             if ui.input(|i| i.modifiers.shift) {
                 self.camera.pan(response.drag_delta().x, response.drag_delta().y);
             } else {
@@ -179,6 +245,8 @@ impl Custom3d {
                 camera_uniform: CameraUniform::from_camera(&self.camera, self.draw_flag),
                 draw_wireframe: self.draw_flag.wireframe,
                 filters: self.filters.clone(),
+                pose: self.playback.update(),
+                mats: std::mem::take(&mut self.pending_mats),
             },
         ));
         ui.painter().add(egui_wgpu::Callback::new_paint_callback(
@@ -196,6 +264,11 @@ struct TriangleRenderResources {
     vert_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
     mat_bind_groups: Vec<wgpu::BindGroup>,
+    mat_uniform_buffers: Vec<wgpu::Buffer>,
+    /// diffuse alpha of each material including morphs, fully transparent materials are skipped
+    mat_alphas: Vec<f32>,
+    /// material morphs of the last pose, kept to upload edited materials
+    mat_morphs: Vec<MatMorph>,
     pmx: Pmx,
     wireframe_pipeline: wgpu::RenderPipeline,
     draw_wireframe: bool,
@@ -270,14 +343,11 @@ impl TriangleRenderResources {
             toon_texture_wrappers.push(TextureWrapper::from_image(&device, &queue, tex_image, None));
         }
         let mut mat_bind_groups = Vec::new();
+        let mut mat_uniform_buffers = Vec::new();
         for mat in &pmx.mats {
             let mat_uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("mat uniform"),
-                contents: bytemuck::cast_slice(&[MatUniform {
-                    diffuse: mat.diffuse,
-                    specular: mat.specular,
-                    ambient: mat.ambient.extend(0.0),
-                }]),
+                contents: bytemuck::cast_slice(&[MatUniform::from_mat(mat)]),
                 usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
             });
             let tex_index = if tex_images.contains_key(&mat.tex_index) { mat.tex_index } else { -1 };
@@ -318,6 +388,7 @@ impl TriangleRenderResources {
                 label: Some("mat_bind_group"),
             });
             mat_bind_groups.push(mat_bind_group);
+            mat_uniform_buffers.push(mat_uniform_buffer);
         }
 
         let shader_path = Path::new("shader/mesh.wgsl");
@@ -469,7 +540,7 @@ impl TriangleRenderResources {
         let vert_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("custom3d vert"),
             contents: bytemuck::cast_slice(&verts),
-            usage: wgpu::BufferUsages::VERTEX,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
 
         let index_buffer = device.create_buffer_init(
@@ -496,21 +567,54 @@ impl TriangleRenderResources {
             vert_buffer,
             index_buffer,
             mat_bind_groups,
+            mat_uniform_buffers,
+            mat_alphas: pmx.mats.iter().map(|m| m.diffuse.w).collect(),
+            mat_morphs: Vec::new(),
             pmx,
             wireframe_pipeline,
             draw_wireframe: false,
             filters: Vec::new(),
         }
     }
+    #[allow(clippy::too_many_arguments)]
     fn prepare(
         &mut self, _device: &wgpu::Device,
         queue: &wgpu::Queue,
         camera_uniform: CameraUniform,
         draw_wireframe: bool,
         filters: Vec<(String, bool)>,
+        pose: Option<&PlaybackPose>,
+        mats: Vec<(usize, Mat)>,
     ) {
         self.draw_wireframe = draw_wireframe;
         self.filters = filters;
+        let mut changed: Vec<usize> = Vec::new();
+        for (i, mat) in mats {
+            if let Some(m) = self.pmx.mats.get_mut(i) {
+                *m = mat;
+                changed.push(i);
+            }
+        }
+        if let Some(pose) = pose {
+            let mesh = &pose.mesh;
+            let verts: Vec<Vertex> = (0..mesh.pos.len())
+                .map(|i| Vertex { pos: mesh.pos[i], nrm: mesh.nrm[i], uv: mesh.uv[i] })
+                .collect();
+            queue.write_buffer(&self.vert_buffer, 0, bytemuck::cast_slice(&verts));
+            self.mat_morphs = pose.mats.clone();
+            // material morphs may have changed any of the materials
+            changed = (0..self.pmx.mats.len()).collect();
+        }
+        for i in changed {
+            if let Some(buffer) = self.mat_uniform_buffers.get(i) {
+                let mut mat = self.pmx.mats[i].clone();
+                if let Some(morph) = self.mat_morphs.get(i) {
+                    morph.apply(&mut mat);
+                }
+                self.mat_alphas[i] = mat.diffuse.w;
+                queue.write_buffer(buffer, 0, bytemuck::cast_slice(&[MatUniform::from_mat(&mat)]));
+            }
+        }
         // Update our uniform buffer with the angle from the UI
         queue.write_buffer(
             &self.uniform_buffer,
@@ -529,7 +633,7 @@ impl TriangleRenderResources {
             if self.filters[i].1 == false {
                 continue;
             }
-            if mat.diffuse.w == 0.0 {
+            if self.mat_alphas[i] == 0.0 {
                 continue;
             }
             let mut start_index = 0;
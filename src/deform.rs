@@ -0,0 +1,254 @@
+#![allow(dead_code)]
+
+use glam::*;
+
+use crate::format::pmx::*;
+use crate::morph::MorphOffsets;
+use crate::skeleton::Skeleton;
+
+/// Vertex data of a `Pmx` after morphs and skinning, indexed like `Pmx::verts`
+#[derive(Clone)]
+pub struct DeformedMesh {
+    pub pos: Vec<Vec3>,
+    pub nrm: Vec<Vec3>,
+    pub uv: Vec<Vec2>,
+    pub appendix_uvs: Vec<Vec<Vec4>>,
+}
+
+impl Skeleton {
+    /// Matrices taking rest positions to the current pose, as used for skinning
+    pub fn skinning_matrices(&self, pmx: &Pmx) -> Vec<Mat4> {
+        pmx.bones.iter().enumerate()
+            .map(|(i, bone)| self.global[i] * Mat4::from_translation(-bone.pos))
+            .collect()
+    }
+}
+
+fn bone_matrix(skin: &[Mat4], i: i32) -> Mat4 {
+    usize::try_from(i).ok().and_then(|i| skin.get(i)).copied().unwrap_or(Mat4::IDENTITY)
+}
+
+/// Dual quaternion of a rigid transform, the translation part is 0.5 * t * q
+fn dual_quat(m: Mat4) -> (Quat, Quat) {
+    let (_, q, t) = m.to_scale_rotation_translation();
+    let d = Quat::from_xyzw(t.x, t.y, t.z, 0.0) * q * 0.5;
+    (q, d)
+}
+
+fn skin_linear(skin: &[Mat4], bones: &[(i32, f32)], pos: Vec3, nrm: Vec3) -> (Vec3, Vec3) {
+    let mut m = Mat4::ZERO;
+    for (i, w) in bones {
+        if *i >= 0 && *w != 0.0 {
+            m += bone_matrix(skin, *i) * *w;
+        }
+    }
+    (m.transform_point3(pos), m.transform_vector3(nrm).normalize_or_zero())
+}
+
+/// SDEF as in MMD: the vertex rotates with the slerped bone rotation around the center C,
+/// which follows both bones through the midpoints of C and the corrected R0 and R1
+#[allow(clippy::too_many_arguments)]
+fn skin_sphere(skin: &[Mat4], i0: i32, i1: i32, w0: f32, c: Vec3, r0: Vec3, r1: Vec3, pos: Vec3, nrm: Vec3) -> (Vec3, Vec3) {
+    let w1 = 1.0 - w0;
+    let m0 = bone_matrix(skin, i0);
+    let m1 = bone_matrix(skin, i1);
+    let rw = r0 * w0 + r1 * w1;
+    let cr0 = (c + (c + r0 - rw)) * 0.5;
+    let cr1 = (c + (c + r1 - rw)) * 0.5;
+    let q0 = Quat::from_mat4(&m0).normalize();
+    let q1 = Quat::from_mat4(&m1).normalize();
+    let rot = q0.slerp(q1, w1);
+    let pos = rot * (pos - c) + m0.transform_point3(cr0) * w0 + m1.transform_point3(cr1) * w1;
+    (pos, (rot * nrm).normalize_or_zero())
+}
+
+/// QDEF, dual quaternion blending of up to four bones
+fn skin_dual_quat(skin: &[Mat4], bones: &[(i32, f32)], pos: Vec3, nrm: Vec3) -> (Vec3, Vec3) {
+    let mut real = Quat::from_xyzw(0.0, 0.0, 0.0, 0.0);
+    let mut dual = Quat::from_xyzw(0.0, 0.0, 0.0, 0.0);
+    let mut first: Option<Quat> = None;
+    for (i, w) in bones {
+        if *i < 0 || *w == 0.0 {
+            continue;
+        }
+        let (q, d) = dual_quat(bone_matrix(skin, *i));
+        // blend on the hemisphere of the first rotation
+        let w = if first.get_or_insert(q).dot(q) < 0.0 { -*w } else { *w };
+        real = real + q * w;
+        dual = dual + d * w;
+    }
+    let len = real.length();
+    if len == 0.0 {
+        return (pos, nrm);
+    }
+    let real = real / len;
+    let dual = dual / len;
+    let t = (dual * real.conjugate()) * 2.0;
+    (real * pos + vec3(t.x, t.y, t.z), (real * nrm).normalize_or_zero())
+}
+
+impl Pmx {
+    /// Applies the vertex and uv offsets of `morphs` from `Pmx::eval_morphs`,
+    /// then skins every vertex with `skin` from `Skeleton::skinning_matrices`
+    pub fn deform(&self, skin: &[Mat4], morphs: &MorphOffsets) -> DeformedMesh {
+        let mut mesh = DeformedMesh {
+            pos: self.verts.iter().zip(&morphs.vertex).map(|(v, offset)| v.pos + *offset).collect(),
+            nrm: self.verts.iter().map(|v| v.nrm).collect(),
+            uv: self.verts.iter().zip(&morphs.uv).map(|(v, offset)| v.uv + offset.xy()).collect(),
+            appendix_uvs: self.appendix_uvs.iter().zip(&morphs.appendix_uvs)
+                .map(|(uvs, offsets)| uvs.iter().zip(offsets).map(|(uv, offset)| *uv + *offset).collect())
+                .collect(),
+        };
+
+        for (i, v) in self.verts.iter().enumerate() {
+            let (pos, nrm) = (mesh.pos[i], mesh.nrm[i]);
+            (mesh.pos[i], mesh.nrm[i]) = match v.weight {
+                VertexWeight::One(i0) => skin_linear(skin, &[(i0, 1.0)], pos, nrm),
+                VertexWeight::Two(i0, i1, w) => skin_linear(skin, &[(i0, w), (i1, 1.0 - w)], pos, nrm),
+                VertexWeight::Four(i, w) => {
+                    skin_linear(skin, &[(i.x, w.x), (i.y, w.y), (i.z, w.z), (i.w, w.w)], pos, nrm)
+                }
+                VertexWeight::Sphere(i0, i1, w, c, r0, r1) => skin_sphere(skin, i0, i1, w, c, r0, r1, pos, nrm),
+                VertexWeight::Quat(i, w) => {
+                    skin_dual_quat(skin, &[(i.x, w.x), (i.y, w.y), (i.z, w.z), (i.w, w.w)], pos, nrm)
+                }
+            };
+        }
+        mesh
+    }
+
+    /// Replaces the vertices with a deformed mesh, for exporting a posed model
+    pub fn apply_deformed(&mut self, mesh: &DeformedMesh) {
+        for (i, v) in self.verts.iter_mut().enumerate() {
+            v.pos = mesh.pos[i];
+            v.nrm = mesh.nrm[i];
+            v.uv = mesh.uv[i];
+        }
+        self.appendix_uvs = mesh.appendix_uvs.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    /// A bone at the origin and a child one unit along x, with one vertex of every weight type on a
+    /// tube of radius 0.5 around the x axis at the joint
+    fn arm() -> Pmx {
+        let mut m = Pmx::new();
+        m.bones.push(bone("upper", None, Vec3::ZERO));
+        m.bones.push(bone("lower", Some(0), Vec3::X));
+        let pos = vec3(1.0, 0.5, 0.0);
+        let half = vec4(0.5, 0.5, 0.0, 0.0);
+        for weight in [
+            VertexWeight::One(1),
+            VertexWeight::Two(0, 1, 0.5),
+            VertexWeight::Four(ivec4(0, 1, 0, 0), half),
+            VertexWeight::Sphere(0, 1, 0.5, Vec3::X, Vec3::ZERO, vec3(2.0, 0.0, 0.0)),
+            VertexWeight::Quat(ivec4(0, 1, 0, 0), half),
+        ] {
+            m.verts.push(Vertex { weight, ..vertex(pos) });
+        }
+        m
+    }
+
+    fn pose(m: &Pmx, trans: Vec3, rot: Quat) -> DeformedMesh {
+        let mut s = Skeleton::new(m);
+        s.trans[1] = trans;
+        s.rot[1] = rot;
+        s.evaluate(m);
+        m.deform(&s.skinning_matrices(m), &MorphOffsets::new(m))
+    }
+
+    #[test]
+    fn rest_pose_leaves_vertices_unchanged() {
+        let m = arm();
+        let mesh = pose(&m, Vec3::ZERO, Quat::IDENTITY);
+        for (i, v) in m.verts.iter().enumerate() {
+            assert!(mesh.pos[i].abs_diff_eq(v.pos, 1e-6), "vertex {}: {}", i, mesh.pos[i]);
+            assert!(mesh.nrm[i].abs_diff_eq(v.nrm, 1e-6), "vertex {}: {}", i, mesh.nrm[i]);
+        }
+    }
+
+    #[test]
+    fn bdef2_blends_by_weight() {
+        let m = arm();
+        let mesh = pose(&m, vec3(0.0, 2.0, 0.0), Quat::IDENTITY);
+        assert!(mesh.pos[0].abs_diff_eq(vec3(1.0, 2.5, 0.0), 1e-6));
+        assert!(mesh.pos[1].abs_diff_eq(vec3(1.0, 1.5, 0.0), 1e-6));
+        assert!(mesh.pos[2].abs_diff_eq(vec3(1.0, 1.5, 0.0), 1e-6));
+    }
+
+    #[test]
+    fn sdef_and_qdef_keep_volume_at_half_turn_twist() {
+        let m = arm();
+        let mesh = pose(&m, Vec3::ZERO, Quat::from_rotation_x(PI));
+        let radius = |p: Vec3| p.yz().length();
+        // linear blending collapses onto the bone axis
+        assert!(radius(mesh.pos[1]) < 1e-5);
+        assert!(radius(mesh.pos[2]) < 1e-5);
+        for i in [3, 4] {
+            assert!((radius(mesh.pos[i]) - 0.5).abs() < 1e-5, "vertex {}: {}", i, mesh.pos[i]);
+            assert!((mesh.pos[i].x - 1.0).abs() < 1e-5, "vertex {}: {}", i, mesh.pos[i]);
+            // the normal turns a quarter with the surface
+            assert!(mesh.nrm[i].y.abs() < 1e-5 && (mesh.nrm[i].length() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    /// The arm with a vertex and a bone morph, some of every field `right_hand` mirrors
+    fn mirrored_model() -> Pmx {
+        let mut m = arm();
+        m.bones[1].bone_flags |= BoneFlags::FIXED_AXIS | BoneFlags::LOCAL_AXIS;
+        m.bones[1].fixed_axis = Some(vec3(1.0, 0.0, 1.0));
+        m.bones[1].local_axis = Some((vec3(1.0, 0.0, 0.5), vec3(0.0, 0.5, 1.0)));
+        m.verts[0].nrm = vec3(0.0, 0.6, 0.8);
+        m.verts[3].weight = VertexWeight::Sphere(0, 1, 0.5, vec3(1.0, 0.0, 0.25), vec3(0.0, 0.0, 0.5), vec3(2.0, 0.0, -0.5));
+        m.faces.push([0, 1, 2]);
+        m.mats.push(mat("arm", 1));
+        m.morphs.push(morph("bulge", Morph::MorphVertex(vec![MorphVertexItem { index: 3, trans: vec3(0.1, 0.2, 0.3) }])));
+        m.morphs.push(morph("bend", Morph::MorphBone(vec![MorphBoneItem {
+            index: 1,
+            trans: vec3(0.0, 0.0, 1.0),
+            rot: Quat::from_euler(EulerRot::XYZ, 0.1, 0.2, 0.3),
+        }])));
+        m.rigidbodys.push(Rigidbody { pos: vec3(0.0, 1.0, 2.0), rot: vec3(0.1, 0.2, 0.3), ..rigidbody("arm", 1) });
+        m
+    }
+
+    #[test]
+    fn right_hand_mirrors_z() {
+        let m = mirrored_model();
+        let mut r = m.clone();
+        r.right_hand();
+        let VertexWeight::Sphere(_, _, _, c, r0, r1) = r.verts[3].weight else { panic!() };
+        assert_eq!((c, r0, r1), (vec3(1.0, 0.0, -0.25), vec3(0.0, 0.0, -0.5), vec3(2.0, 0.0, 0.5)));
+        assert_eq!(r.verts[0].nrm, vec3(0.0, 0.6, -0.8));
+        assert_eq!(r.faces[0], [0, 2, 1]);
+        let Morph::MorphVertex(items) = &r.morphs[0].data else { panic!() };
+        assert_eq!(items[0].trans, vec3(0.1, 0.2, -0.3));
+        let Morph::MorphBone(items) = &r.morphs[1].data else { panic!() };
+        let Morph::MorphBone(original) = &m.morphs[1].data else { panic!() };
+        assert_eq!(items[0].trans, vec3(0.0, 0.0, -1.0));
+        let q = original[0].rot;
+        assert_eq!(items[0].rot, Quat::from_xyzw(-q.x, -q.y, q.z, q.w));
+        assert_eq!(r.bones[1].local_axis, Some((vec3(1.0, 0.0, -0.5), vec3(0.0, 0.5, -1.0))));
+
+        r.right_hand();
+        assert_eq!(r.write(), m.write());
+    }
+
+    #[test]
+    fn loading_and_saving_keeps_the_file() {
+        // the app mirrors a model into its right handed space on load and back on save
+        let file = mirrored_model().write();
+        let mut m = Pmx::read(file.clone(), "").unwrap();
+        m.right_hand();
+        let mesh = pose(&m, Vec3::ZERO, Quat::IDENTITY);
+        assert!(mesh.pos[3].abs_diff_eq(vec3(1.0, 0.5, 0.0), 1e-6));
+        m.right_hand();
+        assert_eq!(m.write(), file);
+    }
+}
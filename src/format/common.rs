@@ -3,14 +3,14 @@
 #![allow(unused_variables)]
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Read, Write, Cursor};
+use std::io::{self, Read, Write, Cursor};
 use glam::*;
 
-pub fn read_float2<T>(file: &mut T) -> Vec2
+pub fn read_float2<T>(file: &mut T) -> io::Result<Vec2>
     where T: Read {
-    let x = file.read_f32::<LittleEndian>().unwrap();
-    let y = file.read_f32::<LittleEndian>().unwrap();
-    vec2(x, y)
+    let x = file.read_f32::<LittleEndian>()?;
+    let y = file.read_f32::<LittleEndian>()?;
+    Ok(vec2(x, y))
 }
 pub fn write_float2<T>(file: &mut T, v: Vec2)
     where T: Write {
@@ -18,12 +18,12 @@ pub fn write_float2<T>(file: &mut T, v: Vec2)
     file.write_f32::<LittleEndian>(v.y).unwrap();
 }
 
-pub fn read_float3<T>(file: &mut T) -> Vec3
+pub fn read_float3<T>(file: &mut T) -> io::Result<Vec3>
     where T: Read {
-    let x = file.read_f32::<LittleEndian>().unwrap();
-    let y = file.read_f32::<LittleEndian>().unwrap();
-    let z = file.read_f32::<LittleEndian>().unwrap();
-    vec3(x, y, z)
+    let x = file.read_f32::<LittleEndian>()?;
+    let y = file.read_f32::<LittleEndian>()?;
+    let z = file.read_f32::<LittleEndian>()?;
+    Ok(vec3(x, y, z))
 }
 pub fn write_float3<T>(file: &mut T, v: Vec3)
     where T: Write {
@@ -32,22 +32,22 @@ pub fn write_float3<T>(file: &mut T, v: Vec3)
     file.write_f32::<LittleEndian>(v.z).unwrap();
 }
 
-pub fn read_float4<T>(file: &mut T) -> Vec4
+pub fn read_float4<T>(file: &mut T) -> io::Result<Vec4>
     where T: Read {
-    let x = file.read_f32::<LittleEndian>().unwrap();
-    let y = file.read_f32::<LittleEndian>().unwrap();
-    let z = file.read_f32::<LittleEndian>().unwrap();
-    let w = file.read_f32::<LittleEndian>().unwrap();
-    vec4(x, y, z, w)
+    let x = file.read_f32::<LittleEndian>()?;
+    let y = file.read_f32::<LittleEndian>()?;
+    let z = file.read_f32::<LittleEndian>()?;
+    let w = file.read_f32::<LittleEndian>()?;
+    Ok(vec4(x, y, z, w))
 }
 
-pub fn read_quat<T>(file: &mut T) -> Quat
+pub fn read_quat<T>(file: &mut T) -> io::Result<Quat>
     where T: Read {
-    let x = file.read_f32::<LittleEndian>().unwrap();
-    let y = file.read_f32::<LittleEndian>().unwrap();
-    let z = file.read_f32::<LittleEndian>().unwrap();
-    let w = file.read_f32::<LittleEndian>().unwrap();
-    quat(x, y, z, w)
+    let x = file.read_f32::<LittleEndian>()?;
+    let y = file.read_f32::<LittleEndian>()?;
+    let z = file.read_f32::<LittleEndian>()?;
+    let w = file.read_f32::<LittleEndian>()?;
+    Ok(quat(x, y, z, w))
 }
 pub fn write_float4<T>(file: &mut T, v: Vec4)
     where T: Write {
@@ -1,7 +1,19 @@
 pub(crate) mod motion;
 pub(crate) mod vmd_reader;
 pub(crate) mod vmd_writer;
+pub(crate) mod mvd_reader;
+pub(crate) mod vpd;
 pub(crate) mod pmx;
 pub(crate) mod pmx_writer;
+pub(crate) mod pmx_bones;
+pub(crate) mod pmx_morphs;
+pub(crate) mod pmx_mats;
+pub(crate) mod pmx_merge;
+pub(crate) mod pmx_remap;
+#[cfg(test)]
+pub(crate) mod pmx_fixtures;
+pub(crate) mod pmd;
+pub(crate) mod pmd_writer;
 pub(crate) mod pmm;
+pub(crate) mod pmm_writer;
 pub(crate) mod common;
\ No newline at end of file
@@ -4,7 +4,201 @@
 
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::io;
 use glam::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MotionSection {
+    Header,
+    Model,
+    Bone,
+    Morph,
+    Camera,
+    Light,
+    Shadow,
+    Ik,
+    NameList,
+    Property,
+    Accessory,
+    Gravity,
+    Settings,
+}
+
+impl fmt::Display for MotionSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MotionSection::Header => "header",
+            MotionSection::Model => "model",
+            MotionSection::Bone => "bone",
+            MotionSection::Morph => "morph",
+            MotionSection::Camera => "camera",
+            MotionSection::Light => "light",
+            MotionSection::Shadow => "shadow",
+            MotionSection::Ik => "IK",
+            MotionSection::NameList => "name list",
+            MotionSection::Property => "property",
+            MotionSection::Accessory => "accessory",
+            MotionSection::Gravity => "gravity",
+            MotionSection::Settings => "settings",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Error of the VMD and PMM readers, `model` is only set for the per model tracks of a PMM
+#[derive(Debug)]
+pub struct MotionError {
+    pub model: Option<usize>,
+    pub section: MotionSection,
+    pub index: Option<usize>,
+    pub source: io::Error,
+}
+
+impl MotionError {
+    pub fn new(section: MotionSection, index: Option<usize>, source: io::Error) -> Self {
+        Self {
+            model: None,
+            section,
+            index,
+            source,
+        }
+    }
+
+    pub fn invalid(section: MotionSection, index: Option<usize>, msg: String) -> Self {
+        Self::new(section, index, io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    pub fn in_model(mut self, model: usize) -> Self {
+        self.model = Some(model);
+        self
+    }
+}
+
+impl fmt::Display for MotionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(model) = self.model {
+            write!(f, "model {} ", model)?;
+        }
+        match self.index {
+            Some(index) => write!(f, "{} record {}: {}", self.section, index, self.source),
+            None => write!(f, "{} section: {}", self.section, self.source),
+        }
+    }
+}
+
+impl Error for MotionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Control points of MMD's default linear interpolation curve
+pub const LINEAR_CURVE: Vec4 = Vec4::new(20.0 / 127.0, 20.0 / 127.0, 107.0 / 127.0, 107.0 / 127.0);
+
+/// Control points of the linear curve in the (x1, x2, y1, y2) order of camera keyframes
+pub const LINEAR_CAMERA_CURVE: Vec4 = Vec4::new(20.0 / 127.0, 107.0 / 127.0, 20.0 / 127.0, 107.0 / 127.0);
+
+fn cubic(p1: f64, p2: f64, t: f64) -> f64 {
+    let s = 1.0 - t;
+    3.0 * s * s * t * p1 + 3.0 * s * t * t * p2 + t * t * t
+}
+
+/// Evaluates an interpolation curve with control points (x1, y1, x2, y2) at `x` in 0..1
+pub fn eval_curve(c: Vec4, x: f32) -> f32 {
+    // x(t) is monotonic as long as x1 and x2 are in 0..1, so bisect for t.
+    // Steep curves are flat in x around their middle, which needs more precision than f32 has.
+    let c = c.as_dvec4();
+    let x = x as f64;
+    let (mut lo, mut hi) = (0.0f64, 1.0f64);
+    let mut t = x;
+    for _ in 0..48 {
+        t = (lo + hi) * 0.5;
+        if cubic(c.x, c.z, t) < x {
+            lo = t;
+        } else {
+            hi = t;
+        }
+    }
+    cubic(c.y, c.w, t) as f32
+}
+
+/// Finds the keyframes around `frame` and the linear progress between them,
+/// before the first or after the last keyframe the nearest one is held
+fn surrounding<T, F>(keyframes: &[T], frame: f32, frame_of: F) -> Option<(&T, &T, f32)>
+    where F: Fn(&T) -> u32 {
+    let mut prev: Option<&T> = None;
+    let mut next: Option<&T> = None;
+    for kf in keyframes {
+        let f = frame_of(kf) as f32;
+        if f <= frame {
+            if prev.map_or(true, |p| frame_of(p) as f32 <= f) {
+                prev = Some(kf);
+            }
+        } else if next.map_or(true, |n| frame_of(n) as f32 > f) {
+            next = Some(kf);
+        }
+    }
+    match (prev, next) {
+        (Some(p), Some(n)) => {
+            let (f0, f1) = (frame_of(p) as f32, frame_of(n) as f32);
+            Some((p, n, (frame - f0) / (f1 - f0)))
+        },
+        (Some(k), None) | (None, Some(k)) => Some((k, k, 0.0)),
+        (None, None) => None,
+    }
+}
+
+/// Interpolated translation and rotation of a bone track, the curves of the later keyframe apply
+fn interpolate_bone(keyframes: &[BoneKeyframe], frame: f32) -> Option<(Vec3, Quat)> {
+    let (k0, k1, t) = surrounding(keyframes, frame, |k| k.frame)?;
+    let trans = vec3(
+        k0.trans.x + (k1.trans.x - k0.trans.x) * eval_curve(k1.txc, t),
+        k0.trans.y + (k1.trans.y - k0.trans.y) * eval_curve(k1.tyc, t),
+        k0.trans.z + (k1.trans.z - k0.trans.z) * eval_curve(k1.tzc, t),
+    );
+    let rot = k0.rot.slerp(k1.rot, eval_curve(k1.rc, t));
+    Some((trans, rot))
+}
+
+/// Linearly interpolated weight of a morph track
+fn interpolate_morph(keyframes: &[MorphKeyframe], frame: f32) -> Option<f32> {
+    let (k0, k1, t) = surrounding(keyframes, frame, |k| k.frame)?;
+    Some(k0.weight + (k1.weight - k0.weight) * t)
+}
+
+/// Camera state between keyframes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraPose {
+    pub dist: f32,
+    pub trans: Vec3,
+    pub rot: Vec3,
+    pub fov: f32,
+    pub perspective: bool,
+}
+
+/// Interpolated camera track. Camera curves are stored as (x1, x2, y1, y2) and the rotation is interpolated
+/// per euler angle. Keyframes on consecutive frames are a cut, so the earlier one is held until the later.
+pub fn interpolate_camera(keyframes: &[CameraKeyframe], frame: f32) -> Option<CameraPose> {
+    let (k0, k1, t) = surrounding(keyframes, frame, |k| k.frame)?;
+    let t = if k1.frame <= k0.frame + 1 { 0.0 } else { t };
+    let curve = |c: Vec4| eval_curve(vec4(c.x, c.z, c.y, c.w), t);
+    let lerp = |a: f32, b: f32, c: Vec4| a + (b - a) * curve(c);
+    Some(CameraPose {
+        dist: lerp(k0.dist, k1.dist, k1.dc),
+        trans: vec3(
+            lerp(k0.trans.x, k1.trans.x, k1.txc),
+            lerp(k0.trans.y, k1.trans.y, k1.tyc),
+            lerp(k0.trans.z, k1.trans.z, k1.tzc),
+        ),
+        rot: k0.rot + (k1.rot - k0.rot) * curve(k1.rc),
+        fov: lerp(k0.fov as f32, k1.fov as f32, k1.vc),
+        perspective: k0.perspective,
+    })
+}
+
+#[derive(Clone)]
 pub struct Motion {
     pub model_name:       String,
     pub bone_keyframes:   BTreeMap<String, Vec<BoneKeyframe>>,
@@ -97,6 +291,55 @@ impl Motion {
         }
     }
 
+    /// Translation and rotation of bone `name` at a fractional `frame`
+    pub fn sample_bone(&self, name: &str, frame: f32) -> Option<(Vec3, Quat)> {
+        interpolate_bone(self.bone_keyframes.get(name)?, frame)
+    }
+
+    pub fn sample_morph(&self, name: &str, frame: f32) -> Option<f32> {
+        interpolate_morph(self.morph_keyframes.get(name)?, frame)
+    }
+
+    pub fn sample_camera(&self, frame: f32) -> Option<CameraPose> {
+        interpolate_camera(&self.camera_keyframes, frame)
+    }
+
+    /// Adds a camera keyframe in frame order, replacing one already on the same frame
+    pub fn insert_camera_keyframe(&mut self, keyframe: CameraKeyframe) {
+        if let Some(k) = self.camera_keyframes.iter_mut().find(|k| k.frame == keyframe.frame) {
+            *k = keyframe;
+        } else {
+            let i = self.camera_keyframes.iter().position(|k| k.frame > keyframe.frame)
+                .unwrap_or(self.camera_keyframes.len());
+            self.camera_keyframes.insert(i, keyframe);
+        }
+    }
+
+    /// Weights of every morph track at `frame`
+    pub fn sample_morphs(&self, frame: f32) -> BTreeMap<String, f32> {
+        self.morph_keyframes.iter()
+            .filter_map(|(name, keyframes)| Some((name.clone(), interpolate_morph(keyframes, frame)?)))
+            .collect()
+    }
+
+    /// Frame of the last keyframe of any track
+    pub fn max_frame(&self) -> u32 {
+        let bone = self.bone_keyframes.values().flatten().map(|k| k.frame);
+        let morph = self.morph_keyframes.values().flatten().map(|k| k.frame);
+        let camera = self.camera_keyframes.iter().map(|k| k.frame);
+        let ik = self.ik_keyframes.iter().map(|k| k.frame);
+        bone.chain(morph).chain(camera).chain(ik).max().unwrap_or(0)
+    }
+
+    /// Whether the IK of bone `name` is enabled at `frame`, the state switches at keyframes without interpolation
+    pub fn sample_ik(&self, name: &str, frame: f32) -> bool {
+        self.ik_keyframes.iter()
+            .filter(|k| k.frame as f32 <= frame)
+            .filter_map(|k| k.infos.iter().find(|(n, _)| n == name).map(|(_, enabled)| (k.frame, *enabled)))
+            .max_by_key(|(f, _)| *f)
+            .map_or(true, |(_, enabled)| enabled)
+    }
+
     pub fn clear_empty_morph(&self) -> BTreeMap<String, Vec<MorphKeyframe>> {
         let mut keyframes: BTreeMap<String, Vec<MorphKeyframe>> = BTreeMap::new();
         let useful_names = self.get_useful_morph_names();
@@ -212,4 +455,114 @@ impl Motion {
         buf += "\n";
         buf
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::vmd_reader::read_camera_keyframe;
+
+    fn curve(x1: u8, y1: u8, x2: u8, y2: u8) -> Vec4 {
+        vec4(x1 as f32, y1 as f32, x2 as f32, y2 as f32) / 127.0
+    }
+
+    fn bone(frame: u32, trans: Vec3, rot: Quat, c: Vec4) -> BoneKeyframe {
+        BoneKeyframe { frame, trans, rot, txc: c, tyc: c, tzc: c, rc: c }
+    }
+
+    #[test]
+    fn linear_curve_is_identity() {
+        for x in [0.0, 0.1, 0.5, 0.9, 1.0] {
+            assert!((eval_curve(LINEAR_CURVE, x) - x).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn curve_matches_reference() {
+        // y at x on the curve, solved outside the crate by a 50 digit bisection of the cubic
+        let c = curve(100, 10, 30, 120);
+        assert!((eval_curve(c, 0.25) - 0.06977859).abs() < 1e-5);
+        assert!((eval_curve(c, 0.5) - 0.47232745).abs() < 1e-5);
+        assert!((eval_curve(c, 0.75) - 0.93396707).abs() < 1e-5);
+        let ease = curve(127, 0, 0, 127);
+        assert!((eval_curve(ease, 0.25) - 0.02972461).abs() < 1e-5);
+        assert!((eval_curve(ease, 0.5) - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn curve_follows_the_bezier() {
+        // points of the curve computed forward from its parameter, without solving for it
+        let (x1, y1, x2, y2) = (100.0 / 127.0, 10.0 / 127.0, 30.0 / 127.0, 120.0 / 127.0);
+        let bezier = |p1: f32, p2: f32, t: f32| 3.0 * (1.0 - t) * (1.0 - t) * t * p1 + 3.0 * (1.0 - t) * t * t * p2 + t * t * t;
+        for i in 0..=20 {
+            let t = i as f32 / 20.0;
+            let (x, y) = (bezier(x1, x2, t), bezier(y1, y2, t));
+            assert!((eval_curve(vec4(x1, y1, x2, y2), x) - y).abs() < 1e-4, "t = {}", t);
+        }
+    }
+
+    #[test]
+    fn bone_sampling() {
+        let mut motion = Motion::new();
+        let half_turn = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        motion.bone_keyframes.insert("センター".to_string(), vec![
+            bone(10, Vec3::ZERO, Quat::IDENTITY, LINEAR_CURVE),
+            bone(30, vec3(2.0, 4.0, -6.0), half_turn, curve(127, 0, 0, 127)),
+        ]);
+        let (trans, rot) = motion.sample_bone("センター", 20.0).unwrap();
+        assert!(trans.abs_diff_eq(vec3(1.0, 2.0, -3.0), 1e-4));
+        assert!(rot.abs_diff_eq(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4), 1e-4));
+        // a quarter of the way the ease curve is still at 0.0297
+        let (trans, _) = motion.sample_bone("センター", 15.0).unwrap();
+        assert!((trans.x - 2.0 * 0.0297246).abs() < 1e-4);
+        // the nearest keyframe is held outside the track
+        assert_eq!(motion.sample_bone("センター", 0.0).unwrap().0, Vec3::ZERO);
+        assert_eq!(motion.sample_bone("センター", 99.5).unwrap().0, vec3(2.0, 4.0, -6.0));
+        assert!(motion.sample_bone("missing", 0.0).is_none());
+    }
+
+    #[test]
+    fn morph_sampling() {
+        let mut motion = Motion::new();
+        motion.morph_keyframes.insert("あ".to_string(), vec![
+            MorphKeyframe { frame: 4, weight: 1.0 },
+            MorphKeyframe { frame: 0, weight: 0.0 },
+        ]);
+        assert!((motion.sample_morph("あ", 1.0).unwrap() - 0.25).abs() < 1e-6);
+        assert!((motion.sample_morph("あ", 3.5).unwrap() - 0.875).abs() < 1e-6);
+    }
+
+    /// A camera keyframe as MMD writes it to a VMD, with every curve stored as the bytes x1, x2, y1, y2
+    fn camera_record(frame: u32, dist: f32, curve: [u8; 4], fov: u32) -> CameraKeyframe {
+        let mut raw = Vec::new();
+        raw.extend(frame.to_le_bytes());
+        raw.extend(dist.to_le_bytes());
+        raw.extend([0u8; 24]);
+        for _ in 0..6 {
+            raw.extend(curve);
+        }
+        raw.extend(fov.to_le_bytes());
+        raw.push(0);
+        read_camera_keyframe(&mut std::io::Cursor::new(raw)).unwrap()
+    }
+
+    #[test]
+    fn camera_sampling() {
+        let mut motion = Motion::new();
+        let linear = [20, 107, 20, 107];
+        motion.camera_keyframes = vec![
+            camera_record(0, -45.0, linear, 30),
+            camera_record(10, -25.0, [100, 30, 10, 120], 50),
+            camera_record(11, -10.0, linear, 20),
+        ];
+        // halfway through x the curve (100, 10, 30, 120) is at 0.47232745, read in the
+        // keyframe order it would be the curve (100, 30, 10, 120) at 0.75552560
+        let pose = motion.sample_camera(5.0).unwrap();
+        assert!((pose.dist - -35.55345).abs() < 1e-3);
+        assert!((pose.fov - 39.44655).abs() < 1e-3);
+        let pose = motion.sample_camera(2.5).unwrap();
+        assert!((pose.dist - -43.604428).abs() < 1e-3);
+        // consecutive keyframes are a cut
+        assert_eq!(motion.sample_camera(10.5).unwrap().dist, -25.0);
+        assert_eq!(motion.sample_camera(11.0).unwrap().dist, -10.0);
+    }
+}
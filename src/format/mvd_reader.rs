@@ -0,0 +1,441 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{self, prelude::*, Cursor};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use glam::*;
+
+use super::common::*;
+use super::motion::*;
+use super::vmd_reader::read_string;
+
+// An MVD file is a header followed by objects (a model, the camera, ...), each made of tagged sections.
+// Apart from the name list every section starts with
+// `key: i32, item_size: i32, item_count: i32, extra_size: i32, extra: [u8; extra_size]`
+// followed by `item_count` records of `item_size` bytes, so records are read from a cursor bounded
+// by `item_size` and unsupported sections are skipped as a whole.
+const MAGIC: &str = "Motion Vector Data file";
+
+const TAG_NAME_LIST: u8 = 0x00;
+const TAG_BONE: u8 = 0x10;
+const TAG_MORPH: u8 = 0x20;
+const TAG_MODEL_PROPERTY: u8 = 0x30;
+const TAG_CAMERA: u8 = 0x60;
+const TAG_END: u8 = 0xFF;
+
+/// VMD keyframes are counted at 30 fps
+const VMD_FPS: f32 = 30.0;
+
+struct SectionHeader {
+    key: i32,
+    item_size: usize,
+    item_count: usize,
+    extra: Vec<u8>,
+}
+
+fn remaining(file: &Cursor<Vec<u8>>) -> u64 {
+    (file.get_ref().len() as u64).saturating_sub(file.position())
+}
+
+fn read_mvd_string(file: &mut Cursor<Vec<u8>>, utf8: bool) -> io::Result<String> {
+    let len = file.read_i32::<LittleEndian>()?;
+    if len < 0 || len as u64 > remaining(file) {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut content = vec![0u8; len as usize];
+    file.read_exact(&mut content)?;
+    if utf8 {
+        return String::from_utf8(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    let chunks = content.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "odd length UTF-16 string"));
+    }
+    let units: Vec<u16> = chunks.map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16(&units).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// MVD curves store the control points as bytes in 0..127 in the order (x1, y1, x2, y2)
+fn read_curve(file: &mut Cursor<Vec<u8>>) -> io::Result<Vec4> {
+    let mut c = [0u8; 4];
+    file.read_exact(&mut c)?;
+    Ok(vec4(c[0] as f32, c[1] as f32, c[2] as f32, c[3] as f32) / 127.0)
+}
+
+fn read_section_header(file: &mut Cursor<Vec<u8>>) -> io::Result<SectionHeader> {
+    let key = file.read_i32::<LittleEndian>()?;
+    let item_size = file.read_i32::<LittleEndian>()?;
+    let item_count = file.read_i32::<LittleEndian>()?;
+    let extra_size = file.read_i32::<LittleEndian>()?;
+    if item_size < 0 || item_count < 0 || extra_size < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "negative section size"));
+    }
+    if extra_size as u64 + item_size as u64 * item_count as u64 > remaining(file) {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut extra = vec![0u8; extra_size as usize];
+    file.read_exact(&mut extra)?;
+    Ok(SectionHeader {
+        key,
+        item_size: item_size as usize,
+        item_count: item_count as usize,
+        extra,
+    })
+}
+
+/// Reads every record of a section from its own `item_size` long cursor, newer minor versions append fields
+fn read_section_items<T, F>(
+    file: &mut Cursor<Vec<u8>>,
+    header: &SectionHeader,
+    section: MotionSection,
+    mut f: F
+) -> Result<Vec<T>, MotionError>
+    where F: FnMut(&mut Cursor<Vec<u8>>) -> io::Result<T> {
+    let mut items = Vec::with_capacity(header.item_count);
+    for i in 0..header.item_count {
+        let mut item = vec![0u8; header.item_size];
+        file.read_exact(&mut item).map_err(|e| MotionError::new(section, Some(i), e))?;
+        items.push(f(&mut Cursor::new(item)).map_err(|e| MotionError::new(section, Some(i), e))?);
+    }
+    Ok(items)
+}
+
+fn read_name_list(file: &mut Cursor<Vec<u8>>, utf8: bool) -> io::Result<Vec<(i32, String)>> {
+    // reserved
+    file.read_i32::<LittleEndian>()?;
+    let count = file.read_i32::<LittleEndian>()?;
+    let reserved_size = file.read_i32::<LittleEndian>()?;
+    if count < 0 || reserved_size < 0 || reserved_size as u64 > remaining(file) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid name list size"));
+    }
+    file.seek(io::SeekFrom::Current(reserved_size as i64))?;
+    let mut names = Vec::new();
+    for _ in 0..count {
+        let key = file.read_i32::<LittleEndian>()?;
+        names.push((key, read_mvd_string(file, utf8)?));
+    }
+    Ok(names)
+}
+
+struct MvdBoneFrame {
+    layer: i32,
+    frame: i64,
+    keyframe: BoneKeyframe,
+}
+
+fn read_bone_frame(file: &mut Cursor<Vec<u8>>) -> io::Result<MvdBoneFrame> {
+    let layer = file.read_i32::<LittleEndian>()?;
+    let frame = file.read_i64::<LittleEndian>()?;
+    let trans = read_float3(file)?;
+    let rot = read_quat(file)?;
+    let keyframe = BoneKeyframe {
+        frame: 0,
+        trans,
+        rot,
+        txc: read_curve(file)?,
+        tyc: read_curve(file)?,
+        tzc: read_curve(file)?,
+        rc: read_curve(file)?,
+    };
+    Ok(MvdBoneFrame {
+        layer,
+        frame,
+        keyframe,
+    })
+}
+
+fn read_morph_frame(file: &mut Cursor<Vec<u8>>) -> io::Result<(i64, f32)> {
+    let frame = file.read_i64::<LittleEndian>()?;
+    let weight = file.read_f32::<LittleEndian>()?;
+    Ok((frame, weight))
+}
+
+struct MvdCameraFrame {
+    layer: i32,
+    frame: i64,
+    keyframe: CameraKeyframe,
+}
+
+fn read_camera_frame(file: &mut Cursor<Vec<u8>>) -> io::Result<MvdCameraFrame> {
+    let layer = file.read_i32::<LittleEndian>()?;
+    let frame = file.read_i64::<LittleEndian>()?;
+    let radius = file.read_f32::<LittleEndian>()?;
+    let trans = read_float3(file)?;
+    let rot = read_float3(file)?;
+    let fov = file.read_f32::<LittleEndian>()?;
+    let perspective = file.read_u8()? != 0;
+    // VMD camera curves are stored as (x1, x2, y1, y2)
+    let mut curve = || read_curve(file).map(|c| vec4(c.x, c.z, c.y, c.w));
+    let tc = curve()?;
+    let rc = curve()?;
+    let dc = curve()?;
+    let vc = curve()?;
+    Ok(MvdCameraFrame {
+        layer,
+        frame,
+        keyframe: CameraKeyframe {
+            frame: 0,
+            // VMD measures the distance from the target towards the camera along -z
+            dist: -radius,
+            trans,
+            rot,
+            txc: tc,
+            tyc: tc,
+            tzc: tc,
+            rc,
+            dc,
+            vc,
+            fov: fov.round().max(0.0) as u32,
+            perspective,
+        },
+    })
+}
+
+/// Minor version 1 added the physics still mode after the physics flag
+fn read_model_property_frame(file: &mut Cursor<Vec<u8>>, minor: u8, ik_count: usize) -> io::Result<(i64, bool, Vec<bool>)> {
+    let frame = file.read_i64::<LittleEndian>()?;
+    let visible = file.read_u8()? != 0;
+    // shadow, add blend and physics flags, the physics still mode and the edge width and color
+    // have no VMD counterpart
+    let skipped = 3 + if minor >= 1 { 1 } else { 0 } + 4 + 16;
+    file.seek(io::SeekFrom::Current(skipped))?;
+    let mut iks = Vec::with_capacity(ik_count);
+    for _ in 0..ik_count {
+        iks.push(file.read_u8()? != 0);
+    }
+    Ok((frame, visible, iks))
+}
+
+fn read_header(file: &mut Cursor<Vec<u8>>) -> Result<bool, MotionError> {
+    let header = |e| MotionError::new(MotionSection::Header, None, e);
+    let magic = read_string(file, 30).map_err(header)?;
+    if !magic.starts_with(MAGIC) {
+        return Err(MotionError::invalid(MotionSection::Header, None, format!("unknown MVD header {:?}", magic)));
+    }
+    let version = file.read_f32::<LittleEndian>().map_err(header)?;
+    if !(1.0..2.0).contains(&version) {
+        return Err(MotionError::invalid(MotionSection::Header, None, format!("unsupported MVD version {}", version)));
+    }
+    let utf8 = match file.read_u8().map_err(header)? {
+        0 => false,
+        1 => true,
+        e => return Err(MotionError::invalid(MotionSection::Header, None, format!("unknown MVD encoding {}", e))),
+    };
+    Ok(utf8)
+}
+
+/// The object name, or the name of the model it was mapped to when it has none, and its frame rate
+fn read_object_header(file: &mut Cursor<Vec<u8>>, utf8: bool) -> io::Result<(String, f32)> {
+    let name = read_mvd_string(file, utf8)?;
+    let mapped_name = read_mvd_string(file, utf8)?;
+    let name = if name.is_empty() { mapped_name } else { name };
+    let key_fps = file.read_f32::<LittleEndian>()?;
+    let reserved_size = file.read_i32::<LittleEndian>()?;
+    if reserved_size < 0 || reserved_size as u64 > remaining(file) {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    file.seek(io::SeekFrom::Current(reserved_size as i64))?;
+    let key_fps = if key_fps > 0.0 { key_fps } else { VMD_FPS };
+    Ok((name, key_fps))
+}
+
+/// Reads every object of an MVD file as a motion. Only the base layer of
+/// MMM's layered bone and camera tracks is kept, as VMD has no layers
+pub fn read_mvd(content: Vec<u8>, path: &str) -> Result<Vec<Motion>, MotionError> {
+    let file = &mut Cursor::new(content);
+    let utf8 = read_header(file)?;
+    let mut motions = Vec::new();
+    while remaining(file) > 0 {
+        let model = motions.len();
+        let (name, key_fps) = read_object_header(file, utf8)
+            .map_err(|e| MotionError::new(MotionSection::Model, None, e).in_model(model))?;
+        let to_frame = |frame: i64| (frame as f32 * VMD_FPS / key_fps).round().max(0.0) as u32;
+        let mut motion = Motion::new();
+        motion.model_name = name;
+        motion.path = path.to_string();
+        let mut names: HashMap<i32, String> = HashMap::new();
+        loop {
+            let err = |section, e| MotionError::new(section, None, e).in_model(model);
+            let tag = file.read_u8().map_err(|e| err(MotionSection::Model, e))?;
+            if tag == TAG_END {
+                break;
+            }
+            let minor = file.read_u8().map_err(|e| err(MotionSection::Model, e))?;
+            if tag == TAG_NAME_LIST {
+                let list = read_name_list(file, utf8).map_err(|e| err(MotionSection::NameList, e))?;
+                names.extend(list);
+                continue;
+            }
+            let section = match tag {
+                TAG_BONE => MotionSection::Bone,
+                TAG_MORPH => MotionSection::Morph,
+                TAG_CAMERA => MotionSection::Camera,
+                _ => MotionSection::Property,
+            };
+            let header = read_section_header(file).map_err(|e| err(section, e))?;
+            let name = names.get(&header.key).cloned().unwrap_or_default();
+            match tag {
+                TAG_BONE => {
+                    let frames = read_section_items(file, &header, section, read_bone_frame)
+                        .map_err(|e| e.in_model(model))?;
+                    let keyframes = motion.bone_keyframes.entry(name).or_default();
+                    for f in frames.into_iter().filter(|f| f.layer == 0) {
+                        keyframes.push(BoneKeyframe { frame: to_frame(f.frame), ..f.keyframe });
+                    }
+                },
+                TAG_MORPH => {
+                    // VMD morphs are always linear, the MVD curve is dropped
+                    let frames = read_section_items(file, &header, section, read_morph_frame)
+                        .map_err(|e| e.in_model(model))?;
+                    let keyframes = motion.morph_keyframes.entry(name).or_default();
+                    for (frame, weight) in frames {
+                        keyframes.push(MorphKeyframe { frame: to_frame(frame), weight });
+                    }
+                },
+                TAG_MODEL_PROPERTY => {
+                    // the extra data holds the IK count and the name keys of the IK bones whose state every record stores
+                    let ik_count = header.extra.get(..4).map_or(0, |c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]).max(0) as usize);
+                    let ik_names: Vec<String> = header.extra.chunks_exact(4)
+                        .skip(1)
+                        .take(ik_count)
+                        .map(|c| names.get(&i32::from_le_bytes([c[0], c[1], c[2], c[3]])).cloned().unwrap_or_default())
+                        .collect();
+                    let frames = read_section_items(file, &header, section, |f| read_model_property_frame(f, minor, ik_names.len()))
+                        .map_err(|e| e.in_model(model))?;
+                    for (frame, show, iks) in frames {
+                        motion.ik_keyframes.push(IkKeyframe {
+                            frame: to_frame(frame),
+                            show,
+                            infos: ik_names.iter().cloned().zip(iks).collect(),
+                        });
+                    }
+                },
+                TAG_CAMERA => {
+                    let frames = read_section_items(file, &header, section, read_camera_frame)
+                        .map_err(|e| e.in_model(model))?;
+                    for f in frames.into_iter().filter(|f| f.layer == 0) {
+                        motion.camera_keyframes.push(CameraKeyframe { frame: to_frame(f.frame), ..f.keyframe });
+                    }
+                },
+                _ => {
+                    // accessories, effects, lights and project settings have no VMD counterpart
+                    file.seek(io::SeekFrom::Current((header.item_size * header.item_count) as i64))
+                        .map_err(|e| err(section, e))?;
+                },
+            }
+        }
+        motions.push(motion);
+    }
+    Ok(motions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        let units: Vec<u8> = s.encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+        out.extend((units.len() as i32).to_le_bytes());
+        out.extend(units);
+    }
+
+    fn section(out: &mut Vec<u8>, tag: u8, key: i32, item_size: usize, items: &[Vec<u8>]) {
+        out.extend([tag, 0]);
+        for v in [key, item_size as i32, items.len() as i32, 0] {
+            out.extend(v.to_le_bytes());
+        }
+        for item in items {
+            out.extend(item);
+            out.resize(out.len() + item_size - item.len(), 0);
+        }
+    }
+
+    fn floats(out: &mut Vec<u8>, values: &[f32]) {
+        out.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+    }
+
+    fn bone_item(layer: i32, frame: i64, y: f32) -> Vec<u8> {
+        let mut item = layer.to_le_bytes().to_vec();
+        item.extend(frame.to_le_bytes());
+        floats(&mut item, &[0.0, y, 0.0, 0.0, 0.0, 0.0, 1.0]);
+        for _ in 0..4 {
+            item.extend([20, 20, 107, 107]);
+        }
+        item
+    }
+
+    /// A model at 60 keys per second with a bone track on two layers and a morph track whose
+    /// records carry a trailing curve, in UTF-16
+    fn mvd() -> Vec<u8> {
+        let mut out = b"Motion Vector Data file".to_vec();
+        out.resize(30, 0);
+        floats(&mut out, &[1.0]);
+        out.push(0);
+
+        string(&mut out, "");
+        string(&mut out, "初音ミク");
+        floats(&mut out, &[60.0]);
+        out.extend(0i32.to_le_bytes());
+
+        out.extend([TAG_NAME_LIST, 0]);
+        for v in [0i32, 2, 0] {
+            out.extend(v.to_le_bytes());
+        }
+        for (key, name) in [(3, "センター"), (7, "あ")] {
+            out.extend(i32::to_le_bytes(key));
+            string(&mut out, name);
+        }
+        section(&mut out, TAG_BONE, 3, 56, &[bone_item(0, 0, 1.0), bone_item(1, 30, 5.0), bone_item(0, 60, 2.0)]);
+        let morph = |frame: i64, weight: f32| {
+            let mut item = frame.to_le_bytes().to_vec();
+            floats(&mut item, &[weight]);
+            item.extend([20, 20, 107, 107]);
+            item
+        };
+        section(&mut out, TAG_MORPH, 7, 16, &[morph(0, 0.0), morph(90, 1.0)]);
+        out.push(TAG_END);
+        out
+    }
+
+    #[test]
+    fn reads_bone_and_morph_tracks() {
+        let motions = read_mvd(mvd(), "").unwrap();
+        assert_eq!(motions.len(), 1);
+        let motion = &motions[0];
+        assert_eq!(motion.model_name, "初音ミク");
+        // the second layer is dropped and 60 keys per second become 30 frames per second
+        let bones: Vec<(u32, f32)> = motion.bone_keyframes["センター"].iter().map(|k| (k.frame, k.trans.y)).collect();
+        assert_eq!(bones, vec![(0, 1.0), (30, 2.0)]);
+        let curve = motion.bone_keyframes["センター"][0].txc;
+        assert_eq!(curve, vec4(20.0, 20.0, 107.0, 107.0) / 127.0);
+        let morphs: Vec<(u32, f32)> = motion.morph_keyframes["あ"].iter().map(|k| (k.frame, k.weight)).collect();
+        assert_eq!(morphs, vec![(0, 0.0), (45, 1.0)]);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let content = mvd();
+        // the header alone is a file without objects
+        let header_len = 30 + 4 + 1;
+        assert!(read_mvd(content[..header_len].to_vec(), "").unwrap().is_empty());
+        for len in (0..content.len()).filter(|len| *len != header_len) {
+            assert!(read_mvd(content[..len].to_vec(), "").is_err(), "read {} of {} bytes", len, content.len());
+        }
+    }
+
+    #[test]
+    fn odd_utf16_string_is_an_error() {
+        let mut out = 3i32.to_le_bytes().to_vec();
+        out.extend([0x42, 0x30, 0x44]);
+        let err = read_mvd_string(&mut Cursor::new(out), false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_version_is_an_error() {
+        let mut content = mvd();
+        content[30..34].copy_from_slice(&2.0f32.to_le_bytes());
+        assert!(read_mvd(content, "").is_err());
+    }
+}
@@ -0,0 +1,837 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(unused_variables)]
+
+use std::f32::consts::PI;
+use std::io::prelude::*;
+use std::io::Cursor;
+
+use byteorder::{LE, ReadBytesExt};
+use glam::*;
+use uuid::Uuid;
+
+use super::common::*;
+use super::pmx::*;
+use super::vmd_reader::read_string;
+
+const TOON_COUNT: usize = 10;
+
+struct PmdMat {
+    diffuse: Vec4,
+    specularity: f32,
+    specular: Vec3,
+    ambient: Vec3,
+    toon_index: u8,
+    edge: bool,
+    face_vert_count: u32,
+    tex_name: String,
+}
+
+struct PmdBone {
+    name: String,
+    parent: u16,
+    tail: u16,
+    kind: u8,
+    ik_parent: u16,
+    pos: Vec3,
+}
+
+struct PmdIk {
+    bone: u16,
+    effector: u16,
+    loop_count: u16,
+    limit_angle: f32,
+    chain: Vec<u16>,
+}
+
+struct PmdDisplayFrames {
+    morph_items: Vec<u16>,
+    bone_frame_names: Vec<String>,
+    bone_items: Vec<(u16, u8)>,
+}
+
+struct PmdEnglish {
+    name: String,
+    comment: String,
+    bone_names: Vec<String>,
+    morph_names: Vec<String>,
+    bone_frame_names: Vec<String>,
+}
+
+impl Pmx {
+    /// Reads a PMD 1.0 model and converts it to the PMX representation,
+    /// the sections added by later MMD versions (english names, toons, physics) are optional
+    pub fn read_pmd(content: Vec<u8>, path: &str) -> PmxResult<Self> {
+        let file = &mut Cursor::new(content);
+        let (name, comment) = Pmx::section(file, "header", read_header)?;
+        let verts = Pmx::section(file, "vertex", read_verts)?;
+        let faces = Pmx::section(file, "face", |file| read_faces(file, verts.len()))?;
+        let pmd_mats = Pmx::section(file, "material", |file| read_mats(file, faces.len()))?;
+        let pmd_bones = Pmx::section(file, "bone", read_bones)?;
+        let pmd_iks = Pmx::section(file, "IK", read_iks)?;
+        let mut morphs = Pmx::section(file, "morph", |file| read_morphs(file, verts.len()))?;
+        let pmd_frames = Pmx::section(file, "display frame", read_display_frames)?;
+        let english = if Pmx::remaining(file) > 0 {
+            Pmx::section(file, "english name", |file| {
+                read_english(file, pmd_bones.len(), morphs.len(), pmd_frames.bone_frame_names.len())
+            })?
+        } else {
+            None
+        };
+        let toon_names = if Pmx::remaining(file) > 0 {
+            Pmx::section(file, "toon", read_toon_names)?
+        } else {
+            (0..TOON_COUNT).map(default_toon_name).collect()
+        };
+        let mut rigidbodys = if Pmx::remaining(file) > 0 {
+            Pmx::section(file, "rigidbody", read_rigidbodys)?
+        } else {
+            Vec::new()
+        };
+        let joints = if Pmx::remaining(file) > 0 {
+            Pmx::section(file, "joint", read_joints)?
+        } else {
+            Vec::new()
+        };
+
+        let mut texs = Vec::new();
+        let mats = pmd_mats.iter().enumerate().map(|(i, m)| {
+            convert_mat(i, m, &toon_names, &mut texs)
+        }).collect();
+
+        let mut bones: Vec<Bone> = pmd_bones.iter().map(convert_bone).collect();
+        for (i, b) in pmd_bones.iter().enumerate() {
+            // twist bones rotate around the direction to their tail
+            if b.kind == 8 {
+                if let Some(tail) = pmd_bones.get(b.tail as usize) {
+                    bones[i].fixed_axis = Some((tail.pos - b.pos).normalize_or_zero());
+                    bones[i].bone_flags |= BoneFlags::FIXED_AXIS;
+                }
+            }
+        }
+        let mut iks = Vec::new();
+        for ik in &pmd_iks {
+            if let Some(b) = bones.get_mut(ik.bone as usize) {
+                b.bone_flags |= BoneFlags::IK;
+            }
+            let ik_joints = ik.chain.iter().map(|&c| {
+                // PMD has no angle limits, MMD hard codes them for knees
+                let is_knee = bones.get(c as usize).map_or(false, |b| b.name.contains("ひざ"));
+                IkJoint {
+                    bone: c as i32,
+                    limit: if is_knee {
+                        Some((vec3(-PI, 0.0, 0.0), vec3(-0.5f32.to_radians(), 0.0, 0.0)))
+                    } else {
+                        None
+                    },
+                }
+            }).collect();
+            iks.push(Ik {
+                bone: ik.bone as i32,
+                effector: ik.effector as i32,
+                loop_count: ik.loop_count as i32,
+                limit_angle: ik.limit_angle * 4.0,
+                ik_joints,
+            });
+        }
+
+        // PMD rigidbody positions are relative to their bone, or to the first bone when unbound
+        for r in &mut rigidbodys {
+            let bone = if r.bone >= 0 { r.bone as usize } else { 0 };
+            if let Some(b) = bones.get(bone) {
+                r.pos += b.pos;
+            }
+        }
+
+        let mut display_frames = DisplayFrame::defaults();
+        if !bones.is_empty() {
+            display_frames[0].morph_items.push(DisplayFrameIndex::Bone(0));
+        }
+        // the display list counts the base morph, which is not kept
+        display_frames[1].morph_items = pmd_frames.morph_items.iter()
+            .filter(|&&i| i > 0 && (i as usize) <= morphs.len())
+            .map(|&i| DisplayFrameIndex::Morph(i as u32 - 1))
+            .collect();
+        for (i, name) in pmd_frames.bone_frame_names.iter().enumerate() {
+            let morph_items = pmd_frames.bone_items.iter()
+                .filter(|(bone, frame)| *frame as usize == i + 1 && (*bone as usize) < bones.len())
+                .map(|(bone, _)| DisplayFrameIndex::Bone(*bone as u32))
+                .collect();
+            display_frames.push(DisplayFrame {
+                name: name.clone(),
+                name_en: String::new(),
+                special: false,
+                morph_items,
+            });
+        }
+
+        let (name_en, comment_en) = if let Some(english) = english {
+            for (b, n) in bones.iter_mut().zip(english.bone_names) {
+                b.name_en = n;
+            }
+            for (m, n) in morphs.iter_mut().zip(english.morph_names) {
+                m.name_en = n;
+            }
+            for (f, n) in display_frames.iter_mut().skip(2).zip(english.bone_frame_names) {
+                f.name_en = n;
+            }
+            (english.name, english.comment)
+        } else {
+            (String::new(), String::new())
+        };
+
+        Ok(Self {
+            name,
+            name_en,
+            comment,
+            comment_en,
+            verts,
+            appendix_uvs: Vec::new(),
+            faces,
+            texs,
+            mats,
+            bones,
+            iks,
+            morphs,
+            rigidbodys,
+            joints,
+            softbodys: Vec::new(),
+            display_frames,
+            header: PmxHeader::default(),
+            path: path.to_string(),
+            uuid: Uuid::new_v4(),
+        })
+    }
+}
+
+fn default_toon_name(i: usize) -> String {
+    format!("toon{:02}.bmp", i + 1)
+}
+
+// PMD records have a fixed size, so a count that does not fit the remaining data is a truncated file
+fn check_count(file: &Cursor<Vec<u8>>, count: usize, record_size: usize) -> PmxResult<usize> {
+    if (count as u64) * (record_size as u64) > Pmx::remaining(file) {
+        return Err(PmxError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(count)
+}
+
+fn read_header(file: &mut Cursor<Vec<u8>>) -> PmxResult<(String, String)> {
+    let mut magic = [0u8; 3];
+    file.read_exact(&mut magic)?;
+    if &magic != b"Pmd" {
+        return Err(PmxError::BadMagic([magic[0], magic[1], magic[2], 0]));
+    }
+    let version = file.read_f32::<LE>()?;
+    if version != 1.0 {
+        return Err(PmxError::UnsupportedVersion(version));
+    }
+    let name = read_string(file, 20)?;
+    let comment = read_string(file, 256)?;
+    Ok((name, comment))
+}
+
+fn read_verts(file: &mut Cursor<Vec<u8>>) -> PmxResult<Vec<Vertex>> {
+    let len = file.read_u32::<LE>()? as usize;
+    let len = check_count(file, len, 38)?;
+    let mut vct = Vec::with_capacity(len);
+    for _ in 0..len {
+        let pos = read_float3(file)?;
+        let nrm = read_float3(file)?;
+        let uv = read_float2(file)?;
+        let bone0 = file.read_u16::<LE>()? as i32;
+        let bone1 = file.read_u16::<LE>()? as i32;
+        let weight = file.read_u8()? as f32 / 100.0;
+        let no_edge = file.read_u8()? != 0;
+        vct.push(Vertex {
+            pos,
+            nrm,
+            uv,
+            weight: VertexWeight::Two(bone0, bone1, weight),
+            edge_scale: if no_edge { 0.0 } else { 1.0 },
+        });
+    }
+    Ok(vct)
+}
+
+fn read_faces(file: &mut Cursor<Vec<u8>>, vertex_count: usize) -> PmxResult<Vec<[u32; 3]>> {
+    let len = file.read_u32::<LE>()? as usize;
+    let len = check_count(file, len, 2)?;
+    if len % 3 != 0 {
+        return Err(Pmx::invalid_value(file, "face index count"));
+    }
+    let mut vct = Vec::with_capacity(len / 3);
+    let read_index = |file: &mut Cursor<Vec<u8>>| -> PmxResult<u32> {
+        let i = file.read_u16::<LE>()?;
+        if i as usize >= vertex_count {
+            return Err(Pmx::invalid_value(file, "vertex index"));
+        }
+        Ok(i as u32)
+    };
+    for _ in 0..len / 3 {
+        let a = read_index(file)?;
+        let b = read_index(file)?;
+        let c = read_index(file)?;
+        vct.push([a, b, c]);
+    }
+    Ok(vct)
+}
+
+fn read_mats(file: &mut Cursor<Vec<u8>>, face_count: usize) -> PmxResult<Vec<PmdMat>> {
+    let len = file.read_u32::<LE>()? as usize;
+    let len = check_count(file, len, 70)?;
+    let mut vct = Vec::with_capacity(len);
+    let mut used_faces = 0;
+    for _ in 0..len {
+        let diffuse = read_float4(file)?;
+        let specularity = file.read_f32::<LE>()?;
+        let specular = read_float3(file)?;
+        let ambient = read_float3(file)?;
+        let toon_index = file.read_u8()?;
+        let edge = file.read_u8()? == 1;
+        let face_vert_count = file.read_u32::<LE>()?;
+        // the faces are sliced by these counts, they have to add up within the face section
+        used_faces += face_vert_count as usize / 3;
+        if used_faces > face_count {
+            return Err(Pmx::invalid_value(file, "face count"));
+        }
+        let tex_name = read_string(file, 20)?;
+        vct.push(PmdMat {
+            diffuse,
+            specularity,
+            specular,
+            ambient,
+            toon_index,
+            edge,
+            face_vert_count,
+            tex_name,
+        });
+    }
+    Ok(vct)
+}
+
+fn read_bones(file: &mut Cursor<Vec<u8>>) -> PmxResult<Vec<PmdBone>> {
+    let len = file.read_u16::<LE>()? as usize;
+    let len = check_count(file, len, 39)?;
+    let mut vct = Vec::with_capacity(len);
+    for _ in 0..len {
+        let name = read_string(file, 20)?;
+        let parent = file.read_u16::<LE>()?;
+        let tail = file.read_u16::<LE>()?;
+        let kind = file.read_u8()?;
+        let ik_parent = file.read_u16::<LE>()?;
+        let pos = read_float3(file)?;
+        vct.push(PmdBone {
+            name,
+            parent,
+            tail,
+            kind,
+            ik_parent,
+            pos,
+        });
+    }
+    Ok(vct)
+}
+
+fn read_iks(file: &mut Cursor<Vec<u8>>) -> PmxResult<Vec<PmdIk>> {
+    let len = file.read_u16::<LE>()? as usize;
+    let len = check_count(file, len, 11)?;
+    let mut vct = Vec::with_capacity(len);
+    for _ in 0..len {
+        let bone = file.read_u16::<LE>()?;
+        let effector = file.read_u16::<LE>()?;
+        let chain_len = file.read_u8()?;
+        let loop_count = file.read_u16::<LE>()?;
+        let limit_angle = file.read_f32::<LE>()?;
+        let mut chain = Vec::with_capacity(chain_len as usize);
+        for _ in 0..chain_len {
+            chain.push(file.read_u16::<LE>()?);
+        }
+        vct.push(PmdIk {
+            bone,
+            effector,
+            loop_count,
+            limit_angle,
+            chain,
+        });
+    }
+    Ok(vct)
+}
+
+/// PMD stores a base morph with absolute positions of every vertex touched by any morph,
+/// the other morphs index into it, so they are resolved to plain vertex morphs here
+fn read_morphs(file: &mut Cursor<Vec<u8>>, vertex_count: usize) -> PmxResult<Vec<MorphInfo>> {
+    let len = file.read_u16::<LE>()? as usize;
+    let len = check_count(file, len, 25)?;
+    let mut base: Vec<u32> = Vec::new();
+    let mut vct = Vec::with_capacity(len);
+    for i in 0..len {
+        let name = read_string(file, 20)?;
+        let count = file.read_u32::<LE>()? as usize;
+        let panel = file.read_u8()?;
+        let count = check_count(file, count, 16)?;
+        let mut items = Vec::with_capacity(count);
+        for _ in 0..count {
+            let index = file.read_u32::<LE>()?;
+            let trans = read_float3(file)?;
+            if panel == 0 {
+                if index as usize >= vertex_count {
+                    return Err(Pmx::invalid_value(file, "base morph vertex index"));
+                }
+                base.push(index);
+            } else {
+                let index = *base.get(index as usize)
+                    .ok_or_else(|| Pmx::invalid_value(file, "morph vertex index"))?;
+                items.push(MorphVertexItem {
+                    index,
+                    trans,
+                });
+            }
+        }
+        if panel != 0 {
+            vct.push(MorphInfo {
+                name,
+                name_en: String::new(),
+                panel: panel as i8,
+                category: 1,
+                data: Morph::MorphVertex(items),
+            });
+        }
+    }
+    Ok(vct)
+}
+
+fn read_display_frames(file: &mut Cursor<Vec<u8>>) -> PmxResult<PmdDisplayFrames> {
+    let len = file.read_u8()? as usize;
+    let mut morph_items = Vec::with_capacity(len);
+    for _ in 0..len {
+        morph_items.push(file.read_u16::<LE>()?);
+    }
+    let len = file.read_u8()? as usize;
+    let mut bone_frame_names = Vec::with_capacity(len);
+    for _ in 0..len {
+        bone_frame_names.push(read_string(file, 50)?.trim_end().to_string());
+    }
+    let len = file.read_u32::<LE>()? as usize;
+    let len = check_count(file, len, 3)?;
+    let mut bone_items = Vec::with_capacity(len);
+    for _ in 0..len {
+        let bone = file.read_u16::<LE>()?;
+        let frame = file.read_u8()?;
+        bone_items.push((bone, frame));
+    }
+    Ok(PmdDisplayFrames {
+        morph_items,
+        bone_frame_names,
+        bone_items,
+    })
+}
+
+fn read_english(
+    file: &mut Cursor<Vec<u8>>,
+    bone_count: usize,
+    morph_count: usize,
+    bone_frame_count: usize
+) -> PmxResult<Option<PmdEnglish>> {
+    if file.read_u8()? == 0 {
+        return Ok(None);
+    }
+    let name = read_string(file, 20)?;
+    let comment = read_string(file, 256)?;
+    let mut bone_names = Vec::with_capacity(bone_count);
+    for _ in 0..bone_count {
+        bone_names.push(read_string(file, 20)?);
+    }
+    let mut morph_names = Vec::with_capacity(morph_count);
+    for _ in 0..morph_count {
+        morph_names.push(read_string(file, 20)?);
+    }
+    let mut bone_frame_names = Vec::with_capacity(bone_frame_count);
+    for _ in 0..bone_frame_count {
+        bone_frame_names.push(read_string(file, 50)?.trim_end().to_string());
+    }
+    Ok(Some(PmdEnglish {
+        name,
+        comment,
+        bone_names,
+        morph_names,
+        bone_frame_names,
+    }))
+}
+
+fn read_toon_names(file: &mut Cursor<Vec<u8>>) -> PmxResult<Vec<String>> {
+    let mut vct = Vec::with_capacity(TOON_COUNT);
+    for _ in 0..TOON_COUNT {
+        vct.push(read_string(file, 100)?);
+    }
+    Ok(vct)
+}
+
+fn read_rigidbodys(file: &mut Cursor<Vec<u8>>) -> PmxResult<Vec<Rigidbody>> {
+    let len = file.read_u32::<LE>()? as usize;
+    let len = check_count(file, len, 83)?;
+    let mut vct = Vec::with_capacity(len);
+    for _ in 0..len {
+        let name = read_string(file, 20)?;
+        let bone = file.read_u16::<LE>()?;
+        let group = file.read_u8()?;
+        let collision_group = file.read_u16::<LE>()?;
+        let shape = match file.read_u8()? {
+            0 => RigidbodyShape::Shpere,
+            1 => RigidbodyShape::Box,
+            2 => RigidbodyShape::Capsule,
+            _ => return Err(Pmx::invalid_value(file, "rigidbody shape")),
+        };
+        let size = read_float3(file)?;
+        let pos = read_float3(file)?;
+        let rot = read_float3(file)?;
+        let mass = file.read_f32::<LE>()?;
+        let linear_damping = file.read_f32::<LE>()?;
+        let angular_damping = file.read_f32::<LE>()?;
+        let restitution = file.read_f32::<LE>()?;
+        let friction = file.read_f32::<LE>()?;
+        let mode = match file.read_u8()? {
+            0 => RigidbodyMode::Kinematics,
+            1 => RigidbodyMode::Dynamics,
+            2 => RigidbodyMode::DynamicsPassRotation,
+            _ => return Err(Pmx::invalid_value(file, "rigidbody mode")),
+        };
+        vct.push(Rigidbody {
+            name,
+            name_en: String::new(),
+            bone: if bone == u16::MAX { -1 } else { bone as i32 },
+            group,
+            collision_group,
+            shape,
+            size,
+            pos,
+            rot,
+            mass,
+            linear_damping,
+            angular_damping,
+            restitution,
+            friction,
+            mode,
+            uuid: Uuid::new_v4(),
+        });
+    }
+    Ok(vct)
+}
+
+fn read_joints(file: &mut Cursor<Vec<u8>>) -> PmxResult<Vec<Joint>> {
+    let len = file.read_u32::<LE>()? as usize;
+    let len = check_count(file, len, 124)?;
+    let mut vct = Vec::with_capacity(len);
+    for _ in 0..len {
+        let name = read_string(file, 20)?;
+        let rigidbody_a = file.read_u32::<LE>()? as i32;
+        let rigidbody_b = file.read_u32::<LE>()? as i32;
+        let pos = read_float3(file)?;
+        let rot = read_float3(file)?;
+        let pos_min = read_float3(file)?;
+        let pos_max = read_float3(file)?;
+        let rot_min = read_float3(file)?;
+        let rot_max = read_float3(file)?;
+        let pos_spring = read_float3(file)?;
+        let rot_spring = read_float3(file)?;
+        vct.push(Joint {
+            name,
+            name_en: String::new(),
+            kind: JointKind::Spring6Dof,
+            rigidbody_a,
+            rigidbody_b,
+            pos,
+            rot,
+            pos_min,
+            pos_max,
+            rot_min,
+            rot_max,
+            pos_spring,
+            rot_spring,
+            uuid: Uuid::new_v4(),
+        });
+    }
+    Ok(vct)
+}
+
+fn tex_index(texs: &mut Vec<String>, name: &str) -> i32 {
+    if let Some(i) = texs.iter().position(|t| t == name) {
+        return i as i32;
+    }
+    texs.push(name.to_string());
+    texs.len() as i32 - 1
+}
+
+/// PMD packs the texture and sphere map into one "tex.bmp*sphere.sph" string and
+/// references the toon textures through the file's own toon list
+fn convert_mat(i: usize, m: &PmdMat, toon_names: &[String], texs: &mut Vec<String>) -> Mat {
+    let mut tex_name = "";
+    let mut env_name = "";
+    let mut env_blend_mode = BlendMode::Disable;
+    for part in m.tex_name.split('*').filter(|p| !p.is_empty()) {
+        let lower = part.to_ascii_lowercase();
+        if lower.ends_with(".sph") {
+            env_name = part;
+            env_blend_mode = BlendMode::Mul;
+        } else if lower.ends_with(".spa") {
+            env_name = part;
+            env_blend_mode = BlendMode::Add;
+        } else {
+            tex_name = part;
+        }
+    }
+    let tex_index_of = |texs: &mut Vec<String>, name: &str| if name.is_empty() { -1 } else { tex_index(texs, name) };
+    let tex_index = tex_index_of(texs, tex_name);
+    let env_index = tex_index_of(texs, env_name);
+
+    let toon = match toon_names.get(m.toon_index as usize) {
+        Some(name) if *name == default_toon_name(m.toon_index as usize) => Toon::Inner(m.toon_index),
+        Some(name) if !name.is_empty() => Toon::Tex(tex_index_of(texs, name)),
+        _ => Toon::Tex(-1),
+    };
+
+    let mut draw_flag = DrawFlags::GROUND_SHADOW;
+    if m.diffuse.w < 1.0 {
+        draw_flag |= DrawFlags::NO_CULL;
+    }
+    // MMD uses an alpha of exactly 0.98 to turn off self shadowing
+    if m.diffuse.w != 0.98 {
+        draw_flag |= DrawFlags::CAST_SHADOW | DrawFlags::RECEIVE_SHADOW;
+    }
+    if m.edge {
+        draw_flag |= DrawFlags::HAS_EDGE;
+    }
+
+    Mat {
+        name: format!("材質{}", i + 1),
+        name_en: format!("Material{}", i + 1),
+        diffuse: m.diffuse,
+        specular: m.specular.extend(m.specularity),
+        ambient: m.ambient,
+        draw_flag,
+        tex_index,
+        env_index,
+        env_blend_mode,
+        toon,
+        associated_face_count: m.face_vert_count / 3,
+        ..Default::default()
+    }
+}
+
+fn convert_bone(b: &PmdBone) -> Bone {
+    let mut bone_flags = BoneFlags::ROTATABLE | BoneFlags::ENABLED;
+    // 6: IK target, 7: hidden
+    if !matches!(b.kind, 6 | 7) {
+        bone_flags |= BoneFlags::VISIBLE;
+    }
+    // 1: rotate and move, 2: IK
+    if matches!(b.kind, 1 | 2) {
+        bone_flags |= BoneFlags::TRANSLATABLE;
+    }
+    let has_tail = b.tail != 0 && b.tail != u16::MAX;
+    let mut bone_tail_pos = BoneTailPos::Pos(Vec3::ZERO);
+    let mut inherit = None;
+    match b.kind {
+        // rotation influenced, the source bone is stored in the IK field
+        5 => {
+            bone_flags |= BoneFlags::INHERIT_ROTATION;
+            inherit = Some((b.ik_parent as i32, 1.0));
+        },
+        // rotation linked, the source bone is stored as the tail and the ratio in percent in the IK field
+        9 => {
+            bone_flags |= BoneFlags::INHERIT_ROTATION;
+            inherit = Some((b.tail as i32, b.ik_parent as f32 / 100.0));
+        },
+        _ => {
+            if has_tail {
+                bone_flags |= BoneFlags::INDEXED_TAIL_BONE;
+                bone_tail_pos = BoneTailPos::Bone(b.tail as i32);
+            }
+        },
+    }
+    Bone {
+        name: b.name.clone(),
+        name_en: String::new(),
+        pos: b.pos,
+        parent_index: if b.parent == u16::MAX { None } else { Some(b.parent as usize) },
+        layer: 0,
+        bone_flags,
+        bone_tail_pos,
+        inherit,
+        fixed_axis: None,
+        local_axis: None,
+        external_parent: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding::{Encoding, EncoderTrap};
+    use encoding::all::WINDOWS_31J;
+
+    fn fixed(out: &mut Vec<u8>, s: &str, len: usize) {
+        let mut bytes = WINDOWS_31J.encode(s, EncoderTrap::Strict).unwrap();
+        bytes.resize(len, 0);
+        out.extend(bytes);
+    }
+
+    fn floats(out: &mut Vec<u8>, values: &[f32]) {
+        out.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+    }
+
+    fn u16s(out: &mut Vec<u8>, values: &[u16]) {
+        out.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+    }
+
+    /// (name, parent, tail, kind, ik parent)
+    const BONES: [(&str, u16, u16, u8, u16); 4] = [
+        ("センター", u16::MAX, 1, 1, 0),
+        ("腕", 0, 0, 0, 0),
+        ("腕捩", 1, 0, 5, 1),
+        ("手捩", 1, 1, 9, 25),
+    ];
+
+    type Skin = (&'static str, u8, &'static [(u32, [f32; 3])]);
+
+    /// The base lists vertices 2 and 0, the morphs refer to them by their position in the base
+    const SKINS: [Skin; 3] = [
+        ("base", 0, &[(2, [2.0, 0.0, 0.0]), (0, [0.0, 0.0, 0.0])]),
+        ("あ", 1, &[(1, [0.0, 1.0, 0.0]), (0, [0.0, 2.0, 0.0])]),
+        ("い", 3, &[(0, [0.0, 0.0, 3.0])]),
+    ];
+
+    /// Three vertices in one face, a material with texture and sphere map and one with a sphere map only,
+    /// the bones above, two morphs over a base skin and a bone frame, ending after `english`
+    fn pmd(english: Option<bool>) -> Vec<u8> {
+        let mut out = b"Pmd".to_vec();
+        floats(&mut out, &[1.0]);
+        fixed(&mut out, "model", 20);
+        fixed(&mut out, "comment", 256);
+
+        out.extend(3u32.to_le_bytes());
+        for i in 0..3 {
+            floats(&mut out, &[i as f32, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+            u16s(&mut out, &[0, 1]);
+            out.extend([100, 0]);
+        }
+        out.extend(3u32.to_le_bytes());
+        u16s(&mut out, &[0, 1, 2]);
+
+        out.extend(2u32.to_le_bytes());
+        for (face_verts, tex) in [(3u32, "tex.bmp*env.spa"), (0, "env.sph")] {
+            floats(&mut out, &[1.0; 4 + 1 + 3 + 3]);
+            out.extend([0, 1]);
+            out.extend(face_verts.to_le_bytes());
+            fixed(&mut out, tex, 20);
+        }
+
+        u16s(&mut out, &[BONES.len() as u16]);
+        for (name, parent, tail, kind, ik_parent) in BONES {
+            fixed(&mut out, name, 20);
+            u16s(&mut out, &[parent, tail]);
+            out.push(kind);
+            u16s(&mut out, &[ik_parent]);
+            floats(&mut out, &[0.0; 3]);
+        }
+        u16s(&mut out, &[0]);
+
+        u16s(&mut out, &[SKINS.len() as u16]);
+        for (name, panel, items) in SKINS {
+            fixed(&mut out, name, 20);
+            out.extend((items.len() as u32).to_le_bytes());
+            out.push(panel);
+            for (index, pos) in items {
+                out.extend(index.to_le_bytes());
+                floats(&mut out, pos);
+            }
+        }
+
+        out.push(0);
+        out.push(1);
+        fixed(&mut out, "腕\n", 50);
+        out.extend(1u32.to_le_bytes());
+        u16s(&mut out, &[1]);
+        out.push(1);
+
+        match english {
+            Some(true) => {
+                out.push(1);
+                fixed(&mut out, "model en", 20);
+                fixed(&mut out, "comment en", 256);
+                for name in ["center", "arm", "arm twist", "wrist twist", "a", "i"] {
+                    fixed(&mut out, name, 20);
+                }
+                fixed(&mut out, "Arm", 50);
+            },
+            Some(false) => out.push(0),
+            None => {},
+        }
+        out
+    }
+
+    #[test]
+    fn texture_and_sphere_are_split() {
+        let m = Pmx::read_pmd(pmd(None), "").unwrap();
+        let tex = |i: i32| if i < 0 { "" } else { m.texs[i as usize].as_str() };
+        let mats: Vec<(&str, &str, BlendMode)> = m.mats.iter().map(|mat| (tex(mat.tex_index), tex(mat.env_index), mat.env_blend_mode)).collect();
+        assert_eq!(mats, vec![("tex.bmp", "env.spa", BlendMode::Add), ("", "env.sph", BlendMode::Mul)]);
+        assert_eq!(m.mats[0].associated_face_count, 1);
+    }
+
+    #[test]
+    fn skins_are_resolved_against_the_base() {
+        let m = Pmx::read_pmd(pmd(None), "").unwrap();
+        let morphs: Vec<_> = m.morphs.iter().map(|morph| match &morph.data {
+            Morph::MorphVertex(items) => (morph.name.as_str(), morph.panel, items.iter().map(|i| (i.index, i.trans)).collect::<Vec<_>>()),
+            _ => panic!("PMD skins are vertex morphs"),
+        }).collect();
+        assert_eq!(morphs, vec![
+            ("あ", 1, vec![(0, Vec3::Y), (2, 2.0 * Vec3::Y)]),
+            ("い", 3, vec![(2, 3.0 * Vec3::Z)]),
+        ]);
+    }
+
+    #[test]
+    fn influenced_bones_inherit_rotation() {
+        let m = Pmx::read_pmd(pmd(None), "").unwrap();
+        let inherits: Vec<Option<(i32, f32)>> = m.bones.iter().map(|b| b.inherit).collect();
+        assert_eq!(inherits, vec![None, None, Some((1, 1.0)), Some((1, 0.25))]);
+        assert!(m.bones[2].bone_flags.contains(BoneFlags::INHERIT_ROTATION));
+        assert!(m.bones[3].bone_flags.contains(BoneFlags::INHERIT_ROTATION));
+        // the tail of a linked bone holds its source, not a tail
+        assert!(matches!(m.bones[3].bone_tail_pos, BoneTailPos::Pos(_)));
+        assert!(matches!(m.bones[0].bone_tail_pos, BoneTailPos::Bone(1)));
+    }
+
+    #[test]
+    fn english_names_are_optional() {
+        let m = Pmx::read_pmd(pmd(Some(true)), "").unwrap();
+        assert_eq!((m.name_en.as_str(), m.comment_en.as_str()), ("model en", "comment en"));
+        let bones: Vec<&str> = m.bones.iter().map(|b| b.name_en.as_str()).collect();
+        assert_eq!(bones, vec!["center", "arm", "arm twist", "wrist twist"]);
+        let morphs: Vec<&str> = m.morphs.iter().map(|m| m.name_en.as_str()).collect();
+        assert_eq!(morphs, vec!["a", "i"]);
+        assert_eq!((m.display_frames[2].name.as_str(), m.display_frames[2].name_en.as_str()), ("腕", "Arm"));
+
+        for english in [Some(false), None] {
+            let m = Pmx::read_pmd(pmd(english), "").unwrap();
+            assert!(m.name_en.is_empty() && m.bones.iter().all(|b| b.name_en.is_empty()));
+            assert_eq!(m.display_frames[2].name, "腕");
+        }
+    }
+
+    #[test]
+    fn faces_out_of_range_are_an_error() {
+        let mut content = pmd(None);
+        // the face indices follow the 3 vertices
+        let faces = 3 + 4 + 20 + 256 + 4 + 3 * 38 + 4;
+        content[faces + 4] = 3;
+        assert!(matches!(Pmx::read_pmd(content, ""), Err(PmxError::InvalidValue { section: "face", .. })));
+
+        let mut content = pmd(None);
+        let mats = faces + 6 + 4;
+        content[mats + 46] = 6;
+        assert!(matches!(Pmx::read_pmd(content, ""), Err(PmxError::InvalidValue { section: "material", .. })));
+    }
+}
@@ -0,0 +1,710 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(unused_variables)]
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Cursor, Write};
+
+use byteorder::{LE, WriteBytesExt};
+use encoding::{Encoding, EncoderTrap};
+use encoding::all::WINDOWS_31J;
+use glam::*;
+
+use super::common::*;
+use super::pmx::*;
+use super::vmd_writer::write_string;
+
+const MAX_VERTS: usize = 65535;
+// bone indices are u16 with 0xffff marking no bone
+const MAX_BONES: usize = u16::MAX as usize;
+const TOON_COUNT: usize = 10;
+
+// fixed length Shift-JIS field, remembers whether the text did not fit
+fn write_fixed(file: &mut Cursor<Vec<u8>>, content: &str, len: usize, lossy: &mut usize) {
+    match WINDOWS_31J.encode(content, EncoderTrap::Strict) {
+        Ok(encoded) if encoded.len() < len => {},
+        _ => *lossy += 1,
+    }
+    write_string(file, &content.to_string(), len);
+}
+
+fn bone_u16(index: i32) -> u16 {
+    if index < 0 || index as usize >= MAX_BONES { u16::MAX } else { index as u16 }
+}
+
+impl Pmx {
+    /// Writes the model as PMD 1.0, returning the file together with a list of
+    /// the features that PMD cannot store and were dropped or approximated
+    pub fn write_pmd(&self) -> (Vec<u8>, Vec<String>) {
+        let mut report = Vec::new();
+        let mut lossy_names = 0;
+        let file = &mut Cursor::new(Vec::new());
+
+        file.write_all(b"Pmd").unwrap();
+        file.write_f32::<LE>(1.0).unwrap();
+        write_fixed(file, &self.name, 20, &mut lossy_names);
+        write_fixed(file, &self.comment, 256, &mut lossy_names);
+
+        self.write_pmd_verts(file, &mut report);
+        self.write_pmd_faces_and_mats(file, &mut report);
+        self.write_pmd_bones(file, &mut report, &mut lossy_names);
+        self.write_pmd_iks(file, &mut report);
+        let morph_map = self.write_pmd_morphs(file, &mut report, &mut lossy_names);
+        let frames = self.write_pmd_display_frames(file, &morph_map, &mut report, &mut lossy_names);
+
+        // english names
+        file.write_u8(1).unwrap();
+        write_fixed(file, &self.name_en, 20, &mut lossy_names);
+        write_fixed(file, &self.comment_en, 256, &mut lossy_names);
+        for b in self.bones.iter().take(MAX_BONES) {
+            write_fixed(file, &b.name_en, 20, &mut lossy_names);
+        }
+        for i in morph_map.keys() {
+            write_fixed(file, &self.morphs[*i].name_en, 20, &mut lossy_names);
+        }
+        for f in &frames {
+            write_fixed(file, &self.display_frames[*f].name_en, 50, &mut lossy_names);
+        }
+
+        self.write_pmd_toons(file, &mut report);
+        self.write_pmd_rigidbodys(file, &mut lossy_names);
+        self.write_pmd_joints(file, &mut report, &mut lossy_names);
+
+        if !self.softbodys.is_empty() {
+            report.push(format!("{} soft bodies dropped", self.softbodys.len()));
+        }
+        if lossy_names > 0 {
+            report.push(format!("{} names or comments truncated or not representable in Shift-JIS", lossy_names));
+        }
+        (file.get_ref().clone(), report)
+    }
+
+    fn write_pmd_verts(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>) {
+        let count = self.verts.len().min(MAX_VERTS);
+        if self.verts.len() > MAX_VERTS {
+            report.push(format!("model has {} vertices, only the first {} and the faces using them were kept", self.verts.len(), MAX_VERTS));
+        }
+        if !self.appendix_uvs.is_empty() {
+            report.push(format!("{} additional UV channels dropped", self.appendix_uvs.len()));
+        }
+        let mut reduced = 0;
+        let mut sdef = 0;
+        let mut edge_scale = 0;
+        file.write_u32::<LE>(count as _).unwrap();
+        for v in &self.verts[..count] {
+            write_float3(file, v.pos);
+            write_float3(file, v.nrm);
+            write_float2(file, v.uv);
+            let (b0, b1, w) = match v.weight {
+                VertexWeight::One(b) => (b, 0, 1.0),
+                VertexWeight::Two(b0, b1, w) => (b0, b1, w),
+                VertexWeight::Sphere(b0, b1, w, _, _, _) => {
+                    sdef += 1;
+                    (b0, b1, w)
+                },
+                VertexWeight::Four(bs, ws) | VertexWeight::Quat(bs, ws) => {
+                    let mut order = [0, 1, 2, 3];
+                    order.sort_by(|&a, &b| ws[b].total_cmp(&ws[a]));
+                    let [first, second, ..] = order;
+                    if ws[order[2]] > 0.0 {
+                        reduced += 1;
+                    }
+                    let sum = ws[first] + ws[second];
+                    if ws[second] <= 0.0 || sum <= 0.0 {
+                        (bs[first], 0, 1.0)
+                    } else {
+                        (bs[first], bs[second], ws[first] / sum)
+                    }
+                },
+            };
+            file.write_u16::<LE>(bone_u16(b0)).unwrap();
+            file.write_u16::<LE>(bone_u16(b1)).unwrap();
+            file.write_u8((w.clamp(0.0, 1.0) * 100.0).round() as u8).unwrap();
+            if v.edge_scale != 0.0 && v.edge_scale != 1.0 {
+                edge_scale += 1;
+            }
+            file.write_u8(if v.edge_scale == 0.0 { 1 } else { 0 }).unwrap();
+        }
+        if reduced > 0 {
+            report.push(format!("{} BDEF4/QDEF vertices reduced to their two strongest bones", reduced));
+        }
+        if sdef > 0 {
+            report.push(format!("{} SDEF vertices written as BDEF2", sdef));
+        }
+        if edge_scale > 0 {
+            report.push(format!("{} vertex edge scales rounded to on/off", edge_scale));
+        }
+    }
+
+    fn write_pmd_faces_and_mats(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>) {
+        let mut faces = Vec::new();
+        let mut face_counts = Vec::new();
+        let mut offset = 0;
+        for m in &self.mats {
+            let end = (offset + m.associated_face_count as usize).min(self.faces.len());
+            let kept: Vec<[u32; 3]> = self.faces[offset..end].iter()
+                .filter(|f| f.iter().all(|&i| (i as usize) < MAX_VERTS))
+                .copied()
+                .collect();
+            face_counts.push(kept.len());
+            faces.extend(kept);
+            offset = end;
+        }
+        file.write_u32::<LE>(faces.len() as u32 * 3).unwrap();
+        for f in &faces {
+            for i in f {
+                file.write_u16::<LE>(*i as u16).unwrap();
+            }
+        }
+
+        let toon_slots = self.pmd_toon_slots(report);
+        let mut long_tex = 0;
+        let mut sphere_mismatch = 0;
+        let mut sub_tex = 0;
+        let mut draw_flags = 0;
+        let mut edges = 0;
+        file.write_u32::<LE>(self.mats.len() as _).unwrap();
+        for (m, face_count) in self.mats.iter().zip(face_counts) {
+            write_float4(file, m.diffuse);
+            file.write_f32::<LE>(m.specular.w).unwrap();
+            write_float3(file, m.specular.xyz());
+            write_float3(file, m.ambient);
+            let toon = match m.toon {
+                Toon::Inner(i) => i,
+                Toon::Tex(t) => toon_slots.get(&t).copied().unwrap_or(u8::MAX),
+            };
+            file.write_u8(toon).unwrap();
+            file.write_u8(if m.draw_flag.contains(DrawFlags::HAS_EDGE) { 1 } else { 0 }).unwrap();
+            file.write_u32::<LE>(face_count as u32 * 3).unwrap();
+
+            let tex = self.texs.get(m.tex_index as usize).filter(|_| m.tex_index >= 0);
+            let env = self.texs.get(m.env_index as usize).filter(|_| m.env_index >= 0);
+            // PMD picks the sphere mode from the file extension
+            if let Some(env) = env {
+                let lower = env.to_ascii_lowercase();
+                match m.env_blend_mode {
+                    BlendMode::Mul if !lower.ends_with(".sph") => sphere_mismatch += 1,
+                    BlendMode::Add if !lower.ends_with(".spa") => sphere_mismatch += 1,
+                    BlendMode::Other => sub_tex += 1,
+                    _ => {},
+                }
+            }
+            let tex_name = match (tex, env) {
+                (Some(t), Some(e)) if !matches!(m.env_blend_mode, BlendMode::Disable | BlendMode::Other) => format!("{}*{}", t, e),
+                (Some(t), _) => t.clone(),
+                (None, Some(e)) if !matches!(m.env_blend_mode, BlendMode::Disable | BlendMode::Other) => e.clone(),
+                _ => String::new(),
+            };
+            write_fixed(file, &tex_name, 20, &mut long_tex);
+
+            let double_sided = m.diffuse.w < 1.0;
+            if m.draw_flag.contains(DrawFlags::NO_CULL) != double_sided
+                || m.draw_flag.intersects(DrawFlags::VERTEX_COLOR | DrawFlags::FILL_MODE_POINT | DrawFlags::FILL_MODE_EDGE) {
+                draw_flags += 1;
+            }
+            if m.edge_color != vec4(0.0, 0.0, 0.0, 1.0) || m.edge_scale != 1.0 {
+                edges += 1;
+            }
+        }
+        if !self.mats.is_empty() {
+            report.push("material names and comments dropped".to_string());
+        }
+        if long_tex > 0 {
+            report.push(format!("{} material texture paths longer than 20 bytes truncated", long_tex));
+        }
+        if sphere_mismatch > 0 {
+            report.push(format!("{} sphere maps whose extension does not match their blend mode", sphere_mismatch));
+        }
+        if sub_tex > 0 {
+            report.push(format!("{} sub-texture sphere maps dropped", sub_tex));
+        }
+        if draw_flags > 0 {
+            report.push(format!("{} materials with culling or draw flags PMD derives from alpha", draw_flags));
+        }
+        if edges > 0 {
+            report.push(format!("{} material edge colors or sizes dropped", edges));
+        }
+    }
+
+    /// Assigns custom toon textures to the slots of the 10 entry PMD toon table that are not used as built-in toons
+    fn pmd_toon_slots(&self, report: &mut Vec<String>) -> BTreeMap<i32, u8> {
+        let used: BTreeSet<u8> = self.mats.iter().filter_map(|m| match m.toon {
+            Toon::Inner(i) => Some(i),
+            _ => None,
+        }).collect();
+        let mut free = (0..TOON_COUNT as u8).filter(|i| !used.contains(i));
+        let mut slots = BTreeMap::new();
+        let mut dropped = 0;
+        for m in &self.mats {
+            if let Toon::Tex(t) = m.toon {
+                if t < 0 || slots.contains_key(&t) {
+                    continue;
+                }
+                if let Some(slot) = free.next() {
+                    slots.insert(t, slot);
+                } else {
+                    dropped += 1;
+                }
+            }
+        }
+        if dropped > 0 {
+            report.push(format!("{} toon textures dropped, the PMD toon table has only {} slots", dropped, TOON_COUNT));
+        }
+        slots
+    }
+
+    fn write_pmd_toons(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>) {
+        let mut toons: Vec<String> = (1..=TOON_COUNT).map(|i| format!("toon{:02}.bmp", i)).collect();
+        for (t, slot) in self.pmd_toon_slots(&mut Vec::new()) {
+            toons[slot as usize] = self.texs.get(t as usize).cloned().unwrap_or_default();
+        }
+        let mut long_names = 0;
+        for t in &toons {
+            write_fixed(file, t, 100, &mut long_names);
+        }
+        if long_names > 0 {
+            report.push(format!("{} toon texture paths longer than 100 bytes truncated", long_names));
+        }
+    }
+
+    fn write_pmd_bones(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>, lossy_names: &mut usize) {
+        let effectors: BTreeSet<i32> = self.iks.iter().map(|ik| ik.effector).collect();
+        let mut chain_owner = HashMap::new();
+        for ik in &self.iks {
+            for j in &ik.ik_joints {
+                chain_owner.entry(j.bone).or_insert(ik.bone);
+            }
+        }
+        let mut inherit_translation = 0;
+        let mut tail_offsets = 0;
+        let mut local_axis = 0;
+        let mut external_parent = 0;
+        let mut layers = 0;
+        let mut fixed_axis = 0;
+        let count = self.bones.len().min(MAX_BONES);
+        if self.bones.len() > MAX_BONES {
+            report.push(format!("model has {} bones, only the first {} were kept and references to the rest dropped", self.bones.len(), MAX_BONES));
+        }
+        file.write_u16::<LE>(count as _).unwrap();
+        for (i, b) in self.bones[..count].iter().enumerate() {
+            let i = i as i32;
+            let mut tail = match b.bone_tail_pos {
+                BoneTailPos::Bone(t) if t >= 0 => bone_u16(t),
+                BoneTailPos::Pos(pos) if pos != Vec3::ZERO => {
+                    tail_offsets += 1;
+                    0
+                },
+                _ => 0,
+            };
+            let mut ik_parent = 0;
+            let flags = b.bone_flags;
+            let inherit = b.inherit.filter(|_| flags.contains(BoneFlags::INHERIT_ROTATION));
+            if flags.contains(BoneFlags::INHERIT_TRANSLATION) {
+                inherit_translation += 1;
+            }
+            let kind = if flags.contains(BoneFlags::IK) {
+                2
+            } else if let Some((source, ratio)) = inherit {
+                if ratio == 1.0 {
+                    ik_parent = bone_u16(source);
+                    5
+                } else {
+                    tail = bone_u16(source);
+                    ik_parent = (ratio.clamp(0.0, 1.0) * 100.0).round() as u16;
+                    9
+                }
+            } else if flags.contains(BoneFlags::FIXED_AXIS) {
+                // PMD twist bones rotate around the direction to their tail
+                if !matches!(b.bone_tail_pos, BoneTailPos::Bone(t) if t >= 0) {
+                    fixed_axis += 1;
+                }
+                8
+            } else if !flags.contains(BoneFlags::VISIBLE) {
+                if effectors.contains(&i) { 6 } else { 7 }
+            } else if let Some(&owner) = chain_owner.get(&i) {
+                ik_parent = bone_u16(owner);
+                4
+            } else if flags.contains(BoneFlags::TRANSLATABLE) {
+                1
+            } else {
+                0
+            };
+            if b.local_axis.is_some() {
+                local_axis += 1;
+            }
+            if b.external_parent.is_some() {
+                external_parent += 1;
+            }
+            if b.layer != 0 || flags.contains(BoneFlags::PHYSICS_AFTER_DEFORM) {
+                layers += 1;
+            }
+            write_fixed(file, &b.name, 20, lossy_names);
+            file.write_u16::<LE>(b.parent_index.map_or(u16::MAX, |p| bone_u16(p as i32))).unwrap();
+            file.write_u16::<LE>(tail).unwrap();
+            file.write_u8(kind).unwrap();
+            file.write_u16::<LE>(ik_parent).unwrap();
+            write_float3(file, b.pos);
+        }
+        if inherit_translation > 0 {
+            report.push(format!("{} translation inherit bones dropped", inherit_translation));
+        }
+        let ratio_inherit = self.bones.iter()
+            .filter(|b| b.bone_flags.contains(BoneFlags::INHERIT_ROTATION))
+            .filter(|b| b.inherit.map_or(false, |(_, r)| !(0.0..=1.0).contains(&r)))
+            .count();
+        if ratio_inherit > 0 {
+            report.push(format!("{} rotation inherit ratios outside 0..1 clamped", ratio_inherit));
+        }
+        if tail_offsets > 0 {
+            report.push(format!("{} bone tail offsets dropped", tail_offsets));
+        }
+        if fixed_axis > 0 {
+            report.push(format!("{} fixed axis bones without a tail bone lost their axis", fixed_axis));
+        }
+        if local_axis > 0 {
+            report.push(format!("{} bone local axes dropped", local_axis));
+        }
+        if external_parent > 0 {
+            report.push(format!("{} bone external parents dropped", external_parent));
+        }
+        if layers > 0 {
+            report.push(format!("{} bone deform layers or after-physics flags dropped", layers));
+        }
+    }
+
+    fn write_pmd_iks(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>) {
+        let mut limits = 0;
+        let mut long_chains = 0;
+        file.write_u16::<LE>(self.iks.len() as _).unwrap();
+        for ik in &self.iks {
+            let chain = &ik.ik_joints[..ik.ik_joints.len().min(u8::MAX as usize)];
+            if chain.len() < ik.ik_joints.len() {
+                long_chains += 1;
+            }
+            file.write_u16::<LE>(bone_u16(ik.bone)).unwrap();
+            file.write_u16::<LE>(bone_u16(ik.effector)).unwrap();
+            file.write_u8(chain.len() as u8).unwrap();
+            file.write_u16::<LE>(ik.loop_count.clamp(0, u16::MAX as i32) as u16).unwrap();
+            file.write_f32::<LE>(ik.limit_angle / 4.0).unwrap();
+            for j in chain {
+                file.write_u16::<LE>(bone_u16(j.bone)).unwrap();
+                // MMD applies the knee limit by name, every other limit is lost
+                let is_knee = self.bones.get(j.bone as usize).map_or(false, |b| b.name.contains("ひざ"));
+                if j.limit.is_some() && !is_knee {
+                    limits += 1;
+                }
+            }
+        }
+        if limits > 0 {
+            report.push(format!("{} IK angle limits dropped", limits));
+        }
+        if long_chains > 0 {
+            report.push(format!("{} IK chains longer than 255 links truncated", long_chains));
+        }
+    }
+
+    /// Writes the vertex morphs as PMD skins and returns the skin index of every written morph,
+    /// skin 0 is the base holding the original position of every vertex touched by a morph
+    fn write_pmd_morphs(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>, lossy_names: &mut usize) -> BTreeMap<usize, usize> {
+        let mut base = BTreeSet::new();
+        let mut dropped: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for m in &self.morphs {
+            let kind = match &m.data {
+                Morph::MorphVertex(vs) => {
+                    base.extend(vs.iter().map(|v| v.index).filter(|&i| (i as usize) < MAX_VERTS.min(self.verts.len())));
+                    continue;
+                },
+                Morph::MorphGroup(_) => "group",
+                Morph::MorphFlip(_) => "flip",
+                Morph::MorphBone(_) => "bone",
+                Morph::MorphUv(_) => "UV",
+                Morph::MorphRigidbody(_) => "rigidbody",
+                Morph::MorphMat(_) => "material",
+            };
+            dropped.entry(kind).or_default().push(&m.name);
+        }
+        for (kind, names) in &dropped {
+            report.push(format!("{} {} morphs dropped: {}", names.len(), kind, names.join(", ")));
+        }
+
+        let base: Vec<u32> = base.into_iter().collect();
+        let base_index: HashMap<u32, u32> = base.iter().enumerate().map(|(i, &v)| (v, i as u32)).collect();
+        let mut morph_map = BTreeMap::new();
+        let vertex_morphs: Vec<(usize, &MorphInfo, &Vec<MorphVertexItem>)> = self.morphs.iter().enumerate()
+            .filter_map(|(i, m)| match &m.data {
+                Morph::MorphVertex(vs) => Some((i, m, vs)),
+                _ => None,
+            })
+            .collect();
+
+        let skin_count = if vertex_morphs.is_empty() { 0 } else { vertex_morphs.len() + 1 };
+        file.write_u16::<LE>(skin_count as _).unwrap();
+        if skin_count == 0 {
+            return morph_map;
+        }
+        write_fixed(file, "base", 20, lossy_names);
+        file.write_u32::<LE>(base.len() as _).unwrap();
+        file.write_u8(0).unwrap();
+        for &v in &base {
+            file.write_u32::<LE>(v).unwrap();
+            write_float3(file, self.verts[v as usize].pos);
+        }
+        let mut panels = 0;
+        for (skin, (i, m, vs)) in vertex_morphs.into_iter().enumerate() {
+            morph_map.insert(i, skin + 1);
+            let items: Vec<(u32, Vec3)> = vs.iter()
+                .filter_map(|v| base_index.get(&v.index).map(|&b| (b, v.trans)))
+                .collect();
+            write_fixed(file, &m.name, 20, lossy_names);
+            file.write_u32::<LE>(items.len() as _).unwrap();
+            let panel = if (1..=4).contains(&m.panel) {
+                m.panel as u8
+            } else {
+                panels += 1;
+                4
+            };
+            file.write_u8(panel).unwrap();
+            for (index, trans) in items {
+                file.write_u32::<LE>(index).unwrap();
+                write_float3(file, trans);
+            }
+        }
+        if panels > 0 {
+            report.push(format!("{} morphs without a valid panel moved to the other panel", panels));
+        }
+        morph_map
+    }
+
+    /// Returns the display frames written as PMD bone frames
+    fn write_pmd_display_frames(
+        &self,
+        file: &mut Cursor<Vec<u8>>,
+        morph_map: &BTreeMap<usize, usize>,
+        report: &mut Vec<String>,
+        lossy_names: &mut usize
+    ) -> Vec<usize> {
+        let mut skins = Vec::new();
+        for f in &self.display_frames {
+            for item in &f.morph_items {
+                if let DisplayFrameIndex::Morph(m) = item {
+                    if let Some(&skin) = morph_map.get(&(*m as usize)) {
+                        skins.push(skin as u16);
+                    }
+                }
+            }
+        }
+        if skins.len() > u8::MAX as usize {
+            report.push(format!("{} morphs in display frames, only the first 255 are shown", skins.len()));
+            skins.truncate(u8::MAX as usize);
+        }
+        file.write_u8(skins.len() as _).unwrap();
+        for s in &skins {
+            file.write_u16::<LE>(*s).unwrap();
+        }
+
+        // the special Root and expression frames have no PMD counterpart
+        let mut frames: Vec<usize> = (0..self.display_frames.len())
+            .filter(|&i| !self.display_frames[i].special)
+            .collect();
+        if frames.len() > u8::MAX as usize {
+            report.push(format!("{} bone display frames, only the first 255 were kept", frames.len()));
+            frames.truncate(u8::MAX as usize);
+        }
+        file.write_u8(frames.len() as _).unwrap();
+        for &f in &frames {
+            // MMD terminates frame names with a line feed
+            write_fixed(file, &format!("{}\n", self.display_frames[f].name), 50, lossy_names);
+        }
+        let mut items = Vec::new();
+        for (n, &f) in frames.iter().enumerate() {
+            for item in &self.display_frames[f].morph_items {
+                match item {
+                    DisplayFrameIndex::Bone(b) if (*b as usize) < MAX_BONES => items.push((*b as u16, n as u8 + 1)),
+                    _ => {}
+                }
+            }
+        }
+        file.write_u32::<LE>(items.len() as _).unwrap();
+        for (b, n) in items {
+            file.write_u16::<LE>(b).unwrap();
+            file.write_u8(n).unwrap();
+        }
+        frames
+    }
+
+    fn write_pmd_rigidbodys(&self, file: &mut Cursor<Vec<u8>>, lossy_names: &mut usize) {
+        file.write_u32::<LE>(self.rigidbodys.len() as _).unwrap();
+        for r in &self.rigidbodys {
+            write_fixed(file, &r.name, 20, lossy_names);
+            file.write_u16::<LE>(bone_u16(r.bone)).unwrap();
+            file.write_u8(r.group).unwrap();
+            file.write_u16::<LE>(r.collision_group).unwrap();
+            file.write_u8(match r.shape {
+                RigidbodyShape::Shpere => 0,
+                RigidbodyShape::Box => 1,
+                RigidbodyShape::Capsule => 2,
+            }).unwrap();
+            write_float3(file, r.size);
+            // PMD rigidbody positions are relative to their bone, or to the first bone when unbound
+            let bone = if r.bone >= 0 { r.bone as usize } else { 0 };
+            let origin = self.bones.get(bone).map(|b| b.pos).unwrap_or_default();
+            write_float3(file, r.pos - origin);
+            write_float3(file, r.rot);
+            file.write_f32::<LE>(r.mass).unwrap();
+            file.write_f32::<LE>(r.linear_damping).unwrap();
+            file.write_f32::<LE>(r.angular_damping).unwrap();
+            file.write_f32::<LE>(r.restitution).unwrap();
+            file.write_f32::<LE>(r.friction).unwrap();
+            file.write_u8(match r.mode {
+                RigidbodyMode::Kinematics => 0,
+                RigidbodyMode::Dynamics => 1,
+                RigidbodyMode::DynamicsPassRotation => 2,
+            }).unwrap();
+        }
+    }
+
+    fn write_pmd_joints(&self, file: &mut Cursor<Vec<u8>>, report: &mut Vec<String>, lossy_names: &mut usize) {
+        let mut kinds = 0;
+        file.write_u32::<LE>(self.joints.len() as _).unwrap();
+        for j in &self.joints {
+            if !matches!(j.kind, JointKind::Spring6Dof) {
+                kinds += 1;
+            }
+            write_fixed(file, &j.name, 20, lossy_names);
+            file.write_u32::<LE>(j.rigidbody_a as u32).unwrap();
+            file.write_u32::<LE>(j.rigidbody_b as u32).unwrap();
+            write_float3(file, j.pos);
+            write_float3(file, j.rot);
+            write_float3(file, j.pos_min);
+            write_float3(file, j.pos_max);
+            write_float3(file, j.rot_min);
+            write_float3(file, j.rot_max);
+            write_float3(file, j.pos_spring);
+            write_float3(file, j.rot_spring);
+        }
+        if kinds > 0 {
+            report.push(format!("{} non spring joints written as spring 6DOF joints", kinds));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    #[test]
+    fn too_many_bones_are_reported() {
+        let mut m = skeleton_model();
+        let (_, report) = m.write_pmd();
+        assert!(!report.iter().any(|line| line.contains("bones, only the first")));
+
+        m.bones.extend((m.bones.len()..MAX_BONES + 2).map(|i| bone(&format!("b{}", i), Some(0), Vec3::ZERO)));
+        let (pmd, report) = m.write_pmd();
+        assert!(report.iter().any(|line| line.contains(&format!("model has {} bones, only the first {}", MAX_BONES + 2, MAX_BONES))));
+        let read = Pmx::read_pmd(pmd, "").unwrap();
+        assert_eq!(read.bones.len(), MAX_BONES);
+    }
+    fn has_line(report: &[String], line: &str) -> bool {
+        report.iter().any(|l| l == line)
+    }
+
+    #[test]
+    fn four_bone_weights_keep_the_two_strongest() {
+        let mut m = skeleton_model();
+        m.verts = vec![
+            Vertex { weight: VertexWeight::Four(ivec4(0, 1, 2, 3), vec4(0.1, 0.6, 0.2, 0.1)), ..vertex(Vec3::ZERO) },
+            Vertex { weight: VertexWeight::Quat(ivec4(3, 2, 1, 0), vec4(0.0, 0.75, 0.25, 0.0)), ..vertex(Vec3::X) },
+            Vertex { weight: VertexWeight::Four(ivec4(1, 2, 3, 0), vec4(1.0, 0.0, 0.0, 0.0)), ..vertex(Vec3::Y) },
+        ];
+        let (pmd, report) = m.write_pmd();
+        // only the first vertex had a third bone to drop
+        assert!(has_line(&report, "1 BDEF4/QDEF vertices reduced to their two strongest bones"), "{:?}", report);
+        let read = Pmx::read_pmd(pmd, "").unwrap();
+        let weights: Vec<(i32, i32, f32)> = read.verts.iter().map(|v| match v.weight {
+            VertexWeight::Two(b0, b1, w) => (b0, b1, w),
+            _ => panic!("PMD vertices are BDEF2"),
+        }).collect();
+        assert_eq!(weights, vec![(1, 2, 0.75), (2, 1, 0.75), (1, 0, 1.0)]);
+    }
+
+    #[test]
+    fn vertex_morphs_index_a_base_skin() {
+        let mut m = Pmx::new();
+        m.bones.push(bone("root", None, Vec3::ZERO));
+        m.verts = (0..4).map(|i| vertex(vec3(i as f32, 0.0, 0.0))).collect();
+        let item = |index: u32, trans: Vec3| MorphVertexItem { index, trans };
+        m.morphs.push(morph("a", Morph::MorphVertex(vec![item(2, Vec3::Y), item(0, Vec3::X)])));
+        m.morphs.push(morph("g", group(&[0])));
+        m.morphs.push(MorphInfo { panel: 0, ..morph("b", Morph::MorphVertex(vec![item(1, Vec3::Z), item(2, -Vec3::Y)])) });
+        let (pmd, report) = m.write_pmd();
+        assert!(has_line(&report, "1 group morphs dropped: g"), "{:?}", report);
+        assert!(has_line(&report, "1 morphs without a valid panel moved to the other panel"), "{:?}", report);
+
+        // the morphs are stored as offsets into the base skin and resolved back to vertices on read
+        let read = Pmx::read_pmd(pmd, "").unwrap();
+        let morphs: Vec<_> = read.morphs.iter().map(|m| match &m.data {
+            Morph::MorphVertex(items) => (m.name.as_str(), m.panel, items.iter().map(|i| (i.index, i.trans)).collect::<Vec<_>>()),
+            _ => panic!("PMD morphs are vertex morphs"),
+        }).collect();
+        assert_eq!(morphs, vec![
+            ("a", 4, vec![(2, Vec3::Y), (0, Vec3::X)]),
+            ("b", 4, vec![(1, Vec3::Z), (2, -Vec3::Y)]),
+        ]);
+    }
+
+    #[test]
+    fn base_skin_holds_the_rest_positions() {
+        let mut m = Pmx::new();
+        m.verts = (0..4).map(|i| vertex(vec3(i as f32, 1.0, 0.0))).collect();
+        m.morphs.push(morph("a", Morph::MorphVertex(vec![
+            MorphVertexItem { index: 3, trans: Vec3::Y },
+            MorphVertexItem { index: 1, trans: Vec3::Y },
+        ])));
+        let (pmd, _) = m.write_pmd();
+        // header, 4 vertices, no faces, materials, bones or IKs
+        let skins = 3 + 4 + 20 + 256 + 4 + 4 * 38 + 4 + 4 + 2 + 2;
+        assert_eq!(u16::from_le_bytes([pmd[skins], pmd[skins + 1]]), 2);
+        let base = &pmd[skins + 2..];
+        assert_eq!(&base[20..25], &[2, 0, 0, 0, 0]);
+        let entry = |i: usize| {
+            let e = &base[25 + i * 16..25 + (i + 1) * 16];
+            let f = |o: usize| f32::from_le_bytes([e[o], e[o + 1], e[o + 2], e[o + 3]]);
+            (u32::from_le_bytes([e[0], e[1], e[2], e[3]]), vec3(f(4), f(8), f(12)))
+        };
+        // sorted by vertex, with the absolute position of every vertex touched by a morph
+        assert_eq!([entry(0), entry(1)], [(1, vec3(1.0, 1.0, 0.0)), (3, vec3(3.0, 1.0, 0.0))]);
+    }
+
+    #[test]
+    fn custom_toons_take_the_free_slots() {
+        let mut m = Pmx::new();
+        m.texs = vec!["tex.bmp".to_string(), "toon_a.bmp".to_string(), "toon_b.bmp".to_string()];
+        for toon in [Toon::Inner(0), Toon::Inner(3), Toon::Tex(1), Toon::Tex(2), Toon::Tex(1)] {
+            m.mats.push(Mat { toon, ..mat("m", 0) });
+        }
+        let (pmd, report) = m.write_pmd();
+        assert_eq!(m.pmd_toon_slots(&mut Vec::new()), BTreeMap::from([(1, 1), (2, 2)]));
+        assert!(!report.iter().any(|l| l.contains("toon textures dropped")), "{:?}", report);
+
+        let read = Pmx::read_pmd(pmd, "").unwrap();
+        let toons: Vec<String> = read.mats.iter().map(|mat| match mat.toon {
+            Toon::Inner(i) => format!("inner {}", i),
+            Toon::Tex(t) => read.texs[t as usize].clone(),
+        }).collect();
+        assert_eq!(toons, vec!["inner 0", "inner 3", "toon_a.bmp", "toon_b.bmp", "toon_a.bmp"]);
+    }
+
+    #[test]
+    fn toons_beyond_the_table_are_dropped() {
+        let mut m = Pmx::new();
+        m.texs = (0..10).map(|i| format!("toon_{}.bmp", i)).collect();
+        m.mats.push(Mat { toon: Toon::Inner(0), ..mat("m", 0) });
+        m.mats.extend((0..10).map(|t| Mat { toon: Toon::Tex(t), ..mat("m", 0) }));
+        let (pmd, report) = m.write_pmd();
+        assert!(has_line(&report, "1 toon textures dropped, the PMD toon table has only 10 slots"), "{:?}", report);
+        let read = Pmx::read_pmd(pmd, "").unwrap();
+        assert!(matches!(read.mats[9].toon, Toon::Tex(t) if read.texs[t as usize] == "toon_8.bmp"));
+        assert!(matches!(read.mats[10].toon, Toon::Tex(-1)));
+    }
+}
@@ -11,340 +11,998 @@ use encoding::{Encoding, DecoderTrap};
 use encoding::all::WINDOWS_31J;
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{SeekFrom, Cursor};
-use super::vmd_reader::{read_string, read_bezier_control_point_pair1};
-use super::common::{read_items, read_fix_items, read_float3, read_float4, read_quat};
+use std::io::{self, SeekFrom, Cursor};
+use super::vmd_reader::{read_string, read_bezier_control_point_pair1, read_records};
+use super::common::{read_float3, read_float4, read_quat};
 use super::motion::*;
 use std::collections::BTreeMap;
 use glam::*;
 
 use super::motion::*;
 
-const PMM_HEADER: &str = "Polygon Movie maker 0002";
+pub(crate) const PMM_HEADER: &str = "Polygon Movie maker 0002";
+
+/// An MMD project, every model, accessory and scene track together with the editor state MMD restores
+pub struct PmmProject {
+    pub view_width: u32,
+    pub view_height: u32,
+    pub frame_width: u32,
+    pub edit_view_angle: f32,
+    pub camera_light_accessory_edited: bool,
+    /// camera, light, accessory, bone, morph and self shadow panels
+    pub panels_opened: [bool; 6],
+    pub selected_model_index: u8,
+    pub models: Vec<PmmModel>,
+    pub camera: PmmCamera,
+    pub light_keyframes: Vec<PmmLightKeyframe>,
+    /// the light as set in the panel, with whether it is selected
+    pub light_current: PmmLightKeyframe,
+    pub selected_accessory_index: u8,
+    pub accessory_v_scroll: u32,
+    pub accessories: Vec<PmmAccessory>,
+    pub current_frame_position: u32,
+    pub h_scroll: u32,
+    pub h_scroll_scale: u32,
+    pub bone_operation: u32,
+    pub looking_at: u8,
+    pub repeat: bool,
+    pub play_from_enabled: bool,
+    pub play_to_enabled: bool,
+    pub play_start_frame: u32,
+    pub play_end_frame: u32,
+    pub wave_enabled: bool,
+    pub wave_path: String,
+    pub avi: PmmBackground,
+    pub background_image: PmmBackground,
+    pub show_information: bool,
+    pub show_axis: bool,
+    pub show_ground_shadow: bool,
+    pub fps_limit: f32,
+    pub screen_capture_mode: u32,
+    pub accessory_render_after_model: u32,
+    pub ground_shadow_brightness: f32,
+    pub transparent_ground_shadow: bool,
+    pub physics_mode: u8,
+    pub gravity_current: GravityKeyframe,
+    pub gravity_keyframes: Vec<GravityKeyframe>,
+    pub show_self_shadow: bool,
+    pub self_shadow_current: f32,
+    pub shadow_keyframes: Vec<PmmShadowKeyframe>,
+    /// Settings appended by later MMD versions, `None` when the file ends before them
+    pub extra: Option<PmmExtra>,
+    /// Bytes after the shadow track too short to hold `extra`, written back as they are
+    pub trailing: Vec<u8>,
+}
+
+pub struct PmmModel {
+    pub number: u8,
+    pub name: String,
+    pub name_en: String,
+    pub path: String,
+    pub keyframe_editor_top_level_rows: u8,
+    pub bone_names: Vec<String>,
+    pub morph_names: Vec<String>,
+    /// bone indexes of the IK bones, in the order of `PmmPropertyKeyframe::ik_enabled`
+    pub ik_indexes: Vec<u32>,
+    /// bone indexes of the bones with an outside parent, in the order of `PmmPropertyKeyframe::op_parents`
+    pub op_indexes: Vec<u32>,
+    pub draw_order: u8,
+    pub edit_is_display: bool,
+    pub edit_selected_bone: u32,
+    pub skin_panel: [u32; 4],
+    pub frame_opened: Vec<u8>,
+    pub v_scroll: u32,
+    pub last_frame: u32,
+    /// keyframes of every bone, indexed like `bone_names`
+    pub bone_keyframes: Vec<Vec<PmmBoneKeyframe>>,
+    /// keyframes of every morph, indexed like `morph_names`
+    pub morph_keyframes: Vec<Vec<PmmMorphKeyframe>>,
+    pub property_keyframes: Vec<PmmPropertyKeyframe>,
+    pub bone_current: Vec<PmmBoneCurrent>,
+    pub morph_current: Vec<f32>,
+    pub ik_current: Vec<bool>,
+    pub op_current: Vec<PmmOpCurrent>,
+    pub blend_added: bool,
+    pub edge_width: f32,
+    pub self_shadow_enabled: bool,
+    pub calc_order: u8,
+}
+
+/// Where MMD stored a keyframe and whether it is selected in the keyframe editor,
+/// kept so that tracks which were not edited are written back as they were read
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct PmmEditorState {
+    /// data index of the keyframe in the file, `None` for keyframes added since
+    pub index: Option<u32>,
+    pub selected: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmBoneKeyframe {
+    pub keyframe: BoneKeyframe,
+    pub physics_disabled: bool,
+    pub editor: PmmEditorState,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmMorphKeyframe {
+    pub keyframe: MorphKeyframe,
+    pub editor: PmmEditorState,
+}
+
+/// Visibility, IK switches and outside parents of a model at a frame
+#[derive(Clone)]
+pub struct PmmPropertyKeyframe {
+    pub frame: u32,
+    pub visible: bool,
+    pub ik_enabled: Vec<bool>,
+    /// (model index, bone index) for every outside parent bone, -1 when unset
+    pub op_parents: Vec<(i32, i32)>,
+    pub editor: PmmEditorState,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmBoneCurrent {
+    pub trans: Vec3,
+    pub rot: Quat,
+    pub uncommitted: bool,
+    pub physics_disabled: bool,
+    pub selected: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmOpCurrent {
+    pub keyframe_begin: i32,
+    pub keyframe_end: i32,
+    pub model_index: i32,
+    pub bone_index: i32,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmCameraKeyframe {
+    pub keyframe: CameraKeyframe,
+    pub looking_model: i32,
+    pub looking_bone: i32,
+    pub editor: PmmEditorState,
+}
+
+pub struct PmmCamera {
+    pub keyframes: Vec<PmmCameraKeyframe>,
+    pub eye: Vec3,
+    pub target: Vec3,
+    pub rot: Vec3,
+    pub orthographic: bool,
+}
 
-fn read_v_string<T>(file: &mut T) -> String
+pub struct PmmAccessory {
+    pub index: u8,
+    pub name: String,
+    pub path: String,
+    pub draw_order: u8,
+    pub keyframes: Vec<PmmAccessoryKeyframe>,
+    pub current: PmmAccessoryState,
+    pub add_blend: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmAccessoryKeyframe {
+    pub frame: u32,
+    pub state: PmmAccessoryState,
+    pub editor: PmmEditorState,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmAccessoryState {
+    /// 0..1, stored by MMD in steps of 0.01
+    pub opacity: f32,
+    pub visible: bool,
+    pub parent_model: i32,
+    pub parent_bone: i32,
+    pub trans: Vec3,
+    pub rot: Vec3,
+    pub scale: f32,
+    pub shadow: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct GravityKeyframe {
+    pub frame: u32,
+    pub acceleration: f32,
+    pub add_noise: bool,
+    pub noise_amount: u32,
+    pub direction: Vec3,
+    pub editor: PmmEditorState,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmLightKeyframe {
+    pub keyframe: LightKeyframe,
+    pub editor: PmmEditorState,
+}
+
+#[derive(Copy, Clone)]
+pub struct PmmShadowKeyframe {
+    pub keyframe: ShadowKeyframe,
+    pub editor: PmmEditorState,
+}
+
+/// Background video or image shown behind the scene, an empty path means none
+pub struct PmmBackground {
+    pub path: String,
+    pub offset: IVec2,
+    pub scale: f32,
+    pub shown: bool,
+}
+
+pub struct PmmExtra {
+    pub edge_color: [u32; 3],
+    pub black_background: bool,
+    pub camera_looking_model: i32,
+    pub camera_looking_bone: i32,
+    pub view_matrix: [f32; 16],
+    pub view_look_at_enabled: bool,
+    pub unknown: u8,
+    pub physics_ground: bool,
+    pub current_frame: u32,
+    /// Bytes after the known fields, kept so newer files survive a round trip
+    pub trailing: Vec<u8>,
+}
+
+fn read_v_string<T>(file: &mut T) -> io::Result<String>
     where T: Read {
-    let len = file.read_u8().unwrap() as usize;
+    let len = file.read_u8()? as usize;
     let mut string_raw = vec![0u8; len];
-    file.read(&mut string_raw).unwrap();
-    WINDOWS_31J.decode(&string_raw, DecoderTrap::Ignore).unwrap()
+    file.read_exact(&mut string_raw)?;
+    Ok(WINDOWS_31J.decode(&string_raw, DecoderTrap::Ignore).unwrap_or_default())
 }
 
-pub fn read_header<T>(mut file: &mut T)
-        where T: Read {
-    let header_string = read_string(&mut file, 30);
-    assert!(header_string.starts_with(PMM_HEADER));
-    let view_width = file.read_u32::<LittleEndian>().unwrap();
-    let view_height = file.read_u32::<LittleEndian>().unwrap();
-    let frame_width = file.read_u32::<LittleEndian>().unwrap();
-    let edit_view_angle = file.read_f32::<LittleEndian>().unwrap();
-
-    let camera_light_accessory_edited = file.read_u8().unwrap() == 1;
-    let camera_panel_opened = file.read_u8().unwrap() == 1 ;
-    let light_panel_opened = file.read_u8().unwrap() == 1 ;
-    let accessory_panel_opened = file.read_u8().unwrap() == 1 ;
-    let bone_panel_opened = file.read_u8().unwrap() == 1 ;
-    let morph_panel_opened = file.read_u8().unwrap() == 1 ;
-    let self_shadow_panel = file.read_u8().unwrap() == 1 ;
-    let selected_model_index = file.read_u8().unwrap();
-}
-pub fn read_model(mut file: &mut Cursor<Vec<u8>>) -> Motion {
-    let number = file.read_u8().unwrap();
-    let name = read_v_string(&mut file);
-    let name_en = read_v_string(&mut file);
-    let path = read_string(&mut file, 256);
-    let keyframe_editor_top_level_rows = file.read_u8().unwrap();
-    let bone_names = read_items(&mut file, read_v_string);
-    let morph_names = read_items(&mut file, read_v_string);
-    let ik_indexes = read_items(&mut file, |file| {
-        file.read_u32::<LittleEndian>().unwrap()
-    });
-    let op_indexes = read_items(&mut file, |file| {
-        file.read_u32::<LittleEndian>().unwrap()
-    });
-    let draw_order = file.read_u8().unwrap();
-    let edit_is_display = file.read_u8().unwrap() == 1;
-    let edit_selected_bone = file.read_u32::<LittleEndian>().unwrap();
-    let skin_panel = read_fix_items(&mut file, 4, |file| {
-        file.read_u32::<LittleEndian>().unwrap()
-    });
-
-    let frame_opened_count = file.read_u8().unwrap() as usize;
-    let frame_opened = read_fix_items(&mut file, frame_opened_count, |file| {
-        file.read_u8().unwrap()
-    });
-    let v_scroll = file.read_u32::<LittleEndian>().unwrap();
-    let last_frame = file.read_u32::<LittleEndian>().unwrap();
-
-    let mut bone_key_frames: BTreeMap<u32, (usize, BoneKeyframe)> = BTreeMap::new();
-    for _ in 0..bone_names.len() {
-        read_bone_frame(&mut file, &mut bone_key_frames, &bone_names);
+fn read_u32_items(file: &mut Cursor<Vec<u8>>) -> io::Result<Vec<u32>> {
+    let count = file.read_u32::<LittleEndian>()? as usize;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        items.push(file.read_u32::<LittleEndian>()?);
     }
-    let remaining_bone_frame = file.read_u32::<LittleEndian>().unwrap();
-    for _ in 0..remaining_bone_frame {
-        read_bone_frame(&mut file, &mut bone_key_frames, &bone_names);
+    Ok(items)
+}
+
+fn read_v_string_items(file: &mut Cursor<Vec<u8>>) -> io::Result<Vec<String>> {
+    let count = file.read_u32::<LittleEndian>()? as usize;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        items.push(read_v_string(file)?);
     }
+    Ok(items)
+}
+
+fn read_bool<T>(file: &mut T) -> io::Result<bool>
+    where T: Read {
+    Ok(file.read_u8()? == 1)
+}
+
+/// Every PMM keyframe starts with its data index (implicit for the initial keyframe of a track),
+/// its frame and the data indexes of the previous and next keyframe of the same track.
+/// Returns the data index, 0 for the initial keyframe, the frame and the next index.
+fn read_link<T>(file: &mut T, init: bool) -> io::Result<(u32, u32, usize)>
+    where T: Read {
+    let index = if init { 0 } else { file.read_u32::<LittleEndian>()? };
+    let frame = file.read_u32::<LittleEndian>()?;
+    let pre_index = file.read_u32::<LittleEndian>()?;
+    let next_index = file.read_u32::<LittleEndian>()? as usize;
+    Ok((index, frame, next_index))
+}
 
-    let mut morph_key_frames: BTreeMap<u32, (usize, MorphKeyframe)> = BTreeMap::new();
-    for _ in 0..morph_names.len() {
-        read_morph_frame(&mut file, &mut morph_key_frames, &morph_names);
+fn editor_state<T>(file: &mut T, index: u32) -> io::Result<PmmEditorState>
+    where T: Read {
+    Ok(PmmEditorState { index: Some(index), selected: read_bool(file)? })
+}
+
+/// Reads a single track stored as an initial keyframe followed by a counted list.
+/// All of them belong to the same track, so the links are not followed.
+fn read_track<T, F>(file: &mut Cursor<Vec<u8>>, section: MotionSection, mut f: F) -> Result<Vec<T>, MotionError>
+    where F: FnMut(&mut Cursor<Vec<u8>>, bool) -> io::Result<T> {
+    let mut items = vec![f(file, true).map_err(|e| MotionError::new(section, Some(0), e))?];
+    items.extend(read_records(file, section, |file| f(file, false)).map_err(|mut e| {
+        e.index = e.index.map(|i| i + 1);
+        e
+    })?);
+    Ok(items)
+}
+
+pub fn read_header<T>(mut file: &mut T, project: &mut PmmProject) -> Result<(), MotionError>
+        where T: Read {
+    let header = |e| MotionError::new(MotionSection::Header, None, e);
+    let header_string = read_string(&mut file, 30).map_err(header)?;
+    if !header_string.starts_with(PMM_HEADER) {
+        return Err(MotionError::invalid(
+            MotionSection::Header,
+            None,
+            format!("unknown PMM header {:?}", header_string),
+        ));
     }
-    let remaining_morph_frame = file.read_u32::<LittleEndian>().unwrap();
-    for _ in 0..remaining_morph_frame {
-        read_morph_frame(&mut file, &mut morph_key_frames, &morph_names);
+    read_header_fields(file, project).map_err(header)
+}
+
+fn read_header_fields<T>(file: &mut T, project: &mut PmmProject) -> io::Result<()>
+        where T: Read {
+    project.view_width = file.read_u32::<LittleEndian>()?;
+    project.view_height = file.read_u32::<LittleEndian>()?;
+    project.frame_width = file.read_u32::<LittleEndian>()?;
+    project.edit_view_angle = file.read_f32::<LittleEndian>()?;
+
+    project.camera_light_accessory_edited = read_bool(file)?;
+    for opened in project.panels_opened.iter_mut() {
+        *opened = read_bool(file)?;
     }
+    project.selected_model_index = file.read_u8()?;
+    Ok(())
+}
+
+pub fn read_model(file: &mut Cursor<Vec<u8>>, model: usize) -> Result<PmmModel, MotionError> {
+    read_model_inner(file).map_err(|e| e.in_model(model))
+}
 
-    let op_init_frame = read_op_frame(&mut file, ik_indexes.len(), op_indexes.len(), true);
-    let op_key_frames = read_items(&mut file, |mut file| {
-        read_op_frame(&mut file, ik_indexes.len(), op_indexes.len(), false);
-    });
-    let bone_current_data = read_fix_items(&mut file, bone_names.len(), |mut file| {
-        read_bone_current_data(&mut file)
-    });
-    let morph_current_datas = read_fix_items(&mut file, morph_names.len(), |file| {
-        file.read_f32::<LittleEndian>().unwrap()
-    });
-    let is_current_ik_enabled_data = read_fix_items(&mut file, ik_indexes.len(), |file| {
-        file.read_u8().unwrap() == 1
-    });
-    let op_current_data = read_fix_items(&mut file, op_indexes.len(), |mut file| {
-        read_op_current_data(&mut file)
-    });
-    let blend_added = file.read_u8().unwrap() == 1;
-    let edge_width = file.read_f32::<LittleEndian>().unwrap();
-    let self_shadow_enabled = file.read_u8().unwrap() == 1;
-    let calc_order = file.read_u8().unwrap();
-
-
-
-    let mut bone_frame_list = vec![];
+fn read_model_inner(mut file: &mut Cursor<Vec<u8>>) -> Result<PmmModel, MotionError> {
+    let info = |e| MotionError::new(MotionSection::Model, None, e);
+    let number = file.read_u8().map_err(info)?;
+    let name = read_v_string(&mut file).map_err(info)?;
+    let name_en = read_v_string(&mut file).map_err(info)?;
+    let path = read_string(&mut file, 256).map_err(info)?;
+    let keyframe_editor_top_level_rows = file.read_u8().map_err(info)?;
+    let bone_names = read_v_string_items(file).map_err(info)?;
+    let morph_names = read_v_string_items(file).map_err(info)?;
+    let ik_indexes = read_u32_items(file).map_err(info)?;
+    let op_indexes = read_u32_items(file).map_err(info)?;
+    let draw_order = file.read_u8().map_err(info)?;
+    let edit_is_display = read_bool(file).map_err(info)?;
+    let edit_selected_bone = file.read_u32::<LittleEndian>().map_err(info)?;
+    let mut skin_panel = [0u32; 4];
+    file.read_u32_into::<LittleEndian>(&mut skin_panel).map_err(info)?;
+
+    let frame_opened_count = file.read_u8().map_err(info)? as usize;
+    let mut frame_opened = vec![0u8; frame_opened_count];
+    file.read_exact(&mut frame_opened).map_err(info)?;
+    let v_scroll = file.read_u32::<LittleEndian>().map_err(info)?;
+    let last_frame = file.read_u32::<LittleEndian>().map_err(info)?;
+
+    let bone = |i| move |e| MotionError::new(MotionSection::Bone, Some(i), e);
+    let mut bone_key_frames: BTreeMap<u32, (usize, PmmBoneKeyframe)> = BTreeMap::new();
     for i in 0..bone_names.len() {
-        let name = bone_names[i].clone();
-        let mut index = i;
-        loop {
-            let (next, kf) =  &bone_key_frames[&(index as u32)];
-            bone_frame_list.push((name.clone(), BoneKeyframe {
-                frame: kf.frame,
-                trans: kf.trans,
-                rot: kf.rot,
-                txc: kf.txc,
-                tyc: kf.tyc,
-                tzc: kf.tzc,
-                rc: kf.rc,
-            }));
-            if *next != 0 {
-                index = *next;
-            } else {
-                break;
-            }
-        }
+        read_bone_frame(file, &mut bone_key_frames, &bone_names).map_err(bone(i))?;
     }
-    let mut bone_keyframes: BTreeMap<String, Vec<BoneKeyframe>> = BTreeMap::new();
-    {
-        for (name, kf) in &bone_frame_list {
-            bone_keyframes.entry(name.clone()).or_insert(vec![]);
-            bone_keyframes.get_mut(name).unwrap().push(kf.clone());
-        }
+    let remaining_bone_frame = file.read_u32::<LittleEndian>().map_err(bone(bone_names.len()))? as usize;
+    for i in 0..remaining_bone_frame {
+        let i = bone_names.len() + i;
+        read_bone_frame(file, &mut bone_key_frames, &bone_names).map_err(bone(i))?;
     }
 
-
-    let mut morph_frame_list = vec![];
+    let morph = |i| move |e| MotionError::new(MotionSection::Morph, Some(i), e);
+    let mut morph_key_frames: BTreeMap<u32, (usize, PmmMorphKeyframe)> = BTreeMap::new();
     for i in 0..morph_names.len() {
-        let name = morph_names[i].clone();
+        read_morph_frame(&mut file, &mut morph_key_frames, &morph_names).map_err(morph(i))?;
+    }
+    let remaining_morph_frame = file.read_u32::<LittleEndian>().map_err(morph(morph_names.len()))? as usize;
+    for i in 0..remaining_morph_frame {
+        let i = morph_names.len() + i;
+        read_morph_frame(&mut file, &mut morph_key_frames, &morph_names).map_err(morph(i))?;
+    }
+
+    let mut property_keyframes = read_track(file, MotionSection::Ik, |file, init| {
+        read_op_frame(file, ik_indexes.len(), op_indexes.len(), init)
+    })?;
+    property_keyframes.sort_by_key(|k| k.frame);
+
+    let mut bone_current = Vec::with_capacity(bone_names.len());
+    for _ in 0..bone_names.len() {
+        bone_current.push(read_bone_current_data(&mut file).map_err(info)?);
+    }
+    let mut morph_current = vec![0f32; morph_names.len()];
+    file.read_f32_into::<LittleEndian>(&mut morph_current).map_err(info)?;
+    let mut is_current_ik_enabled_data = vec![0u8; ik_indexes.len()];
+    file.read_exact(&mut is_current_ik_enabled_data).map_err(info)?;
+    let mut op_current = Vec::with_capacity(op_indexes.len());
+    for _ in 0..op_indexes.len() {
+        op_current.push(read_op_current_data(&mut file).map_err(info)?);
+    }
+    let blend_added = read_bool(file).map_err(info)?;
+    let edge_width = file.read_f32::<LittleEndian>().map_err(info)?;
+    let self_shadow_enabled = read_bool(file).map_err(info)?;
+    let calc_order = file.read_u8().map_err(info)?;
+
+    let bone_keyframes = collect_keyframe_chains(&bone_names, &bone_key_frames)
+        .map_err(|(i, msg)| MotionError::invalid(MotionSection::Bone, Some(i), msg))?;
+    let morph_keyframes = collect_keyframe_chains(&morph_names, &morph_key_frames)
+        .map_err(|(i, msg)| MotionError::invalid(MotionSection::Morph, Some(i), msg))?;
+
+    Ok(PmmModel {
+        number,
+        name,
+        name_en,
+        path,
+        keyframe_editor_top_level_rows,
+        bone_names,
+        morph_names,
+        ik_indexes,
+        op_indexes,
+        draw_order,
+        edit_is_display,
+        edit_selected_bone,
+        skin_panel,
+        frame_opened,
+        v_scroll,
+        last_frame,
+        bone_keyframes,
+        morph_keyframes,
+        property_keyframes,
+        bone_current,
+        morph_current,
+        ik_current: is_current_ik_enabled_data.iter().map(|v| *v == 1).collect(),
+        op_current,
+        blend_added,
+        edge_width,
+        self_shadow_enabled,
+        calc_order,
+    })
+}
+
+/// Follows the `next` links starting at the initial keyframe of every name, the result is indexed like `names`.
+/// A dangling or cyclic link is reported with the data index it was found at.
+fn collect_keyframe_chains<K: Copy>(
+    names: &[String],
+    keyframes: &BTreeMap<u32, (usize, K)>,
+) -> Result<Vec<Vec<K>>, (usize, String)> {
+    let mut res = Vec::with_capacity(names.len());
+    for (i, name) in names.iter().enumerate() {
         let mut index = i;
+        let mut chain = Vec::new();
         loop {
-            let (next, kf) =  &morph_key_frames[&(index as u32)];
-            morph_frame_list.push((name.clone(), MorphKeyframe {
-                frame: kf.frame,
-                weight: kf.weight,
-            }));
+            let Some((next, kf)) = keyframes.get(&(index as u32)) else {
+                return Err((index, format!("dangling keyframe link in {:?}", name)));
+            };
+            chain.push(*kf);
+            if chain.len() > keyframes.len() {
+                return Err((index, format!("cyclic keyframe links in {:?}", name)));
+            }
             if *next != 0 {
                 index = *next;
             } else {
                 break;
             }
         }
+        res.push(chain);
     }
-    let mut morph_keyframes: BTreeMap<String, Vec<MorphKeyframe>> = BTreeMap::new();
-    {
-        for (name, kf) in &morph_frame_list {
-            morph_keyframes.entry(name.clone()).or_insert(vec![]);
-            morph_keyframes.get_mut(name).unwrap().push(kf.clone());
-        }
-    }
-
-    Motion {
-        model_name: name,
-        bone_keyframes,
-        morph_keyframes,
-        camera_keyframes: vec![],
-        light_keyframes: vec![],
-        shadow_keyframes: vec![],
-        ik_keyframes: vec![],
-        path,
-    }.clear_empty_keyframe()
+    Ok(res)
 }
 
 pub fn read_bone_frame(mut file: &mut Cursor<Vec<u8>>,
-                       keyframes: &mut BTreeMap<u32, (usize, BoneKeyframe)>, names: &Vec<String>) {
+                       keyframes: &mut BTreeMap<u32, (usize, PmmBoneKeyframe)>, names: &[String]) -> io::Result<()> {
     let data_index = if keyframes.len() < names.len() {
         keyframes.len() as u32
     } else {
-        file.read_u32::<LittleEndian>().unwrap()
+        file.read_u32::<LittleEndian>()?
     };
-    let frame = file.read_u32::<LittleEndian>().unwrap();
-    let pre_index = file.read_u32::<LittleEndian>().unwrap() as usize;
-    let next_index = file.read_u32::<LittleEndian>().unwrap() as usize;
-    let txc = read_fix_items(&mut file, 4, |file| {
-        file.read_u8().unwrap() as f32 / 127f32
-    });
-    let tyc = read_fix_items(&mut file, 4, |file| {
-        file.read_u8().unwrap() as f32 / 127f32
-    });
-    let tzc = read_fix_items(&mut file, 4, |file| {
-        file.read_u8().unwrap() as f32 / 127f32
-    });
-    let rc = read_fix_items(&mut file, 4, |file| {
-        file.read_u8().unwrap() as f32 / 127f32
-    });
-    let trans = read_float3(&mut file);
-    let rot = read_quat(&mut file);
-    let selected = file.read_u8().unwrap() == 1;
-    let physics_disabled = file.read_u8().unwrap() == 1;
-
-
-    keyframes.insert(data_index,  (next_index, BoneKeyframe {
-        frame,
-        trans,
-        rot,
-        txc: vec4(txc[0], txc[1], txc[2], txc[3]),
-        tyc: vec4(tyc[0], tyc[1], tyc[2], tyc[3]),
-        tzc: vec4(tzc[0], tzc[1], tzc[2], tzc[3]),
-        rc:  vec4(rc[0], rc[1], rc[2], rc[3]),
+    let (_, frame, next_index) = read_link(file, true)?;
+    let txc = read_bezier_control_point_pair1(&mut file)?;
+    let tyc = read_bezier_control_point_pair1(&mut file)?;
+    let tzc = read_bezier_control_point_pair1(&mut file)?;
+    let rc = read_bezier_control_point_pair1(&mut file)?;
+    let trans = read_float3(&mut file)?;
+    let rot = read_quat(&mut file)?;
+    let editor = editor_state(file, data_index)?;
+    let physics_disabled = read_bool(file)?;
+
+    keyframes.insert(data_index,  (next_index, PmmBoneKeyframe {
+        keyframe: BoneKeyframe {
+            frame,
+            trans,
+            rot,
+            txc,
+            tyc,
+            tzc,
+            rc,
+        },
+        physics_disabled,
+        editor,
     }));
+    Ok(())
 }
 
 pub fn read_morph_frame<T>(file: &mut T,
-                         keyframes: &mut BTreeMap<u32, (usize, MorphKeyframe)>, names: &Vec<String>)
+                         keyframes: &mut BTreeMap<u32, (usize, PmmMorphKeyframe)>, names: &[String]) -> io::Result<()>
     where T: Read {
     let data_index = if keyframes.len() < names.len() {
         keyframes.len() as u32
     } else {
-        file.read_u32::<LittleEndian>().unwrap()
+        file.read_u32::<LittleEndian>()?
     };
-    let frame = file.read_u32::<LittleEndian>().unwrap();
-
-    let pre_index = file.read_u32::<LittleEndian>().unwrap() as usize;
-    let next_index = file.read_u32::<LittleEndian>().unwrap() as usize;
+    let (_, frame, next_index) = read_link(file, true)?;
 
-    let weight = file.read_f32::<LittleEndian>().unwrap();
-    let selected = file.read_u8().unwrap() == 1;
+    let weight = file.read_f32::<LittleEndian>()?;
+    let editor = editor_state(file, data_index)?;
 
-    keyframes.insert(data_index,  (next_index, MorphKeyframe {
-        frame,
-        weight,
+    keyframes.insert(data_index,  (next_index, PmmMorphKeyframe {
+        keyframe: MorphKeyframe {
+            frame,
+            weight,
+        },
+        editor,
     }));
+    Ok(())
 }
-pub fn read_op_frame(mut file: &mut Cursor<Vec<u8>>, ik_count: usize, op_count: usize, inited: bool) {
-    let data_index = if inited { -1 } else { file.read_i32::<LittleEndian>().unwrap() };
-    let frame = file.read_u32::<LittleEndian>().unwrap();
-    let pre_index = file.read_u32::<LittleEndian>().unwrap();
-    let next_index = file.read_u32::<LittleEndian>().unwrap();
-    let displayed = file.read_u8().unwrap() == 1;
-    let ik_enabled = read_fix_items(&mut file, ik_count, |file| {
-        file.read_u8().unwrap() == 1
-    });
-    let op_data = read_fix_items(&mut file, op_count, |file| {
-        (file.read_u32::<LittleEndian>().unwrap(),
-         file.read_u32::<LittleEndian>().unwrap())
-    });
-    let selected = file.read_u8().unwrap() == 1;
-}
-
-fn read_op_current_data<T>(file: &mut T)
+
+pub fn read_op_frame<T>(file: &mut T, ik_count: usize, op_count: usize, init: bool) -> io::Result<PmmPropertyKeyframe>
     where T: Read {
-    let key_frame_begin = file.read_u32::<LittleEndian>().unwrap();
-    let key_frame_end = file.read_u32::<LittleEndian>().unwrap();
-    let model_index = file.read_u32::<LittleEndian>().unwrap();
-    let parent_bone_index = file.read_u32::<LittleEndian>().unwrap();
+    let (index, frame, _) = read_link(file, init)?;
+    let visible = read_bool(file)?;
+    let mut ik_enabled = vec![0u8; ik_count];
+    file.read_exact(&mut ik_enabled)?;
+    let mut op_data = vec![0i32; op_count * 2];
+    file.read_i32_into::<LittleEndian>(&mut op_data)?;
+    let editor = editor_state(file, index)?;
+    Ok(PmmPropertyKeyframe {
+        frame,
+        visible,
+        ik_enabled: ik_enabled.iter().map(|v| *v == 1).collect(),
+        op_parents: op_data.chunks(2).map(|p| (p[0], p[1])).collect(),
+        editor,
+    })
 }
 
-fn read_bone_current_data<T>(mut file: &mut T)
+fn read_op_current_data<T>(file: &mut T) -> io::Result<PmmOpCurrent>
     where T: Read {
-    let trans = read_float3(&mut file);
-    let rot = read_float4(&mut file);
-    let edit_un_commited = file.read_u8().unwrap() == 1;
-    let physics_disabled = file.read_u8().unwrap() == 1;
-    let row_selected = file.read_u8().unwrap() == 1;
+    Ok(PmmOpCurrent {
+        keyframe_begin: file.read_i32::<LittleEndian>()?,
+        keyframe_end: file.read_i32::<LittleEndian>()?,
+        model_index: file.read_i32::<LittleEndian>()?,
+        bone_index: file.read_i32::<LittleEndian>()?,
+    })
 }
 
-pub fn read_camera_keyframe<T>(mut file: &mut T, init: bool) -> CameraKeyframe 
+fn read_bone_current_data<T>(mut file: &mut T) -> io::Result<PmmBoneCurrent>
     where T: Read {
-    let data_index = if init {0} else { file.read_i32::<LittleEndian>().unwrap() };
-    let frame = file.read_u32::<LittleEndian>().unwrap();
+    Ok(PmmBoneCurrent {
+        trans: read_float3(&mut file)?,
+        rot: read_quat(&mut file)?,
+        uncommitted: read_bool(file)?,
+        physics_disabled: read_bool(file)?,
+        selected: read_bool(file)?,
+    })
+}
 
-    let pre_index = file.read_u32::<LittleEndian>().unwrap() as usize;
-    let next_index = file.read_u32::<LittleEndian>().unwrap() as usize;
+pub fn read_camera_keyframe<T>(mut file: &mut T, init: bool) -> io::Result<PmmCameraKeyframe>
+    where T: Read {
+    let (index, frame, _) = read_link(file, init)?;
+
+    let dist = file.read_f32::<LittleEndian>()?;
+
+    let trans = read_float3(&mut file)?;
+    let rot = read_float3(&mut file)?;
+
+    let looking_model = file.read_i32::<LittleEndian>()?;
+    let looking_bone = file.read_i32::<LittleEndian>()?;
+
+    let txc = read_bezier_control_point_pair1(file)?;
+    let tyc = read_bezier_control_point_pair1(file)?;
+    let tzc = read_bezier_control_point_pair1(file)?;
+    let rc  = read_bezier_control_point_pair1(file)?;
+    let dc  = read_bezier_control_point_pair1(file)?;
+    let vc  = read_bezier_control_point_pair1(file)?;
+
+    let perspective = file.read_u8()? == 0;
+    let fov = file.read_u32::<LittleEndian>()?;
+    let editor = editor_state(file, index)?;
+
+    Ok(PmmCameraKeyframe {
+        keyframe: CameraKeyframe {
+            frame,
+            dist,
+            trans,
+            rot,
+            txc,
+            tyc,
+            tzc,
+            rc,
+            dc,
+            vc,
+            fov,
+            perspective,
+        },
+        looking_model,
+        looking_bone,
+        editor,
+    })
+}
 
-    let dist = file.read_f32::<LittleEndian>().unwrap();
+fn read_camera(file: &mut Cursor<Vec<u8>>) -> Result<PmmCamera, MotionError> {
+    let mut keyframes = read_track(file, MotionSection::Camera, read_camera_keyframe)?;
+    keyframes.sort_by_key(|k| k.keyframe.frame);
+    let current = |e| MotionError::new(MotionSection::Camera, None, e);
+    Ok(PmmCamera {
+        keyframes,
+        eye: read_float3(file).map_err(current)?,
+        target: read_float3(file).map_err(current)?,
+        rot: read_float3(file).map_err(current)?,
+        orthographic: read_bool(file).map_err(current)?,
+    })
+}
 
-    let trans = read_float3(&mut file);
-    let rot = read_float3(&mut file);
+fn read_light_keyframe<T>(mut file: &mut T, init: bool) -> io::Result<PmmLightKeyframe>
+    where T: Read {
+    let (index, frame, _) = read_link(file, init)?;
+    let color = read_float3(&mut file)?;
+    let direction = read_float3(&mut file)?;
+    Ok(PmmLightKeyframe {
+        keyframe: LightKeyframe {
+            frame,
+            color,
+            direction,
+        },
+        editor: editor_state(file, index)?,
+    })
+}
 
-    let looking_model_index = file.read_u32::<LittleEndian>().unwrap();
-    let looking_bone_index = file.read_u32::<LittleEndian>().unwrap();
+fn read_accessory_state<T>(mut file: &mut T) -> io::Result<PmmAccessoryState>
+    where T: Read {
+    // the low bit is the visibility, the rest is the transparency in percent
+    let opacity_visible = file.read_u8()?;
+    Ok(PmmAccessoryState {
+        opacity: (100 - (opacity_visible >> 1).min(100)) as f32 / 100.0,
+        visible: opacity_visible & 1 == 1,
+        parent_model: file.read_i32::<LittleEndian>()?,
+        parent_bone: file.read_i32::<LittleEndian>()?,
+        trans: read_float3(&mut file)?,
+        rot: read_float3(&mut file)?,
+        scale: file.read_f32::<LittleEndian>()?,
+        shadow: read_bool(file)?,
+    })
+}
 
-    let txc = read_bezier_control_point_pair1(file);
-    let tyc = read_bezier_control_point_pair1(file);
-    let tzc = read_bezier_control_point_pair1(file);
-    let rc  = read_bezier_control_point_pair1(file);
-    let dc  = read_bezier_control_point_pair1(file);
-    let vc  = read_bezier_control_point_pair1(file);
+fn read_accessory_keyframe<T>(file: &mut T, init: bool) -> io::Result<PmmAccessoryKeyframe>
+    where T: Read {
+    let (index, frame, _) = read_link(file, init)?;
+    let state = read_accessory_state(file)?;
+    Ok(PmmAccessoryKeyframe {
+        frame,
+        state,
+        editor: editor_state(file, index)?,
+    })
+}
 
-    let perspective = file.read_u8().unwrap() == 0;
-    let fov = file.read_u32::<LittleEndian>().unwrap();
-    let selected = file.read_u8().unwrap() == 1;
+fn read_accessory(file: &mut Cursor<Vec<u8>>) -> Result<PmmAccessory, MotionError> {
+    let info = |e| MotionError::new(MotionSection::Accessory, None, e);
+    let index = file.read_u8().map_err(info)?;
+    let name = read_string(file, 100).map_err(info)?;
+    let path = read_string(file, 256).map_err(info)?;
+    let draw_order = file.read_u8().map_err(info)?;
+    let mut keyframes = read_track(file, MotionSection::Accessory, read_accessory_keyframe)?;
+    keyframes.sort_by_key(|k| k.frame);
+    let current = read_accessory_state(file).map_err(info)?;
+    let add_blend = read_bool(file).map_err(info)?;
+    Ok(PmmAccessory {
+        index,
+        name,
+        path,
+        draw_order,
+        keyframes,
+        current,
+        add_blend,
+    })
+}
 
-    CameraKeyframe {
+fn read_gravity_keyframe<T>(mut file: &mut T, init: bool) -> io::Result<GravityKeyframe>
+    where T: Read {
+    let (index, frame, _) = read_link(file, init)?;
+    let add_noise = read_bool(file)?;
+    let noise_amount = file.read_u32::<LittleEndian>()?;
+    let acceleration = file.read_f32::<LittleEndian>()?;
+    let direction = read_float3(&mut file)?;
+    Ok(GravityKeyframe {
         frame,
-        dist,
-        trans,
-        rot,
-        txc,
-        tyc,
-        tzc,
-        rc,
-        dc,
-        vc,
-        fov,
-        perspective,
+        acceleration,
+        add_noise,
+        noise_amount,
+        direction,
+        editor: editor_state(file, index)?,
+    })
+}
+
+fn read_shadow_keyframe<T>(file: &mut T, init: bool) -> io::Result<PmmShadowKeyframe>
+    where T: Read {
+    let (index, frame, _) = read_link(file, init)?;
+    let mode = file.read_u8()?;
+    let dist = file.read_f32::<LittleEndian>()?;
+    Ok(PmmShadowKeyframe {
+        keyframe: ShadowKeyframe {
+            frame,
+            mode,
+            dist,
+        },
+        editor: editor_state(file, index)?,
+    })
+}
+
+/// Playback, background and ground settings between the accessories and the gravity track
+fn read_settings(mut file: &mut Cursor<Vec<u8>>, project: &mut PmmProject) -> io::Result<()> {
+    project.current_frame_position = file.read_u32::<LittleEndian>()?;
+    project.h_scroll = file.read_u32::<LittleEndian>()?;
+    project.h_scroll_scale = file.read_u32::<LittleEndian>()?;
+    project.bone_operation = file.read_u32::<LittleEndian>()?;
+    project.looking_at = file.read_u8()?;
+    project.repeat = read_bool(file)?;
+    project.play_from_enabled = read_bool(file)?;
+    project.play_to_enabled = read_bool(file)?;
+    project.play_start_frame = file.read_u32::<LittleEndian>()?;
+    project.play_end_frame = file.read_u32::<LittleEndian>()?;
+    project.wave_enabled = read_bool(file)?;
+    project.wave_path = read_string(&mut file, 256)?;
+
+    project.avi = PmmBackground {
+        offset: ivec2(file.read_i32::<LittleEndian>()?, file.read_i32::<LittleEndian>()?),
+        scale: file.read_f32::<LittleEndian>()?,
+        path: read_string(&mut file, 256)?,
+        shown: read_bool(file)?,
+    };
+    // unlike the video, the image scale is stored as an integer
+    project.background_image = PmmBackground {
+        offset: ivec2(file.read_i32::<LittleEndian>()?, file.read_i32::<LittleEndian>()?),
+        scale: file.read_u32::<LittleEndian>()? as f32,
+        path: read_string(&mut file, 256)?,
+        shown: read_bool(file)?,
+    };
+
+    project.show_information = read_bool(file)?;
+    project.show_axis = read_bool(file)?;
+    project.show_ground_shadow = read_bool(file)?;
+    project.fps_limit = file.read_f32::<LittleEndian>()?;
+    project.screen_capture_mode = file.read_u32::<LittleEndian>()?;
+    project.accessory_render_after_model = file.read_u32::<LittleEndian>()?;
+    project.ground_shadow_brightness = file.read_f32::<LittleEndian>()?;
+    project.transparent_ground_shadow = read_bool(file)?;
+    project.physics_mode = file.read_u8()?;
+
+    project.gravity_current = GravityKeyframe {
+        frame: 0,
+        acceleration: file.read_f32::<LittleEndian>()?,
+        noise_amount: file.read_u32::<LittleEndian>()?,
+        direction: read_float3(&mut file)?,
+        add_noise: read_bool(file)?,
+        editor: PmmEditorState::default(),
+    };
+    Ok(())
+}
+
+/// Size of the fields in `PmmExtra` before the trailing bytes
+const PMM_EXTRA_SIZE: usize = 12 + 1 + 4 + 4 + 64 + 1 + 1 + 1 + 4;
+
+fn read_extra(file: &mut Cursor<Vec<u8>>) -> io::Result<Option<PmmExtra>> {
+    let remaining = (file.get_ref().len() as u64).saturating_sub(file.position()) as usize;
+    if remaining < PMM_EXTRA_SIZE {
+        return Ok(None);
+    }
+    let mut edge_color = [0u32; 3];
+    file.read_u32_into::<LittleEndian>(&mut edge_color)?;
+    let black_background = read_bool(file)?;
+    let camera_looking_model = file.read_i32::<LittleEndian>()?;
+    let camera_looking_bone = file.read_i32::<LittleEndian>()?;
+    let mut view_matrix = [0f32; 16];
+    file.read_f32_into::<LittleEndian>(&mut view_matrix)?;
+    let view_look_at_enabled = read_bool(file)?;
+    let unknown = file.read_u8()?;
+    let physics_ground = read_bool(file)?;
+    let current_frame = file.read_u32::<LittleEndian>()?;
+    let mut trailing = Vec::new();
+    file.read_to_end(&mut trailing)?;
+    Ok(Some(PmmExtra {
+        edge_color,
+        black_background,
+        camera_looking_model,
+        camera_looking_bone,
+        view_matrix,
+        view_look_at_enabled,
+        unknown,
+        physics_ground,
+        current_frame,
+        trailing,
+    }))
+}
+
+impl PmmProject {
+    pub fn new() -> PmmProject {
+        let background = || PmmBackground {
+            path: String::new(),
+            offset: IVec2::ZERO,
+            scale: 1.0,
+            shown: false,
+        };
+        PmmProject {
+            view_width: 0,
+            view_height: 0,
+            frame_width: 0,
+            edit_view_angle: 0.0,
+            camera_light_accessory_edited: false,
+            panels_opened: [false; 6],
+            selected_model_index: 0,
+            models: vec![],
+            camera: PmmCamera {
+                keyframes: vec![],
+                eye: Vec3::ZERO,
+                target: Vec3::ZERO,
+                rot: Vec3::ZERO,
+                orthographic: false,
+            },
+            light_keyframes: vec![],
+            light_current: PmmLightKeyframe {
+                keyframe: LightKeyframe {
+                    frame: 0,
+                    color: Vec3::ZERO,
+                    direction: Vec3::ZERO,
+                },
+                editor: PmmEditorState::default(),
+            },
+            selected_accessory_index: 0,
+            accessory_v_scroll: 0,
+            accessories: vec![],
+            current_frame_position: 0,
+            h_scroll: 0,
+            h_scroll_scale: 0,
+            bone_operation: 0,
+            looking_at: 0,
+            repeat: false,
+            play_from_enabled: false,
+            play_to_enabled: false,
+            play_start_frame: 0,
+            play_end_frame: 0,
+            wave_enabled: false,
+            wave_path: String::new(),
+            avi: background(),
+            background_image: background(),
+            show_information: false,
+            show_axis: false,
+            show_ground_shadow: false,
+            fps_limit: 0.0,
+            screen_capture_mode: 0,
+            accessory_render_after_model: 0,
+            ground_shadow_brightness: 0.0,
+            transparent_ground_shadow: false,
+            physics_mode: 0,
+            gravity_current: GravityKeyframe {
+                frame: 0,
+                acceleration: 0.0,
+                add_noise: false,
+                noise_amount: 0,
+                direction: Vec3::ZERO,
+                editor: PmmEditorState::default(),
+            },
+            gravity_keyframes: vec![],
+            show_self_shadow: false,
+            self_shadow_current: 0.0,
+            shadow_keyframes: vec![],
+            extra: None,
+            trailing: vec![],
+        }
+    }
+
+    pub fn read(content: Vec<u8>) -> Result<PmmProject, MotionError> {
+        let mut file = Cursor::new(content);
+        let mut project = PmmProject::new();
+        read_header(&mut file, &mut project)?;
+        let count = file.read_u8().map_err(|e| MotionError::new(MotionSection::Header, None, e))? as usize;
+        for i in 0..count {
+            project.models.push(read_model(&mut file, i)?);
+        }
+        project.camera = read_camera(&mut file)?;
+
+        project.light_keyframes = read_track(&mut file, MotionSection::Light, read_light_keyframe)?;
+        project.light_keyframes.sort_by_key(|k| k.keyframe.frame);
+        let light = |e| MotionError::new(MotionSection::Light, None, e);
+        let keyframe = LightKeyframe {
+            frame: 0,
+            color: read_float3(&mut file).map_err(light)?,
+            direction: read_float3(&mut file).map_err(light)?,
+        };
+        let selected = read_bool(&mut file).map_err(light)?;
+        project.light_current = PmmLightKeyframe { keyframe, editor: PmmEditorState { index: None, selected } };
+
+        let accessory = |e| MotionError::new(MotionSection::Accessory, None, e);
+        project.selected_accessory_index = file.read_u8().map_err(accessory)?;
+        project.accessory_v_scroll = file.read_u32::<LittleEndian>().map_err(accessory)?;
+        let count = file.read_u8().map_err(accessory)? as usize;
+        // the names are repeated in every accessory
+        file.seek(SeekFrom::Current(100 * count as i64)).map_err(accessory)?;
+        for i in 0..count {
+            let acc = read_accessory(&mut file)
+                .map_err(|e| MotionError { index: Some(i), ..e })?;
+            project.accessories.push(acc);
+        }
+
+        read_settings(&mut file, &mut project).map_err(|e| MotionError::new(MotionSection::Settings, None, e))?;
+        project.gravity_keyframes = read_track(&mut file, MotionSection::Gravity, read_gravity_keyframe)?;
+        project.gravity_keyframes.sort_by_key(|k| k.frame);
+
+        let shadow = |e| MotionError::new(MotionSection::Shadow, None, e);
+        project.show_self_shadow = read_bool(&mut file).map_err(shadow)?;
+        project.self_shadow_current = file.read_f32::<LittleEndian>().map_err(shadow)?;
+        project.shadow_keyframes = read_track(&mut file, MotionSection::Shadow, read_shadow_keyframe)?;
+        project.shadow_keyframes.sort_by_key(|k| k.keyframe.frame);
+
+        let settings = |e| MotionError::new(MotionSection::Settings, None, e);
+        project.extra = read_extra(&mut file).map_err(settings)?;
+        if project.extra.is_none() {
+            file.read_to_end(&mut project.trailing).map_err(settings)?;
+        }
+        Ok(project)
+    }
+
+    /// Camera, light and self shadow tracks as a VMD style motion
+    pub fn camera_motion(&self) -> Motion {
+        let mut motion = Motion::new();
+        motion.model_name = "Camera".to_string();
+        motion.camera_keyframes = self.camera.keyframes.iter().map(|k| k.keyframe).collect();
+        motion.light_keyframes = self.light_keyframes.iter().map(|k| k.keyframe).collect();
+        motion.shadow_keyframes = self.shadow_keyframes.iter().map(|k| k.keyframe).collect();
+        motion
+    }
+
+    pub fn summary(&self) -> String {
+        let mut buf = String::new();
+        for (i, m) in self.models.iter().enumerate() {
+            buf += &format!("Model {}: {} ({})\n", i, m.name, m.path);
+        }
+        for (i, a) in self.accessories.iter().enumerate() {
+            buf += &format!("Accessory {}: {} ({}), {} frames\n", i, a.name, a.path, a.keyframes.len());
+        }
+        buf += &format!("Camera Frames: {}\n", self.camera.keyframes.len());
+        buf += &format!("Light Frames: {}\n", self.light_keyframes.len());
+        buf += &format!("Gravity Frames: {}\n", self.gravity_keyframes.len());
+        buf += &format!("Self Shadow Frames: {}\n", self.shadow_keyframes.len());
+        if self.play_from_enabled || self.play_to_enabled {
+            buf += &format!("Play Range: {}..{}\n", self.play_start_frame, self.play_end_frame);
+        }
+        if !self.wave_path.is_empty() {
+            buf += &format!("Audio: {}\n", self.wave_path);
+        }
+        if !self.avi.path.is_empty() {
+            buf += &format!("Background Video: {}\n", self.avi.path);
+        }
+        if !self.background_image.path.is_empty() {
+            buf += &format!("Background Image: {}\n", self.background_image.path);
+        }
+        buf += "\n";
+        buf
+    }
+
+    /// One motion per model followed by the camera motion
+    pub fn to_motions(&self) -> Vec<Motion> {
+        let mut motions: Vec<Motion> = self.models.iter().map(|m| m.to_motion()).collect();
+        motions.push(self.camera_motion());
+        motions
     }
 }
 
-fn read_camera_motion(file: &mut Cursor<Vec<u8>>) -> Motion {
-    let mut camera_keyframes = vec![];
-    camera_keyframes.push(read_camera_keyframe(file, true));
-    camera_keyframes.extend(read_items(
-        file,
-        | f | read_camera_keyframe(f, false)
-    ));
-    Motion {
-        model_name: "Camera".to_string(),
-        bone_keyframes: Default::default(),
-        morph_keyframes: Default::default(),
-        camera_keyframes,
-        light_keyframes: vec![],
-        shadow_keyframes: vec![],
-        ik_keyframes: vec![],
-        path: Default::default(),
+impl PmmModel {
+    /// Bone, morph and IK tracks of the model, tracks of bones sharing a name are merged
+    pub fn to_motion(&self) -> Motion {
+        let mut motion = Motion::new();
+        motion.model_name = self.name.clone();
+        motion.path = self.path.clone();
+        for (name, kfs) in self.bone_names.iter().zip(&self.bone_keyframes) {
+            motion.bone_keyframes.entry(name.clone()).or_default().extend(kfs.iter().map(|k| k.keyframe));
+        }
+        for (name, kfs) in self.morph_names.iter().zip(&self.morph_keyframes) {
+            motion.morph_keyframes.entry(name.clone()).or_default().extend(kfs.iter().map(|k| k.keyframe));
+        }
+        let ik_names: Vec<String> = self.ik_indexes.iter()
+            .map(|i| self.bone_names.get(*i as usize).cloned().unwrap_or_default())
+            .collect();
+        motion.ik_keyframes = self.property_keyframes.iter().map(|k| IkKeyframe {
+            frame: k.frame,
+            show: k.visible,
+            infos: ik_names.iter().cloned().zip(k.ik_enabled.iter().copied()).collect(),
+        }).collect();
+        motion
     }
 }
-pub fn read_pmm(path: &Path) -> Vec<Motion> {
-    let content = fs::read(path).unwrap();
-    let mut file = Cursor::new(content);
-    read_header(&mut file);
-    let count = file.read_u8().unwrap() as usize;
-    let mut motions = read_fix_items(&mut file, count, read_model);
-    motions.push(read_camera_motion(&mut file));
-    motions
+
+pub fn read_pmm(content: Vec<u8>) -> Result<Vec<Motion>, MotionError> {
+    let project = PmmProject::read(content)?;
+    Ok(project.to_motions().iter().map(|m| m.clear_empty_keyframe()).collect())
 }
@@ -0,0 +1,875 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(unused_variables)]
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use byteorder::{WriteBytesExt, LittleEndian};
+use encoding::{Encoding, EncoderTrap};
+use encoding::all::WINDOWS_31J;
+use glam::*;
+
+use super::common::{write_float3, write_quat};
+use super::motion::*;
+use super::pmm::*;
+use super::pmx::Pmx;
+use super::vmd_writer::{write_string, write_bezier_control_point_pair1};
+
+fn write_v_string<T>(file: &mut T, content: &str)
+    where T: Write {
+    let mut raw = WINDOWS_31J.encode(content, EncoderTrap::Replace).unwrap();
+    raw.truncate(u8::MAX as usize);
+    file.write_u8(raw.len() as u8).unwrap();
+    file.write_all(&raw).unwrap();
+}
+
+fn write_u32_items<T>(file: &mut T, items: &[u32])
+    where T: Write {
+    file.write_u32::<LittleEndian>(items.len() as u32).unwrap();
+    for item in items {
+        file.write_u32::<LittleEndian>(*item).unwrap();
+    }
+}
+
+fn write_v_string_items<T>(file: &mut T, items: &[String])
+    where T: Write {
+    file.write_u32::<LittleEndian>(items.len() as u32).unwrap();
+    for item in items {
+        write_v_string(file, item);
+    }
+}
+
+fn write_bool<T>(file: &mut T, v: bool)
+    where T: Write {
+    file.write_u8(if v {1} else {0}).unwrap();
+}
+
+/// Position of a keyframe in its track, `index` is `None` for the initial keyframe
+struct Link {
+    index: Option<u32>,
+    pre: u32,
+    next: u32,
+}
+
+fn write_link<T>(file: &mut T, link: &Link, frame: u32)
+    where T: Write {
+    if let Some(index) = link.index {
+        file.write_u32::<LittleEndian>(index).unwrap();
+    }
+    file.write_u32::<LittleEndian>(frame).unwrap();
+    file.write_u32::<LittleEndian>(link.pre).unwrap();
+    file.write_u32::<LittleEndian>(link.next).unwrap();
+}
+
+/// Writes a single track as its initial keyframe followed by the counted rest, linked in order.
+/// MMD needs the initial keyframe, so an empty track is written as `default`.
+fn write_track<W, T, F, G>(file: &mut W, keyframes: &[T], default: &T, editor_of: G, f: F)
+    where W: Write, T: Clone, F: FnMut(&mut W, &T, &Link), G: Fn(&T) -> PmmEditorState {
+    write_tracks(file, &[keyframes.to_vec()], default, editor_of, f);
+}
+
+/// Data indexes of the keyframes of every track, the initial keyframe of track `i` is at `i` and the
+/// rest follow all of them. The indexes read from the file are kept as long as they are still distinct
+/// and past the initial keyframes, otherwise the keyframes are numbered in track order.
+fn data_indexes<T, G>(tracks: &[&[T]], editor_of: G) -> Vec<Vec<u32>>
+    where G: Fn(&T) -> PmmEditorState {
+    let first = tracks.len() as u32;
+    let mut seen = BTreeSet::new();
+    let kept = tracks.iter().flat_map(|t| &t[1..]).all(|k| {
+        editor_of(k).index.map_or(false, |i| i >= first && seen.insert(i))
+    });
+    let mut data_index = first;
+    tracks.iter().enumerate().map(|(i, t)| {
+        let mut indexes = vec![i as u32];
+        for k in &t[1..] {
+            indexes.push(if kept { editor_of(k).index.unwrap() } else { data_index });
+            data_index += 1;
+        }
+        indexes
+    }).collect()
+}
+
+/// Writes one track per bone or morph: the initial keyframes at the indexes of their names,
+/// then the counted rest in the order of their data indexes
+fn write_tracks<W, T, F, G>(file: &mut W, tracks: &[Vec<T>], default: &T, editor_of: G, mut f: F)
+    where W: Write, F: FnMut(&mut W, &T, &Link), G: Fn(&T) -> PmmEditorState {
+    let tracks: Vec<&[T]> = tracks.iter()
+        .map(|t| if t.is_empty() { std::slice::from_ref(default) } else { &t[..] })
+        .collect();
+    let indexes = data_indexes(&tracks, editor_of);
+    let link = |indexes: &[u32], k: usize| Link {
+        index: if k == 0 { None } else { Some(indexes[k]) },
+        pre: if k == 0 { 0 } else { indexes[k - 1] },
+        next: indexes.get(k + 1).copied().unwrap_or(0),
+    };
+    for (t, ix) in tracks.iter().zip(&indexes) {
+        f(file, &t[0], &link(ix, 0));
+    }
+    let mut rest: Vec<(u32, usize, usize)> = indexes.iter().enumerate()
+        .flat_map(|(i, ix)| (1..ix.len()).map(move |k| (ix[k], i, k)))
+        .collect();
+    rest.sort();
+    file.write_u32::<LittleEndian>(rest.len() as u32).unwrap();
+    for (_, i, k) in rest {
+        f(file, &tracks[i][k], &link(&indexes[i], k));
+    }
+}
+
+/// Sorts a track by frame and makes it start at frame 0, as MMD expects of every track.
+/// Before the first keyframe of the source the first pose is held, so it is copied to frame 0.
+fn starting_at_zero<T, F, G>(mut keyframes: Vec<T>, frame_of: F, at_zero: G) -> Vec<T>
+    where T: Copy, F: Fn(&T) -> u32, G: Fn(T) -> T {
+    keyframes.sort_by_key(&frame_of);
+    if let Some(first) = keyframes.first() {
+        if frame_of(first) != 0 {
+            keyframes.insert(0, at_zero(*first));
+        }
+    }
+    keyframes
+}
+
+const NEUTRAL_BONE: PmmBoneKeyframe = PmmBoneKeyframe {
+    keyframe: BoneKeyframe {
+        frame: 0,
+        trans: Vec3::ZERO,
+        rot: Quat::IDENTITY,
+        txc: LINEAR_CURVE,
+        tyc: LINEAR_CURVE,
+        tzc: LINEAR_CURVE,
+        rc: LINEAR_CURVE,
+    },
+    physics_disabled: false,
+    editor: PmmEditorState { index: None, selected: false },
+};
+
+const NEUTRAL_BONE_CURRENT: PmmBoneCurrent = PmmBoneCurrent {
+    trans: Vec3::ZERO,
+    rot: Quat::IDENTITY,
+    uncommitted: false,
+    physics_disabled: false,
+    selected: false,
+};
+
+const NEUTRAL_MORPH: PmmMorphKeyframe = PmmMorphKeyframe {
+    keyframe: MorphKeyframe { frame: 0, weight: 0.0 },
+    editor: PmmEditorState { index: None, selected: false },
+};
+
+fn write_bone_frame<T>(mut file: &mut T, kf: &PmmBoneKeyframe, link: &Link)
+    where T: Write {
+    let k = &kf.keyframe;
+    write_link(file, link, k.frame);
+    write_bezier_control_point_pair1(&mut file, k.txc);
+    write_bezier_control_point_pair1(&mut file, k.tyc);
+    write_bezier_control_point_pair1(&mut file, k.tzc);
+    write_bezier_control_point_pair1(&mut file, k.rc);
+    write_float3(&mut file, k.trans);
+    write_quat(&mut file, k.rot);
+    write_bool(file, kf.editor.selected);
+    write_bool(file, kf.physics_disabled);
+}
+
+fn write_morph_frame<T>(file: &mut T, kf: &PmmMorphKeyframe, link: &Link)
+    where T: Write {
+    write_link(file, link, kf.keyframe.frame);
+    file.write_f32::<LittleEndian>(kf.keyframe.weight).unwrap();
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_op_frame<T>(file: &mut T, kf: &PmmPropertyKeyframe, link: &Link)
+    where T: Write {
+    write_link(file, link, kf.frame);
+    write_bool(file, kf.visible);
+    for enabled in &kf.ik_enabled {
+        write_bool(file, *enabled);
+    }
+    for (model, bone) in &kf.op_parents {
+        file.write_i32::<LittleEndian>(*model).unwrap();
+        file.write_i32::<LittleEndian>(*bone).unwrap();
+    }
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_model<T>(mut file: &mut T, model: &PmmModel)
+    where T: Write {
+    file.write_u8(model.number).unwrap();
+    write_v_string(file, &model.name);
+    write_v_string(file, &model.name_en);
+    write_string(&mut file, &model.path, 256);
+    file.write_u8(model.keyframe_editor_top_level_rows).unwrap();
+    write_v_string_items(file, &model.bone_names);
+    write_v_string_items(file, &model.morph_names);
+    write_u32_items(file, &model.ik_indexes);
+    write_u32_items(file, &model.op_indexes);
+    file.write_u8(model.draw_order).unwrap();
+    write_bool(file, model.edit_is_display);
+    file.write_u32::<LittleEndian>(model.edit_selected_bone).unwrap();
+    for v in model.skin_panel {
+        file.write_u32::<LittleEndian>(v).unwrap();
+    }
+    file.write_u8(model.frame_opened.len() as u8).unwrap();
+    file.write_all(&model.frame_opened).unwrap();
+    file.write_u32::<LittleEndian>(model.v_scroll).unwrap();
+    file.write_u32::<LittleEndian>(model.last_frame).unwrap();
+
+    let bone_keyframes = resized(&model.bone_keyframes, model.bone_names.len(), Vec::new());
+    write_tracks(file, &bone_keyframes, &NEUTRAL_BONE, |k| k.editor, write_bone_frame);
+    let morph_keyframes = resized(&model.morph_keyframes, model.morph_names.len(), Vec::new());
+    write_tracks(file, &morph_keyframes, &NEUTRAL_MORPH, |k| k.editor, write_morph_frame);
+
+    let property_keyframes: Vec<PmmPropertyKeyframe> = model.property_keyframes.iter().map(|k| PmmPropertyKeyframe {
+        frame: k.frame,
+        visible: k.visible,
+        ik_enabled: resized(&k.ik_enabled, model.ik_indexes.len(), true),
+        op_parents: resized(&k.op_parents, model.op_indexes.len(), (-1, -1)),
+        editor: k.editor,
+    }).collect();
+    let default_property = PmmPropertyKeyframe {
+        frame: 0,
+        visible: true,
+        ik_enabled: vec![true; model.ik_indexes.len()],
+        op_parents: vec![(-1, -1); model.op_indexes.len()],
+        editor: PmmEditorState::default(),
+    };
+    write_track(file, &property_keyframes, &default_property, |k| k.editor, write_op_frame);
+
+    for current in resized(&model.bone_current, model.bone_names.len(), NEUTRAL_BONE_CURRENT) {
+        write_float3(&mut file, current.trans);
+        write_quat(&mut file, current.rot);
+        write_bool(file, current.uncommitted);
+        write_bool(file, current.physics_disabled);
+        write_bool(file, current.selected);
+    }
+    for weight in resized(&model.morph_current, model.morph_names.len(), 0.0) {
+        file.write_f32::<LittleEndian>(weight).unwrap();
+    }
+    for enabled in resized(&model.ik_current, model.ik_indexes.len(), true) {
+        write_bool(file, enabled);
+    }
+    let no_op = PmmOpCurrent {
+        keyframe_begin: -1,
+        keyframe_end: -1,
+        model_index: -1,
+        bone_index: -1,
+    };
+    for op in resized(&model.op_current, model.op_indexes.len(), no_op) {
+        file.write_i32::<LittleEndian>(op.keyframe_begin).unwrap();
+        file.write_i32::<LittleEndian>(op.keyframe_end).unwrap();
+        file.write_i32::<LittleEndian>(op.model_index).unwrap();
+        file.write_i32::<LittleEndian>(op.bone_index).unwrap();
+    }
+    write_bool(file, model.blend_added);
+    file.write_f32::<LittleEndian>(model.edge_width).unwrap();
+    write_bool(file, model.self_shadow_enabled);
+    file.write_u8(model.calc_order).unwrap();
+}
+
+/// The per name arrays must match the name lists, which edits of the public fields may break
+fn resized<T: Clone>(items: &[T], len: usize, fill: T) -> Vec<T> {
+    let mut items = items.to_vec();
+    items.resize(len, fill);
+    items
+}
+
+fn write_camera_frame<T>(mut file: &mut T, kf: &PmmCameraKeyframe, link: &Link)
+    where T: Write {
+    let k = &kf.keyframe;
+    write_link(file, link, k.frame);
+    file.write_f32::<LittleEndian>(k.dist).unwrap();
+    write_float3(&mut file, k.trans);
+    write_float3(&mut file, k.rot);
+    file.write_i32::<LittleEndian>(kf.looking_model).unwrap();
+    file.write_i32::<LittleEndian>(kf.looking_bone).unwrap();
+    write_bezier_control_point_pair1(&mut file, k.txc);
+    write_bezier_control_point_pair1(&mut file, k.tyc);
+    write_bezier_control_point_pair1(&mut file, k.tzc);
+    write_bezier_control_point_pair1(&mut file, k.rc);
+    write_bezier_control_point_pair1(&mut file, k.dc);
+    write_bezier_control_point_pair1(&mut file, k.vc);
+    file.write_u8(if k.perspective {0} else {1}).unwrap();
+    file.write_u32::<LittleEndian>(k.fov).unwrap();
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_light_frame<T>(mut file: &mut T, kf: &PmmLightKeyframe, link: &Link)
+    where T: Write {
+    let k = &kf.keyframe;
+    write_link(file, link, k.frame);
+    write_float3(&mut file, k.color);
+    write_float3(&mut file, k.direction);
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_accessory_state<T>(mut file: &mut T, state: &PmmAccessoryState)
+    where T: Write {
+    let transparency = 100 - (state.opacity.clamp(0.0, 1.0) * 100.0).round() as u8;
+    file.write_u8(transparency << 1 | if state.visible {1} else {0}).unwrap();
+    file.write_i32::<LittleEndian>(state.parent_model).unwrap();
+    file.write_i32::<LittleEndian>(state.parent_bone).unwrap();
+    write_float3(&mut file, state.trans);
+    write_float3(&mut file, state.rot);
+    file.write_f32::<LittleEndian>(state.scale).unwrap();
+    write_bool(file, state.shadow);
+}
+
+fn write_accessory_frame<T>(file: &mut T, kf: &PmmAccessoryKeyframe, link: &Link)
+    where T: Write {
+    write_link(file, link, kf.frame);
+    write_accessory_state(file, &kf.state);
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_accessory<T>(mut file: &mut T, accessory: &PmmAccessory)
+    where T: Write {
+    file.write_u8(accessory.index).unwrap();
+    write_string(&mut file, &accessory.name, 100);
+    write_string(&mut file, &accessory.path, 256);
+    file.write_u8(accessory.draw_order).unwrap();
+    let default = PmmAccessoryKeyframe {
+        frame: 0,
+        state: accessory.current,
+        editor: PmmEditorState::default(),
+    };
+    write_track(file, &accessory.keyframes, &default, |k| k.editor, write_accessory_frame);
+    write_accessory_state(file, &accessory.current);
+    write_bool(file, accessory.add_blend);
+}
+
+fn write_gravity_frame<T>(mut file: &mut T, kf: &GravityKeyframe, link: &Link)
+    where T: Write {
+    write_link(file, link, kf.frame);
+    write_bool(file, kf.add_noise);
+    file.write_u32::<LittleEndian>(kf.noise_amount).unwrap();
+    file.write_f32::<LittleEndian>(kf.acceleration).unwrap();
+    write_float3(&mut file, kf.direction);
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_shadow_frame<T>(file: &mut T, kf: &PmmShadowKeyframe, link: &Link)
+    where T: Write {
+    write_link(file, link, kf.keyframe.frame);
+    file.write_u8(kf.keyframe.mode).unwrap();
+    file.write_f32::<LittleEndian>(kf.keyframe.dist).unwrap();
+    write_bool(file, kf.editor.selected);
+}
+
+fn write_background<T>(mut file: &mut T, background: &PmmBackground, int_scale: bool)
+    where T: Write {
+    file.write_i32::<LittleEndian>(background.offset.x).unwrap();
+    file.write_i32::<LittleEndian>(background.offset.y).unwrap();
+    if int_scale {
+        file.write_u32::<LittleEndian>(background.scale.round() as u32).unwrap();
+    } else {
+        file.write_f32::<LittleEndian>(background.scale).unwrap();
+    }
+    write_string(&mut file, &background.path, 256);
+    write_bool(file, background.shown);
+}
+
+/// Maps a bone index of a rebound model, bones that no longer exist become -1
+fn remap_bone(bone: i32, bone_map: &[Option<usize>]) -> i32 {
+    usize::try_from(bone).ok()
+        .and_then(|b| bone_map.get(b).copied().flatten())
+        .map_or(-1, |b| b as i32)
+}
+
+impl PmmProject {
+    pub fn write(&self) -> Vec<u8> {
+        let mut file = vec![];
+        write_string(&mut file, &PMM_HEADER.to_string(), 30);
+        file.write_u32::<LittleEndian>(self.view_width).unwrap();
+        file.write_u32::<LittleEndian>(self.view_height).unwrap();
+        file.write_u32::<LittleEndian>(self.frame_width).unwrap();
+        file.write_f32::<LittleEndian>(self.edit_view_angle).unwrap();
+        write_bool(&mut file, self.camera_light_accessory_edited);
+        for opened in self.panels_opened {
+            write_bool(&mut file, opened);
+        }
+        file.write_u8(self.selected_model_index).unwrap();
+
+        file.write_u8(self.models.len() as u8).unwrap();
+        for model in &self.models {
+            write_model(&mut file, model);
+        }
+
+        let default_camera = PmmCameraKeyframe {
+            keyframe: CameraKeyframe {
+                frame: 0,
+                dist: -45.0,
+                trans: vec3(0.0, 10.0, 0.0),
+                rot: Vec3::ZERO,
+                txc: LINEAR_CAMERA_CURVE,
+                tyc: LINEAR_CAMERA_CURVE,
+                tzc: LINEAR_CAMERA_CURVE,
+                rc: LINEAR_CAMERA_CURVE,
+                dc: LINEAR_CAMERA_CURVE,
+                vc: LINEAR_CAMERA_CURVE,
+                fov: 30,
+                perspective: true,
+            },
+            looking_model: -1,
+            looking_bone: -1,
+            editor: PmmEditorState::default(),
+        };
+        write_track(&mut file, &self.camera.keyframes, &default_camera, |k| k.editor, write_camera_frame);
+        write_float3(&mut file, self.camera.eye);
+        write_float3(&mut file, self.camera.target);
+        write_float3(&mut file, self.camera.rot);
+        write_bool(&mut file, self.camera.orthographic);
+
+        let default_light = PmmLightKeyframe {
+            keyframe: self.light_current.keyframe,
+            editor: PmmEditorState::default(),
+        };
+        write_track(&mut file, &self.light_keyframes, &default_light, |k| k.editor, write_light_frame);
+        write_float3(&mut file, self.light_current.keyframe.color);
+        write_float3(&mut file, self.light_current.keyframe.direction);
+        write_bool(&mut file, self.light_current.editor.selected);
+
+        file.write_u8(self.selected_accessory_index).unwrap();
+        file.write_u32::<LittleEndian>(self.accessory_v_scroll).unwrap();
+        file.write_u8(self.accessories.len() as u8).unwrap();
+        for accessory in &self.accessories {
+            write_string(&mut file, &accessory.name, 100);
+        }
+        for accessory in &self.accessories {
+            write_accessory(&mut file, accessory);
+        }
+
+        file.write_u32::<LittleEndian>(self.current_frame_position).unwrap();
+        file.write_u32::<LittleEndian>(self.h_scroll).unwrap();
+        file.write_u32::<LittleEndian>(self.h_scroll_scale).unwrap();
+        file.write_u32::<LittleEndian>(self.bone_operation).unwrap();
+        file.write_u8(self.looking_at).unwrap();
+        write_bool(&mut file, self.repeat);
+        write_bool(&mut file, self.play_from_enabled);
+        write_bool(&mut file, self.play_to_enabled);
+        file.write_u32::<LittleEndian>(self.play_start_frame).unwrap();
+        file.write_u32::<LittleEndian>(self.play_end_frame).unwrap();
+        write_bool(&mut file, self.wave_enabled);
+        write_string(&mut file, &self.wave_path, 256);
+        write_background(&mut file, &self.avi, false);
+        write_background(&mut file, &self.background_image, true);
+        write_bool(&mut file, self.show_information);
+        write_bool(&mut file, self.show_axis);
+        write_bool(&mut file, self.show_ground_shadow);
+        file.write_f32::<LittleEndian>(self.fps_limit).unwrap();
+        file.write_u32::<LittleEndian>(self.screen_capture_mode).unwrap();
+        file.write_u32::<LittleEndian>(self.accessory_render_after_model).unwrap();
+        file.write_f32::<LittleEndian>(self.ground_shadow_brightness).unwrap();
+        write_bool(&mut file, self.transparent_ground_shadow);
+        file.write_u8(self.physics_mode).unwrap();
+
+        let gravity = &self.gravity_current;
+        file.write_f32::<LittleEndian>(gravity.acceleration).unwrap();
+        file.write_u32::<LittleEndian>(gravity.noise_amount).unwrap();
+        write_float3(&mut file, gravity.direction);
+        write_bool(&mut file, gravity.add_noise);
+        write_track(&mut file, &self.gravity_keyframes, gravity, |k| k.editor, write_gravity_frame);
+
+        write_bool(&mut file, self.show_self_shadow);
+        file.write_f32::<LittleEndian>(self.self_shadow_current).unwrap();
+        let default_shadow = PmmShadowKeyframe {
+            keyframe: ShadowKeyframe {
+                frame: 0,
+                mode: 1,
+                dist: self.self_shadow_current,
+            },
+            editor: PmmEditorState::default(),
+        };
+        write_track(&mut file, &self.shadow_keyframes, &default_shadow, |k| k.editor, write_shadow_frame);
+
+        if let Some(extra) = &self.extra {
+            for v in extra.edge_color {
+                file.write_u32::<LittleEndian>(v).unwrap();
+            }
+            write_bool(&mut file, extra.black_background);
+            file.write_i32::<LittleEndian>(extra.camera_looking_model).unwrap();
+            file.write_i32::<LittleEndian>(extra.camera_looking_bone).unwrap();
+            for v in extra.view_matrix {
+                file.write_f32::<LittleEndian>(v).unwrap();
+            }
+            write_bool(&mut file, extra.view_look_at_enabled);
+            file.write_u8(extra.unknown).unwrap();
+            write_bool(&mut file, extra.physics_ground);
+            file.write_u32::<LittleEndian>(extra.current_frame).unwrap();
+            file.write_all(&extra.trailing).unwrap();
+        } else {
+            file.write_all(&self.trailing).unwrap();
+        }
+        file
+    }
+
+    /// Replaces the camera track with the camera keyframes of `motion`,
+    /// the light and self shadow tracks are only replaced when the motion has them
+    pub fn set_camera_motion(&mut self, motion: &Motion) {
+        let keyframes = motion.camera_keyframes.iter().map(|k| PmmCameraKeyframe {
+            keyframe: *k,
+            looking_model: -1,
+            looking_bone: -1,
+            editor: PmmEditorState::default(),
+        }).collect();
+        self.camera.keyframes = starting_at_zero(keyframes, |k| k.keyframe.frame, |mut k| {
+            k.keyframe.frame = 0;
+            k
+        });
+        if !motion.light_keyframes.is_empty() {
+            let keyframes = motion.light_keyframes.iter()
+                .map(|k| PmmLightKeyframe { keyframe: *k, editor: PmmEditorState::default() })
+                .collect();
+            self.light_keyframes = starting_at_zero(keyframes, |k| k.keyframe.frame, |mut k| {
+                k.keyframe.frame = 0;
+                k
+            });
+        }
+        if !motion.shadow_keyframes.is_empty() {
+            let keyframes = motion.shadow_keyframes.iter()
+                .map(|k| PmmShadowKeyframe { keyframe: *k, editor: PmmEditorState::default() })
+                .collect();
+            self.shadow_keyframes = starting_at_zero(keyframes, |k| k.keyframe.frame, |mut k| {
+                k.keyframe.frame = 0;
+                k
+            });
+        }
+    }
+
+    /// Points model `index` at another PMX file, see `PmmModel::rebind`.
+    /// Outside parents, accessories and camera targets attached to its bones follow the new bone indexes.
+    pub fn rebind_model(&mut self, index: usize, pmx: &Pmx, path: &str) -> Result<(), MotionError> {
+        let model = self.models.get_mut(index)
+            .ok_or_else(|| MotionError::invalid(MotionSection::Model, Some(index), format!("no model {}", index)))?;
+        let bone_map = model.rebind(pmx, path).map_err(|e| e.in_model(index))?;
+        let model_index = index as i32;
+        for model in self.models.iter_mut() {
+            for kf in model.property_keyframes.iter_mut() {
+                for (model, bone) in kf.op_parents.iter_mut() {
+                    if *model == model_index {
+                        *bone = remap_bone(*bone, &bone_map);
+                    }
+                }
+            }
+            for op in model.op_current.iter_mut() {
+                if op.model_index == model_index {
+                    op.bone_index = remap_bone(op.bone_index, &bone_map);
+                }
+            }
+        }
+        for accessory in self.accessories.iter_mut() {
+            let states = accessory.keyframes.iter_mut().map(|k| &mut k.state)
+                .chain(std::iter::once(&mut accessory.current));
+            for state in states {
+                if state.parent_model == model_index {
+                    state.parent_bone = remap_bone(state.parent_bone, &bone_map);
+                }
+            }
+        }
+        for kf in self.camera.keyframes.iter_mut() {
+            if kf.looking_model == model_index {
+                kf.looking_bone = remap_bone(kf.looking_bone, &bone_map);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PmmModel {
+    /// Replaces the bone, morph and IK tracks with the tracks of `motion` matched by name,
+    /// tracks the motion does not have are reset to a single neutral keyframe
+    pub fn set_motion(&mut self, motion: &Motion) {
+        self.bone_keyframes = self.bone_names.iter().map(|name| {
+            let keyframes = motion.bone_keyframes.get(name).map(|kfs| kfs.iter().map(|k| PmmBoneKeyframe {
+                keyframe: *k,
+                physics_disabled: false,
+                editor: PmmEditorState::default(),
+            }).collect()).unwrap_or_default();
+            starting_at_zero(keyframes, |k| k.keyframe.frame, |mut k| {
+                k.keyframe.frame = 0;
+                k
+            })
+        }).collect();
+        self.morph_keyframes = self.morph_names.iter().map(|name| {
+            let keyframes = motion.morph_keyframes.get(name).map(|kfs| kfs.iter().map(|k| PmmMorphKeyframe {
+                keyframe: *k,
+                editor: PmmEditorState::default(),
+            }).collect()).unwrap_or_default();
+            starting_at_zero(keyframes, |k| k.keyframe.frame, |mut k| {
+                k.keyframe.frame = 0;
+                k
+            })
+        }).collect();
+
+        if !motion.ik_keyframes.is_empty() {
+            let op_parents = self.property_keyframes.first()
+                .map(|k| k.op_parents.clone())
+                .unwrap_or_else(|| vec![(-1, -1); self.op_indexes.len()]);
+            let ik_names: Vec<&str> = self.ik_indexes.iter()
+                .map(|i| self.bone_names.get(*i as usize).map_or("", |n| n.as_str()))
+                .collect();
+            let mut keyframes: Vec<PmmPropertyKeyframe> = motion.ik_keyframes.iter().map(|k| PmmPropertyKeyframe {
+                frame: k.frame,
+                visible: k.show,
+                ik_enabled: ik_names.iter().map(|name| {
+                    k.infos.iter().find(|(n, _)| n == name).map_or(true, |(_, enabled)| *enabled)
+                }).collect(),
+                op_parents: op_parents.clone(),
+                editor: PmmEditorState::default(),
+            }).collect();
+            keyframes.sort_by_key(|k| k.frame);
+            if keyframes[0].frame != 0 {
+                keyframes.insert(0, PmmPropertyKeyframe { frame: 0, ..keyframes[0].clone() });
+            }
+            self.property_keyframes = keyframes;
+        }
+
+        let bone_frames = self.bone_keyframes.iter().flatten().map(|k| k.keyframe.frame);
+        let morph_frames = self.morph_keyframes.iter().flatten().map(|k| k.keyframe.frame);
+        let property_frames = self.property_keyframes.iter().map(|k| k.frame);
+        self.last_frame = bone_frames.chain(morph_frames).chain(property_frames).max().unwrap_or(0);
+    }
+
+    /// Points the model at another PMX file. Tracks, current state and IK/outside parent settings
+    /// are carried over by bone and morph name, names the old model did not have start out neutral.
+    /// Returns the new index of every old bone, or an error when the tracks do not match the name lists.
+    pub fn rebind(&mut self, pmx: &Pmx, path: &str) -> Result<Vec<Option<usize>>, MotionError> {
+        if self.bone_keyframes.len() != self.bone_names.len() {
+            return Err(MotionError::invalid(MotionSection::Bone, None, format!(
+                "{} bone tracks for {} bones", self.bone_keyframes.len(), self.bone_names.len())));
+        }
+        if self.morph_keyframes.len() != self.morph_names.len() {
+            return Err(MotionError::invalid(MotionSection::Morph, None, format!(
+                "{} morph tracks for {} morphs", self.morph_keyframes.len(), self.morph_names.len())));
+        }
+        let bone_names: Vec<String> = pmx.bones.iter().map(|b| b.name.clone()).collect();
+        let morph_names: Vec<String> = pmx.morphs.iter().map(|m| m.name.clone()).collect();
+        let old_bone = |name: &String| self.bone_names.iter().position(|n| n == name);
+        let old_morph = |name: &String| self.morph_names.iter().position(|n| n == name);
+        let bone_map: Vec<Option<usize>> = self.bone_names.iter()
+            .map(|name| bone_names.iter().position(|n| n == name))
+            .collect();
+
+        let bone_keyframes = bone_names.iter()
+            .map(|name| old_bone(name).and_then(|i| self.bone_keyframes.get(i).cloned()).unwrap_or_default())
+            .collect();
+        let bone_current = bone_names.iter()
+            .map(|name| old_bone(name).and_then(|i| self.bone_current.get(i).copied()).unwrap_or(NEUTRAL_BONE_CURRENT))
+            .collect();
+        let morph_keyframes = morph_names.iter()
+            .map(|name| old_morph(name).and_then(|i| self.morph_keyframes.get(i).cloned()).unwrap_or_default())
+            .collect();
+        let morph_current = morph_names.iter()
+            .map(|name| old_morph(name).and_then(|i| self.morph_current.get(i).copied()).unwrap_or(0.0))
+            .collect();
+
+        // position of every new IK and outside parent bone in the old lists
+        let ik_indexes: Vec<u32> = pmx.iks.iter().map(|ik| ik.bone as u32).collect();
+        let ik_sources: Vec<Option<usize>> = ik_indexes.iter()
+            .map(|b| self.ik_indexes.iter().position(|old| bone_map.get(*old as usize) == Some(&Some(*b as usize))))
+            .collect();
+        let op_sources: Vec<usize> = (0..self.op_indexes.len())
+            .filter(|i| bone_map.get(self.op_indexes[*i] as usize).copied().flatten().is_some())
+            .collect();
+        let op_indexes = op_sources.iter()
+            .filter_map(|i| bone_map[self.op_indexes[*i] as usize].map(|b| b as u32))
+            .collect();
+
+        for kf in self.property_keyframes.iter_mut() {
+            kf.ik_enabled = ik_sources.iter()
+                .map(|s| s.and_then(|i| kf.ik_enabled.get(i).copied()).unwrap_or(true))
+                .collect();
+            kf.op_parents = op_sources.iter()
+                .map(|i| kf.op_parents.get(*i).copied().unwrap_or((-1, -1)))
+                .collect();
+        }
+        self.ik_current = ik_sources.iter()
+            .map(|s| s.and_then(|i| self.ik_current.get(i).copied()).unwrap_or(true))
+            .collect();
+        self.op_current = op_sources.iter()
+            .filter_map(|i| self.op_current.get(*i).copied())
+            .collect();
+
+        self.edit_selected_bone = bone_map.get(self.edit_selected_bone as usize)
+            .copied().flatten().unwrap_or(0) as u32;
+        self.frame_opened = vec![0; pmx.display_frames.len()];
+        self.name = pmx.name.clone();
+        self.name_en = pmx.name_en.clone();
+        self.path = path.to_string();
+        self.bone_names = bone_names;
+        self.morph_names = morph_names;
+        self.bone_keyframes = bone_keyframes;
+        self.bone_current = bone_current;
+        self.morph_keyframes = morph_keyframes;
+        self.morph_current = morph_current;
+        self.ik_indexes = ik_indexes;
+        self.op_indexes = op_indexes;
+        Ok(bone_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(index: u32, selected: bool) -> PmmEditorState {
+        PmmEditorState { index: Some(index), selected }
+    }
+
+    fn bone_keyframe(frame: u32, index: u32, selected: bool) -> PmmBoneKeyframe {
+        PmmBoneKeyframe {
+            keyframe: BoneKeyframe { frame, trans: Vec3::X * frame as f32, ..NEUTRAL_BONE.keyframe },
+            physics_disabled: false,
+            editor: editor(index, selected),
+        }
+    }
+
+    fn accessory_keyframe(frame: u32, index: u32, selected: bool) -> PmmAccessoryKeyframe {
+        PmmAccessoryKeyframe {
+            frame,
+            state: PmmAccessoryState {
+                opacity: 1.0,
+                visible: true,
+                parent_model: 0,
+                parent_bone: 1,
+                trans: Vec3::Y * frame as f32,
+                rot: Vec3::ZERO,
+                scale: 1.0,
+                shadow: true,
+            },
+            editor: editor(index, selected),
+        }
+    }
+
+    /// A model with two bones whose keyframes sit at data indexes with gaps, as MMD leaves them after
+    /// deleting keyframes, some of them selected, and an accessory track stored the same way
+    fn project() -> PmmProject {
+        let mut p = PmmProject::new();
+        p.models.push(PmmModel {
+            number: 0,
+            name: "model".to_string(),
+            name_en: "model".to_string(),
+            path: "C:\\model.pmx".to_string(),
+            keyframe_editor_top_level_rows: 0,
+            bone_names: vec!["center".to_string(), "arm".to_string()],
+            morph_names: vec!["smile".to_string()],
+            ik_indexes: vec![],
+            op_indexes: vec![],
+            draw_order: 1,
+            edit_is_display: false,
+            edit_selected_bone: 1,
+            skin_panel: [0; 4],
+            frame_opened: vec![0, 1],
+            v_scroll: 0,
+            last_frame: 30,
+            bone_keyframes: vec![
+                vec![bone_keyframe(0, 0, false), bone_keyframe(10, 7, true), bone_keyframe(30, 3, false)],
+                vec![bone_keyframe(0, 1, true), bone_keyframe(20, 5, false)],
+            ],
+            morph_keyframes: vec![vec![
+                PmmMorphKeyframe { keyframe: MorphKeyframe { frame: 0, weight: 0.0 }, editor: editor(0, false) },
+                PmmMorphKeyframe { keyframe: MorphKeyframe { frame: 15, weight: 1.0 }, editor: editor(4, true) },
+            ]],
+            property_keyframes: vec![PmmPropertyKeyframe {
+                frame: 0,
+                visible: true,
+                ik_enabled: vec![],
+                op_parents: vec![],
+                editor: editor(0, true),
+            }],
+            bone_current: vec![NEUTRAL_BONE_CURRENT; 2],
+            morph_current: vec![0.0],
+            ik_current: vec![],
+            op_current: vec![],
+            blend_added: false,
+            edge_width: 1.0,
+            self_shadow_enabled: true,
+            calc_order: 1,
+        });
+        p.accessories.push(PmmAccessory {
+            index: 0,
+            name: "stage".to_string(),
+            path: "C:\\stage.x".to_string(),
+            draw_order: 0,
+            keyframes: vec![accessory_keyframe(0, 0, false), accessory_keyframe(5, 4, true), accessory_keyframe(9, 2, false)],
+            current: accessory_keyframe(0, 0, false).state,
+            add_blend: false,
+        });
+        p
+    }
+
+    fn bone_editors(p: &PmmProject) -> Vec<Vec<PmmEditorState>> {
+        p.models[0].bone_keyframes.iter().map(|t| t.iter().map(|k| k.editor).collect()).collect()
+    }
+
+    #[test]
+    fn read_write_read_keeps_the_file() {
+        let written = project().write();
+        let read = PmmProject::read(written.clone()).unwrap();
+        assert_eq!(read.write(), written);
+
+        let reread = PmmProject::read(read.write()).unwrap();
+        assert_eq!(bone_editors(&reread), bone_editors(&project()));
+        let model = &reread.models[0];
+        assert_eq!(model.morph_keyframes[0][1].editor, editor(4, true));
+        assert!(model.property_keyframes[0].editor.selected);
+        let accessory: Vec<(u32, PmmEditorState)> = reread.accessories[0].keyframes.iter().map(|k| (k.frame, k.editor)).collect();
+        assert_eq!(accessory, vec![(0, editor(0, false)), (5, editor(4, true)), (9, editor(2, false))]);
+        assert_eq!(reread.accessories[0].keyframes[2].state.parent_bone, 1);
+    }
+
+    #[test]
+    fn light_panel_is_kept() {
+        let mut p = project();
+        p.light_current.keyframe.color = vec3(0.25, 0.5, 0.75);
+        p.light_current.keyframe.direction = vec3(-0.5, -1.0, 0.5);
+        let mut content = p.write();
+        // mark the light as selected in the bytes, as MMD stores it, after the color and direction
+        let floats: Vec<u8> = [0.25f32, 0.5, 0.75, -0.5, -1.0, 0.5].iter().flat_map(|f| f.to_le_bytes()).collect();
+        let at = content.windows(floats.len()).rposition(|w| w == floats.as_slice()).unwrap() + floats.len();
+        assert_eq!(content[at], 0);
+        content[at] = 1;
+
+        let read = PmmProject::read(content.clone()).unwrap();
+        assert_eq!(read.light_current.keyframe.color, vec3(0.25, 0.5, 0.75));
+        assert_eq!(read.light_current.keyframe.direction, vec3(-0.5, -1.0, 0.5));
+        assert!(read.light_current.editor.selected);
+        assert_eq!(read.write(), content);
+    }
+
+    #[test]
+    fn short_trailing_block_is_kept() {
+        let mut content = project().write();
+        content.extend_from_slice(&[1, 2, 3, 4, 5]);
+        let read = PmmProject::read(content.clone()).unwrap();
+        assert!(read.extra.is_none());
+        assert_eq!(read.trailing, vec![1, 2, 3, 4, 5]);
+        assert_eq!(read.write(), content);
+    }
+
+    #[test]
+    fn edited_tracks_are_renumbered() {
+        let mut p = project();
+        let mut motion = Motion::new();
+        motion.bone_keyframes.insert("arm".to_string(), vec![
+            BoneKeyframe { frame: 5, ..NEUTRAL_BONE.keyframe },
+            BoneKeyframe { frame: 25, ..NEUTRAL_BONE.keyframe },
+        ]);
+        p.models[0].set_motion(&motion);
+        let read = PmmProject::read(p.write()).unwrap();
+        let model = &read.models[0];
+        let frames: Vec<Vec<u32>> = model.bone_keyframes.iter().map(|t| t.iter().map(|k| k.keyframe.frame).collect()).collect();
+        assert_eq!(frames, vec![vec![0], vec![0, 5, 25]]);
+        assert_eq!(bone_editors(&read), vec![vec![editor(0, false)], vec![editor(1, false), editor(2, false), editor(3, false)]]);
+    }
+    #[test]
+    fn rebinding_mismatched_tracks_is_an_error() {
+        let mut p = project();
+        let mut pmx = Pmx::new();
+        pmx.bones.push(crate::format::pmx_fixtures::bone("arm", None, Vec3::ZERO));
+        assert!(p.rebind_model(1, &pmx, "C:\\other.pmx").is_err());
+        p.models[0].morph_keyframes.clear();
+        assert!(p.rebind_model(0, &pmx, "C:\\other.pmx").is_err());
+        p.models[0].morph_keyframes.push(vec![]);
+        p.rebind_model(0, &pmx, "C:\\other.pmx").unwrap();
+        assert_eq!(p.models[0].bone_names, vec!["arm".to_string()]);
+        assert_eq!(p.models[0].bone_keyframes[0].len(), 2);
+    }
+}
@@ -21,9 +21,45 @@ use image::DynamicImage;
 
 use super::motion::Motion;
 use super::common::*;
+use super::pmx_remap::remap_vec;
 use bitflags::bitflags;
 
 
+#[derive(Debug)]
+pub enum PmxError {
+    BadMagic([u8; 4]),
+    UnsupportedVersion(f32),
+    Truncated { section: &'static str, offset: u64 },
+    InvalidIndexSize { kind: &'static str, size: u8 },
+    InvalidEncoding { offset: u64 },
+    InvalidValue { section: &'static str, offset: u64, what: &'static str },
+    Io(std::io::Error),
+}
+
+pub type PmxResult<T> = std::result::Result<T, PmxError>;
+
+impl std::fmt::Display for PmxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PmxError::BadMagic(magic) => write!(f, "unrecognized file magic {:?}", String::from_utf8_lossy(magic)),
+            PmxError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            PmxError::Truncated { section, offset } => write!(f, "truncated {} section starting at byte {}", section, offset),
+            PmxError::InvalidIndexSize { kind, size } => write!(f, "invalid {} index size {}", kind, size),
+            PmxError::InvalidEncoding { offset } => write!(f, "invalid string encoding at byte {}", offset),
+            PmxError::InvalidValue { section, offset, what } => write!(f, "invalid {} in {} section at byte {}", what, section, offset),
+            PmxError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PmxError {}
+
+impl From<std::io::Error> for PmxError {
+    fn from(e: std::io::Error) -> Self {
+        PmxError::Io(e)
+    }
+}
+
 #[derive(Clone)]
 pub struct Pmx {
     pub name: String,
@@ -40,11 +76,43 @@ pub struct Pmx {
     pub morphs: Vec<MorphInfo>,
     pub rigidbodys: Vec<Rigidbody>,
     pub joints: Vec<Joint>,
+    pub softbodys: Vec<Softbody>,
     pub display_frames: Vec<DisplayFrame>,
+    pub header: PmxHeader,
     pub path: String,
     pub uuid: Uuid,
 }
 
+/// Header settings of the source file, kept so that `write` can reproduce them
+#[derive(Copy, Clone)]
+pub struct PmxHeader {
+    pub version: f32,
+    pub utf8: bool,
+    pub appendix_uv: u8,
+    pub vertex_index_size: u8,
+    pub texture_index_size: u8,
+    pub material_index_size: u8,
+    pub bone_index_size: u8,
+    pub morph_index_size: u8,
+    pub rigidbody_index_size: u8,
+}
+
+impl Default for PmxHeader {
+    fn default() -> Self {
+        Self {
+            version: 2.0,
+            utf8: false,
+            appendix_uv: 0,
+            vertex_index_size: 1,
+            texture_index_size: 1,
+            material_index_size: 1,
+            bone_index_size: 1,
+            morph_index_size: 1,
+            rigidbody_index_size: 1,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Vertex {
     pub pos: Vec3,
@@ -63,13 +131,13 @@ pub enum VertexWeight {
     Quat(IVec4, Vec4),
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum Toon {
     Tex(i32),
     Inner(u8),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum BlendMode {
     Disable,
     Mul,
@@ -187,6 +255,41 @@ impl Default for Bone {
     }
 }
 
+impl Bone {
+    /// Makes the optional fields match `bone_flags` after the flags were edited,
+    /// the writer stores whichever fields are present
+    pub fn sync_flag_fields(&mut self) {
+        let flags = self.bone_flags;
+        self.bone_tail_pos = match (flags.contains(BoneFlags::INDEXED_TAIL_BONE), self.bone_tail_pos) {
+            (true, BoneTailPos::Pos(_)) => BoneTailPos::Bone(-1),
+            (false, BoneTailPos::Bone(_)) => BoneTailPos::Pos(Vec3::ZERO),
+            (_, tail) => tail,
+        };
+        self.inherit = if flags.intersects(BoneFlags::INHERIT_ROTATION | BoneFlags::INHERIT_TRANSLATION) {
+            Some(self.inherit.unwrap_or((-1, 1.0)))
+        } else {
+            None
+        };
+        self.fixed_axis = flags.contains(BoneFlags::FIXED_AXIS).then(|| self.fixed_axis.unwrap_or(Vec3::X));
+        self.local_axis = flags.contains(BoneFlags::LOCAL_AXIS).then(|| self.local_axis.unwrap_or((Vec3::X, Vec3::Z)));
+        self.external_parent = flags.contains(BoneFlags::EXTERNAL_PARENT).then(|| self.external_parent.unwrap_or(0));
+    }
+    /// Flips the bone between left and right handed space, as `Pmx::right_hand` does
+    pub fn right_hand(&mut self) {
+        self.pos.z *= -1.0;
+        if let BoneTailPos::Pos(ref mut pos) = self.bone_tail_pos {
+            pos.z *= -1.0;
+        }
+        if let Some(ref mut axis) = self.fixed_axis {
+            axis.z *= -1.0;
+        }
+        if let Some((ref mut x, ref mut z)) = self.local_axis {
+            x.z *= -1.0;
+            z.z *= -1.0;
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Ik {
     pub bone: i32,
@@ -202,11 +305,21 @@ pub struct IkJoint {
     pub limit: Option<(Vec3, Vec3)>,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JointKind {
+    Spring6Dof,
+    SixDof,
+    P2p,
+    ConeTwist,
+    Slider,
+    Hinge,
+}
+
 #[derive(Clone)]
 pub struct Joint {
     pub name: String,
     pub name_en: String,
-    pub category: u8,
+    pub kind: JointKind,
     pub rigidbody_a: i32,
     pub rigidbody_b: i32,
     pub pos: Vec3,
@@ -220,14 +333,14 @@ pub struct Joint {
     pub uuid: Uuid,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum RigidbodyShape {
     Shpere,
     Box,
     Capsule,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum RigidbodyMode {
     Kinematics,
     Dynamics,
@@ -254,6 +367,82 @@ pub struct Rigidbody {
     pub uuid: Uuid,
 }
 
+#[derive(Copy, Clone)]
+pub enum SoftbodyShape {
+    TriMesh,
+    Rope,
+}
+
+bitflags! {
+    #[derive(Clone, Copy)]
+    pub struct SoftbodyFlags: u8 {
+        const B_LINK        = 0b00000001;
+        const CLUSTER       = 0b00000010;
+        const LINK_CROSSING = 0b00000100;
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum AeroModel {
+    VPoint,
+    VTwoSided,
+    VOneSided,
+    FTwoSided,
+    FOneSided,
+}
+
+#[derive(Copy, Clone)]
+pub struct SoftbodyAnchor {
+    pub rigidbody: i32,
+    pub vertex: i32,
+    pub near_mode: bool,
+}
+
+/// PMX 2.1 soft body, the parameter names follow the bullet physics config
+#[derive(Clone)]
+pub struct Softbody {
+    pub name: String,
+    pub name_en: String,
+    pub shape: SoftbodyShape,
+    pub mat: i32,
+    pub group: u8,
+    pub collision_group: u16,
+    pub flags: SoftbodyFlags,
+    pub b_link_distance: i32,
+    pub cluster_count: i32,
+    pub mass: f32,
+    pub collision_margin: f32,
+    pub aero_model: AeroModel,
+    pub vcf: f32,
+    pub dp: f32,
+    pub dg: f32,
+    pub lf: f32,
+    pub pr: f32,
+    pub vc: f32,
+    pub df: f32,
+    pub mt: f32,
+    pub chr: f32,
+    pub khr: f32,
+    pub shr: f32,
+    pub ahr: f32,
+    pub srhr_cl: f32,
+    pub skhr_cl: f32,
+    pub sshr_cl: f32,
+    pub sr_splt_cl: f32,
+    pub sk_splt_cl: f32,
+    pub ss_splt_cl: f32,
+    pub v_it: i32,
+    pub p_it: i32,
+    pub d_it: i32,
+    pub c_it: i32,
+    pub lst: f32,
+    pub ast: f32,
+    pub vst: f32,
+    pub anchors: Vec<SoftbodyAnchor>,
+    pub pin_verts: Vec<i32>,
+    pub uuid: Uuid,
+}
+
 #[derive(Copy, Clone)]
 pub enum DisplayFrameIndex {
     Bone(u32),
@@ -264,10 +453,21 @@ pub enum DisplayFrameIndex {
 pub struct DisplayFrame {
     pub name: String,
     pub name_en: String,
-    pub deletable: bool,
+    /// the Root and 表情 frames every model has, their names are fixed
+    pub special: bool,
     pub morph_items: Vec<DisplayFrameIndex>,
 }
 
+impl DisplayFrame {
+    /// The empty Root and 表情 frames a new or converted model starts with
+    pub fn defaults() -> Vec<DisplayFrame> {
+        vec![
+            DisplayFrame { name: "Root".to_string(), name_en: "Root".to_string(), special: true, morph_items: vec![] },
+            DisplayFrame { name: "表情".to_string(), name_en: "Exp".to_string(), special: true, morph_items: vec![] },
+        ]
+    }
+}
+
 #[derive(Clone)]
 pub struct MorphInfo {
     pub name: String,
@@ -277,6 +477,27 @@ pub struct MorphInfo {
     pub data: Morph,
 }
 
+impl MorphInfo {
+    /// Flips the morph between left and right handed space, as `Pmx::right_hand` does
+    pub fn right_hand(&mut self) {
+        match self.data {
+            Morph::MorphVertex(ref mut items) => {
+                for item in items {
+                    item.trans.z *= -1.0;
+                }
+            }
+            Morph::MorphBone(ref mut items) => {
+                for item in items {
+                    item.trans.z *= -1.0;
+                    item.rot.x *= -1.0;
+                    item.rot.y *= -1.0;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Morph {
     MorphGroup(Vec<MorphGroupItem>),
@@ -342,7 +563,37 @@ pub struct MorphMatItem {
     pub toon_tint: Vec4,
 }
 
+impl Default for Pmx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Pmx {
+    /// An empty model with only the special display frames
+    pub fn new() -> Pmx {
+        Pmx {
+            name: String::new(),
+            name_en: String::new(),
+            comment: String::new(),
+            comment_en: String::new(),
+            verts: Vec::new(),
+            appendix_uvs: Vec::new(),
+            faces: Vec::new(),
+            texs: Vec::new(),
+            mats: Vec::new(),
+            bones: Vec::new(),
+            iks: Vec::new(),
+            morphs: Vec::new(),
+            rigidbodys: Vec::new(),
+            joints: Vec::new(),
+            softbodys: Vec::new(),
+            display_frames: DisplayFrame::defaults(),
+            header: Default::default(),
+            path: String::new(),
+            uuid: Uuid::new_v4(),
+        }
+    }
     pub fn mat_merge(&mut self, mats: &BTreeSet<usize>) {
         let mut merged_face = Vec::new();
         let mut merged_face_count = 0;
@@ -377,20 +628,35 @@ impl Pmx {
                 start += mat.associated_face_count as usize;
             }
         }
+        let first = *mats.iter().next().unwrap();
+        let mut map: Vec<Option<usize>> = Vec::new();
+        let mut next = 0;
+        for i in 0..self.mats.len() {
+            if mats.contains(&i) && i != first {
+                map.push(map[first]);
+            } else {
+                map.push(Some(next));
+                next += 1;
+            }
+        }
+        self.remap_mat_refs(&map);
         self.mats = new_mats;
         self.faces = new_faces;
     }
     pub fn delete_mats(&mut self, mats: &Vec<String>) {
         let mut new_mats = Vec::new();
         let mut new_faces: Vec<[u32; 3]> = Vec::new();
+        let mut map = Vec::new();
         let mut start: usize = 0;
         for mat in &self.mats {
+            map.push((!mats.contains(&mat.name)).then_some(new_mats.len()));
             if mats.contains(&mat.name) == false {
                 new_mats.push(mat.clone());
                 new_faces.extend_from_slice(&self.faces[start..(start + mat.associated_face_count as usize)]);
             }
             start += mat.associated_face_count as usize;
         }
+        self.remap_mat_refs(&map);
         self.mats = new_mats;
         self.faces = new_faces;
         self.delete_unref_point();
@@ -398,26 +664,47 @@ impl Pmx {
     pub fn delete_unref_point(&mut self) {
         let mut ref_set = BTreeSet::new();
         for [x, y, z] in &self.faces {
-            ref_set.insert(x);
-            ref_set.insert(y);
-            ref_set.insert(z);
+            ref_set.insert(*x as usize);
+            ref_set.insert(*y as usize);
+            ref_set.insert(*z as usize);
         }
-        let mut new_verts = Vec::new();
-        let mut mapping: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut mapping = vec![None; self.verts.len()];
         for (new_index, old_index) in ref_set.iter().enumerate() {
-            new_verts.push(self.verts[**old_index as usize]);
-            mapping.insert(**old_index, new_index as _);
+            mapping[*old_index] = Some(new_index);
         }
-        self.verts = new_verts;
-        let mut new_faces: Vec<[u32; 3]> = Vec::new();
-        for [x, y, z] in &self.faces {
-            new_faces.push([
-                mapping[x],
-                mapping[y],
-                mapping[z],
-            ]);
+        self.remap_verts(&mapping);
+    }
+    /// Keeps vertex `i` as vertex `map[i]`, or removes it when it is `None`, and remaps every
+    /// reference to a vertex. Faces must only use kept vertices, the morph items and softbody
+    /// entries of removed vertices are dropped.
+    pub fn remap_verts(&mut self, map: &[Option<usize>]) {
+        let new = |i: usize| map.get(i).copied().flatten();
+        self.verts = remap_vec(std::mem::take(&mut self.verts), map);
+        for uvs in &mut self.appendix_uvs {
+            uvs.resize(map.len(), Vec4::ZERO);
+            *uvs = remap_vec(std::mem::take(uvs), map);
+        }
+        for face in &mut self.faces {
+            for i in face.iter_mut() {
+                *i = new(*i as usize).expect("face uses a removed vertex") as u32;
+            }
+        }
+        for morph in &mut self.morphs {
+            match &mut morph.data {
+                Morph::MorphVertex(items) => {
+                    items.retain_mut(|item| new(item.index as usize).map(|i| item.index = i as u32).is_some());
+                }
+                Morph::MorphUv(items) => {
+                    items.retain_mut(|item| new(item.index as usize).map(|i| item.index = i as u32).is_some());
+                }
+                _ => {}
+            }
+        }
+        let new_i32 = |i: i32| usize::try_from(i).ok().and_then(new).map(|i| i as i32);
+        for sb in &mut self.softbodys {
+            sb.anchors.retain_mut(|a| new_i32(a.vertex).map(|i| a.vertex = i).is_some());
+            sb.pin_verts.retain_mut(|v| new_i32(*v).map(|i| *v = i).is_some());
         }
-        self.faces = new_faces;
     }
     pub fn calc_connected_nrms_to_uv1(&mut self) {
         let mut mapping = Vec::new();
@@ -487,68 +774,124 @@ impl Pmx {
         res.insert(-1, default_image);
         res
     }
-    fn read_string(file: &mut Cursor<Vec<u8>>, utf8: bool) -> String {
-        let len = file.read_i32::<LE>().unwrap() as usize;
+    fn read_string(file: &mut Cursor<Vec<u8>>, utf8: bool) -> PmxResult<String> {
+        let offset = file.position();
+        let len = file.read_i32::<LE>()?;
         if len == 0 {
-            return String::new();
+            return Ok(String::new());
         };
-        let mut content = vec![0u8; len];
-        file.read(&mut content).unwrap();
+        if len < 0 {
+            return Err(PmxError::InvalidValue { section: "", offset, what: "string length" });
+        }
+        if len as u64 > Pmx::remaining(file) {
+            return Err(PmxError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let mut content = vec![0u8; len as usize];
+        file.read_exact(&mut content)?;
         if utf8 {
-            String::from_utf8(content).unwrap()
+            String::from_utf8(content).map_err(|_| PmxError::InvalidEncoding { offset })
         } else {
-            String::from_utf16(bytemuck::cast_slice_mut(&mut content)).unwrap()
+            let chunks = content.chunks_exact(2);
+            if !chunks.remainder().is_empty() {
+                return Err(PmxError::InvalidEncoding { offset });
+            }
+            let units: Vec<u16> = chunks.map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).map_err(|_| PmxError::InvalidEncoding { offset })
         }
     }
-    pub fn read_with_preset(content: Vec<u8>, path: &str) -> Self {
-        let mut pmx = Self::read(content, path);
+    pub(crate) fn remaining(file: &Cursor<Vec<u8>>) -> u64 {
+        (file.get_ref().len() as u64).saturating_sub(file.position())
+    }
+    pub(crate) fn read_count(file: &mut Cursor<Vec<u8>>) -> PmxResult<u32> {
+        let count = file.read_u32::<LE>()?;
+        // every record is at least one byte long, so a count beyond the remaining data is a truncated file
+        if count as u64 > Pmx::remaining(file) {
+            return Err(PmxError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(count)
+    }
+    pub(crate) fn section<T, F>(file: &mut Cursor<Vec<u8>>, section: &'static str, f: F) -> PmxResult<T>
+        where F: FnOnce(&mut Cursor<Vec<u8>>) -> PmxResult<T> {
+        let offset = file.position();
+        f(file).map_err(|e| match e {
+            PmxError::Io(_) => PmxError::Truncated { section, offset },
+            PmxError::InvalidValue { offset, what, .. } => PmxError::InvalidValue { section, offset, what },
+            e => e,
+        })
+    }
+    pub(crate) fn invalid_value(file: &Cursor<Vec<u8>>, what: &'static str) -> PmxError {
+        PmxError::InvalidValue { section: "", offset: file.position() - 1, what }
+    }
+    pub fn read_with_preset(content: Vec<u8>, path: &str) -> PmxResult<Self> {
+        let mut pmx = Self::read(content, path)?;
         pmx.reverse_ik_joints();
         pmx.linear_four_weight();
         pmx.scale(0.08);
         pmx.right_hand();
-        pmx
+        Ok(pmx)
     }
 
-    pub fn read(content: Vec<u8>, path: &str) -> Self {
+    pub fn read(content: Vec<u8>, path: &str) -> PmxResult<Self> {
         let file = &mut std::io::Cursor::new(content);
-        let mut magic = vec![0u8; 3];
-        file.read(&mut magic).unwrap();
-        file.read_u8().unwrap();
-        assert_eq!(String::from_utf8(magic), Ok("PMX".to_string()));
-        let version = file.read_f32::<LE>().unwrap();
-        file.read_u8().unwrap();
-        let utf8 = file.read_u8().unwrap() == 1;
-        let appendix_uv = file.read_u8().unwrap();
-        let vertex_index_size = file.read_u8().unwrap();
-        let texture_index_size = file.read_u8().unwrap();
-        let material_index_size = file.read_u8().unwrap();
-        let bone_index_size = file.read_u8().unwrap();
-        let morph_index_size = file.read_u8().unwrap();
-        let rigidbody_index_size = file.read_u8().unwrap();
-        let name = Pmx::read_string(file, utf8);
-        let name_en = Pmx::read_string(file, utf8);
-        let comment = Pmx::read_string(file, utf8);
-        let comment_en = Pmx::read_string(file, utf8);
-        let (verts, appendix_uvs) = Pmx::read_verts(file, bone_index_size, appendix_uv);
-        let faces = Pmx::read_faces(file, vertex_index_size);
-        let texs = Pmx::read_texs(file, utf8);
-        let mats = Pmx::read_mats(file, utf8, texture_index_size);
-        let (bones, iks) = Pmx::read_bones(file, utf8, bone_index_size);
-        let morphs = Pmx::read_morphs(
-            file,
+        let header = Pmx::section(file, "header", Pmx::read_header)?;
+        let PmxHeader {
+            version,
             utf8,
+            appendix_uv,
             vertex_index_size,
+            texture_index_size,
             material_index_size,
             bone_index_size,
             morph_index_size,
-            rigidbody_index_size
-        );
-        let display_frames = Pmx::read_display_frames(file, utf8, bone_index_size, morph_index_size);
-        let rigidbodys = Pmx::read_rigidbodys(file, utf8, bone_index_size);
-        let joints = Pmx::read_joints(file, utf8, rigidbody_index_size);
+            rigidbody_index_size,
+        } = header;
+        let (name, name_en, comment, comment_en) = Pmx::section(file, "model info", |file| {
+            Ok((
+                Pmx::read_string(file, utf8)?,
+                Pmx::read_string(file, utf8)?,
+                Pmx::read_string(file, utf8)?,
+                Pmx::read_string(file, utf8)?,
+            ))
+        })?;
+        let (verts, appendix_uvs) = Pmx::section(file, "vertex", |file| {
+            Pmx::read_verts(file, bone_index_size, appendix_uv)
+        })?;
+        let faces = Pmx::section(file, "face", |file| Pmx::read_faces(file, vertex_index_size, verts.len()))?;
+        let texs = Pmx::section(file, "texture", |file| Pmx::read_texs(file, utf8))?;
+        let mats = Pmx::section(file, "material", |file| Pmx::read_mats(file, utf8, texture_index_size, faces.len()))?;
+        let (bones, iks) = Pmx::section(file, "bone", |file| Pmx::read_bones(file, utf8, bone_index_size))?;
+        let morphs = Pmx::section(file, "morph", |file| {
+            Pmx::read_morphs(
+                file,
+                utf8,
+                vertex_index_size,
+                material_index_size,
+                bone_index_size,
+                morph_index_size,
+                rigidbody_index_size
+            )
+        })?;
+        let display_frames = Pmx::section(file, "display frame", |file| {
+            Pmx::read_display_frames(file, utf8, bone_index_size, morph_index_size)
+        })?;
+        let rigidbodys = Pmx::section(file, "rigidbody", |file| Pmx::read_rigidbodys(file, utf8, bone_index_size))?;
+        let joints = Pmx::section(file, "joint", |file| Pmx::read_joints(file, utf8, rigidbody_index_size))?;
+        let softbodys = if version > 2.0 && Pmx::remaining(file) > 0 {
+            Pmx::section(file, "softbody", |file| {
+                Pmx::read_softbodys(
+                    file,
+                    utf8,
+                    vertex_index_size,
+                    material_index_size,
+                    rigidbody_index_size
+                )
+            })?
+        } else {
+            Vec::new()
+        };
 
         
-        Self {
+        Ok(Self {
             name,
             name_en,
             comment,
@@ -563,42 +906,93 @@ impl Pmx {
             morphs,
             rigidbodys,
             joints,
+            softbodys,
+            header,
             path: path.to_string(),
             uuid: Uuid::new_v4(),
             display_frames,
-        }
+        })
+
+    }
 
+    fn read_header(file: &mut Cursor<Vec<u8>>) -> PmxResult<PmxHeader> {
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic[0..3] != b"PMX" {
+            return Err(PmxError::BadMagic(magic));
+        }
+        let version = file.read_f32::<LE>()?;
+        if !(2.0..=2.1).contains(&version) {
+            return Err(PmxError::UnsupportedVersion(version));
+        }
+        file.read_u8()?;
+        let utf8 = file.read_u8()? == 1;
+        let appendix_uv = file.read_u8()?;
+        let mut index_sizes = [0u8; 6];
+        file.read_exact(&mut index_sizes)?;
+        let kinds = ["vertex", "texture", "material", "bone", "morph", "rigidbody"];
+        for (kind, size) in kinds.into_iter().zip(index_sizes) {
+            if !matches!(size, 1 | 2 | 4) {
+                return Err(PmxError::InvalidIndexSize { kind, size });
+            }
+        }
+        let [
+            vertex_index_size,
+            texture_index_size,
+            material_index_size,
+            bone_index_size,
+            morph_index_size,
+            rigidbody_index_size,
+        ] = index_sizes;
+        Ok(PmxHeader {
+            version,
+            utf8,
+            appendix_uv,
+            vertex_index_size,
+            texture_index_size,
+            material_index_size,
+            bone_index_size,
+            morph_index_size,
+            rigidbody_index_size,
+        })
     }
 
-    fn read_mats(file: &mut Cursor<Vec<u8>>, utf8: bool, texture_index_size: u8) -> Vec<Mat> {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_mats(file: &mut Cursor<Vec<u8>>, utf8: bool, texture_index_size: u8, face_count: usize) -> PmxResult<Vec<Mat>> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
+        let mut used_faces = 0;
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let diffuse = read_float4(file);
-            let specular = read_float4(file);
-            let ambient = read_float3(file);
-            let draw_flag = DrawFlags::from_bits(file.read_u8().unwrap()).unwrap();
-            let edge_color = read_float4(file);
-            let edge_scale = file.read_f32::<LE>().unwrap();
-            let tex_index = Pmx::read_int(file, texture_index_size);
-            let env_index = Pmx::read_int(file, texture_index_size);
-            let env_blend_mode = match file.read_u8().unwrap() {
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let diffuse = read_float4(file)?;
+            let specular = read_float4(file)?;
+            let ambient = read_float3(file)?;
+            let draw_flag = DrawFlags::from_bits_retain(file.read_u8()?);
+            let edge_color = read_float4(file)?;
+            let edge_scale = file.read_f32::<LE>()?;
+            let tex_index = Pmx::read_int(file, texture_index_size)?;
+            let env_index = Pmx::read_int(file, texture_index_size)?;
+            let env_blend_mode = match file.read_u8()? {
                 0 => BlendMode::Disable,
                 1 => BlendMode::Mul,
                 2 => BlendMode::Add,
                 3 => BlendMode::Other,
-                _ => unreachable!(),
+                _ => return Err(Pmx::invalid_value(file, "environment blend mode")),
             };
-            let toon_ref = file.read_u8().unwrap();
+            let toon_ref = file.read_u8()?;
             let toon = if toon_ref == 0 {
-                Toon::Tex(Pmx::read_int(file, texture_index_size))
+                Toon::Tex(Pmx::read_int(file, texture_index_size)?)
             } else {
-                Toon::Inner(file.read_u8().unwrap())
+                Toon::Inner(file.read_u8()?)
             };
-            let comment = Pmx::read_string(file, utf8);
-            let associated_face_count = Pmx::read_int(file, 4) as u32 / 3;
+            let comment = Pmx::read_string(file, utf8)?;
+            // the faces are sliced by these counts, they have to add up within the face section
+            let associated_face_count = Pmx::read_int(file, 4)?;
+            if associated_face_count < 0 || used_faces + associated_face_count as usize / 3 > face_count {
+                return Err(Pmx::invalid_value(file, "face count"));
+            }
+            used_faces += associated_face_count as usize / 3;
+            let associated_face_count = associated_face_count as u32 / 3;
             vct.push(Mat {
                 name,
                 name_en,
@@ -616,63 +1010,63 @@ impl Pmx {
                 associated_face_count,
             })
         }
-        vct
+        Ok(vct)
     }
 
-    fn read_bones(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> (Vec<Bone>, Vec<Ik>) {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_bones(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> PmxResult<(Vec<Bone>, Vec<Ik>)> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
-        let mut iks = Vec::with_capacity(len as usize);
+        let mut iks = Vec::new();
         for i in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let pos = read_float3(file);
-            let parent_index = Pmx::read_int(file, bone_index_size);
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let pos = read_float3(file)?;
+            let parent_index = Pmx::read_int(file, bone_index_size)?;
             let parent_index = if parent_index >= 0 {
                 Some(parent_index as usize)
             } else {
                 None
             };
-            let layer = file.read_i32::<LE>().unwrap();
-            let bone_flags = BoneFlags::from_bits(file.read_u16::<LE>().unwrap()).unwrap();
+            let layer = file.read_i32::<LE>()?;
+            let bone_flags = BoneFlags::from_bits_retain(file.read_u16::<LE>()?);
             let bone_tail_pos = if bone_flags.contains(BoneFlags::INDEXED_TAIL_BONE) {
-                BoneTailPos::Bone(Pmx::read_int(file, bone_index_size))
+                BoneTailPos::Bone(Pmx::read_int(file, bone_index_size)?)
             } else {
-                BoneTailPos::Pos(read_float3(file))
+                BoneTailPos::Pos(read_float3(file)?)
             };
             let inherit = if bone_flags.contains(BoneFlags::INHERIT_ROTATION) || bone_flags.contains(BoneFlags::INHERIT_TRANSLATION) {
-                let parent_index = Pmx::read_int(file, bone_index_size);
-                let affect = file.read_f32::<LE>().unwrap();
+                let parent_index = Pmx::read_int(file, bone_index_size)?;
+                let affect = file.read_f32::<LE>()?;
                 Some((parent_index, affect))
             } else {
                 None
             };
             let fixed_axis = if bone_flags.contains(BoneFlags::FIXED_AXIS) {
-                Some(read_float3(file))
+                Some(read_float3(file)?)
             } else {
                 None
             };
             let local_axis = if bone_flags.contains(BoneFlags::LOCAL_AXIS) {
-                Some((read_float3(file), read_float3(file)))
+                Some((read_float3(file)?, read_float3(file)?))
             } else {
                 None
             };
             let external_parent = if bone_flags.contains(BoneFlags::EXTERNAL_PARENT) {
-                Some(Pmx::read_int(file, bone_index_size))
+                Some(Pmx::read_int(file, bone_index_size)?)
             } else {
                 None
             };
             if bone_flags.contains(BoneFlags::IK) {
-                let effector = Pmx::read_int(file, bone_index_size);
-                let loop_count = file.read_i32::<LE>().unwrap();
-                let limit_angle = file.read_f32::<LE>().unwrap();
-                let link_count = file.read_i32::<LE>().unwrap();
+                let effector = Pmx::read_int(file, bone_index_size)?;
+                let loop_count = file.read_i32::<LE>()?;
+                let limit_angle = file.read_f32::<LE>()?;
+                let link_count = file.read_i32::<LE>()?;
                 let mut ik_joints = Vec::new();
                 for i in 0..link_count {
-                    let bone = Pmx::read_int(file, bone_index_size);
-                    let limit = if file.read_u8().unwrap() == 1 {
-                        let limit_min = read_float3(file);
-                        let limit_max = read_float3(file);
+                    let bone = Pmx::read_int(file, bone_index_size)?;
+                    let limit = if file.read_u8()? == 1 {
+                        let limit_min = read_float3(file)?;
+                        let limit_max = read_float3(file)?;
                         Some((limit_min, limit_max))
                     } else {
                         None
@@ -704,40 +1098,47 @@ impl Pmx {
                 external_parent,
             })
         }
-        (vct, iks)
+        Ok((vct, iks))
     }
 
-    fn read_texs(file: &mut Cursor<Vec<u8>>, utf8: bool) -> Vec<String> {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_texs(file: &mut Cursor<Vec<u8>>, utf8: bool) -> PmxResult<Vec<String>> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            let tex = Pmx::read_string(file, utf8);
+            let tex = Pmx::read_string(file, utf8)?;
             vct.push(tex)
         }
-        vct
+        Ok(vct)
     }
-    fn read_joints(file: &mut Cursor<Vec<u8>>, utf8: bool, rigidbody_index_size: u8) -> Vec<Joint> {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_joints(file: &mut Cursor<Vec<u8>>, utf8: bool, rigidbody_index_size: u8) -> PmxResult<Vec<Joint>> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let category = file.read_u8().unwrap();
-            assert_eq!(category, 0);
-            let rigidbody_a = Pmx::read_int(file, rigidbody_index_size);
-            let rigidbody_b = Pmx::read_int(file, rigidbody_index_size);
-            let pos = read_float3(file);
-            let rot = read_float3(file);
-            let pos_min = read_float3(file);
-            let pos_max = read_float3(file);
-            let rot_min = read_float3(file);
-            let rot_max = read_float3(file);
-            let pos_spring = read_float3(file);
-            let rot_spring = read_float3(file);
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let kind = match file.read_u8()? {
+                0 => JointKind::Spring6Dof,
+                1 => JointKind::SixDof,
+                2 => JointKind::P2p,
+                3 => JointKind::ConeTwist,
+                4 => JointKind::Slider,
+                5 => JointKind::Hinge,
+                _ => return Err(Pmx::invalid_value(file, "joint type")),
+            };
+            let rigidbody_a = Pmx::read_int(file, rigidbody_index_size)?;
+            let rigidbody_b = Pmx::read_int(file, rigidbody_index_size)?;
+            let pos = read_float3(file)?;
+            let rot = read_float3(file)?;
+            let pos_min = read_float3(file)?;
+            let pos_max = read_float3(file)?;
+            let rot_min = read_float3(file)?;
+            let rot_max = read_float3(file)?;
+            let pos_spring = read_float3(file)?;
+            let rot_spring = read_float3(file)?;
             vct.push(Joint {
                 name,
                 name_en,
-                category,
+                kind,
                 rigidbody_a,
                 rigidbody_b,
                 pos,
@@ -751,37 +1152,143 @@ impl Pmx {
                 uuid: Uuid::new_v4(),
             });
         }
-        vct
+        Ok(vct)
     }
 
-    fn read_rigidbodys(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> Vec<Rigidbody> {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_softbodys(
+        file: &mut Cursor<Vec<u8>>,
+        utf8: bool,
+        vertex_index_size: u8,
+        material_index_size: u8,
+        rigidbody_index_size: u8
+    ) -> PmxResult<Vec<Softbody>> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let bone = Pmx::read_int(file, bone_index_size);
-            let group = file.read_u8().unwrap();
-            let collision_group = file.read_u16::<LE>().unwrap();
-            let shape = match file.read_u8().unwrap() {
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let shape = match file.read_u8()? {
+                0 => SoftbodyShape::TriMesh,
+                1 => SoftbodyShape::Rope,
+                _ => return Err(Pmx::invalid_value(file, "softbody shape")),
+            };
+            let mat = Pmx::read_int(file, material_index_size)?;
+            let group = file.read_u8()?;
+            let collision_group = file.read_u16::<LE>()?;
+            let flags = SoftbodyFlags::from_bits_retain(file.read_u8()?);
+            let b_link_distance = file.read_i32::<LE>()?;
+            let cluster_count = file.read_i32::<LE>()?;
+            let mass = file.read_f32::<LE>()?;
+            let collision_margin = file.read_f32::<LE>()?;
+            let aero_model = match file.read_i32::<LE>()? {
+                0 => AeroModel::VPoint,
+                1 => AeroModel::VTwoSided,
+                2 => AeroModel::VOneSided,
+                3 => AeroModel::FTwoSided,
+                4 => AeroModel::FOneSided,
+                _ => return Err(Pmx::invalid_value(file, "softbody aero model")),
+            };
+            let mut config = [0f32; 18];
+            file.read_f32_into::<LE>(&mut config)?;
+            let mut iteration = [0i32; 4];
+            file.read_i32_into::<LE>(&mut iteration)?;
+            let mut material = [0f32; 3];
+            file.read_f32_into::<LE>(&mut material)?;
+            let anchor_count = file.read_i32::<LE>()?;
+            let mut anchors = Vec::new();
+            for _ in 0..anchor_count {
+                let rigidbody = Pmx::read_int(file, rigidbody_index_size)?;
+                let vertex = Pmx::read_uint(file, vertex_index_size)?;
+                let near_mode = file.read_u8()? == 1;
+                anchors.push(SoftbodyAnchor {
+                    rigidbody,
+                    vertex,
+                    near_mode,
+                });
+            }
+            let pin_count = file.read_i32::<LE>()?;
+            let mut pin_verts = Vec::new();
+            for _ in 0..pin_count {
+                pin_verts.push(Pmx::read_uint(file, vertex_index_size)?);
+            }
+            let [vcf, dp, dg, lf, pr, vc, df, mt, chr, khr, shr, ahr,
+                srhr_cl, skhr_cl, sshr_cl, sr_splt_cl, sk_splt_cl, ss_splt_cl] = config;
+            let [v_it, p_it, d_it, c_it] = iteration;
+            let [lst, ast, vst] = material;
+            vct.push(Softbody {
+                name,
+                name_en,
+                shape,
+                mat,
+                group,
+                collision_group,
+                flags,
+                b_link_distance,
+                cluster_count,
+                mass,
+                collision_margin,
+                aero_model,
+                vcf,
+                dp,
+                dg,
+                lf,
+                pr,
+                vc,
+                df,
+                mt,
+                chr,
+                khr,
+                shr,
+                ahr,
+                srhr_cl,
+                skhr_cl,
+                sshr_cl,
+                sr_splt_cl,
+                sk_splt_cl,
+                ss_splt_cl,
+                v_it,
+                p_it,
+                d_it,
+                c_it,
+                lst,
+                ast,
+                vst,
+                anchors,
+                pin_verts,
+                uuid: Uuid::new_v4(),
+            });
+        }
+        Ok(vct)
+    }
+
+    fn read_rigidbodys(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8) -> PmxResult<Vec<Rigidbody>> {
+        let len = Pmx::read_count(file)?;
+        let mut vct = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let bone = Pmx::read_int(file, bone_index_size)?;
+            let group = file.read_u8()?;
+            let collision_group = file.read_u16::<LE>()?;
+            let shape = match file.read_u8()? {
                 0 => RigidbodyShape::Shpere,
                 1 => RigidbodyShape::Box,
                 2 => RigidbodyShape::Capsule,
-                _ => unreachable!(),
+                _ => return Err(Pmx::invalid_value(file, "rigidbody shape")),
             };
-            let size = read_float3(file);
-            let pos = read_float3(file);
-            let rot = read_float3(file);
-            let mass = file.read_f32::<LE>().unwrap();
-            let linear_damping = file.read_f32::<LE>().unwrap();
-            let angular_damping = file.read_f32::<LE>().unwrap();
-            let restitution = file.read_f32::<LE>().unwrap();
-            let friction = file.read_f32::<LE>().unwrap();
-            let mode = match file.read_u8().unwrap() {
+            let size = read_float3(file)?;
+            let pos = read_float3(file)?;
+            let rot = read_float3(file)?;
+            let mass = file.read_f32::<LE>()?;
+            let linear_damping = file.read_f32::<LE>()?;
+            let angular_damping = file.read_f32::<LE>()?;
+            let restitution = file.read_f32::<LE>()?;
+            let friction = file.read_f32::<LE>()?;
+            let mode = match file.read_u8()? {
                 0 => RigidbodyMode::Kinematics,
                 1 => RigidbodyMode::Dynamics,
                 2 => RigidbodyMode::DynamicsPassRotation,
-                _ => unreachable!(),
+                _ => return Err(Pmx::invalid_value(file, "rigidbody mode")),
             };
             vct.push(Rigidbody {
                 name,
@@ -802,34 +1309,34 @@ impl Pmx {
                 uuid: Uuid::new_v4(),
             });
         }
-        vct
+        Ok(vct)
     }
 
-    fn read_display_frames(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8, morph_index_size: u8) -> Vec<DisplayFrame> {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_display_frames(file: &mut Cursor<Vec<u8>>, utf8: bool, bone_index_size: u8, morph_index_size: u8) -> PmxResult<Vec<DisplayFrame>> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let deletable = file.read_i8().unwrap() == 1;
-            let frame_count = file.read_i32::<LE>().unwrap();
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let special = file.read_i8()? == 1;
+            let frame_count = file.read_i32::<LE>()?;
             let mut morph_items = Vec::new();
             for __ in 0..frame_count {
-                let is_morph_frame = file.read_u8().unwrap() == 1;
+                let is_morph_frame = file.read_u8()? == 1;
                 morph_items.push(if is_morph_frame {
-                    DisplayFrameIndex::Morph(Pmx::read_int(file, morph_index_size) as u32)
+                    DisplayFrameIndex::Morph(Pmx::read_int(file, morph_index_size)? as u32)
                 } else {
-                    DisplayFrameIndex::Bone(Pmx::read_int(file, bone_index_size) as u32)
+                    DisplayFrameIndex::Bone(Pmx::read_int(file, bone_index_size)? as u32)
                 });
             }
             vct.push(DisplayFrame {
                 name,
                 name_en,
-                deletable,
+                special,
                 morph_items,
             });
         }
-        vct
+        Ok(vct)
     }
 
     fn read_morphs(
@@ -840,20 +1347,20 @@ impl Pmx {
         bone_index_size: u8,
         morph_index_size: u8,
         rigidbody_index_size: u8
-    ) -> Vec<MorphInfo> {
-        let len = file.read_u32::<LE>().unwrap();
+    ) -> PmxResult<Vec<MorphInfo>> {
+        let len = Pmx::read_count(file)?;
         let mut vct = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            let name = Pmx::read_string(file, utf8);
-            let name_en = Pmx::read_string(file, utf8);
-            let panel = file.read_i8().unwrap();
-            let category = file.read_i8().unwrap();
-            let count = file.read_i32::<LE>().unwrap();
+            let name = Pmx::read_string(file, utf8)?;
+            let name_en = Pmx::read_string(file, utf8)?;
+            let panel = file.read_i8()?;
+            let category = file.read_i8()?;
+            let count = file.read_i32::<LE>()?;
             if category == 0 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, morph_index_size) as u32;
-                    let affect = file.read_f32::<LE>().unwrap();
+                    let index = Pmx::read_int(file, morph_index_size)? as u32;
+                    let affect = file.read_f32::<LE>()?;
                     v.push(MorphGroupItem {
                         index,
                         affect,
@@ -869,8 +1376,8 @@ impl Pmx {
             } else if category == 1 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_uint(file, vertex_index_size) as u32;
-                    let trans = read_float3(file);
+                    let index = Pmx::read_uint(file, vertex_index_size)? as u32;
+                    let trans = read_float3(file)?;
                     v.push(MorphVertexItem {
                         index,
                         trans,
@@ -886,9 +1393,9 @@ impl Pmx {
             } else if category == 2 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, bone_index_size) as u32;
-                    let trans = read_float3(file);
-                    let rot = read_quat(file);
+                    let index = Pmx::read_int(file, bone_index_size)? as u32;
+                    let trans = read_float3(file)?;
+                    let rot = read_quat(file)?;
                     v.push(MorphBoneItem {
                         index,
                         trans,
@@ -902,11 +1409,12 @@ impl Pmx {
                     category,
                     data: Morph::MorphBone(v),
                 });
-            } else if category == 3 {
+            } else if (3..=7).contains(&category) {
+                // 3 is the base uv, 4..=7 are the appendix uvs
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_uint(file, vertex_index_size) as u32;
-                    let trans = read_float4(file);
+                    let index = Pmx::read_uint(file, vertex_index_size)? as u32;
+                    let trans = read_float4(file)?;
                     v.push(MorphUvItem {
                         index,
                         trans,
@@ -919,26 +1427,24 @@ impl Pmx {
                     category,
                     data: Morph::MorphUv(v),
                 });
-            } else if category == 4 || category == 5 || category == 6 || category == 7 {
-                unreachable!()
             } else if category == 8 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, material_index_size) as u32;
-                    let blend_mode = match file.read_u8().unwrap() {
+                    let index = Pmx::read_int(file, material_index_size)? as u32;
+                    let blend_mode = match file.read_u8()? {
                         0 => BlendMode::Mul, 
                         1 => BlendMode::Add, 
-                        _ => unreachable!(),
+                        _ => return Err(Pmx::invalid_value(file, "material morph blend mode")),
                     };
-                    let diffuse = read_float4(file);
-                    let specular = read_float3(file);
-                    let specularity = file.read_f32::<LE>().unwrap();
-                    let ambient = read_float3(file);
-                    let edge_color = read_float4(file);
-                    let edge_size = file.read_f32::<LE>().unwrap();
-                    let texture_tint = read_float4(file);
-                    let environment_tint = read_float4(file);
-                    let toon_tint = read_float4(file);
+                    let diffuse = read_float4(file)?;
+                    let specular = read_float3(file)?;
+                    let specularity = file.read_f32::<LE>()?;
+                    let ambient = read_float3(file)?;
+                    let edge_color = read_float4(file)?;
+                    let edge_size = file.read_f32::<LE>()?;
+                    let texture_tint = read_float4(file)?;
+                    let environment_tint = read_float4(file)?;
+                    let toon_tint = read_float4(file)?;
                     v.push(MorphMatItem {
                         index,
                         blend_mode,
@@ -963,8 +1469,8 @@ impl Pmx {
             } else if category == 9 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, morph_index_size) as u32;
-                    let affect = file.read_f32::<LE>().unwrap();
+                    let index = Pmx::read_int(file, morph_index_size)? as u32;
+                    let affect = file.read_f32::<LE>()?;
                     v.push(MorphFlipItem {
                         index,
                         affect,
@@ -980,10 +1486,10 @@ impl Pmx {
             } else if category == 10 {
                 let mut v = Vec::new();
                 for __ in 0..count {
-                    let index = Pmx::read_int(file, rigidbody_index_size) as u32;
-                    let local = file.read_u8().unwrap() == 1;
-                    let trans_speed = read_float3(file);
-                    let rot_torque = read_float3(file);
+                    let index = Pmx::read_int(file, rigidbody_index_size)? as u32;
+                    let local = file.read_u8()? == 1;
+                    let trans_speed = read_float3(file)?;
+                    let rot_torque = read_float3(file)?;
                     v.push(MorphRigidbodyItem {
                         index,
                         local,
@@ -998,71 +1504,80 @@ impl Pmx {
                     category,
                     data: Morph::MorphRigidbody(v),
                 });
+            } else {
+                return Err(Pmx::invalid_value(file, "morph category"));
             }
         }
-        vct
+        Ok(vct)
     }
 
-    fn read_faces(file: &mut Cursor<Vec<u8>>, vertex_index_size: u8) -> Vec<[u32; 3]> {
-        let len = file.read_u32::<LE>().unwrap() / 3;
+    fn read_faces(file: &mut Cursor<Vec<u8>>, vertex_index_size: u8, vert_count: usize) -> PmxResult<Vec<[u32; 3]>> {
+        let len = Pmx::read_count(file)? / 3;
         let mut vct = Vec::with_capacity(len as usize);
+        let read_index = |file: &mut Cursor<Vec<u8>>| -> PmxResult<u32> {
+            let i = Pmx::read_uint(file, vertex_index_size)?;
+            if i < 0 || i as usize >= vert_count {
+                return Err(Pmx::invalid_value(file, "vertex index"));
+            }
+            Ok(i as u32)
+        };
         for _ in 0..len {
-            let a = Pmx::read_uint(file, vertex_index_size) as u32;
-            let b = Pmx::read_uint(file, vertex_index_size) as u32;
-            let c = Pmx::read_uint(file, vertex_index_size) as u32;
+            let a = read_index(file)?;
+            let b = read_index(file)?;
+            let c = read_index(file)?;
             vct.push([a, b, c])
         }
-        vct
+        Ok(vct)
     }
-    fn read_verts(file: &mut Cursor<Vec<u8>>, bone_index_size: u8, appendix_uv: u8) -> (Vec<Vertex>, Vec<Vec<Vec4>>) {
-        let len = file.read_u32::<LE>().unwrap();
+    fn read_verts(file: &mut Cursor<Vec<u8>>, bone_index_size: u8, appendix_uv: u8) -> PmxResult<(Vec<Vertex>, Vec<Vec<Vec4>>)> {
+        let len = Pmx::read_count(file)?;
         let mut appendix_uvs: Vec<Vec<Vec4>> = vec![Vec::with_capacity(len as usize); appendix_uv as usize];
         let mut vct = Vec::with_capacity(len as usize);
         for i in 0..len {
-            let pos = read_float3(file);
-            let nrm = read_float3(file);
-            let uv = read_float2(file);
+            let pos = read_float3(file)?;
+            let nrm = read_float3(file)?;
+            let uv = read_float2(file)?;
             for j in 0..appendix_uv {
-                appendix_uvs[j as usize].push(read_float4(file));
+                appendix_uvs[j as usize].push(read_float4(file)?);
             }
 
-            let weight_type = file.read_u8().unwrap();
+            let weight_type = file.read_u8()?;
             let weight = if weight_type == 0 {
-                let a = Pmx::read_int(file, bone_index_size);
+                let a = Pmx::read_int(file, bone_index_size)?;
                 VertexWeight::One(a)
             } else if weight_type == 1 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let weight = file.read_f32::<LE>().unwrap();
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let weight = file.read_f32::<LE>()?;
                 VertexWeight::Two(a, b, weight)
             } else if weight_type == 2 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let c = Pmx::read_int(file, bone_index_size);
-                let d = Pmx::read_int(file, bone_index_size);
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let c = Pmx::read_int(file, bone_index_size)?;
+                let d = Pmx::read_int(file, bone_index_size)?;
                 let index = ivec4(a, b, c, d);
-                let weight = read_float4(file);
+                let weight = read_float4(file)?;
                 VertexWeight::Four(index, weight)
             } else if weight_type == 3 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let weight = file.read_f32::<LE>().unwrap();
-                let c = read_float3(file);
-                let r0 = read_float3(file);
-                let r1 = read_float3(file);
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let weight = file.read_f32::<LE>()?;
+                let c = read_float3(file)?;
+                let r0 = read_float3(file)?;
+                let r1 = read_float3(file)?;
                 VertexWeight::Sphere(a, b, weight, c, r0, r1)
             } else if weight_type == 4 {
-                let a = Pmx::read_int(file, bone_index_size);
-                let b = Pmx::read_int(file, bone_index_size);
-                let c = Pmx::read_int(file, bone_index_size);
-                let d = Pmx::read_int(file, bone_index_size);
+                let a = Pmx::read_int(file, bone_index_size)?;
+                let b = Pmx::read_int(file, bone_index_size)?;
+                let c = Pmx::read_int(file, bone_index_size)?;
+                let d = Pmx::read_int(file, bone_index_size)?;
                 let index = ivec4(a, b, c, d);
-                let weight = read_float4(file);
+                let weight = read_float4(file)?;
                 VertexWeight::Quat(index, weight)
             } else {
-                unreachable!()
+                return Err(Pmx::invalid_value(file, "vertex weight type"));
             };
-            let edge_scale = file.read_f32::<LE>().unwrap();
+            let edge_scale = file.read_f32::<LE>()?;
             vct.push(Vertex {
                 pos,
                 nrm,
@@ -1071,26 +1586,27 @@ impl Pmx {
                 edge_scale,
             })
         }
-        (vct, appendix_uvs)
+        Ok((vct, appendix_uvs))
     }
     
 
-    fn read_int(file: &mut Cursor<Vec<u8>>, index_size: u8) -> i32 {
-        match index_size {
-            1 => file.read_i8().unwrap() as i32,
-            2 => file.read_i16::<LE>().unwrap() as i32,
-            4 => file.read_i32::<LE>().unwrap(),
+    // index sizes are validated in read_header, so only 1, 2 and 4 reach here
+    fn read_int(file: &mut Cursor<Vec<u8>>, index_size: u8) -> PmxResult<i32> {
+        Ok(match index_size {
+            1 => file.read_i8()? as i32,
+            2 => file.read_i16::<LE>()? as i32,
+            4 => file.read_i32::<LE>()?,
             _ => unreachable!(),
-        }
+        })
     }
 
-    fn read_uint(file: &mut Cursor<Vec<u8>>, index_size: u8) -> i32 {
-        match index_size {
-            1 => file.read_u8().unwrap() as i32,
-            2 => file.read_u16::<LE>().unwrap() as i32,
-            4 => file.read_i32::<LE>().unwrap(),
+    fn read_uint(file: &mut Cursor<Vec<u8>>, index_size: u8) -> PmxResult<i32> {
+        Ok(match index_size {
+            1 => file.read_u8()? as i32,
+            2 => file.read_u16::<LE>()? as i32,
+            4 => file.read_i32::<LE>()?,
             _ => unreachable!(),
-        }
+        })
     }
 
     pub fn scale(&mut self, scale: f32) {
@@ -1184,22 +1700,20 @@ impl Pmx {
         for v in &mut self.verts {
             v.pos.z *= -1.0;
             v.nrm.z *= -1.0;
+            if let VertexWeight::Sphere(_, _, _, ref mut c, ref mut r0, ref mut r1) = v.weight {
+                c.z *= -1.0;
+                r0.z *= -1.0;
+                r1.z *= -1.0;
+            }
         }
         for f in &mut self.faces {
             f.swap(1, 2);
         }
+        for m in &mut self.morphs {
+            m.right_hand();
+        }
         for b in &mut self.bones {
-            b.pos.z *= -1.0;
-            if let BoneTailPos::Pos(ref mut pos) = b.bone_tail_pos {
-                pos.z *= -1.0;
-            }
-            if let Some(ref mut axis) = b.fixed_axis {
-                axis.z *= -1.0;
-            }
-            if let Some((ref mut x, ref mut z)) = b.local_axis {
-                x.z *= -1.0;
-                z.z *= -1.0;
-            }
+            b.right_hand();
         }
         for r in &mut self.rigidbodys {
             r.pos.z *= -1.0;
@@ -1251,3 +1765,52 @@ impl Pmx {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    fn read(content: Vec<u8>) -> PmxResult<Pmx> {
+        Pmx::read(content, "")
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let mut m = mat_model();
+        m.name = "model".to_string();
+        let content = m.write();
+        for len in 0..content.len() {
+            assert!(read(content[..len].to_vec()).is_err(), "read {} of {} bytes", len, content.len());
+        }
+        // a name cut off in the middle is a truncated section, not a bad encoding
+        let utf16: Vec<u8> = "model".encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+        let name_at = content.windows(utf16.len()).position(|w| w == utf16.as_slice())
+            .or_else(|| content.windows(5).position(|w| w == b"model"))
+            .unwrap();
+        match read(content[..name_at + 2].to_vec()) {
+            Err(PmxError::Truncated { section: "model info", .. }) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn material_faces_beyond_the_face_section_are_an_error() {
+        let mut m = mat_model();
+        m.mats[2].associated_face_count = 2;
+        match read(m.write()) {
+            Err(PmxError::InvalidValue { section: "material", what: "face count", .. }) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn face_index_beyond_the_vertices_is_an_error() {
+        let mut m = mat_model();
+        m.faces[1][2] = m.verts.len() as u32;
+        match read(m.write()) {
+            Err(PmxError::InvalidValue { section: "face", what: "vertex index", .. }) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeSet;
+
+use super::pmx::*;
+use super::pmx_remap::*;
+
+/// Maps every bone index of a weight with `f`
+pub(super) fn map_weight_bones(weight: &mut VertexWeight, mut f: impl FnMut(i32) -> i32) {
+    match weight {
+        VertexWeight::One(i0) => *i0 = f(*i0),
+        VertexWeight::Two(i0, i1, _) | VertexWeight::Sphere(i0, i1, ..) => {
+            *i0 = f(*i0);
+            *i1 = f(*i1);
+        }
+        VertexWeight::Four(i, _) | VertexWeight::Quat(i, _) => {
+            for c in i.as_mut() {
+                *c = f(*c);
+            }
+        }
+    }
+}
+
+impl Pmx {
+    /// Rebuilds the bone list with bone `i` moved to `map[i]`, or removed when it is `None`,
+    /// and remaps every reference to a bone. `map` must send the kept bones onto `0..kept`.
+    /// References to removed bones are dropped where they are list entries and become -1 otherwise,
+    /// bones inheriting from a removed bone stop inheriting,
+    /// IKs lose removed links and are dropped with their target or effector.
+    fn remap_bones(&mut self, map: &[Option<usize>]) {
+        let new = |i: usize| map.get(i).copied().flatten();
+        let new_i32 = |i: i32| usize::try_from(i).ok().and_then(new).map_or(-1, |i| i as i32);
+        let new_u32 = |i: u32| new(i as usize).map(|i| i as u32);
+
+        self.bones = remap_vec(std::mem::take(&mut self.bones), map);
+        for bone in &mut self.bones {
+            bone.parent_index = bone.parent_index.and_then(new);
+            if let BoneTailPos::Bone(i) = &mut bone.bone_tail_pos {
+                *i = new_i32(*i);
+            }
+            if let Some((p, _)) = &mut bone.inherit {
+                *p = new_i32(*p);
+                if *p < 0 {
+                    bone.inherit = None;
+                    bone.bone_flags.remove(BoneFlags::INHERIT_ROTATION | BoneFlags::INHERIT_TRANSLATION);
+                }
+            }
+        }
+
+        for v in &mut self.verts {
+            map_weight_bones(&mut v.weight, new_i32);
+        }
+
+        self.iks.retain_mut(|ik| {
+            ik.bone = new_i32(ik.bone);
+            ik.effector = new_i32(ik.effector);
+            ik.ik_joints.retain_mut(|j| {
+                j.bone = new_i32(j.bone);
+                j.bone >= 0
+            });
+            ik.bone >= 0 && ik.effector >= 0
+        });
+        // the writer stores IKs with their target bone, so the flag has to match the list
+        let ik_bones: BTreeSet<i32> = self.iks.iter().map(|ik| ik.bone).collect();
+        for (i, bone) in self.bones.iter_mut().enumerate() {
+            bone.bone_flags.set(BoneFlags::IK, ik_bones.contains(&(i as i32)));
+        }
+
+        for morph in &mut self.morphs {
+            if let Morph::MorphBone(items) = &mut morph.data {
+                items.retain_mut(|item| new_u32(item.index).map(|i| item.index = i).is_some());
+            }
+        }
+        for rb in &mut self.rigidbodys {
+            rb.bone = new_i32(rb.bone);
+        }
+        for frame in &mut self.display_frames {
+            frame.morph_items.retain_mut(|item| match item {
+                DisplayFrameIndex::Bone(i) => new_u32(*i).map(|n| *i = n).is_some(),
+                DisplayFrameIndex::Morph(_) => true,
+            });
+        }
+    }
+
+    /// Deletes bones. Vertices weighted to a deleted bone and children of a deleted bone move to
+    /// the closest ancestor that is kept, or to the first kept bone when there is none.
+    pub fn delete_bones(&mut self, bones: &BTreeSet<usize>) {
+        let n = self.bones.len();
+        let first_kept = (0..n).find(|i| !bones.contains(i));
+        let kept_ancestor = |mut i: usize| {
+            let mut visited = BTreeSet::new();
+            while bones.contains(&i) && visited.insert(i) {
+                match self.bones[i].parent_index.filter(|p| *p < n) {
+                    Some(p) => i = p,
+                    None => return None,
+                }
+            }
+            (!bones.contains(&i)).then_some(i)
+        };
+        let replacement: Vec<Option<usize>> = (0..n).map(kept_ancestor).collect();
+
+        for v in &mut self.verts {
+            map_weight_bones(&mut v.weight, |i| match usize::try_from(i).ok().filter(|i| bones.contains(i)) {
+                Some(i) => replacement[i].or(first_kept).map_or(-1, |r| r as i32),
+                None => i,
+            });
+        }
+        for bone in &mut self.bones {
+            if let Some(p) = bone.parent_index.filter(|p| bones.contains(p)) {
+                bone.parent_index = replacement[p];
+            }
+        }
+
+        self.remap_bones(&delete_map(n, bones));
+    }
+
+    /// Inserts `bone` at `index`, the bones from `index` on move back by one.
+    /// The references of `bone` use the indices from before the insertion.
+    pub fn insert_bone(&mut self, index: usize, bone: Bone) {
+        let map = insert_map(self.bones.len(), index.min(self.bones.len()));
+        self.bones.push(bone);
+        self.remap_bones(&map);
+    }
+
+    /// Inserts a copy of bone `index` right after it and returns the index of the copy.
+    /// The copy is not an IK target, IK settings belong to a single bone.
+    pub fn duplicate_bone(&mut self, index: usize) -> usize {
+        let mut bone = self.bones[index].clone();
+        bone.name = format!("{}+", bone.name);
+        bone.name_en = format!("{}+", bone.name_en);
+        bone.bone_flags.remove(BoneFlags::IK);
+        self.insert_bone(index + 1, bone);
+        index + 1
+    }
+
+    /// Reorders the bones so that bone `order[i]` becomes bone `i`, `order` has to be a permutation
+    pub fn reorder_bones(&mut self, order: &[usize]) {
+        self.remap_bones(&order_map(self.bones.len(), order));
+    }
+
+    /// Moves bone `from` to index `to`, shifting the bones in between
+    pub fn move_bone(&mut self, from: usize, to: usize) {
+        self.reorder_bones(&move_order(self.bones.len(), from, to));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::*;
+
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    /// Every bone reference is in range, IK flags match the IK list and the model survives a write and read
+    fn assert_valid(m: &Pmx) {
+        let n = m.bones.len() as i32;
+        let valid = |i: i32| (0..n).contains(&i);
+        for v in &m.verts {
+            let mut bones = Vec::new();
+            map_weight_bones(&mut v.weight.clone(), |i| {
+                bones.push(i);
+                i
+            });
+            assert!(bones.iter().all(|i| valid(*i)), "vertex weighted to a missing bone");
+        }
+        for (i, b) in m.bones.iter().enumerate() {
+            assert!(b.parent_index.map_or(true, |p| valid(p as i32)), "parent out of range");
+            if let BoneTailPos::Bone(t) = b.bone_tail_pos {
+                assert!(t == -1 || valid(t));
+            }
+            if let Some((p, _)) = b.inherit {
+                assert!(p == -1 || valid(p));
+            }
+            let has_ik = m.iks.iter().any(|ik| ik.bone == i as i32);
+            assert_eq!(b.bone_flags.contains(BoneFlags::IK), has_ik, "IK flag of {}", b.name);
+        }
+        for ik in &m.iks {
+            assert!(valid(ik.bone) && valid(ik.effector));
+            assert!(ik.ik_joints.iter().all(|j| valid(j.bone)));
+        }
+        for morph in &m.morphs {
+            if let Morph::MorphBone(items) = &morph.data {
+                assert!(items.iter().all(|item| valid(item.index as i32)));
+            }
+        }
+        for rb in &m.rigidbodys {
+            assert!(rb.bone == -1 || valid(rb.bone));
+        }
+        for frame in &m.display_frames {
+            for item in &frame.morph_items {
+                if let DisplayFrameIndex::Bone(i) = item {
+                    assert!(valid(*i as i32));
+                }
+            }
+        }
+        let read = reread(m);
+        assert_eq!(read.bones.len(), m.bones.len());
+        assert_eq!(read.iks.len(), m.iks.len());
+    }
+
+    #[test]
+    fn sample_is_valid() {
+        assert_valid(&skeleton_model());
+    }
+
+    #[test]
+    fn delete_moves_weights_and_children_to_parent() {
+        let mut m = skeleton_model();
+        m.delete_bones(&BTreeSet::from([3]));
+        assert_valid(&m);
+        assert_eq!(bone_names(&m), ["root", "center", "arm", "hand", "hand IK"]);
+        // elbow weights go to the arm, the hand hangs from the arm
+        assert!(matches!(m.verts[1].weight, VertexWeight::Two(2, 3, _)));
+        assert_eq!(m.bones[3].parent_index, Some(2));
+        assert!(matches!(m.bones[2].bone_tail_pos, BoneTailPos::Bone(-1)));
+        assert_eq!(m.bones[3].inherit, None);
+        assert!(!m.bones[3].bone_flags.intersects(BoneFlags::INHERIT_ROTATION | BoneFlags::INHERIT_TRANSLATION));
+        assert_eq!(m.iks[0].ik_joints.len(), 1);
+        assert_eq!(m.iks[0].ik_joints[0].bone, 2);
+        let Morph::MorphBone(items) = &m.morphs[0].data else { unreachable!() };
+        assert_eq!(items.iter().map(|i| i.index).collect::<Vec<_>>(), [3]);
+        assert_eq!(m.display_frames[2].morph_items.len(), 2);
+    }
+
+    #[test]
+    fn delete_effector_drops_ik() {
+        let mut m = skeleton_model();
+        m.delete_bones(&BTreeSet::from([2, 4]));
+        assert_valid(&m);
+        assert!(m.iks.is_empty());
+        assert_eq!(m.rigidbodys[0].bone, -1);
+        // arm weights go to the center
+        assert!(matches!(m.verts[0].weight, VertexWeight::One(1)));
+    }
+
+    #[test]
+    fn delete_roots_uses_first_kept_bone() {
+        let mut m = skeleton_model();
+        m.delete_bones(&BTreeSet::from([0, 1, 2]));
+        assert_valid(&m);
+        assert_eq!(bone_names(&m), ["elbow", "hand", "hand IK"]);
+        assert!(matches!(m.verts[0].weight, VertexWeight::One(0)));
+        assert_eq!(m.bones[0].parent_index, None);
+    }
+
+    #[test]
+    fn insert_shifts_references() {
+        let mut m = skeleton_model();
+        m.insert_bone(2, bone("shoulder", Some(1), vec3(0.5, 1.0, 0.0)));
+        assert_valid(&m);
+        assert_eq!(bone_names(&m), ["root", "center", "shoulder", "arm", "elbow", "hand", "hand IK"]);
+        assert_eq!(m.bones[3].parent_index, Some(1));
+        assert_eq!(m.bones[4].parent_index, Some(3));
+        assert_eq!(m.iks[0].bone, 6);
+        assert_eq!(m.iks[0].effector, 5);
+        assert_eq!(m.rigidbodys[0].bone, 3);
+        assert!(matches!(m.verts[2].weight, VertexWeight::Four(i, _) if i == ivec4(1, 3, 4, 5)));
+    }
+
+    #[test]
+    fn duplicate_is_not_an_ik_target() {
+        let mut m = skeleton_model();
+        let copy = m.duplicate_bone(5);
+        assert_valid(&m);
+        assert_eq!(copy, 6);
+        assert_eq!(m.bones[6].name, "hand IK+");
+        assert_eq!(m.iks.len(), 1);
+        assert_eq!(m.iks[0].bone, 5);
+    }
+
+    #[test]
+    fn reorder_keeps_bones_attached() {
+        let mut m = skeleton_model();
+        m.move_bone(5, 0);
+        assert_valid(&m);
+        assert_eq!(bone_names(&m), ["hand IK", "root", "center", "arm", "elbow", "hand"]);
+        assert_eq!(m.bones[0].parent_index, Some(1));
+        assert_eq!(m.iks[0].bone, 0);
+        assert_eq!(m.iks[0].effector, 5);
+        assert!(matches!(m.bones[3].bone_tail_pos, BoneTailPos::Bone(4)));
+
+        let order: Vec<usize> = (0..m.bones.len()).rev().collect();
+        m.reorder_bones(&order);
+        assert_valid(&m);
+        assert_eq!(bone_names(&m), ["hand", "elbow", "arm", "center", "root", "hand IK"]);
+    }
+}
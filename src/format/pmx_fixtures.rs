@@ -0,0 +1,212 @@
+#![allow(dead_code)]
+
+//! Small hand-built models shared by the PMX tests
+
+use glam::*;
+
+use super::pmx::*;
+
+pub fn bone(name: &str, parent: Option<usize>, pos: Vec3) -> Bone {
+    Bone {
+        name: name.to_string(),
+        name_en: name.to_string(),
+        pos,
+        parent_index: parent,
+        bone_flags: BoneFlags::ROTATABLE | BoneFlags::VISIBLE | BoneFlags::ENABLED,
+        ..Default::default()
+    }
+}
+
+/// A vertex at `pos` weighted to bone 0
+pub fn vertex(pos: Vec3) -> Vertex {
+    Vertex { pos, nrm: Vec3::Y, uv: Vec2::ZERO, weight: VertexWeight::One(0), edge_scale: 1.0 }
+}
+
+pub fn mat(name: &str, face_count: u32) -> Mat {
+    Mat { name: name.to_string(), name_en: name.to_string(), associated_face_count: face_count, ..Default::default() }
+}
+
+pub fn morph(name: &str, data: Morph) -> MorphInfo {
+    // the category is the morph kind as stored in the file
+    let category = match data {
+        Morph::MorphGroup(_) => 0,
+        Morph::MorphVertex(_) => 1,
+        Morph::MorphBone(_) => 2,
+        Morph::MorphUv(_) => 3,
+        Morph::MorphMat(_) => 8,
+        Morph::MorphFlip(_) => 9,
+        Morph::MorphRigidbody(_) => 10,
+    };
+    MorphInfo { name: name.to_string(), name_en: name.to_string(), panel: 4, category, data }
+}
+
+pub fn group(items: &[u32]) -> Morph {
+    Morph::MorphGroup(items.iter().map(|i| MorphGroupItem { index: *i, affect: 1.0 }).collect())
+}
+
+pub fn mat_item(index: u32) -> MorphMatItem {
+    MorphMatItem {
+        index,
+        blend_mode: BlendMode::Mul,
+        diffuse: Vec4::ONE,
+        specular: Vec3::ONE,
+        specularity: 1.0,
+        ambient: Vec3::ONE,
+        edge_color: Vec4::ONE,
+        edge_size: 1.0,
+        texture_tint: Vec4::ONE,
+        environment_tint: Vec4::ONE,
+        toon_tint: Vec4::ONE,
+    }
+}
+
+pub fn rigidbody(name: &str, bone: i32) -> Rigidbody {
+    Rigidbody {
+        name: name.to_string(),
+        name_en: name.to_string(),
+        bone,
+        group: 0,
+        collision_group: 0xffff,
+        shape: RigidbodyShape::Capsule,
+        size: Vec3::ONE,
+        pos: Vec3::ZERO,
+        rot: Vec3::ZERO,
+        mass: 1.0,
+        linear_damping: 0.5,
+        angular_damping: 0.5,
+        restitution: 0.0,
+        friction: 0.5,
+        mode: RigidbodyMode::Kinematics,
+        uuid: uuid::Uuid::new_v4(),
+    }
+}
+
+pub fn display_frame(name: &str, items: Vec<DisplayFrameIndex>) -> DisplayFrame {
+    DisplayFrame { name: name.to_string(), name_en: name.to_string(), special: true, morph_items: items }
+}
+
+/// Writes and reads the model back, which fails when an index is out of range or a field does
+/// not match the flags
+pub fn reread(m: &Pmx) -> Pmx {
+    Pmx::read(m.write(), "").unwrap()
+}
+
+/// Indices of the items of a morph
+pub fn morph_indices(morph: &MorphInfo) -> Vec<u32> {
+    match &morph.data {
+        Morph::MorphGroup(items) => items.iter().map(|i| i.index).collect(),
+        Morph::MorphFlip(items) => items.iter().map(|i| i.index).collect(),
+        Morph::MorphVertex(items) => items.iter().map(|i| i.index).collect(),
+        Morph::MorphBone(items) => items.iter().map(|i| i.index).collect(),
+        Morph::MorphUv(items) => items.iter().map(|i| i.index).collect(),
+        Morph::MorphRigidbody(items) => items.iter().map(|i| i.index).collect(),
+        Morph::MorphMat(items) => items.iter().map(|i| i.index).collect(),
+    }
+}
+
+/// Indices of the entries of a display frame, bones as `(false, i)` and morphs as `(true, i)`
+pub fn frame_items(frame: &DisplayFrame) -> Vec<(bool, u32)> {
+    frame.morph_items.iter()
+        .map(|item| match item {
+            DisplayFrameIndex::Bone(i) => (false, *i),
+            DisplayFrameIndex::Morph(i) => (true, *i),
+        })
+        .collect()
+}
+
+pub fn bone_names(m: &Pmx) -> Vec<&str> {
+    m.bones.iter().map(|b| b.name.as_str()).collect()
+}
+
+pub fn morph_names(m: &Pmx) -> Vec<&str> {
+    m.morphs.iter().map(|m| m.name.as_str()).collect()
+}
+
+pub fn mat_names(m: &Pmx) -> Vec<&str> {
+    m.mats.iter().map(|m| m.name.as_str()).collect()
+}
+
+/// root, center, arm, elbow, hand with an IK (5) on the elbow chain, the hand inheriting half
+/// of the elbow rotation, a bone morph, a rigidbody on the arm and a display frame (2)
+pub fn skeleton_model() -> Pmx {
+    let mut m = Pmx::new();
+    m.bones.push(bone("root", None, Vec3::ZERO));
+    m.bones.push(bone("center", Some(0), Vec3::Y));
+    m.bones.push(bone("arm", Some(1), vec3(1.0, 1.0, 0.0)));
+    m.bones.push(bone("elbow", Some(2), vec3(2.0, 1.0, 0.0)));
+    m.bones.push(bone("hand", Some(3), vec3(3.0, 1.0, 0.0)));
+    m.bones.push(bone("hand IK", Some(0), vec3(3.0, 1.0, 0.0)));
+    m.bones[2].bone_flags |= BoneFlags::INDEXED_TAIL_BONE;
+    m.bones[2].bone_tail_pos = BoneTailPos::Bone(3);
+    m.bones[4].bone_flags |= BoneFlags::INHERIT_ROTATION;
+    m.bones[4].inherit = Some((3, 0.5));
+    m.bones[5].bone_flags |= BoneFlags::IK;
+    m.iks.push(Ik {
+        bone: 5,
+        effector: 4,
+        loop_count: 40,
+        limit_angle: 1.0,
+        ik_joints: vec![IkJoint { bone: 3, limit: None }, IkJoint { bone: 2, limit: None }],
+    });
+    m.verts.push(Vertex { weight: VertexWeight::One(2), ..vertex(Vec3::ZERO) });
+    m.verts.push(Vertex { weight: VertexWeight::Two(3, 4, 0.5), ..vertex(Vec3::ZERO) });
+    m.verts.push(Vertex { weight: VertexWeight::Four(ivec4(1, 2, 3, 4), Vec4::splat(0.25)), ..vertex(Vec3::ZERO) });
+    m.faces.push([0, 1, 2]);
+    m.mats.push(mat("body", 1));
+    m.morphs.push(morph("bend", Morph::MorphBone(vec![
+        MorphBoneItem { index: 3, trans: Vec3::ZERO, rot: Quat::IDENTITY },
+        MorphBoneItem { index: 4, trans: Vec3::ZERO, rot: Quat::IDENTITY },
+    ])));
+    m.display_frames.push(display_frame(
+        "arm",
+        vec![DisplayFrameIndex::Bone(2), DisplayFrameIndex::Bone(3), DisplayFrameIndex::Morph(0)],
+    ));
+    m.rigidbodys.push(rigidbody("arm", 2));
+    m
+}
+
+/// Two triangles, each in its own material, with vertex 3 unused; a vertex morph (0), a uv morph (1),
+/// a group (2) and a flip (3) of the first two, and a display frame (2) with every morph
+pub fn morph_model() -> Pmx {
+    let mut m = Pmx::new();
+    m.bones.push(Bone::default());
+    for i in 0..7 {
+        m.verts.push(vertex(Vec3::splat(i as f32)));
+    }
+    m.appendix_uvs.push((0..7).map(|i| Vec4::splat(i as f32)).collect());
+    m.faces = vec![[0, 1, 2], [4, 5, 6]];
+    m.mats.push(mat("a", 1));
+    m.mats.push(mat("b", 1));
+    m.morphs.push(morph("v", Morph::MorphVertex(
+        [1, 3, 5].iter().map(|i| MorphVertexItem { index: *i, trans: Vec3::ONE }).collect()
+    )));
+    m.morphs.push(morph("uv", Morph::MorphUv(
+        [2, 3, 6].iter().map(|i| MorphUvItem { index: *i, trans: Vec4::ONE }).collect()
+    )));
+    m.morphs.push(morph("group", group(&[0, 1])));
+    m.morphs.push(morph("flip", Morph::MorphFlip(vec![
+        MorphFlipItem { index: 1, affect: 1.0 },
+        MorphFlipItem { index: 2, affect: 1.0 },
+    ])));
+    m.display_frames.push(DisplayFrame {
+        special: false,
+        ..display_frame("morphs", (0..4).map(DisplayFrameIndex::Morph).chain([DisplayFrameIndex::Bone(0)]).collect())
+    });
+    m
+}
+
+/// Three materials: "a" with two separate triangles, one on each half of the UV square,
+/// and "b" and "c" with one triangle each; a material morph of "a", "c" and all materials
+pub fn mat_model() -> Pmx {
+    let mut m = Pmx::new();
+    m.bones.push(Bone::default());
+    for i in 0..12 {
+        m.verts.push(Vertex { uv: vec2(if i < 3 { 0.25 } else { 0.75 }, 0.5), ..vertex(Vec3::ZERO) });
+    }
+    m.faces = vec![[0, 1, 2], [3, 4, 5], [6, 7, 8], [9, 10, 11]];
+    m.mats.push(mat("a", 2));
+    m.mats.push(mat("b", 1));
+    m.mats.push(mat("c", 1));
+    m.morphs.push(morph("m", Morph::MorphMat(vec![mat_item(0), mat_item(2), mat_item(u32::MAX)])));
+    m
+}
@@ -0,0 +1,281 @@
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use glam::*;
+
+use super::pmx::*;
+use super::pmx_remap::*;
+
+impl Pmx {
+    /// Faces of every material, split by `associated_face_count`
+    pub fn mat_faces(&self) -> Vec<Vec<[u32; 3]>> {
+        let mut start = 0;
+        self.mats.iter().map(|mat| {
+            let end = (start + mat.associated_face_count as usize).min(self.faces.len());
+            let faces = self.faces[start.min(end)..end].to_vec();
+            start = end;
+            faces
+        }).collect()
+    }
+
+    /// Replaces the faces with the faces of each material in turn and updates the face counts
+    fn set_mat_faces(&mut self, mat_faces: Vec<Vec<[u32; 3]>>) {
+        assert_eq!(mat_faces.len(), self.mats.len(), "faces for every material are needed");
+        self.faces.clear();
+        for (mat, faces) in self.mats.iter_mut().zip(mat_faces) {
+            mat.associated_face_count = faces.len() as u32;
+            self.faces.extend(faces);
+        }
+    }
+
+    /// Points the material morph items and softbodies of material `i` at `map[i]`, items of
+    /// materials mapped to `None` are dropped. Items for all materials (`u32::MAX`) are kept.
+    pub(super) fn remap_mat_refs(&mut self, map: &[Option<usize>]) {
+        let new = |i: usize| map.get(i).copied().flatten();
+        for morph in &mut self.morphs {
+            if let Morph::MorphMat(items) = &mut morph.data {
+                items.retain_mut(|item| {
+                    item.index == u32::MAX || new(item.index as usize).map(|i| item.index = i as u32).is_some()
+                });
+            }
+        }
+        for softbody in &mut self.softbodys {
+            if softbody.mat >= 0 {
+                softbody.mat = new(softbody.mat as usize).map_or(-1, |i| i as i32);
+            }
+        }
+    }
+
+    /// Rebuilds the material list with material `i` moved to `map[i]`, or removed along with its
+    /// faces when it is `None`, and remaps the references to materials. Vertices are left as they are.
+    fn remap_mats(&mut self, map: &[Option<usize>]) {
+        let mat_faces = self.mat_faces();
+        let mats: Vec<(Mat, Vec<[u32; 3]>)> = std::mem::take(&mut self.mats).into_iter().zip(mat_faces).collect();
+        let (mats, faces): (Vec<_>, Vec<_>) = remap_vec(mats, map).into_iter().unzip();
+        self.mats = mats;
+        self.set_mat_faces(faces);
+        self.remap_mat_refs(map);
+    }
+
+    /// Inserts `mat` with `faces` at `index`, the materials from `index` on move back by one
+    pub fn insert_mat(&mut self, index: usize, mat: Mat, faces: Vec<[u32; 3]>) {
+        let map = insert_map(self.mats.len(), index.min(self.mats.len()));
+        let mut mat_faces = self.mat_faces();
+        self.mats.push(mat);
+        mat_faces.push(faces);
+        self.set_mat_faces(mat_faces);
+        self.remap_mats(&map);
+    }
+
+    /// Inserts a copy of material `index` and its faces right after it and returns the index of the copy.
+    /// The copy draws the same vertices, material morphs keep pointing at the original.
+    pub fn duplicate_mat(&mut self, index: usize) -> usize {
+        let mat = self.copy_mat(index);
+        let faces = self.mat_faces().swap_remove(index);
+        self.insert_mat(index + 1, mat, faces);
+        index + 1
+    }
+
+    /// Reorders the materials and their faces so that material `order[i]` becomes material `i`,
+    /// `order` has to be a permutation. Materials are drawn in this order.
+    pub fn reorder_mats(&mut self, order: &[usize]) {
+        self.remap_mats(&order_map(self.mats.len(), order));
+    }
+
+    /// Moves material `from` to index `to`, shifting the materials in between
+    pub fn move_mat(&mut self, from: usize, to: usize) {
+        self.reorder_mats(&move_order(self.mats.len(), from, to));
+    }
+
+    /// Moves the faces of material `from` at the given indices, counted from the start of the
+    /// material, to the end of material `to`
+    pub fn move_faces(&mut self, from: usize, faces: &BTreeSet<usize>, to: usize) {
+        if from == to {
+            return;
+        }
+        let mut mat_faces = self.mat_faces();
+        let (moved, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut mat_faces[from])
+            .into_iter()
+            .enumerate()
+            .partition(|(i, _)| faces.contains(i));
+        mat_faces[from] = kept.into_iter().map(|(_, f)| f).collect();
+        mat_faces[to].extend(moved.into_iter().map(|(_, f)| f));
+        self.set_mat_faces(mat_faces);
+    }
+
+    /// Moves the given faces of material `index` into a copy of it inserted right after it,
+    /// returns the index of the new material
+    pub fn split_mat(&mut self, index: usize, faces: &BTreeSet<usize>) -> usize {
+        let mat = self.copy_mat(index);
+        self.insert_mat(index + 1, mat, Vec::new());
+        self.move_faces(index, faces, index + 1);
+        index + 1
+    }
+
+    /// Splits material `index` into one material per UV island, faces sharing a vertex belong
+    /// to the same island. The first island stays in the material, the others follow it in order.
+    /// Returns the number of materials added.
+    pub fn split_mat_by_uv_islands(&mut self, index: usize) -> usize {
+        let faces = self.mat_faces().swap_remove(index);
+        // union-find over the vertices, iterative with path halving so long strips do not recurse deeply
+        let mut parent: BTreeMap<u32, u32> = BTreeMap::new();
+        fn root(parent: &mut BTreeMap<u32, u32>, mut v: u32) -> u32 {
+            loop {
+                let p = *parent.entry(v).or_insert(v);
+                if p == v {
+                    return v;
+                }
+                let grandparent = parent[&p];
+                parent.insert(v, grandparent);
+                v = grandparent;
+            }
+        }
+        for [a, b, c] in &faces {
+            let ra = root(&mut parent, *a);
+            for v in [*b, *c] {
+                let rv = root(&mut parent, v);
+                if rv != ra {
+                    parent.insert(rv, ra);
+                }
+            }
+        }
+        let mut island_of_root = BTreeMap::new();
+        let mut islands: Vec<BTreeSet<usize>> = Vec::new();
+        for (i, face) in faces.iter().enumerate() {
+            let r = root(&mut parent, face[0]);
+            let island = *island_of_root.entry(r).or_insert_with(|| {
+                islands.push(BTreeSet::new());
+                islands.len() - 1
+            });
+            islands[island].insert(i);
+        }
+        self.split_mat_by_groups(index, islands)
+    }
+
+    /// Splits material `index` into the faces whose UV center lies inside the `min`..`max` rectangle
+    /// and the rest, which stays in the material. Returns the index of the new material, or `None`
+    /// when no face is in the rectangle.
+    pub fn split_mat_by_uv_rect(&mut self, index: usize, min: Vec2, max: Vec2) -> Option<usize> {
+        let faces: BTreeSet<usize> = self.mat_faces()[index].iter().enumerate()
+            .filter(|(_, face)| {
+                let center = face.iter().map(|v| self.verts[*v as usize].uv).sum::<Vec2>() / 3.0;
+                center.cmpge(min).all() && center.cmple(max).all()
+            })
+            .map(|(i, _)| i)
+            .collect();
+        (!faces.is_empty()).then(|| self.split_mat(index, &faces))
+    }
+
+    /// Moves each group of faces after the first into its own copy of material `index`
+    fn split_mat_by_groups(&mut self, index: usize, groups: Vec<BTreeSet<usize>>) -> usize {
+        let added = groups.len().saturating_sub(1);
+        let mut mat_faces = self.mat_faces();
+        let faces = std::mem::take(&mut mat_faces[index]);
+        let mut groups = groups.into_iter();
+        if let Some(first) = groups.next() {
+            mat_faces[index] = first.iter().map(|i| faces[*i]).collect();
+        }
+        self.set_mat_faces(mat_faces);
+        for (n, group) in groups.enumerate() {
+            let mat = self.copy_mat(index);
+            self.insert_mat(index + n + 1, mat, group.iter().map(|i| faces[*i]).collect());
+        }
+        added
+    }
+
+    /// Copy of material `index` named with a "+" appended until no material uses the name,
+    /// the English name gets the same suffix
+    fn copy_mat(&self, index: usize) -> Mat {
+        let mut mat = self.mats[index].clone();
+        let mut name = mat.name.clone();
+        while self.mats.iter().any(|m| m.name == name) {
+            name.push('+');
+        }
+        mat.name_en += &name[mat.name.len()..];
+        mat.name = name;
+        mat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    fn items(m: &Pmx) -> Vec<u32> {
+        morph_indices(&m.morphs[0])
+    }
+
+    #[test]
+    fn reorder_moves_faces_and_morphs() {
+        let mut m = mat_model();
+        m.move_mat(0, 2);
+        assert_eq!(mat_names(&m), ["b", "c", "a"]);
+        assert_eq!(m.faces, [[6, 7, 8], [9, 10, 11], [0, 1, 2], [3, 4, 5]]);
+        assert_eq!(items(&m), [2, 1, u32::MAX]);
+        reread(&m);
+    }
+
+    #[test]
+    fn duplicate_copies_faces() {
+        let mut m = mat_model();
+        let copy = m.duplicate_mat(1);
+        assert_eq!(mat_names(&m), ["a", "b", "b+", "c"]);
+        assert_eq!(m.mats[copy].associated_face_count, 1);
+        assert_eq!(m.faces[3], [6, 7, 8]);
+        assert_eq!(items(&m), [0, 3, u32::MAX]);
+    }
+
+    #[test]
+    fn split_and_move_faces() {
+        let mut m = mat_model();
+        let split = m.split_mat(0, &BTreeSet::from([1]));
+        assert_eq!(mat_names(&m), ["a", "a+", "b", "c"]);
+        assert_eq!(m.mat_faces()[split], [[3, 4, 5]]);
+        m.move_faces(split, &BTreeSet::from([0]), 3);
+        assert_eq!(m.mat_faces(), [vec![[0, 1, 2]], vec![], vec![[6, 7, 8]], vec![[9, 10, 11], [3, 4, 5]]]);
+    }
+
+    #[test]
+    fn split_by_uv() {
+        let mut m = mat_model();
+        assert_eq!(m.split_mat_by_uv_islands(0), 1);
+        assert_eq!(mat_names(&m), ["a", "a+", "b", "c"]);
+        assert_eq!(items(&m), [0, 3, u32::MAX]);
+        // every way of splitting picks the next free name
+        m.split_mat(0, &BTreeSet::from([0]));
+        assert_eq!(mat_names(&m), ["a", "a++", "a+", "b", "c"]);
+        assert_eq!(m.mats[1].name_en, format!("{}++", m.mats[0].name_en));
+
+        let mut m = mat_model();
+        assert_eq!(m.split_mat_by_uv_rect(0, vec2(0.5, 0.0), Vec2::ONE), Some(1));
+        assert_eq!(m.mat_faces()[1], [[3, 4, 5]]);
+        assert_eq!(m.split_mat_by_uv_rect(2, Vec2::ZERO, Vec2::splat(0.1)), None);
+    }
+
+    #[test]
+    fn long_strip_is_one_island() {
+        let mut m = Pmx::new();
+        m.bones.push(Bone::default());
+        let n = 200_000;
+        m.verts = (0..n + 2).map(|i| vertex(Vec3::splat(i as f32))).collect();
+        // each face shares an edge with the next, listed from the far end so the chain is long
+        m.faces = (0..n).rev().map(|i| [i, i + 1, i + 2]).collect();
+        m.mats.push(mat("strip", n));
+        assert_eq!(m.split_mat_by_uv_islands(0), 0);
+        assert_eq!(m.mats.len(), 1);
+    }
+
+    #[test]
+    fn merge_and_delete_remap_morphs() {
+        let mut m = mat_model();
+        m.mat_merge(&BTreeSet::from([1, 2]));
+        assert_eq!(mat_names(&m), ["a", "b"]);
+        assert_eq!(items(&m), [0, 1, u32::MAX]);
+        m.delete_mats(&vec!["a".to_string()]);
+        assert_eq!(items(&m), [0, u32::MAX]);
+        assert_eq!(m.verts.len(), 6);
+        reread(&m);
+    }
+}
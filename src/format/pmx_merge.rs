@@ -0,0 +1,381 @@
+#![allow(dead_code)]
+
+use std::collections::{BTreeSet, HashMap};
+use std::mem::discriminant;
+use std::path::Path;
+
+use glam::*;
+use uuid::Uuid;
+
+use super::pmx::*;
+use super::pmx_bones::map_weight_bones;
+
+/// What `Pmx::merge` matches by name instead of appending
+#[derive(Copy, Clone)]
+pub struct MergeOptions {
+    /// Bones of the other model go onto same-named bones, only missing bones are added
+    pub bones_by_name: bool,
+    /// Items of a morph go into the same-named morph of the same kind, UV morphs also need the same channel
+    pub morphs_by_name: bool,
+    /// Adds the rigid bodies, joints and soft bodies
+    pub physics: bool,
+}
+
+impl MergeOptions {
+    pub fn new() -> MergeOptions {
+        MergeOptions {
+            bones_by_name: true,
+            morphs_by_name: true,
+            physics: true,
+        }
+    }
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Directory of a model file split into components, empty for a model without a path
+fn model_dir(path: &str) -> Vec<String> {
+    let mut dir = path_components(path);
+    dir.pop();
+    dir
+}
+
+/// Components of a path with either separator, "." and resolvable ".." removed
+fn path_components(path: &str) -> Vec<String> {
+    let mut components: Vec<String> = Vec::new();
+    for c in path.split(['/', '\\']) {
+        match c {
+            "" | "." => {}
+            ".." if components.last().map_or(false, |l| l != "..") => {
+                components.pop();
+            }
+            _ => components.push(c.to_string()),
+        }
+    }
+    components
+}
+
+fn is_absolute(tex: &str) -> bool {
+    Path::new(tex).is_absolute() || tex.starts_with(['/', '\\']) || tex.get(1..2) == Some(":")
+}
+
+/// Texture path resolved against the model directory, lower-cased because models come from Windows
+fn tex_key(dir: &[String], tex: &str) -> Vec<String> {
+    let mut path = if is_absolute(tex) { Vec::new() } else { dir.to_vec() };
+    path.extend(path_components(tex));
+    path_components(&path.join("/")).into_iter().map(|c| c.to_lowercase()).collect()
+}
+
+/// `tex` of a model in `from` as a path relative to `to`
+fn relative_tex(from: &[String], to: &[String], tex: &str) -> String {
+    if is_absolute(tex) {
+        return tex.to_string();
+    }
+    let mut target = from.to_vec();
+    target.extend(path_components(tex));
+    let target = path_components(&target.join("/"));
+    let common = to.iter().zip(&target).take_while(|(a, b)| a == b).count();
+    let mut path = vec!["..".to_string(); to.len() - common];
+    path.extend_from_slice(&target[common..]);
+    path.join("/")
+}
+
+impl Pmx {
+    /// Appends `other` to this model, for example an outfit to a body. The bones of `other` are
+    /// matched by name with `options.bones_by_name` so that its vertices follow the existing skeleton,
+    /// textures used by both models are shared and same-named morphs are combined.
+    /// Both models have to use the same handedness.
+    pub fn merge(&mut self, other: &Pmx, options: &MergeOptions) {
+        // bones, the ones matched by name keep their settings and IK
+        let mut next = self.bones.len();
+        let bone_map: Vec<usize> = other.bones.iter().map(|bone| {
+            let existing = options.bones_by_name
+                .then(|| self.bones.iter().position(|b| b.name == bone.name))
+                .flatten();
+            existing.unwrap_or_else(|| {
+                next += 1;
+                next - 1
+            })
+        }).collect();
+        let bone_i32 = |i: i32| usize::try_from(i).ok().and_then(|i| bone_map.get(i)).map_or(-1, |i| *i as i32);
+        let first_new_bone = self.bones.len();
+        for (i, bone) in other.bones.iter().enumerate() {
+            if bone_map[i] < first_new_bone {
+                continue;
+            }
+            let mut bone = bone.clone();
+            while self.bones.iter().any(|b| b.name == bone.name) {
+                bone.name.push('+');
+            }
+            bone.parent_index = bone.parent_index.and_then(|p| bone_map.get(p).copied());
+            if let BoneTailPos::Bone(t) = &mut bone.bone_tail_pos {
+                *t = bone_i32(*t);
+            }
+            if let Some((p, _)) = &mut bone.inherit {
+                *p = bone_i32(*p);
+            }
+            self.bones.push(bone);
+        }
+        for ik in &other.iks {
+            if bone_i32(ik.bone) < first_new_bone as i32 {
+                continue;
+            }
+            let mut ik = ik.clone();
+            ik.bone = bone_i32(ik.bone);
+            ik.effector = bone_i32(ik.effector);
+            for j in &mut ik.ik_joints {
+                j.bone = bone_i32(j.bone);
+            }
+            self.iks.push(ik);
+        }
+
+        // vertices and faces
+        let vert_offset = self.verts.len();
+        let uv_count = self.appendix_uvs.len().max(other.appendix_uvs.len());
+        self.appendix_uvs.resize(uv_count, vec![Vec4::ZERO; vert_offset]);
+        for (k, uvs) in self.appendix_uvs.iter_mut().enumerate() {
+            match other.appendix_uvs.get(k) {
+                Some(other_uvs) => uvs.extend(other_uvs),
+                None => uvs.resize(vert_offset + other.verts.len(), Vec4::ZERO),
+            }
+        }
+        for v in &other.verts {
+            let mut v = *v;
+            map_weight_bones(&mut v.weight, bone_i32);
+            self.verts.push(v);
+        }
+        let offset = vert_offset as u32;
+        self.faces.extend(other.mat_faces().concat().iter().map(|f| f.map(|i| i + offset)));
+
+        // textures, the same file is only listed once
+        let self_dir = model_dir(&self.path);
+        let other_dir = model_dir(&other.path);
+        let mut tex_index: HashMap<Vec<String>, i32> = self.texs.iter().enumerate()
+            .map(|(i, tex)| (tex_key(&self_dir, tex), i as i32))
+            .collect();
+        let tex_map: Vec<i32> = other.texs.iter().map(|tex| {
+            *tex_index.entry(tex_key(&other_dir, tex)).or_insert_with(|| {
+                self.texs.push(relative_tex(&other_dir, &self_dir, tex));
+                self.texs.len() as i32 - 1
+            })
+        }).collect();
+        let tex_i32 = |i: i32| usize::try_from(i).ok().and_then(|i| tex_map.get(i)).copied().unwrap_or(-1);
+
+        // materials
+        let mat_offset = self.mats.len() as u32;
+        for mat in &other.mats {
+            let mut mat = mat.clone();
+            mat.tex_index = tex_i32(mat.tex_index);
+            mat.env_index = tex_i32(mat.env_index);
+            if let Toon::Tex(t) = &mut mat.toon {
+                *t = tex_i32(*t);
+            }
+            self.mats.push(mat);
+        }
+
+        // physics
+        let rigidbody_offset = self.rigidbodys.len() as i32;
+        let rigidbody_map = |i: u32| options.physics.then_some(i + rigidbody_offset as u32);
+        if options.physics {
+            for rb in &other.rigidbodys {
+                let mut rb = rb.clone();
+                rb.bone = bone_i32(rb.bone);
+                rb.uuid = Uuid::new_v4();
+                self.rigidbodys.push(rb);
+            }
+            let rb_i32 = |i: i32| if i < 0 { i } else { i + rigidbody_offset };
+            for joint in &other.joints {
+                let mut joint = joint.clone();
+                joint.rigidbody_a = rb_i32(joint.rigidbody_a);
+                joint.rigidbody_b = rb_i32(joint.rigidbody_b);
+                joint.uuid = Uuid::new_v4();
+                self.joints.push(joint);
+            }
+            for softbody in &other.softbodys {
+                let mut softbody = softbody.clone();
+                if softbody.mat >= 0 {
+                    softbody.mat += mat_offset as i32;
+                }
+                for anchor in &mut softbody.anchors {
+                    anchor.rigidbody = rb_i32(anchor.rigidbody);
+                    anchor.vertex += vert_offset as i32;
+                }
+                for v in &mut softbody.pin_verts {
+                    *v += vert_offset as i32;
+                }
+                softbody.uuid = Uuid::new_v4();
+                self.softbodys.push(softbody);
+            }
+        }
+
+        // morphs, same-named morphs of the same kind take the items of the other model,
+        // the category tells the UV channels apart
+        let mut next = self.morphs.len();
+        let morph_map: Vec<usize> = other.morphs.iter().map(|morph| {
+            let existing = options.morphs_by_name
+                .then(|| self.morphs.iter().position(|m| {
+                    m.name == morph.name && discriminant(&m.data) == discriminant(&morph.data) && m.category == morph.category
+                }))
+                .flatten();
+            existing.unwrap_or_else(|| {
+                next += 1;
+                next - 1
+            })
+        }).collect();
+        for (i, morph) in other.morphs.iter().enumerate() {
+            let mut data = morph.data.clone();
+            match &mut data {
+                Morph::MorphGroup(items) => items.retain_mut(|item| {
+                    morph_map.get(item.index as usize).map(|m| item.index = *m as u32).is_some()
+                }),
+                Morph::MorphFlip(items) => items.retain_mut(|item| {
+                    morph_map.get(item.index as usize).map(|m| item.index = *m as u32).is_some()
+                }),
+                Morph::MorphVertex(items) => items.iter_mut().for_each(|item| item.index += offset),
+                Morph::MorphUv(items) => items.iter_mut().for_each(|item| item.index += offset),
+                Morph::MorphBone(items) => items.retain_mut(|item| {
+                    bone_map.get(item.index as usize).map(|b| item.index = *b as u32).is_some()
+                }),
+                Morph::MorphRigidbody(items) => items.retain_mut(|item| {
+                    rigidbody_map(item.index).map(|r| item.index = r).is_some()
+                }),
+                Morph::MorphMat(items) => items.iter_mut()
+                    .filter(|item| item.index != u32::MAX)
+                    .for_each(|item| item.index += mat_offset),
+            }
+            if let Some(existing) = self.morphs.get_mut(morph_map[i]) {
+                match (&mut existing.data, data) {
+                    (Morph::MorphGroup(a), Morph::MorphGroup(b)) => a.extend(b),
+                    (Morph::MorphFlip(a), Morph::MorphFlip(b)) => a.extend(b),
+                    (Morph::MorphVertex(a), Morph::MorphVertex(b)) => a.extend(b),
+                    (Morph::MorphUv(a), Morph::MorphUv(b)) => a.extend(b),
+                    (Morph::MorphBone(a), Morph::MorphBone(b)) => a.extend(b),
+                    (Morph::MorphRigidbody(a), Morph::MorphRigidbody(b)) => a.extend(b),
+                    (Morph::MorphMat(a), Morph::MorphMat(b)) => a.extend(b),
+                    _ => unreachable!("morphs are only combined with morphs of the same kind"),
+                }
+            } else {
+                let name = self.unique_morph_name(&morph.name);
+                self.morphs.push(MorphInfo { name, data, ..morph.clone() });
+            }
+        }
+
+        // display frames, entries already shown in a frame are not repeated
+        let key = |item: &DisplayFrameIndex| match item {
+            DisplayFrameIndex::Bone(i) => (false, *i),
+            DisplayFrameIndex::Morph(i) => (true, *i),
+        };
+        let mut shown: BTreeSet<(bool, u32)> = self.display_frames.iter()
+            .flat_map(|f| f.morph_items.iter().map(key))
+            .collect();
+        for frame in &other.display_frames {
+            let items: Vec<DisplayFrameIndex> = frame.morph_items.iter()
+                .filter_map(|item| match item {
+                    DisplayFrameIndex::Bone(i) => bone_map.get(*i as usize).map(|b| DisplayFrameIndex::Bone(*b as u32)),
+                    DisplayFrameIndex::Morph(i) => morph_map.get(*i as usize).map(|m| DisplayFrameIndex::Morph(*m as u32)),
+                })
+                .filter(|item| shown.insert(key(item)))
+                .collect();
+            if items.is_empty() {
+                continue;
+            }
+            match self.display_frames.iter_mut().find(|f| f.name == frame.name) {
+                Some(existing) => existing.morph_items.extend(items),
+                None => self.display_frames.push(DisplayFrame { morph_items: items, ..frame.clone() }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    fn vertex_morph(name: &str, index: u32) -> MorphInfo {
+        morph(name, Morph::MorphVertex(vec![MorphVertexItem { index, trans: Vec3::ONE }]))
+    }
+
+    /// One triangle weighted to the last bone of `bones`, textured with `tex`, and a vertex morph "あ" shown in the 表情 frame
+    fn model(path: &str, bones: &[(&str, Option<usize>)], tex: &str) -> Pmx {
+        let mut m = Pmx::new();
+        m.path = path.to_string();
+        m.bones = bones.iter().map(|(name, parent)| bone(name, *parent, Vec3::ZERO)).collect();
+        let weight = VertexWeight::One(bones.len() as i32 - 1);
+        m.verts = (0..3).map(|_| Vertex { weight, ..vertex(Vec3::ZERO) }).collect();
+        m.faces = vec![[0, 1, 2]];
+        m.texs.push(tex.to_string());
+        m.mats.push(Mat { tex_index: 0, ..mat(path, 1) });
+        m.morphs.push(vertex_morph("あ", 1));
+        m.display_frames[1].morph_items.push(DisplayFrameIndex::Morph(0));
+        m
+    }
+
+    #[test]
+    fn bones_are_matched_by_name() {
+        let mut body = model("/m/body/body.pmx", &[("センター", None), ("上半身", Some(0))], "tex/skin.png");
+        let outfit = model("/m/outfit/outfit.pmx", &[("センター", None), ("上半身", Some(0)), ("スカート", Some(1))], "tex/cloth.png");
+        body.merge(&outfit, &MergeOptions::new());
+        assert_eq!(body.bones.len(), 3);
+        assert_eq!(body.bones[2].name, "スカート");
+        assert_eq!(body.bones[2].parent_index, Some(1));
+        assert!(matches!(body.verts[3].weight, VertexWeight::One(2)));
+        assert_eq!(body.faces, [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(body.mats[1].associated_face_count, 1);
+        reread(&body);
+    }
+
+    #[test]
+    fn textures_are_shared_and_relative() {
+        let mut body = model("/m/body/body.pmx", &[("センター", None)], "tex\\skin.png");
+        let mut outfit = model("/m/outfit/outfit.pmx", &[("センター", None)], "../body/TEX/skin.png");
+        outfit.texs.push("cloth.png".into());
+        outfit.mats[0].env_index = 1;
+        body.merge(&outfit, &MergeOptions::new());
+        assert_eq!(body.texs, ["tex\\skin.png", "../outfit/cloth.png"]);
+        assert_eq!(body.mats[1].tex_index, 0);
+        assert_eq!(body.mats[1].env_index, 1);
+    }
+
+    #[test]
+    fn morphs_are_combined_by_name() {
+        let mut body = model("/m/body.pmx", &[("センター", None)], "a.png");
+        let mut outfit = model("/m/outfit.pmx", &[("センター", None)], "b.png");
+        outfit.morphs.push(vertex_morph("い", 0));
+        outfit.display_frames[1].morph_items.push(DisplayFrameIndex::Morph(1));
+        body.merge(&outfit, &MergeOptions::new());
+        assert_eq!(body.morphs.len(), 2);
+        assert_eq!(morph_indices(&body.morphs[0]), [1, 4]);
+        assert_eq!(body.display_frames.len(), 2);
+        assert_eq!(body.display_frames[1].morph_items.len(), 2);
+
+        let mut body = model("/m/body.pmx", &[("センター", None)], "a.png");
+        let options = MergeOptions { morphs_by_name: false, ..MergeOptions::new() };
+        body.merge(&outfit, &options);
+        assert_eq!(morph_names(&body), ["あ", "あ+", "い"]);
+        reread(&body);
+    }
+
+    #[test]
+    fn uv_morphs_are_combined_by_channel() {
+        let uv_morph = |index: u32, category: i8| MorphInfo {
+            category,
+            ..morph("uv", Morph::MorphUv(vec![MorphUvItem { index, trans: Vec4::ONE }]))
+        };
+        let mut body = model("/m/body.pmx", &[("センター", None)], "a.png");
+        body.morphs.push(uv_morph(0, 3));
+        let mut outfit = model("/m/outfit.pmx", &[("センター", None)], "b.png");
+        outfit.morphs.push(uv_morph(1, 4));
+        outfit.morphs.push(uv_morph(2, 3));
+        body.merge(&outfit, &MergeOptions::new());
+        assert_eq!(morph_names(&body), ["あ", "uv", "uv+"]);
+        let categories: Vec<i8> = body.morphs.iter().map(|m| m.category).collect();
+        assert_eq!(categories, [1, 3, 4]);
+        assert_eq!(morph_indices(&body.morphs[1]), [0, 5]);
+        reread(&body);
+    }
+}
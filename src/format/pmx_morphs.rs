@@ -0,0 +1,162 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeSet;
+
+use super::pmx::*;
+use super::pmx_remap::*;
+
+impl Pmx {
+    /// Rebuilds the morph list with morph `i` moved to `map[i]`, or removed when it is `None`,
+    /// and remaps the group and flip items and display frame entries, dropping those of removed morphs
+    fn remap_morphs(&mut self, map: &[Option<usize>]) {
+        let new_u32 = |i: u32| map.get(i as usize).copied().flatten().map(|i| i as u32);
+
+        self.morphs = remap_vec(std::mem::take(&mut self.morphs), map);
+        for morph in &mut self.morphs {
+            match &mut morph.data {
+                Morph::MorphGroup(items) => {
+                    items.retain_mut(|item| new_u32(item.index).map(|i| item.index = i).is_some());
+                }
+                Morph::MorphFlip(items) => {
+                    items.retain_mut(|item| new_u32(item.index).map(|i| item.index = i).is_some());
+                }
+                _ => {}
+            }
+        }
+        for frame in &mut self.display_frames {
+            frame.morph_items.retain_mut(|item| match item {
+                DisplayFrameIndex::Morph(i) => new_u32(*i).map(|n| *i = n).is_some(),
+                DisplayFrameIndex::Bone(_) => true,
+            });
+        }
+    }
+
+    pub fn delete_morphs(&mut self, morphs: &BTreeSet<usize>) {
+        self.remap_morphs(&delete_map(self.morphs.len(), morphs));
+    }
+
+    /// Inserts `morph` at `index`, the morphs from `index` on move back by one.
+    /// Group and flip items of `morph` use the indices from before the insertion.
+    pub fn insert_morph(&mut self, index: usize, morph: MorphInfo) {
+        let map = insert_map(self.morphs.len(), index.min(self.morphs.len()));
+        self.morphs.push(morph);
+        self.remap_morphs(&map);
+    }
+
+    /// Inserts a copy of morph `index` right after it and returns the index of the copy
+    pub fn duplicate_morph(&mut self, index: usize) -> usize {
+        let mut morph = self.morphs[index].clone();
+        morph.name = self.unique_morph_name(&morph.name);
+        morph.name_en = format!("{}+", morph.name_en);
+        self.insert_morph(index + 1, morph);
+        index + 1
+    }
+
+    /// Reorders the morphs so that morph `order[i]` becomes morph `i`, `order` has to be a permutation
+    pub fn reorder_morphs(&mut self, order: &[usize]) {
+        self.remap_morphs(&order_map(self.morphs.len(), order));
+    }
+
+    /// Moves morph `from` to index `to`, shifting the morphs in between
+    pub fn move_morph(&mut self, from: usize, to: usize) {
+        self.reorder_morphs(&move_order(self.morphs.len(), from, to));
+    }
+
+    /// Renames morph `index`. Motions refer to morphs by name, so a name used by another morph is refused.
+    pub fn rename_morph(&mut self, index: usize, name: &str) -> bool {
+        if self.morphs.iter().enumerate().any(|(i, m)| i != index && m.name == name) {
+            return false;
+        }
+        self.morphs[index].name = name.to_string();
+        true
+    }
+
+    /// `name` with a "+" appended until no morph uses it
+    pub(super) fn unique_morph_name(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        while self.morphs.iter().any(|m| m.name == name) {
+            name.push('+');
+        }
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::*;
+
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    fn frame_morphs(m: &Pmx) -> Vec<u32> {
+        frame_items(&m.display_frames[2]).into_iter().filter(|(morph, _)| *morph).map(|(_, i)| i).collect()
+    }
+
+    #[test]
+    fn delete_drops_references() {
+        let mut m = morph_model();
+        m.delete_morphs(&BTreeSet::from([1]));
+        assert_eq!(morph_names(&m), ["v", "group", "flip"]);
+        assert_eq!(morph_indices(&m.morphs[1]), [0]);
+        assert_eq!(morph_indices(&m.morphs[2]), [1]);
+        assert_eq!(frame_morphs(&m), [0, 1, 2]);
+        assert_eq!(m.display_frames[2].morph_items.len(), 4);
+        let read = reread(&m);
+        assert_eq!(read.morphs.len(), 3);
+    }
+
+    #[test]
+    fn reorder_and_insert_follow_morphs() {
+        let mut m = morph_model();
+        m.move_morph(0, 3);
+        assert_eq!(morph_names(&m), ["uv", "group", "flip", "v"]);
+        assert_eq!(morph_indices(&m.morphs[1]), [3, 0]);
+        assert_eq!(morph_indices(&m.morphs[2]), [0, 1]);
+        assert_eq!(frame_morphs(&m), [3, 0, 1, 2]);
+
+        m.insert_morph(0, morph("new", group(&[3])));
+        assert_eq!(morph_indices(&m.morphs[0]), [4]);
+        assert_eq!(morph_indices(&m.morphs[2]), [4, 1]);
+        assert_eq!(frame_morphs(&m), [4, 1, 2, 3]);
+    }
+
+    #[test]
+    fn duplicate_and_rename_keep_names_unique() {
+        let mut m = morph_model();
+        let copy = m.duplicate_morph(2);
+        assert_eq!(m.morphs[copy].name, "group+");
+        assert_eq!(morph_indices(&m.morphs[copy]), [0, 1]);
+        // the flip after the copy moved to index 4 and still points at uv and group
+        assert_eq!(morph_indices(&m.morphs[4]), [1, 2]);
+        assert!(!m.rename_morph(copy, "group"));
+        assert!(m.rename_morph(copy, "group2"));
+        assert_eq!(m.morphs[copy].name, "group2");
+    }
+
+    #[test]
+    fn compacting_vertices_remaps_morphs() {
+        let mut m = morph_model();
+        m.delete_unref_point();
+        assert_eq!(m.verts.len(), 6);
+        assert_eq!(m.faces, [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(m.appendix_uvs[0].len(), 6);
+        assert_eq!(m.appendix_uvs[0][3], Vec4::splat(4.0));
+        // the unused vertex 3 is dropped from the morphs
+        assert_eq!(morph_indices(&m.morphs[0]), [1, 4]);
+        assert_eq!(morph_indices(&m.morphs[1]), [2, 5]);
+        reread(&m);
+    }
+
+    #[test]
+    fn deleting_materials_remaps_morphs() {
+        let mut m = morph_model();
+        m.delete_mats(&vec!["a".to_string()]);
+        assert_eq!(m.verts.len(), 3);
+        assert_eq!(m.faces, [[0, 1, 2]]);
+        assert_eq!(m.verts[0].pos, Vec3::splat(4.0));
+        assert_eq!(m.appendix_uvs[0], [Vec4::splat(4.0), Vec4::splat(5.0), Vec4::splat(6.0)]);
+        assert_eq!(morph_indices(&m.morphs[0]), [1]);
+        assert_eq!(morph_indices(&m.morphs[1]), [2]);
+        reread(&m);
+    }
+}
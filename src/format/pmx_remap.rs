@@ -0,0 +1,53 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeSet;
+
+/// Rebuilds `items` with item `i` moved to `map[i]`, or dropped when it is `None`.
+/// `map` must send the kept items onto `0..kept`.
+pub(super) fn remap_vec<T>(items: Vec<T>, map: &[Option<usize>]) -> Vec<T> {
+    let mut remapped: Vec<Option<T>> = std::iter::repeat_with(|| None).take(map.iter().flatten().count()).collect();
+    for (item, to) in items.into_iter().zip(map) {
+        if let Some(to) = to {
+            remapped[*to] = Some(item);
+        }
+    }
+    remapped.into_iter().map(|item| item.expect("map is not onto the kept items")).collect()
+}
+
+/// Map that keeps the items not in `removed` in their order
+pub(super) fn delete_map(len: usize, removed: &BTreeSet<usize>) -> Vec<Option<usize>> {
+    let mut next = 0;
+    (0..len).map(|i| {
+        (!removed.contains(&i)).then(|| {
+            next += 1;
+            next - 1
+        })
+    }).collect()
+}
+
+/// Map for `len` items followed by a pushed one that goes to `index`, the items from `index` on move back by one
+pub(super) fn insert_map(len: usize, index: usize) -> Vec<Option<usize>> {
+    let mut map: Vec<Option<usize>> = (0..len)
+        .map(|i| Some(if i < index { i } else { i + 1 }))
+        .collect();
+    map.push(Some(index));
+    map
+}
+
+/// Map that makes item `order[i]` item `i`, `order` has to be a permutation of `0..len`
+pub(super) fn order_map(len: usize, order: &[usize]) -> Vec<Option<usize>> {
+    let mut map = vec![None; len];
+    for (to, from) in order.iter().enumerate() {
+        map[*from] = Some(to);
+    }
+    assert!(order.len() == len && map.iter().all(|m| m.is_some()), "order is not a permutation");
+    map
+}
+
+/// Order that moves item `from` to index `to`, shifting the items in between
+pub(super) fn move_order(len: usize, from: usize, to: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..len).collect();
+    let item = order.remove(from);
+    order.insert(to.min(order.len()), item);
+    order
+}
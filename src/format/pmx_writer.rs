@@ -13,10 +13,15 @@ use super::pmx::*;
 
 
 impl Pmx {
-    fn write_string(file: &mut Cursor<Vec<u8>>, content: &str) {
-        let mut string: Vec<u16> = content.encode_utf16().collect();        
-        file.write_u32::<LE>((string.len() * 2 )as u32).unwrap();
-        file.write(bytemuck::cast_slice_mut(&mut string)).unwrap();
+    fn write_string(&self, file: &mut Cursor<Vec<u8>>, content: &str) {
+        if self.header.utf8 {
+            file.write_u32::<LE>(content.len() as u32).unwrap();
+            file.write_all(content.as_bytes()).unwrap();
+        } else {
+            let mut string: Vec<u16> = content.encode_utf16().collect();        
+            file.write_u32::<LE>((string.len() * 2 )as u32).unwrap();
+            file.write_all(bytemuck::cast_slice_mut(&mut string)).unwrap();
+        }
     }
 
     fn get_int_size(s: usize) -> u8 {
@@ -57,21 +62,36 @@ impl Pmx {
         }
     }
 
+    /// 2.1 is only needed for QDEF weights, flip/impulse morphs, the extra draw flags,
+    /// non spring 6DOF joints and soft bodies, everything else is written as 2.0
+    pub fn required_version(&self) -> f32 {
+        let pmx21_draw_flags = DrawFlags::VERTEX_COLOR | DrawFlags::FILL_MODE_POINT | DrawFlags::FILL_MODE_EDGE;
+        let uses_pmx21 = !self.softbodys.is_empty()
+            || self.verts.iter().any(|v| matches!(v.weight, VertexWeight::Quat(..)))
+            || self.mats.iter().any(|m| m.draw_flag.intersects(pmx21_draw_flags))
+            || self.morphs.iter().any(|m| matches!(m.data, Morph::MorphFlip(_) | Morph::MorphRigidbody(_)))
+            || self.joints.iter().any(|j| j.kind != JointKind::Spring6Dof);
+        if uses_pmx21 { 2.1 } else { 2.0 }
+    }
+
+    /// Honors the settings in `self.header` so an untouched model is written back byte for byte,
+    /// the version and index sizes are only raised when the current data no longer fits
     pub fn write(&self) -> Vec<u8> {
         let content = Vec::new();
         let mut file = std::io::Cursor::new(content);
-        file.write(b"PMX ").unwrap();
-        file.write_f32::<LE>(2.0).unwrap(); // version
+        let version = self.required_version().max(self.header.version);
+        file.write_all(b"PMX ").unwrap();
+        file.write_f32::<LE>(version).unwrap();
         file.write_u8(8).unwrap(); // unknown
 
-        file.write_u8(0).unwrap(); // use uft-16
+        file.write_u8(if self.header.utf8 { 1 } else { 0 }).unwrap();
         file.write_u8(self.appendix_uvs.len() as _).unwrap(); // appendix_uv
-        let vertex_index_size = Pmx::get_uint_size(self.verts.len());
-        let texture_index_size = Pmx::get_int_size(self.texs.len());
-        let material_index_size = Pmx::get_int_size(self.mats.len());
-        let bone_index_size = Pmx::get_int_size(self.bones.len());
-        let morph_index_size = Pmx::get_int_size(self.morphs.len());
-        let rigidbody_index_size = Pmx::get_int_size(self.rigidbodys.len());
+        let vertex_index_size = Pmx::get_uint_size(self.verts.len()).max(self.header.vertex_index_size);
+        let texture_index_size = Pmx::get_int_size(self.texs.len()).max(self.header.texture_index_size);
+        let material_index_size = Pmx::get_int_size(self.mats.len()).max(self.header.material_index_size);
+        let bone_index_size = Pmx::get_int_size(self.bones.len()).max(self.header.bone_index_size);
+        let morph_index_size = Pmx::get_int_size(self.morphs.len()).max(self.header.morph_index_size);
+        let rigidbody_index_size = Pmx::get_int_size(self.rigidbodys.len()).max(self.header.rigidbody_index_size);
         file.write_u8(vertex_index_size).unwrap();
         file.write_u8(texture_index_size).unwrap();
         file.write_u8(material_index_size).unwrap();
@@ -79,10 +99,10 @@ impl Pmx {
         file.write_u8(morph_index_size).unwrap();
         file.write_u8(rigidbody_index_size).unwrap();
 
-        Self::write_string(&mut file, &self.name);
-        Self::write_string(&mut file, &self.name_en);
-        Self::write_string(&mut file, &self.comment);
-        Self::write_string(&mut file, &self.comment_en);
+        self.write_string(&mut file, &self.name);
+        self.write_string(&mut file, &self.name_en);
+        self.write_string(&mut file, &self.comment);
+        self.write_string(&mut file, &self.comment_en);
         
         self.write_verts(&mut file, bone_index_size);
         self.write_faces(&mut file, vertex_index_size);
@@ -100,8 +120,11 @@ impl Pmx {
 
 
         self.write_display_frames(&mut file, bone_index_size, morph_index_size);
-        file.write_u32::<LE>(0).unwrap(); // rigidbodys
-        file.write_u32::<LE>(0).unwrap(); // joints
+        self.write_rigidbodys(&mut file, bone_index_size);
+        self.write_joints(&mut file, rigidbody_index_size);
+        if version > 2.0 {
+            self.write_softbodys(&mut file, vertex_index_size, material_index_size, rigidbody_index_size);
+        }
 
         file.into_inner()
     }
@@ -109,7 +132,7 @@ impl Pmx {
     fn write_texs(&self, file: &mut Cursor<Vec<u8>>) {
         file.write_u32::<LE>(self.texs.len() as _).unwrap();
         for tex in &self.texs {
-            Self::write_string(file, tex);
+            self.write_string(file, tex);
         }
     }
 
@@ -184,8 +207,8 @@ impl Pmx {
     )  {
         file.write_u32::<LE>(self.morphs.len() as _).unwrap();
         for morph in &self.morphs {
-            Self::write_string(file, &morph.name);
-            Self::write_string(file, &morph.name_en);
+            self.write_string(file, &morph.name);
+            self.write_string(file, &morph.name_en);
             file.write_i8(morph.panel).unwrap();
             file.write_i8(morph.category).unwrap();
             match &morph.data {
@@ -237,7 +260,7 @@ impl Pmx {
                 Morph::MorphMat(vs) => {
                     file.write_u32::<LE>(vs.len() as _).unwrap();
                     for v in vs {
-                        Pmx::write_int(file, v.index as _, morph_index_size);
+                        Pmx::write_int(file, v.index as _, material_index_size);
                         match v.blend_mode {
                             BlendMode::Mul => {
                                 file.write_u8(0).unwrap();
@@ -281,8 +304,8 @@ impl Pmx {
         };
         file.write_u32::<LE>(mats.len() as _).unwrap();
         for m in mats {
-            Self::write_string(file, &m.name);
-            Self::write_string(file, &m.name_en);
+            self.write_string(file, &m.name);
+            self.write_string(file, &m.name_en);
             write_float4(file, m.diffuse);
             write_float4(file, m.specular);
             write_float3(file, m.ambient);
@@ -309,7 +332,7 @@ impl Pmx {
                 },
             }
 
-            Self::write_string(file, &m.comment);
+            self.write_string(file, &m.comment);
             file.write_u32::<LE>(m.associated_face_count * 3).unwrap();
         }
     }
@@ -323,8 +346,8 @@ impl Pmx {
         };
         file.write_u32::<LE>(bones.len() as _).unwrap();
         for (i, b) in bones.iter().enumerate() {
-            Self::write_string(file, &b.name);
-            Self::write_string(file, &b.name_en);
+            self.write_string(file, &b.name);
+            self.write_string(file, &b.name_en);
             write_float3(file, b.pos);
             if let Some(p) = b.parent_index {
                 Pmx::write_int(file, p as _, bone_index_size);
@@ -378,20 +401,11 @@ impl Pmx {
         }
     }
     fn write_display_frames(&self, file: &mut Cursor<Vec<u8>>, bone_index_size: u8, morph_index_size: u8) {
-        let display_frames: Vec<DisplayFrame> = if self.display_frames.len() < 2 {
-            vec![
-                DisplayFrame { name: "Root".to_string(), name_en: "Root".to_string(), deletable: true, morph_items: vec![DisplayFrameIndex::Bone(0)] },
-                DisplayFrame { name: "表情".to_string(), name_en: "Exp".to_string(), deletable: true, morph_items: vec![] },
-            ]
-        } else {
-            self.display_frames.clone()
-        };
-
-        file.write_u32::<LE>(display_frames.len() as _).unwrap();
-        for df in &display_frames {
-            Self::write_string(file, &df.name);
-            Self::write_string(file, &df.name_en);
-            file.write_u8(if df.deletable { 1 } else { 0 }).unwrap();
+        file.write_u32::<LE>(self.display_frames.len() as _).unwrap();
+        for df in &self.display_frames {
+            self.write_string(file, &df.name);
+            self.write_string(file, &df.name_en);
+            file.write_u8(if df.special { 1 } else { 0 }).unwrap();
             file.write_i32::<LE>(df.morph_items.len() as _).unwrap();
             for index in &df.morph_items {
                 match index {
@@ -408,6 +422,299 @@ impl Pmx {
         }
     }
 
+    fn write_rigidbodys(&self, file: &mut Cursor<Vec<u8>>, bone_index_size: u8) {
+        file.write_u32::<LE>(self.rigidbodys.len() as _).unwrap();
+        for r in &self.rigidbodys {
+            self.write_string(file, &r.name);
+            self.write_string(file, &r.name_en);
+            Pmx::write_int(file, r.bone, bone_index_size);
+            file.write_u8(r.group).unwrap();
+            file.write_u16::<LE>(r.collision_group).unwrap();
+            let shape = match r.shape {
+                RigidbodyShape::Shpere => 0,
+                RigidbodyShape::Box => 1,
+                RigidbodyShape::Capsule => 2,
+            };
+            file.write_u8(shape).unwrap();
+            write_float3(file, r.size);
+            write_float3(file, r.pos);
+            write_float3(file, r.rot);
+            file.write_f32::<LE>(r.mass).unwrap();
+            file.write_f32::<LE>(r.linear_damping).unwrap();
+            file.write_f32::<LE>(r.angular_damping).unwrap();
+            file.write_f32::<LE>(r.restitution).unwrap();
+            file.write_f32::<LE>(r.friction).unwrap();
+            let mode = match r.mode {
+                RigidbodyMode::Kinematics => 0,
+                RigidbodyMode::Dynamics => 1,
+                RigidbodyMode::DynamicsPassRotation => 2,
+            };
+            file.write_u8(mode).unwrap();
+        }
+    }
+
+    fn write_joints(&self, file: &mut Cursor<Vec<u8>>, rigidbody_index_size: u8) {
+        file.write_u32::<LE>(self.joints.len() as _).unwrap();
+        for j in &self.joints {
+            self.write_string(file, &j.name);
+            self.write_string(file, &j.name_en);
+            let kind = match j.kind {
+                JointKind::Spring6Dof => 0,
+                JointKind::SixDof => 1,
+                JointKind::P2p => 2,
+                JointKind::ConeTwist => 3,
+                JointKind::Slider => 4,
+                JointKind::Hinge => 5,
+            };
+            file.write_u8(kind).unwrap();
+            Pmx::write_int(file, j.rigidbody_a, rigidbody_index_size);
+            Pmx::write_int(file, j.rigidbody_b, rigidbody_index_size);
+            write_float3(file, j.pos);
+            write_float3(file, j.rot);
+            write_float3(file, j.pos_min);
+            write_float3(file, j.pos_max);
+            write_float3(file, j.rot_min);
+            write_float3(file, j.rot_max);
+            write_float3(file, j.pos_spring);
+            write_float3(file, j.rot_spring);
+        }
+    }
 
+    fn write_softbodys(
+        &self,
+        file: &mut Cursor<Vec<u8>>,
+        vertex_index_size: u8,
+        material_index_size: u8,
+        rigidbody_index_size: u8
+    ) {
+        file.write_u32::<LE>(self.softbodys.len() as _).unwrap();
+        for b in &self.softbodys {
+            self.write_string(file, &b.name);
+            self.write_string(file, &b.name_en);
+            let shape = match b.shape {
+                SoftbodyShape::TriMesh => 0,
+                SoftbodyShape::Rope => 1,
+            };
+            file.write_u8(shape).unwrap();
+            Pmx::write_int(file, b.mat, material_index_size);
+            file.write_u8(b.group).unwrap();
+            file.write_u16::<LE>(b.collision_group).unwrap();
+            file.write_u8(b.flags.bits()).unwrap();
+            file.write_i32::<LE>(b.b_link_distance).unwrap();
+            file.write_i32::<LE>(b.cluster_count).unwrap();
+            file.write_f32::<LE>(b.mass).unwrap();
+            file.write_f32::<LE>(b.collision_margin).unwrap();
+            let aero_model = match b.aero_model {
+                AeroModel::VPoint => 0,
+                AeroModel::VTwoSided => 1,
+                AeroModel::VOneSided => 2,
+                AeroModel::FTwoSided => 3,
+                AeroModel::FOneSided => 4,
+            };
+            file.write_i32::<LE>(aero_model).unwrap();
+            for v in [
+                b.vcf, b.dp, b.dg, b.lf, b.pr, b.vc, b.df, b.mt, b.chr, b.khr, b.shr, b.ahr,
+                b.srhr_cl, b.skhr_cl, b.sshr_cl, b.sr_splt_cl, b.sk_splt_cl, b.ss_splt_cl,
+            ] {
+                file.write_f32::<LE>(v).unwrap();
+            }
+            for v in [b.v_it, b.p_it, b.d_it, b.c_it] {
+                file.write_i32::<LE>(v).unwrap();
+            }
+            for v in [b.lst, b.ast, b.vst] {
+                file.write_f32::<LE>(v).unwrap();
+            }
+            file.write_i32::<LE>(b.anchors.len() as _).unwrap();
+            for a in &b.anchors {
+                Pmx::write_int(file, a.rigidbody, rigidbody_index_size);
+                Pmx::write_uint(file, a.vertex, vertex_index_size);
+                file.write_u8(if a.near_mode { 1 } else { 0 }).unwrap();
+            }
+            file.write_i32::<LE>(b.pin_verts.len() as _).unwrap();
+            for v in &b.pin_verts {
+                Pmx::write_uint(file, *v, vertex_index_size);
+            }
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    fn joint(a: i32, b: i32) -> Joint {
+        Joint {
+            name: "joint".into(),
+            name_en: "joint".into(),
+            kind: JointKind::Spring6Dof,
+            rigidbody_a: a,
+            rigidbody_b: b,
+            pos: vec3(1.5, 1.0, 0.0),
+            rot: vec3(0.0, 0.0, 0.5),
+            pos_min: Vec3::splat(-0.1),
+            pos_max: Vec3::splat(0.1),
+            rot_min: Vec3::splat(-1.0),
+            rot_max: Vec3::splat(1.0),
+            pos_spring: Vec3::ONE,
+            rot_spring: Vec3::splat(2.0),
+            uuid: uuid::Uuid::new_v4(),
+        }
+    }
+
+    /// A rope on the first material pinned to the first vertex and anchored to rigidbody 1
+    fn softbody() -> Softbody {
+        Softbody {
+            name: "rope".into(),
+            name_en: "rope".into(),
+            shape: SoftbodyShape::Rope,
+            mat: 0,
+            group: 2,
+            collision_group: 0xfffe,
+            flags: SoftbodyFlags::B_LINK | SoftbodyFlags::CLUSTER,
+            b_link_distance: 2,
+            cluster_count: 4,
+            mass: 1.5,
+            collision_margin: 0.05,
+            aero_model: AeroModel::FOneSided,
+            vcf: 1.0,
+            dp: 0.1,
+            dg: 0.0,
+            lf: 0.0,
+            pr: 0.0,
+            vc: 0.0,
+            df: 0.2,
+            mt: 0.0,
+            chr: 1.0,
+            khr: 0.1,
+            shr: 1.0,
+            ahr: 0.7,
+            srhr_cl: 0.1,
+            skhr_cl: 1.0,
+            sshr_cl: 0.5,
+            sr_splt_cl: 0.5,
+            sk_splt_cl: 0.5,
+            ss_splt_cl: 0.5,
+            v_it: 0,
+            p_it: 1,
+            d_it: 0,
+            c_it: 4,
+            lst: 1.0,
+            ast: 0.5,
+            vst: 0.25,
+            anchors: vec![SoftbodyAnchor { rigidbody: 1, vertex: 0, near_mode: true }],
+            pin_verts: vec![0],
+            uuid: uuid::Uuid::new_v4(),
+        }
+    }
+
+    /// The skeleton sample with a second rigidbody on the elbow joined to the arm
+    fn physics_model() -> Pmx {
+        let mut m = skeleton_model();
+        let mut elbow = rigidbody("elbow", 3);
+        elbow.mode = RigidbodyMode::DynamicsPassRotation;
+        elbow.shape = RigidbodyShape::Box;
+        elbow.pos = vec3(2.0, 1.0, 0.0);
+        m.rigidbodys.push(elbow);
+        m.joints.push(joint(0, 1));
+        m
+    }
+
+    #[test]
+    fn write_read_write_is_identical() {
+        let m = physics_model();
+        let written = m.write();
+        let read = Pmx::read(written.clone(), "").unwrap();
+        assert_eq!(read.write(), written);
+    }
+
+    #[test]
+    fn rigidbodys_and_joints_are_written() {
+        let read = reread(&physics_model());
+        assert_eq!(read.rigidbodys.len(), 2);
+        let rb = &read.rigidbodys[1];
+        assert_eq!(rb.name, "elbow");
+        assert_eq!(rb.bone, 3);
+        assert!(rb.shape == RigidbodyShape::Box && rb.mode == RigidbodyMode::DynamicsPassRotation);
+        assert_eq!(rb.pos, vec3(2.0, 1.0, 0.0));
+        assert_eq!(read.joints.len(), 1);
+        let j = &read.joints[0];
+        assert_eq!((j.rigidbody_a, j.rigidbody_b), (0, 1));
+        assert_eq!(j.kind, JointKind::Spring6Dof);
+        assert_eq!(j.rot_spring, Vec3::splat(2.0));
+    }
+
+    #[test]
+    fn pmx21_physics_is_written() {
+        let mut m = physics_model();
+        assert_eq!(m.required_version(), 2.0);
+        m.joints[0].kind = JointKind::Hinge;
+        assert_eq!(m.required_version(), 2.1);
+        m.softbodys.push(softbody());
+
+        let written = m.write();
+        let read = Pmx::read(written.clone(), "").unwrap();
+        assert_eq!(read.header.version, 2.1);
+        assert_eq!(read.required_version(), 2.1);
+        assert_eq!(read.joints[0].kind, JointKind::Hinge);
+        assert_eq!(read.softbodys.len(), 1);
+        let b = &read.softbodys[0];
+        assert_eq!(b.name, "rope");
+        assert!(matches!(b.shape, SoftbodyShape::Rope) && matches!(b.aero_model, AeroModel::FOneSided));
+        assert_eq!((b.mat, b.group, b.collision_group), (0, 2, 0xfffe));
+        assert_eq!(b.flags.bits(), (SoftbodyFlags::B_LINK | SoftbodyFlags::CLUSTER).bits());
+        assert_eq!((b.b_link_distance, b.cluster_count, b.c_it), (2, 4, 4));
+        assert_eq!((b.mass, b.ahr, b.vst), (1.5, 0.7, 0.25));
+        let anchors: Vec<(i32, i32, bool)> = b.anchors.iter().map(|a| (a.rigidbody, a.vertex, a.near_mode)).collect();
+        assert_eq!(anchors, [(1, 0, true)]);
+        assert_eq!(b.pin_verts, [0]);
+        assert_eq!(read.write(), written);
+
+        // a joint alone is enough to need 2.1
+        m.softbodys.clear();
+        assert_eq!(reread(&m).header.version, 2.1);
+    }
+
+    #[test]
+    fn display_frames_are_written_as_they_are() {
+        let mut m = skeleton_model();
+        m.display_frames.drain(..2);
+        let read = reread(&m);
+        assert_eq!(read.display_frames.len(), 1);
+        assert_eq!(read.display_frames[0].name, "arm");
+
+        let read = reread(&Pmx::new());
+        let frames: Vec<(&str, bool, usize)> = read.display_frames.iter().map(|f| (f.name.as_str(), f.special, f.morph_items.len())).collect();
+        assert_eq!(frames, [("Root", true, 0), ("表情", true, 0)]);
+    }
+
+    #[test]
+    fn header_settings_are_kept() {
+        for utf8 in [false, true] {
+            for size in [1, 2, 4] {
+                let mut m = physics_model();
+                m.name = "モデル".into();
+                m.header = PmxHeader {
+                    utf8,
+                    vertex_index_size: size,
+                    texture_index_size: size,
+                    material_index_size: size,
+                    bone_index_size: size,
+                    morph_index_size: size,
+                    rigidbody_index_size: size,
+                    ..Default::default()
+                };
+                let written = m.write();
+                let read = Pmx::read(written.clone(), "").unwrap();
+                let h = read.header;
+                assert_eq!(h.utf8, utf8);
+                assert_eq!(
+                    [h.vertex_index_size, h.texture_index_size, h.material_index_size, h.bone_index_size, h.morph_index_size, h.rigidbody_index_size],
+                    [size; 6],
+                );
+                assert_eq!(read.name, "モデル");
+                assert_eq!(read.write(), written, "utf8 {} index size {}", utf8, size);
+            }
+        }
+    }
+}
@@ -6,7 +6,7 @@ use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::io::{prelude::*, Cursor};
+use std::io::{self, prelude::*, Cursor};
 use std::path::Path;
 use encoding::{Encoding, DecoderTrap};
 use encoding::all::WINDOWS_31J;
@@ -22,24 +22,24 @@ use super::motion::*;
 const VERSION_1: &str = "Vocaloid Motion Data file";
 pub const VERSION_2: &str = "Vocaloid Motion Data 0002";
 
-pub fn read_string<T>(file: &mut T, len: usize) -> String
+pub fn read_string<T>(file: &mut T, len: usize) -> io::Result<String>
         where T: Read {
     let mut string_raw = vec![0u8; len];
-    file.read(&mut string_raw).unwrap();
-    read_string_raw(&string_raw)
+    file.read_exact(&mut string_raw)?;
+    Ok(read_string_raw(&string_raw))
 }
 
 fn read_string_raw(string_raw: &[u8]) -> String {
-    WINDOWS_31J.decode(string_raw, DecoderTrap::Ignore).unwrap()
-        .split('\0').next().unwrap()
+    WINDOWS_31J.decode(string_raw, DecoderTrap::Ignore).unwrap_or_default()
+        .split('\0').next().unwrap_or_default()
         .to_string()
 }
 
-fn read_string_as_u128(file: &mut Cursor<Vec<u8>>) -> u128 {
+fn read_string_as_u128(file: &mut Cursor<Vec<u8>>) -> io::Result<u128> {
     let mut name: [u128; 1] = [0];
     let name_ref: &mut [u8] = bytemuck::cast_slice_mut(&mut name);
-    file.read(&mut name_ref[0..15]).unwrap();
-    name[0]
+    file.read_exact(&mut name_ref[0..15])?;
+    Ok(name[0])
 }
 
 fn case_string_from_u128(string_raw: u128) -> String {
@@ -48,123 +48,149 @@ fn case_string_from_u128(string_raw: u128) -> String {
     read_string_raw(&name[0..15])
 }
 
-pub fn read_bezier_control_point_pair4(file: &mut Cursor<Vec<u8>>) -> Vec4 {
-    let x = (file.read_u32::<LittleEndian>().unwrap() & 0xFF) as f32 / 127f32;
-    let y = (file.read_u32::<LittleEndian>().unwrap() & 0xFF) as f32 / 127f32;
-    let z = (file.read_u32::<LittleEndian>().unwrap() & 0xFF) as f32 / 127f32;
-    let w = (file.read_u32::<LittleEndian>().unwrap() & 0xFF) as f32 / 127f32;
-    vec4(x, y, z, w)
+pub fn read_bezier_control_point_pair4(file: &mut Cursor<Vec<u8>>) -> io::Result<Vec4> {
+    let x = (file.read_u32::<LittleEndian>()? & 0xFF) as f32 / 127f32;
+    let y = (file.read_u32::<LittleEndian>()? & 0xFF) as f32 / 127f32;
+    let z = (file.read_u32::<LittleEndian>()? & 0xFF) as f32 / 127f32;
+    let w = (file.read_u32::<LittleEndian>()? & 0xFF) as f32 / 127f32;
+    Ok(vec4(x, y, z, w))
 }
 
-pub fn read_bezier_control_point_pair1<T>(file: &mut T) -> Vec4
+pub fn read_bezier_control_point_pair1<T>(file: &mut T) -> io::Result<Vec4>
     where T: Read {
-    let x = file.read_u8().unwrap() as f32 / 127f32;
-    let y = file.read_u8().unwrap() as f32 / 127f32;
-    let z = file.read_u8().unwrap() as f32 / 127f32;
-    let w = file.read_u8().unwrap() as f32 / 127f32;
-    vec4(x, y, z, w)
+    let x = file.read_u8()? as f32 / 127f32;
+    let y = file.read_u8()? as f32 / 127f32;
+    let z = file.read_u8()? as f32 / 127f32;
+    let w = file.read_u8()? as f32 / 127f32;
+    Ok(vec4(x, y, z, w))
 }
 
-pub fn read_header(mut file: &mut Cursor<Vec<u8>>) -> String {
-    let header_string = read_string(&mut file, 30);
+/// Reads a u32 count followed by that many records, reporting the index of the record that failed
+pub fn read_records<T, F>(file: &mut Cursor<Vec<u8>>, section: MotionSection, mut f: F) -> Result<Vec<T>, MotionError>
+    where F: FnMut(&mut Cursor<Vec<u8>>) -> io::Result<T> {
+    let count = file.read_u32::<LittleEndian>().map_err(|e| MotionError::new(section, None, e))? as usize;
+    let remaining = (file.get_ref().len() as u64).saturating_sub(file.position()) as usize;
+    let mut items = Vec::with_capacity(count.min(remaining));
+    for i in 0..count {
+        items.push(f(file).map_err(|e| MotionError::new(section, Some(i), e))?);
+    }
+    Ok(items)
+}
+
+/// Same as `read_records`, but a file that already ended before the section counts as empty
+pub fn read_trailing_records<T, F>(file: &mut Cursor<Vec<u8>>, section: MotionSection, f: F) -> Result<Vec<T>, MotionError>
+    where F: FnMut(&mut Cursor<Vec<u8>>) -> io::Result<T> {
+    if file.position() >= file.get_ref().len() as u64 {
+        return Ok(Vec::new());
+    }
+    read_records(file, section, f)
+}
+
+pub fn read_header(mut file: &mut Cursor<Vec<u8>>) -> Result<String, MotionError> {
+    let header = |e| MotionError::new(MotionSection::Header, None, e);
+    let header_string = read_string(&mut file, 30).map_err(header)?;
 
     if header_string.starts_with(VERSION_1) {
-        read_string(&mut file, 10)
+        read_string(&mut file, 10).map_err(header)
     } else if header_string.starts_with(VERSION_2) {
-        read_string(&mut file, 20)
+        read_string(&mut file, 20).map_err(header)
     } else {
-        unreachable!()
+        Err(MotionError::invalid(
+            MotionSection::Header,
+            None,
+            format!("unknown VMD header {:?}", header_string),
+        ))
     }
 }
 
-pub fn read_bone_keyframe(mut file: &mut Cursor<Vec<u8>>) -> (u128, BoneKeyframe) {
-    let name = read_string_as_u128(file);
+pub fn read_bone_keyframe(mut file: &mut Cursor<Vec<u8>>) -> io::Result<(u128, BoneKeyframe)> {
+    let name = read_string_as_u128(file)?;
     let keyframe = BoneKeyframe {
-        frame: file.read_u32::<LittleEndian>().unwrap(),
-        trans: read_float3(&mut file),
-        rot: read_quat(&mut file),
-        txc: read_bezier_control_point_pair4(&mut file),
-        tyc: read_bezier_control_point_pair4(&mut file),
-        tzc: read_bezier_control_point_pair4(&mut file),
-        rc:  read_bezier_control_point_pair4(&mut file),
+        frame: file.read_u32::<LittleEndian>()?,
+        trans: read_float3(&mut file)?,
+        rot: read_quat(&mut file)?,
+        txc: read_bezier_control_point_pair4(file)?,
+        tyc: read_bezier_control_point_pair4(file)?,
+        tzc: read_bezier_control_point_pair4(file)?,
+        rc:  read_bezier_control_point_pair4(file)?,
     };
-    (name, keyframe)
-}
-
-pub fn read_camera_keyframe(mut file: &mut Cursor<Vec<u8>>) -> CameraKeyframe {
-    CameraKeyframe {
-        frame: file.read_u32::<LittleEndian>().unwrap(),
-        dist: file.read_f32::<LittleEndian>().unwrap(),
-        trans: read_float3(&mut file),
-        rot: read_float3(&mut file),
-        txc: read_bezier_control_point_pair1(&mut file),
-        tyc: read_bezier_control_point_pair1(&mut file),
-        tzc: read_bezier_control_point_pair1(&mut file),
-        rc:  read_bezier_control_point_pair1(&mut file),
-        dc:  read_bezier_control_point_pair1(&mut file),
-        vc : read_bezier_control_point_pair1(&mut file),
-        fov: file.read_u32::<LittleEndian>().unwrap(),
-        perspective: file.read_u8().unwrap() == 0,
-    }
+    Ok((name, keyframe))
+}
+
+pub fn read_camera_keyframe(mut file: &mut Cursor<Vec<u8>>) -> io::Result<CameraKeyframe> {
+    Ok(CameraKeyframe {
+        frame: file.read_u32::<LittleEndian>()?,
+        dist: file.read_f32::<LittleEndian>()?,
+        trans: read_float3(&mut file)?,
+        rot: read_float3(&mut file)?,
+        txc: read_bezier_control_point_pair1(&mut file)?,
+        tyc: read_bezier_control_point_pair1(&mut file)?,
+        tzc: read_bezier_control_point_pair1(&mut file)?,
+        rc:  read_bezier_control_point_pair1(&mut file)?,
+        dc:  read_bezier_control_point_pair1(&mut file)?,
+        vc : read_bezier_control_point_pair1(&mut file)?,
+        fov: file.read_u32::<LittleEndian>()?,
+        perspective: file.read_u8()? == 0,
+    })
 }
 
-pub fn read_morph_keyframe(mut file: &mut Cursor<Vec<u8>>) -> (String, MorphKeyframe) {
-    let name = read_string(&mut file, 15);
+pub fn read_morph_keyframe(mut file: &mut Cursor<Vec<u8>>) -> io::Result<(String, MorphKeyframe)> {
+    let name = read_string(&mut file, 15)?;
     let keyframe =  MorphKeyframe {
-        frame: file.read_u32::<LittleEndian>().unwrap(),
-        weight: file.read_f32::<LittleEndian>().unwrap(),
+        frame: file.read_u32::<LittleEndian>()?,
+        weight: file.read_f32::<LittleEndian>()?,
     };
-    (name, keyframe)
+    Ok((name, keyframe))
 }
 
-pub fn read_light_keyframe(file: &mut Cursor<Vec<u8>>) -> LightKeyframe {
-    LightKeyframe {
-        frame: file.read_u32::<LittleEndian>().unwrap(),
-        color: read_float3(file),
-        direction: read_float3(file),
-    }
+pub fn read_light_keyframe(file: &mut Cursor<Vec<u8>>) -> io::Result<LightKeyframe> {
+    Ok(LightKeyframe {
+        frame: file.read_u32::<LittleEndian>()?,
+        color: read_float3(file)?,
+        direction: read_float3(file)?,
+    })
 }
 
-pub fn read_shadow_keyframe(file: &mut Cursor<Vec<u8>>) -> ShadowKeyframe {
-    ShadowKeyframe {
-        frame: file.read_u32::<LittleEndian>().unwrap(),
-        mode:  file.read_u8().unwrap(),
-        dist:  file.read_f32::<LittleEndian>().unwrap(),
-    }
+pub fn read_shadow_keyframe(file: &mut Cursor<Vec<u8>>) -> io::Result<ShadowKeyframe> {
+    Ok(ShadowKeyframe {
+        frame: file.read_u32::<LittleEndian>()?,
+        mode:  file.read_u8()?,
+        dist:  file.read_f32::<LittleEndian>()?,
+    })
 }
 
-pub fn read_ik_keyframe(file: &mut Cursor<Vec<u8>>) -> IkKeyframe {
-    let frame = file.read_u32::<LittleEndian>().unwrap();
-    let show = file.read_u8().unwrap() == 0;
-    let count = file.read_u32::<LittleEndian>().unwrap() as usize;
-    let mut infos = Vec::with_capacity(count);
+pub fn read_ik_keyframe(file: &mut Cursor<Vec<u8>>) -> io::Result<IkKeyframe> {
+    let frame = file.read_u32::<LittleEndian>()?;
+    let show = file.read_u8()? == 0;
+    let count = file.read_u32::<LittleEndian>()? as usize;
+    let mut infos = Vec::new();
     for _ in 0..count {
         infos.push((
-            read_string(file, 20),
-            file.read_u8().unwrap() == 1
+            read_string(file, 20)?,
+            file.read_u8()? == 1
         ));
     }
 
-    IkKeyframe {
+    Ok(IkKeyframe {
         frame,
         show,
         infos,
-    }
+    })
 }
 
 impl Motion {
-    pub fn read(content: Vec<u8>, path: &str) -> Motion {
+    pub fn read(content: Vec<u8>, path: &str) -> Result<Motion, MotionError> {
         let mut file = std::io::Cursor::new(content);
 
-        let model_name = read_header(&mut file);
+        let model_name = read_header(&mut file)?;
         let mut bone_keyframes: BTreeMap<String, Vec<BoneKeyframe>> = BTreeMap::new();
         {
-            let bone_keyframe_list = read_items(&mut file, read_bone_keyframe);
+            let bone_keyframe_list = read_records(&mut file, MotionSection::Bone, read_bone_keyframe)?;
             let mut bone_keyframes_inner: BTreeMap<u128, Vec<BoneKeyframe>> = BTreeMap::new();
 
             for (name, kf) in &bone_keyframe_list {
-                bone_keyframes_inner.entry(name.clone()).or_insert(vec![]);
-                bone_keyframes_inner.get_mut(name).unwrap().push(kf.clone());
+                bone_keyframes_inner.entry(*name).or_insert(vec![]);
+                bone_keyframes_inner.get_mut(name).unwrap().push(*kf);
             }
             for (string_raw, value) in bone_keyframes_inner {
                 let name = case_string_from_u128(string_raw);
@@ -173,21 +199,62 @@ impl Motion {
         }
         let mut morph_keyframes: BTreeMap<String, Vec<MorphKeyframe>> = BTreeMap::new();
         {
-            let morph_keyframe_list = read_items(&mut file, read_morph_keyframe);
+            let morph_keyframe_list = read_records(&mut file, MotionSection::Morph, read_morph_keyframe)?;
             for (name, kf) in &morph_keyframe_list {
                 morph_keyframes.entry(name.clone()).or_insert(vec![]);
-                morph_keyframes.get_mut(name).unwrap().push(kf.clone());
+                morph_keyframes.get_mut(name).unwrap().push(*kf);
             }
         }
-        Motion {
+        // older tools stop writing after the morph section, missing sections are treated as empty
+        Ok(Motion {
             model_name,
             bone_keyframes,
             morph_keyframes,
-            camera_keyframes: read_items(&mut file, read_camera_keyframe),
-            light_keyframes:  read_items(&mut file, read_light_keyframe),
-            shadow_keyframes: read_items(&mut file, read_shadow_keyframe),
-            ik_keyframes:     read_items(&mut file, read_ik_keyframe),
+            camera_keyframes: read_trailing_records(&mut file, MotionSection::Camera, read_camera_keyframe)?,
+            light_keyframes:  read_trailing_records(&mut file, MotionSection::Light, read_light_keyframe)?,
+            shadow_keyframes: read_trailing_records(&mut file, MotionSection::Shadow, read_shadow_keyframe)?,
+            ik_keyframes:     read_trailing_records(&mut file, MotionSection::Ik, read_ik_keyframe)?,
             path: path.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use super::super::vmd_writer::{write_bone_keyframe, write_morph_keyframe, write_string};
+
+    /// A bone and a morph keyframe followed by an empty camera section
+    fn vmd() -> Vec<u8> {
+        let mut file = vec![];
+        write_string(&mut file, &VERSION_2.to_string(), 30);
+        write_string(&mut file, &"モデル".to_string(), 20);
+        file.write_u32::<LittleEndian>(1).unwrap();
+        let bone = BoneKeyframe { frame: 3, trans: Vec3::Y, rot: Quat::IDENTITY, txc: LINEAR_CURVE, tyc: LINEAR_CURVE, tzc: LINEAR_CURVE, rc: LINEAR_CURVE };
+        write_bone_keyframe(&mut file, &"センター".to_string(), &bone);
+        file.write_u32::<LittleEndian>(1).unwrap();
+        write_morph_keyframe(&mut file, &"あ".to_string(), &MorphKeyframe { frame: 5, weight: 0.5 });
+        file.write_u32::<LittleEndian>(0).unwrap();
+        file
+    }
+
+    #[test]
+    fn reads_bone_and_morph_keyframes() {
+        let motion = Motion::read(vmd(), "").unwrap();
+        assert_eq!(motion.model_name, "モデル");
+        assert_eq!(motion.bone_keyframes["センター"][0].trans, Vec3::Y);
+        assert_eq!(motion.morph_keyframes["あ"][0].weight, 0.5);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let content = vmd();
+        // the file may end where the camera section would start
+        let morphs_end = content.len() - 4;
+        for len in 0..content.len() {
+            let motion = Motion::read(content[..len].to_vec(), "");
+            assert_eq!(motion.is_ok(), len == morphs_end, "{} bytes", len);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,201 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(unused_variables)]
+
+use std::collections::BTreeMap;
+use encoding::{Encoding, DecoderTrap, EncoderTrap};
+use encoding::all::WINDOWS_31J;
+use glam::*;
+
+use super::motion::*;
+
+const HEADER: &str = "Vocaloid Pose Data file";
+
+/// A single frame pose as stored in a VPD file, every bone and morph is a keyframe at frame 0
+pub struct Pose {
+    pub model_name: String,
+    pub bones: BTreeMap<String, BoneKeyframe>,
+    pub morphs: BTreeMap<String, MorphKeyframe>,
+}
+
+fn bone_keyframe(frame: u32, trans: Vec3, rot: Quat) -> BoneKeyframe {
+    BoneKeyframe {
+        frame,
+        trans,
+        rot,
+        txc: LINEAR_CURVE,
+        tyc: LINEAR_CURVE,
+        tzc: LINEAR_CURVE,
+        rc: LINEAR_CURVE,
+    }
+}
+
+fn parse_floats(line: &str, count: usize, section: MotionSection, index: usize) -> Result<Vec<f32>, MotionError> {
+    let values: Vec<f32> = line.trim_end_matches(';')
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| MotionError::invalid(section, Some(index), format!("{:?}: {}", line, e)))?;
+    if values.len() != count {
+        return Err(MotionError::invalid(section, Some(index), format!("expected {} values in {:?}", count, line)));
+    }
+    Ok(values)
+}
+
+impl Pose {
+    pub fn new() -> Pose {
+        Pose {
+            model_name: String::new(),
+            bones: BTreeMap::new(),
+            morphs: BTreeMap::new(),
+        }
+    }
+
+    pub fn read(content: Vec<u8>) -> Result<Pose, MotionError> {
+        let text = WINDOWS_31J.decode(&content, DecoderTrap::Replace).unwrap_or_default();
+        // comments run from "//" to the end of the line
+        let mut lines = text.lines()
+            .map(|l| l.split("//").next().unwrap_or_default().trim())
+            .filter(|l| !l.is_empty());
+
+        let header = |msg: String| MotionError::invalid(MotionSection::Header, None, msg);
+        match lines.next() {
+            Some(l) if l.starts_with(HEADER) => {},
+            l => return Err(header(format!("unknown VPD header {:?}", l.unwrap_or_default()))),
+        }
+        let model_name = lines.next().ok_or_else(|| header("missing model name".to_string()))?;
+        let model_name = model_name.trim_end_matches(';').trim_end_matches(".osm").to_string();
+        let bone_count = lines.next()
+            .and_then(|l| l.trim_end_matches(';').trim().parse::<usize>().ok())
+            .ok_or_else(|| header("missing bone count".to_string()))?;
+
+        let mut pose = Pose {
+            model_name,
+            bones: BTreeMap::new(),
+            morphs: BTreeMap::new(),
+        };
+        let mut bone_records = 0;
+        while let Some(line) = lines.next() {
+            let (kind, name) = line.split_once('{')
+                .ok_or_else(|| MotionError::invalid(MotionSection::Bone, None, format!("unexpected line {:?}", line)))?;
+            let name = name.trim().to_string();
+            if kind.starts_with("Bone") {
+                let index = pose.bones.len();
+                let missing = || MotionError::invalid(MotionSection::Bone, Some(index), "unexpected end of file".to_string());
+                let trans = parse_floats(lines.next().ok_or_else(missing)?, 3, MotionSection::Bone, index)?;
+                let rot = parse_floats(lines.next().ok_or_else(missing)?, 4, MotionSection::Bone, index)?;
+                let trans = vec3(trans[0], trans[1], trans[2]);
+                let rot = quat(rot[0], rot[1], rot[2], rot[3]);
+                pose.bones.insert(name, bone_keyframe(0, trans, rot));
+                bone_records += 1;
+            } else if kind.starts_with("Morph") {
+                let index = pose.morphs.len();
+                let missing = || MotionError::invalid(MotionSection::Morph, Some(index), "unexpected end of file".to_string());
+                let weight = parse_floats(lines.next().ok_or_else(missing)?, 1, MotionSection::Morph, index)?;
+                pose.morphs.insert(name, MorphKeyframe { frame: 0, weight: weight[0] });
+            } else {
+                return Err(MotionError::invalid(MotionSection::Bone, None, format!("unknown record {:?}", kind)));
+            }
+            if lines.next() != Some("}") {
+                return Err(MotionError::invalid(MotionSection::Bone, None, format!("record {:?} is not closed", line)));
+            }
+        }
+        if bone_records != bone_count {
+            return Err(header(format!("{} bones declared but {} found", bone_count, bone_records)));
+        }
+        Ok(pose)
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut text = String::new();
+        text += HEADER;
+        text += "\r\n\r\n";
+        text += &format!("{}.osm;\t\t// 親ファイル名\r\n", self.model_name);
+        text += &format!("{};\t\t\t\t// 総ポーズボーン数\r\n\r\n", self.bones.len());
+        for (i, (name, kf)) in self.bones.iter().enumerate() {
+            text += &format!("Bone{}{{{}\r\n", i, name);
+            text += &format!("  {:.6},{:.6},{:.6};\t\t\t\t// trans x,y,z\r\n", kf.trans.x, kf.trans.y, kf.trans.z);
+            text += &format!("  {:.6},{:.6},{:.6},{:.6};\t\t// Quaternion x,y,z,w\r\n", kf.rot.x, kf.rot.y, kf.rot.z, kf.rot.w);
+            text += "}\r\n\r\n";
+        }
+        for (i, (name, kf)) in self.morphs.iter().enumerate() {
+            text += &format!("Morph{}{{{}\r\n", i, name);
+            text += &format!("  {:.6};\t\t\t\t// weight\r\n", kf.weight);
+            text += "}\r\n\r\n";
+        }
+        WINDOWS_31J.encode(&text, EncoderTrap::Replace).unwrap()
+    }
+
+    /// Single frame motion holding the pose at frame 0
+    pub fn to_motion(&self) -> Motion {
+        let mut motion = Motion::new();
+        motion.model_name = self.model_name.clone();
+        for (name, kf) in &self.bones {
+            motion.bone_keyframes.insert(name.clone(), vec![*kf]);
+        }
+        for (name, kf) in &self.morphs {
+            motion.morph_keyframes.insert(name.clone(), vec![*kf]);
+        }
+        motion
+    }
+}
+
+impl Motion {
+    /// Pose of every bone and morph track at `frame`
+    pub fn pose_at(&self, frame: u32) -> Pose {
+        let mut pose = Pose::new();
+        pose.model_name = self.model_name.clone();
+        for name in self.bone_keyframes.keys() {
+            if let Some((trans, rot)) = self.sample_bone(name, frame as f32) {
+                pose.bones.insert(name.clone(), bone_keyframe(0, trans, rot));
+            }
+        }
+        for name in self.morph_keyframes.keys() {
+            if let Some(weight) = self.sample_morph(name, frame as f32) {
+                pose.morphs.insert(name.clone(), MorphKeyframe { frame: 0, weight });
+            }
+        }
+        pose
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vpd(bone_count: usize, body: &str) -> Vec<u8> {
+        let text = format!("{}\r\n\r\nモデル.osm;\t\t// 親ファイル名\r\n{};\t\t// 総ポーズボーン数\r\n\r\n{}", HEADER, bone_count, body);
+        WINDOWS_31J.encode(&text, EncoderTrap::Strict).unwrap()
+    }
+
+    const BODY: &str = "Bone0{右腕\r\n  0.000000,1.500000,0.000000;\t// trans x,y,z\r\n  0.000000,0.000000,0.600000,0.800000;\r\n}\r\n\r\n\
+        Morph0{まばたき\r\n  0.500000;\t// weight\r\n}\r\n";
+
+    #[test]
+    fn reads_shift_jis_records() {
+        let pose = Pose::read(vpd(1, BODY)).unwrap();
+        assert_eq!(pose.model_name, "モデル");
+        let arm = &pose.bones["右腕"];
+        assert_eq!(arm.trans, vec3(0.0, 1.5, 0.0));
+        assert_eq!(arm.rot, quat(0.0, 0.0, 0.6, 0.8));
+        assert_eq!(pose.morphs["まばたき"].weight, 0.5);
+
+        let reread = Pose::read(pose.write()).unwrap();
+        assert_eq!(reread.model_name, pose.model_name);
+        assert_eq!(reread.bones["右腕"].trans, arm.trans);
+        assert_eq!(reread.morphs["まばたき"].weight, 0.5);
+    }
+
+    #[test]
+    fn malformed_line_is_an_error() {
+        let body = BODY.replace("1.500000", "1.5x");
+        assert!(matches!(Pose::read(vpd(1, &body)), Err(MotionError { section: MotionSection::Bone, .. })));
+        let body = BODY.replace("Bone0{", "Bone0 ");
+        assert!(Pose::read(vpd(1, &body)).is_err());
+    }
+
+    #[test]
+    fn bone_count_has_to_match_the_records() {
+        assert!(matches!(Pose::read(vpd(2, BODY)), Err(MotionError { section: MotionSection::Header, .. })));
+    }
+}
@@ -0,0 +1,335 @@
+#![allow(dead_code)]
+
+use std::mem::size_of;
+
+use crate::format::motion::*;
+use crate::format::pmx::*;
+
+/// Default memory the undo history may use for snapshots, the latest edit is always kept
+pub const DEFAULT_BUDGET: usize = 512 * 1024 * 1024;
+
+/// State of a document before an edit, restoring it undoes the edit
+pub enum Snapshot {
+    Model(Box<Pmx>),
+    Motion(Option<Motion>),
+}
+
+impl Snapshot {
+    /// Approximate heap size, good enough to bound the history
+    pub fn size(&self) -> usize {
+        match self {
+            Snapshot::Model(pmx) => pmx_size(pmx),
+            Snapshot::Motion(motion) => motion.as_ref().map_or(0, motion_size),
+        }
+    }
+
+    /// Which document the snapshot is of, states of different documents are restored independently
+    fn kind(&self) -> usize {
+        match self {
+            Snapshot::Model(_) => 0,
+            Snapshot::Motion(_) => 1,
+        }
+    }
+}
+
+fn pmx_size(pmx: &Pmx) -> usize {
+    let morph_items: usize = pmx.morphs.iter().map(|m| match &m.data {
+        Morph::MorphGroup(items) => items.len() * size_of::<MorphGroupItem>(),
+        Morph::MorphFlip(items) => items.len() * size_of::<MorphFlipItem>(),
+        Morph::MorphVertex(items) => items.len() * size_of::<MorphVertexItem>(),
+        Morph::MorphBone(items) => items.len() * size_of::<MorphBoneItem>(),
+        Morph::MorphUv(items) => items.len() * size_of::<MorphUvItem>(),
+        Morph::MorphRigidbody(items) => items.len() * size_of::<MorphRigidbodyItem>(),
+        Morph::MorphMat(items) => items.len() * size_of::<MorphMatItem>(),
+    }).sum();
+    pmx.verts.len() * size_of::<Vertex>()
+        + pmx.appendix_uvs.iter().map(|uvs| uvs.len() * size_of::<glam::Vec4>()).sum::<usize>()
+        + pmx.faces.len() * size_of::<[u32; 3]>()
+        + pmx.mats.len() * size_of::<Mat>()
+        + pmx.bones.len() * size_of::<Bone>()
+        + pmx.morphs.len() * size_of::<MorphInfo>()
+        + morph_items
+        + pmx.rigidbodys.len() * size_of::<Rigidbody>()
+        + pmx.joints.len() * size_of::<Joint>()
+}
+
+fn motion_size(motion: &Motion) -> usize {
+    motion.bone_keyframes.values().map(|v| v.len() * size_of::<BoneKeyframe>()).sum::<usize>()
+        + motion.morph_keyframes.values().map(|v| v.len() * size_of::<MorphKeyframe>()).sum::<usize>()
+        + motion.camera_keyframes.len() * size_of::<CameraKeyframe>()
+        + motion.light_keyframes.len() * size_of::<LightKeyframe>()
+        + motion.shadow_keyframes.len() * size_of::<ShadowKeyframe>()
+        + motion.ik_keyframes.len() * size_of::<IkKeyframe>()
+}
+
+/// An edit that can be undone, holding the states it replaced
+pub struct Command {
+    pub label: String,
+    pub states: Vec<Snapshot>,
+    size: usize,
+}
+
+impl Command {
+    fn new(label: &str, states: Vec<Snapshot>) -> Command {
+        let size = states.iter().map(|s| s.size()).sum();
+        Command { label: label.to_string(), states, size }
+    }
+}
+
+/// Undo and redo stacks of snapshots. The oldest commands are dropped when the snapshots
+/// take more memory than the budget.
+pub struct History {
+    pub undo: Vec<Command>,
+    pub redo: Vec<Command>,
+    pub budget: usize,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            budget: DEFAULT_BUDGET,
+        }
+    }
+
+    /// Records an edit with the states from before it, forgetting what could be redone
+    pub fn push(&mut self, label: &str, states: Vec<Snapshot>) {
+        self.redo.clear();
+        self.undo.push(Command::new(label, states));
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        let mut size: usize = self.undo.iter().chain(&self.redo).map(|c| c.size).sum();
+        while size > self.budget && self.undo.len() > 1 {
+            size -= self.undo.remove(0).size;
+        }
+    }
+
+    /// Undoes the last command. `restore` puts the given states back and returns the ones it replaced,
+    /// which are kept for redo.
+    pub fn undo(&mut self, restore: impl FnOnce(Vec<Snapshot>) -> Vec<Snapshot>) -> bool {
+        self.undo_steps(1, restore)
+    }
+
+    /// Redoes the last undone command, see `undo`
+    pub fn redo(&mut self, restore: impl FnOnce(Vec<Snapshot>) -> Vec<Snapshot>) -> bool {
+        self.redo_steps(1, restore)
+    }
+
+    /// Undoes the last `steps` commands, calling `restore` once with the state they lead back to
+    pub fn undo_steps(&mut self, steps: usize, restore: impl FnOnce(Vec<Snapshot>) -> Vec<Snapshot>) -> bool {
+        travel(&mut self.undo, &mut self.redo, steps, restore)
+    }
+
+    /// Redoes the last `steps` undone commands, see `undo_steps`
+    pub fn redo_steps(&mut self, steps: usize, restore: impl FnOnce(Vec<Snapshot>) -> Vec<Snapshot>) -> bool {
+        let done = travel(&mut self.redo, &mut self.undo, steps, restore);
+        self.trim();
+        done
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Forgets the model snapshots, commands that only recorded the model are dropped
+    pub fn clear_models(&mut self) {
+        for commands in [&mut self.undo, &mut self.redo] {
+            for command in commands.iter_mut() {
+                command.states.retain(|s| !matches!(s, Snapshot::Model(_)));
+                command.size = command.states.iter().map(|s| s.size()).sum();
+            }
+            commands.retain(|c| !c.states.is_empty());
+        }
+    }
+}
+
+/// Moves the last `steps` commands of `from` onto `to`. Only the oldest state of every document is
+/// restored; each moved command takes the state of the next moved command of the same document,
+/// or the state `restore` replaced.
+fn travel(from: &mut Vec<Command>, to: &mut Vec<Command>, steps: usize,
+          restore: impl FnOnce(Vec<Snapshot>) -> Vec<Snapshot>) -> bool {
+    let steps = steps.min(from.len());
+    if steps == 0 {
+        return false;
+    }
+    let mut oldest: Vec<Option<Snapshot>> = vec![None, None];
+    let mut moved = Vec::new();
+    for command in from.drain(from.len() - steps..).rev() {
+        let states: Vec<(usize, Option<Snapshot>)> = command.states.into_iter().map(|s| {
+            let kind = s.kind();
+            (kind, oldest[kind].replace(s))
+        }).collect();
+        moved.push((command.label, states));
+    }
+    let mut replaced: Vec<Option<Snapshot>> = vec![None, None];
+    for s in restore(oldest.into_iter().flatten().collect()) {
+        let kind = s.kind();
+        replaced[kind] = Some(s);
+    }
+    for (label, states) in moved {
+        let states = states.into_iter().filter_map(|(kind, s)| s.or_else(|| replaced[kind].take())).collect();
+        to.push(Command::new(&label, states));
+    }
+    true
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A motion named `name` whose snapshot takes `size` light keyframes
+    fn motion(name: &str, size: usize) -> Snapshot {
+        let mut motion = Motion::new();
+        motion.model_name = name.to_string();
+        motion.light_keyframes = vec![LightKeyframe { frame: 0, color: glam::Vec3::ONE, direction: glam::Vec3::Y }; size];
+        Snapshot::Motion(Some(motion))
+    }
+
+    fn model(name: &str) -> Snapshot {
+        let mut pmx = Pmx::new();
+        pmx.name = name.to_string();
+        Snapshot::Model(Box::new(pmx))
+    }
+
+    /// Documents edited the way the app does, recording the states before every edit
+    struct Documents {
+        history: History,
+        model: String,
+        motion: String,
+        restores: usize,
+    }
+
+    impl Documents {
+        fn new() -> Documents {
+            Documents { history: History::new(), model: "m0".to_string(), motion: "v0".to_string(), restores: 0 }
+        }
+
+        fn edit(&mut self, model: Option<&str>, motion: Option<&str>) {
+            let mut states = Vec::new();
+            if let Some(name) = model {
+                states.push(self::model(&std::mem::replace(&mut self.model, name.to_string())));
+            }
+            if let Some(name) = motion {
+                states.push(self::motion(&std::mem::replace(&mut self.motion, name.to_string()), 1));
+            }
+            self.history.push("edit", states);
+        }
+
+        fn restore(model: &mut String, motion: &mut String, restores: &mut usize, states: Vec<Snapshot>) -> Vec<Snapshot> {
+            *restores += 1;
+            states.into_iter().map(|s| match s {
+                Snapshot::Model(pmx) => self::model(&std::mem::replace(model, pmx.name)),
+                Snapshot::Motion(m) => self::motion(&std::mem::replace(motion, m.unwrap().model_name), 1),
+            }).collect()
+        }
+
+        fn undo(&mut self, steps: usize) -> bool {
+            let (model, motion, restores) = (&mut self.model, &mut self.motion, &mut self.restores);
+            self.history.undo_steps(steps, |states| Documents::restore(model, motion, restores, states))
+        }
+
+        fn redo(&mut self, steps: usize) -> bool {
+            let (model, motion, restores) = (&mut self.model, &mut self.motion, &mut self.restores);
+            self.history.redo_steps(steps, |states| Documents::restore(model, motion, restores, states))
+        }
+
+        fn state(&self) -> (&str, &str) {
+            (&self.model, &self.motion)
+        }
+    }
+
+    fn labels(commands: &[Command]) -> Vec<&str> {
+        commands.iter().map(|c| c.label.as_str()).collect()
+    }
+
+    #[test]
+    fn push_records_and_forgets_redo() {
+        let mut h = History::new();
+        h.push("a", vec![motion("a", 1)]);
+        h.push("b", vec![motion("b", 1)]);
+        assert_eq!(labels(&h.undo), vec!["a", "b"]);
+        assert!(h.undo(|states| states));
+        assert_eq!(labels(&h.redo), vec!["b"]);
+        h.push("c", vec![motion("c", 1)]);
+        assert_eq!(labels(&h.undo), vec!["a", "c"]);
+        assert!(h.redo.is_empty());
+    }
+
+    #[test]
+    fn oldest_commands_are_dropped_over_the_budget() {
+        let mut h = History::new();
+        h.budget = 3 * motion("", 10).size();
+        for label in ["a", "b", "c", "d"] {
+            h.push(label, vec![motion(label, 10)]);
+        }
+        assert_eq!(labels(&h.undo), vec!["b", "c", "d"]);
+        // the latest edit is kept even when it alone is over the budget
+        h.push("e", vec![motion("e", 100)]);
+        assert_eq!(labels(&h.undo), vec!["e"]);
+    }
+
+    #[test]
+    fn undo_and_redo_swap_the_states() {
+        let mut d = Documents::new();
+        d.edit(Some("m1"), None);
+        d.edit(None, Some("v1"));
+        d.edit(Some("m2"), Some("v2"));
+        assert!(d.undo(1));
+        assert_eq!(d.state(), ("m1", "v1"));
+        assert!(d.undo(1));
+        assert_eq!(d.state(), ("m1", "v0"));
+        assert!(d.redo(1));
+        assert_eq!(d.state(), ("m1", "v1"));
+        assert!(d.redo(1));
+        assert_eq!(d.state(), ("m2", "v2"));
+        assert!(!d.redo(1));
+        assert_eq!(d.restores, 4);
+    }
+
+    #[test]
+    fn several_steps_restore_once() {
+        let mut d = Documents::new();
+        d.edit(Some("m1"), None);
+        d.edit(None, Some("v1"));
+        d.edit(Some("m2"), Some("v2"));
+        d.edit(Some("m3"), None);
+        assert!(d.undo(3));
+        assert_eq!(d.state(), ("m1", "v0"));
+        assert_eq!(d.restores, 1);
+        // stepping back one at a time passes the same states as the jump skipped
+        assert!(d.redo(1));
+        assert_eq!(d.state(), ("m1", "v1"));
+        assert!(d.redo(1));
+        assert_eq!(d.state(), ("m2", "v2"));
+        assert!(d.undo(2));
+        assert_eq!(d.state(), ("m1", "v0"));
+        assert!(d.redo(10));
+        assert_eq!(d.state(), ("m3", "v2"));
+        assert_eq!(d.restores, 5);
+        assert!(d.undo(4));
+        assert_eq!(d.state(), ("m0", "v0"));
+        assert!(!d.undo(1));
+    }
+
+    #[test]
+    fn clearing_models_keeps_motion_edits() {
+        let mut d = Documents::new();
+        d.edit(Some("m1"), None);
+        d.edit(None, Some("v1"));
+        d.edit(Some("m2"), Some("v2"));
+        d.history.clear_models();
+        assert_eq!(d.history.undo.len(), 2);
+        assert!(d.undo(2));
+        assert_eq!(d.state(), ("m2", "v0"));
+    }
+}
@@ -0,0 +1,252 @@
+#![allow(dead_code)]
+
+use std::f32::consts::{PI, TAU};
+
+use glam::*;
+
+use crate::format::pmx::*;
+use crate::skeleton::Skeleton;
+
+/// Links closer to the target than this angle are left alone, 1.0e-3 degrees as in MMD
+const MIN_ANGLE: f32 = 1.0e-3 * PI / 180.0;
+
+/// Solver state of an IK link kept across iterations
+#[derive(Copy, Clone, Default)]
+struct LinkState {
+    prev_angle: Vec3,
+    plane_angle: f32,
+}
+
+fn diff_angle(a: f32, b: f32) -> f32 {
+    let diff = a.rem_euclid(TAU) - b.rem_euclid(TAU);
+    if diff > PI {
+        diff - TAU
+    } else if diff < -PI {
+        diff + TAU
+    } else {
+        diff
+    }
+}
+
+/// Euler angles of `m` = Rx * Ry * Rz, of the equivalent solutions the one closest to `before`
+fn decompose(m: Mat3, before: Vec3) -> Vec3 {
+    let at = |r: usize, c: usize| m.col(c)[r];
+    let sy = at(0, 2).clamp(-1.0, 1.0);
+    let mut r = Vec3::ZERO;
+    r.y = sy.asin();
+    if 1.0 - sy.abs() < 1.0e-6 {
+        // gimbal lock, x and z rotate around the same axis so one of them is kept at 0
+        if before.x.sin().abs() < before.z.sin().abs() {
+            r.z = at(1, 0).atan2(at(1, 1));
+        } else {
+            r.x = at(2, 1).atan2(at(1, 1));
+        }
+    } else {
+        r.x = (-at(1, 2)).atan2(at(2, 2));
+        r.z = (-at(0, 1)).atan2(at(0, 0));
+    }
+
+    let error = |v: Vec3| {
+        diff_angle(v.x, before.x).abs() + diff_angle(v.y, before.y).abs() + diff_angle(v.z, before.z).abs()
+    };
+    let mut best = r;
+    let mut min_error = error(r);
+    for x in [r.x + PI, r.x - PI] {
+        for y in [PI - r.y, -PI - r.y] {
+            for z in [r.z + PI, r.z - PI] {
+                let v = vec3(x, y, z);
+                let e = error(v);
+                if e < min_error {
+                    min_error = e;
+                    best = v;
+                }
+            }
+        }
+    }
+    best
+}
+
+/// The axis a link is limited to when the limits of the other two axes are zero, like knees
+fn plane_axis((min, max): (Vec3, Vec3)) -> Option<usize> {
+    let free = |a: usize| min[a] != 0.0 || max[a] != 0.0;
+    (0..3).find(|a| free(*a) && (0..3).filter(|b| b != a).all(|b| !free(b)))
+}
+
+impl Skeleton {
+    /// Solves `ik` with MMD's CCD. Every iteration rotates each link towards the target within
+    /// the per iteration limit angle and the link's euler limits, and stops as soon as the
+    /// effector no longer gets closer, keeping the closest result.
+    pub fn solve_ik(&mut self, pmx: &Pmx, ik: &Ik) {
+        let n = pmx.bones.len();
+        let index = |i: i32| usize::try_from(i).ok().filter(|i| *i < n);
+        let (Some(ik_bone), Some(effector)) = (index(ik.bone), index(ik.effector)) else {
+            return;
+        };
+        // links are listed from the effector towards the root of the chain
+        let links: Vec<(usize, Option<(Vec3, Vec3)>)> = ik.ik_joints.iter()
+            .filter_map(|j| index(j.bone).map(|b| (b, j.limit)))
+            .filter(|(b, _)| *b != effector)
+            .collect();
+        let Some(&(root, _)) = links.last() else {
+            return;
+        };
+        for (b, _) in &links {
+            self.ik_rot[*b] = Quat::IDENTITY;
+        }
+        self.update_subtree(pmx, root);
+
+        let mut states = vec![LinkState::default(); links.len()];
+        let mut best_dist = f32::MAX;
+        let mut best: Vec<Quat> = links.iter().map(|(b, _)| self.ik_rot[*b]).collect();
+        for iteration in 0..ik.loop_count.max(0) {
+            self.solve_ik_step(pmx, ik, ik_bone, effector, &links, &mut states, iteration);
+            let dist = self.position(effector).distance(self.position(ik_bone));
+            if dist < best_dist {
+                best_dist = dist;
+                best = links.iter().map(|(b, _)| self.ik_rot[*b]).collect();
+            } else {
+                for ((b, _), rot) in links.iter().zip(&best) {
+                    self.ik_rot[*b] = *rot;
+                }
+                self.update_subtree(pmx, root);
+                break;
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn solve_ik_step(&mut self, pmx: &Pmx, ik: &Ik, ik_bone: usize, effector: usize,
+                     links: &[(usize, Option<(Vec3, Vec3)>)], states: &mut [LinkState], iteration: i32) {
+        for ((b, limit), state) in links.iter().zip(states.iter_mut()) {
+            let b = *b;
+            let inv = self.global[b].inverse();
+            let ik_vec = inv.transform_point3(self.position(ik_bone)).normalize_or_zero();
+            let target_vec = inv.transform_point3(self.position(effector)).normalize_or_zero();
+            let angle = ik_vec.dot(target_vec).clamp(-1.0, 1.0).acos().min(ik.limit_angle);
+
+            if let Some((min, max)) = limit {
+                if let Some(axis) = plane_axis((*min, *max)) {
+                    // rotate around the single free axis towards whichever side gets closer
+                    let rotate_axis = Vec3::AXES[axis];
+                    let dot1 = (Quat::from_axis_angle(rotate_axis, angle) * target_vec).dot(ik_vec);
+                    let dot2 = (Quat::from_axis_angle(rotate_axis, -angle) * target_vec).dot(ik_vec);
+                    let mut new_angle = state.plane_angle + if dot1 > dot2 { angle } else { -angle };
+                    if iteration == 0 && (new_angle < min[axis] || new_angle > max[axis]) {
+                        // a bent knee starts out in the direction allowed by the limits
+                        let half = (min[axis] + max[axis]) * 0.5;
+                        if (-new_angle > min[axis] && -new_angle < max[axis])
+                            || (half - new_angle).abs() > (half + new_angle).abs() {
+                            new_angle = -new_angle;
+                        }
+                    }
+                    let new_angle = new_angle.max(min[axis]).min(max[axis]);
+                    state.plane_angle = new_angle;
+                    self.ik_rot[b] = Quat::from_axis_angle(rotate_axis, new_angle) * self.rot[b].inverse();
+                    self.update_subtree(pmx, b);
+                    continue;
+                }
+            }
+
+            if angle < MIN_ANGLE {
+                continue;
+            }
+            let axis = target_vec.cross(ik_vec).normalize_or_zero();
+            if axis == Vec3::ZERO {
+                continue;
+            }
+            let mut rot = self.ik_rot[b] * self.rot[b] * Quat::from_axis_angle(axis, angle);
+            if let Some((min, max)) = limit {
+                let euler = decompose(Mat3::from_quat(rot), state.prev_angle);
+                let clamped = euler.max(*min).min(*max);
+                let limit = Vec3::splat(ik.limit_angle);
+                let clamped = (clamped - state.prev_angle).max(-limit).min(limit) + state.prev_angle;
+                rot = Quat::from_euler(EulerRot::XYZ, clamped.x, clamped.y, clamped.z);
+                state.prev_angle = clamped;
+            }
+            self.ik_rot[b] = rot * self.rot[b].inverse();
+            self.update_subtree(pmx, b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::bone;
+
+    /// Knee limits as MMD models set them, the knee only bends one way around x
+    const KNEE_LIMIT: (Vec3, Vec3) = (Vec3::new(-PI, 0.0, 0.0), Vec3::new(-0.5 * PI / 180.0, 0.0, 0.0));
+
+    /// A straight leg: hip, knee and ankle one unit apart and an IK bone on the ankle
+    fn leg() -> Pmx {
+        let mut m = Pmx::new();
+        m.bones.push(bone("leg", None, vec3(0.0, 2.0, 0.0)));
+        m.bones.push(bone("knee", Some(0), Vec3::Y));
+        m.bones.push(bone("ankle", Some(1), Vec3::ZERO));
+        m.bones.push(bone("leg IK", None, Vec3::ZERO));
+        m.bones[3].bone_flags |= BoneFlags::IK;
+        m.iks.push(Ik {
+            bone: 3,
+            effector: 2,
+            loop_count: 40,
+            limit_angle: 2.0,
+            ik_joints: vec![IkJoint { bone: 1, limit: Some(KNEE_LIMIT) }, IkJoint { bone: 0, limit: None }],
+        });
+        m
+    }
+
+    /// Knee rotation as euler angles after solving the leg for the ankle at `target`
+    fn solve(target: Vec3, loop_count: i32) -> (Skeleton, Vec3) {
+        let mut m = leg();
+        m.iks[0].loop_count = loop_count;
+        let mut s = Skeleton::new(&m);
+        s.trans[3] = target;
+        s.evaluate(&m);
+        let (x, y, z) = (s.ik_rot[1] * s.rot[1]).to_euler(EulerRot::XYZ);
+        (s, vec3(x, y, z))
+    }
+
+    fn assert_within_limit(knee: Vec3) {
+        let (min, max) = KNEE_LIMIT;
+        assert!(knee.cmpge(min - 1e-4).all() && knee.cmple(max + 1e-4).all(), "knee at {}", knee);
+    }
+
+    #[test]
+    fn knee_reaches_the_target_within_its_limits() {
+        for target in [vec3(0.0, 0.6, 0.4), vec3(0.0, 0.6, -0.4)] {
+            let (s, knee) = solve(target, 40);
+            assert!(s.position(2).distance(target) < 1e-2, "ankle at {} for {}", s.position(2), target);
+            assert_within_limit(knee);
+        }
+    }
+
+    #[test]
+    fn straight_knee_bends_the_allowed_way() {
+        // towards -z the knee would first bend with a positive angle, which the limits forbid;
+        // instead of sticking at the limit it bends by the same angle the allowed way
+        let (_, knee) = solve(vec3(0.0, 0.6, -0.4), 1);
+        assert_within_limit(knee);
+        assert!(knee.x < -0.5, "knee at {}", knee);
+    }
+
+    #[test]
+    fn inheriting_bones_follow_the_solved_links() {
+        let mut m = leg();
+        m.bones.push(bone("knee follower", None, Vec3::X));
+        m.bones[4].inherit = Some((1, 1.0));
+        m.bones[4].bone_flags |= BoneFlags::INHERIT_ROTATION;
+        let mut s = Skeleton::new(&m);
+        s.trans[3] = vec3(0.0, 0.6, 0.4);
+        s.evaluate(&m);
+        let knee = s.ik_rot[1] * s.rot[1];
+        assert!(knee.angle_between(Quat::IDENTITY) > 0.1);
+        assert!(s.global[4].abs_diff_eq(Mat4::from_rotation_translation(knee, Vec3::X), 1e-5));
+    }
+
+    #[test]
+    fn single_free_axis_is_a_plane_axis() {
+        assert_eq!(plane_axis(KNEE_LIMIT), Some(0));
+        assert_eq!(plane_axis((Vec3::ZERO, vec3(0.0, 0.0, 1.0))), Some(2));
+        assert_eq!(plane_axis((Vec3::splat(-1.0), Vec3::splat(1.0))), None);
+    }
+}
@@ -9,4 +9,10 @@ mod camera;
 mod grid;
 mod texture;
 mod misc;
+mod skeleton;
+mod ik;
+mod deform;
+mod morph;
+mod playback;
+mod history;
 pub use app::TemplateApp;
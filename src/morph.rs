@@ -0,0 +1,368 @@
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use glam::*;
+
+use crate::format::pmx::*;
+use crate::skeleton::Skeleton;
+
+/// Material parameters touched by material morphs, the tints apply to the sampled texture,
+/// sphere and toon colors
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MatParams {
+    pub diffuse: Vec4,
+    pub specular: Vec3,
+    pub specularity: f32,
+    pub ambient: Vec3,
+    pub edge_color: Vec4,
+    pub edge_size: f32,
+    pub texture_tint: Vec4,
+    pub environment_tint: Vec4,
+    pub toon_tint: Vec4,
+}
+
+impl MatParams {
+    fn splat(v: f32) -> MatParams {
+        MatParams {
+            diffuse: Vec4::splat(v),
+            specular: Vec3::splat(v),
+            specularity: v,
+            ambient: Vec3::splat(v),
+            edge_color: Vec4::splat(v),
+            edge_size: v,
+            texture_tint: Vec4::splat(v),
+            environment_tint: Vec4::splat(v),
+            toon_tint: Vec4::splat(v),
+        }
+    }
+
+    fn of_item(item: &MorphMatItem) -> MatParams {
+        MatParams {
+            diffuse: item.diffuse,
+            specular: item.specular,
+            specularity: item.specularity,
+            ambient: item.ambient,
+            edge_color: item.edge_color,
+            edge_size: item.edge_size,
+            texture_tint: item.texture_tint,
+            environment_tint: item.environment_tint,
+            toon_tint: item.toon_tint,
+        }
+    }
+}
+
+/// Combined effect of the material morphs on one material, each parameter becomes `base * mul + add`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MatMorph {
+    pub mul: MatParams,
+    pub add: MatParams,
+}
+
+impl MatMorph {
+    fn blend(&mut self, item: &MorphMatItem, weight: f32) {
+        let p = MatParams::of_item(item);
+        match item.blend_mode {
+            BlendMode::Add => {
+                let add = &mut self.add;
+                add.diffuse += p.diffuse * weight;
+                add.specular += p.specular * weight;
+                add.specularity += p.specularity * weight;
+                add.ambient += p.ambient * weight;
+                add.edge_color += p.edge_color * weight;
+                add.edge_size += p.edge_size * weight;
+                add.texture_tint += p.texture_tint * weight;
+                add.environment_tint += p.environment_tint * weight;
+                add.toon_tint += p.toon_tint * weight;
+            }
+            _ => {
+                // a multiply morph at weight w scales by 1 + (value - 1) * w
+                let mul = &mut self.mul;
+                mul.diffuse *= Vec4::ONE.lerp(p.diffuse, weight);
+                mul.specular *= Vec3::ONE.lerp(p.specular, weight);
+                mul.specularity *= 1.0 + (p.specularity - 1.0) * weight;
+                mul.ambient *= Vec3::ONE.lerp(p.ambient, weight);
+                mul.edge_color *= Vec4::ONE.lerp(p.edge_color, weight);
+                mul.edge_size *= 1.0 + (p.edge_size - 1.0) * weight;
+                mul.texture_tint *= Vec4::ONE.lerp(p.texture_tint, weight);
+                mul.environment_tint *= Vec4::ONE.lerp(p.environment_tint, weight);
+                mul.toon_tint *= Vec4::ONE.lerp(p.toon_tint, weight);
+            }
+        }
+    }
+
+    /// Applies the morph to the parameters stored in `mat`
+    pub fn apply(&self, mat: &mut Mat) {
+        let (mul, add) = (&self.mul, &self.add);
+        mat.diffuse = mat.diffuse * mul.diffuse + add.diffuse;
+        let specular = mat.specular.xyz() * mul.specular + add.specular;
+        let specularity = mat.specular.w * mul.specularity + add.specularity;
+        mat.specular = specular.extend(specularity);
+        mat.ambient = mat.ambient * mul.ambient + add.ambient;
+        mat.edge_color = mat.edge_color * mul.edge_color + add.edge_color;
+        mat.edge_scale = mat.edge_scale * mul.edge_size + add.edge_size;
+    }
+}
+
+impl Default for MatMorph {
+    fn default() -> Self {
+        MatMorph {
+            mul: MatParams::splat(1.0),
+            add: MatParams::splat(0.0),
+        }
+    }
+}
+
+/// Everything a set of morph weights does to a `Pmx`, indexed like its vertices, bones and materials.
+/// Rigidbody morphs have no effect without physics and are ignored.
+#[derive(Clone)]
+pub struct MorphOffsets {
+    /// effective weight of every morph after expanding group and flip morphs
+    pub weights: Vec<f32>,
+    pub vertex: Vec<Vec3>,
+    pub uv: Vec<Vec4>,
+    pub appendix_uvs: Vec<Vec<Vec4>>,
+    pub bone_trans: Vec<Vec3>,
+    pub bone_rot: Vec<Quat>,
+    pub mats: Vec<MatMorph>,
+    /// group or flip morphs that refer back to themselves, the repeated reference is skipped
+    pub cyclic: BTreeSet<usize>,
+}
+
+impl MorphOffsets {
+    /// Offsets with no effect
+    pub fn new(pmx: &Pmx) -> MorphOffsets {
+        let n = pmx.verts.len();
+        MorphOffsets {
+            weights: vec![0.0; pmx.morphs.len()],
+            vertex: vec![Vec3::ZERO; n],
+            uv: vec![Vec4::ZERO; n],
+            appendix_uvs: vec![vec![Vec4::ZERO; n]; pmx.appendix_uvs.len()],
+            bone_trans: vec![Vec3::ZERO; pmx.bones.len()],
+            bone_rot: vec![Quat::IDENTITY; pmx.bones.len()],
+            mats: vec![MatMorph::default(); pmx.mats.len()],
+            cyclic: BTreeSet::new(),
+        }
+    }
+}
+
+impl Pmx {
+    /// Adds `weight` to morph `index`, passing it on through group and flip morphs.
+    /// `stack` holds the morphs being expanded to detect cycles.
+    fn resolve_morph(&self, index: usize, weight: f32, stack: &mut Vec<usize>, offsets: &mut MorphOffsets) {
+        let Some(morph) = self.morphs.get(index) else {
+            return;
+        };
+        if stack.contains(&index) {
+            offsets.cyclic.insert(index);
+            return;
+        }
+        stack.push(index);
+        match &morph.data {
+            Morph::MorphGroup(items) => {
+                for item in items {
+                    self.resolve_morph(item.index as usize, weight * item.affect, stack, offsets);
+                }
+            }
+            Morph::MorphFlip(items) => {
+                // only one of the items applies, picked by the weight and applied at its own affect
+                if weight > 0.0 && !items.is_empty() {
+                    let i = ((weight * items.len() as f32).ceil() as usize).clamp(1, items.len()) - 1;
+                    self.resolve_morph(items[i].index as usize, items[i].affect, stack, offsets);
+                }
+            }
+            _ => offsets.weights[index] += weight,
+        }
+        stack.pop();
+    }
+
+    /// Evaluates the morphs named in `weights`, names not found in the model are ignored
+    pub fn eval_morphs(&self, weights: &BTreeMap<String, f32>) -> MorphOffsets {
+        let mut offsets = MorphOffsets::new(self);
+        for (i, morph) in self.morphs.iter().enumerate() {
+            if let Some(weight) = weights.get(&morph.name) {
+                if *weight != 0.0 {
+                    self.resolve_morph(i, *weight, &mut Vec::new(), &mut offsets);
+                }
+            }
+        }
+
+        for (morph, weight) in self.morphs.iter().zip(offsets.weights.clone()) {
+            if weight == 0.0 {
+                continue;
+            }
+            match &morph.data {
+                Morph::MorphVertex(items) => {
+                    for item in items {
+                        if let Some(v) = offsets.vertex.get_mut(item.index as usize) {
+                            *v += item.trans * weight;
+                        }
+                    }
+                }
+                Morph::MorphUv(items) => {
+                    // category 3 is the base uv, 4..=7 are the appendix uvs
+                    let uvs = if morph.category == 3 {
+                        Some(&mut offsets.uv)
+                    } else {
+                        offsets.appendix_uvs.get_mut((morph.category as usize).saturating_sub(4))
+                    };
+                    if let Some(uvs) = uvs {
+                        for item in items {
+                            if let Some(uv) = uvs.get_mut(item.index as usize) {
+                                *uv += item.trans * weight;
+                            }
+                        }
+                    }
+                }
+                Morph::MorphBone(items) => {
+                    for item in items {
+                        let i = item.index as usize;
+                        if i < self.bones.len() {
+                            offsets.bone_trans[i] += item.trans * weight;
+                            offsets.bone_rot[i] *= Quat::IDENTITY.slerp(item.rot, weight);
+                        }
+                    }
+                }
+                Morph::MorphMat(items) => {
+                    for item in items {
+                        // index -1 targets every material
+                        if item.index == u32::MAX {
+                            for m in &mut offsets.mats {
+                                m.blend(item, weight);
+                            }
+                        } else if let Some(m) = offsets.mats.get_mut(item.index as usize) {
+                            m.blend(item, weight);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        offsets
+    }
+
+    /// Applies a morph combination permanently: vertices take the morphed and bone morph deformed
+    /// shape and materials the morphed parameters
+    pub fn bake_morphs(&mut self, weights: &BTreeMap<String, f32>) {
+        let offsets = self.eval_morphs(weights);
+        let mut skeleton = Skeleton::new(self);
+        skeleton.apply_bone_morphs(&offsets);
+        skeleton.evaluate(self);
+        let mesh = self.deform(&skeleton.skinning_matrices(self), &offsets);
+        self.apply_deformed(&mesh);
+        for (mat, morph) in self.mats.iter_mut().zip(&offsets.mats) {
+            morph.apply(mat);
+        }
+    }
+}
+
+impl Skeleton {
+    /// Adds the bone morph offsets on top of the current pose
+    pub fn apply_bone_morphs(&mut self, offsets: &MorphOffsets) {
+        for (i, (trans, rot)) in offsets.bone_trans.iter().zip(&offsets.bone_rot).enumerate() {
+            self.trans[i] += *trans;
+            self.rot[i] *= *rot;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pmx_fixtures::*;
+
+    fn weights(items: &[(&str, f32)]) -> BTreeMap<String, f32> {
+        items.iter().map(|(name, w)| (name.to_string(), *w)).collect()
+    }
+
+    #[test]
+    fn groups_pass_on_their_weight() {
+        let mut m = morph_model();
+        let Morph::MorphGroup(items) = &mut m.morphs[2].data else { panic!() };
+        items[1].affect = 0.5;
+        let offsets = m.eval_morphs(&weights(&[("group", 0.5), ("v", 0.25)]));
+        assert_eq!(offsets.weights, vec![0.75, 0.25, 0.0, 0.0]);
+        assert_eq!(offsets.vertex[1], Vec3::splat(0.75));
+        assert_eq!(offsets.uv[2], Vec4::splat(0.25));
+        assert!(offsets.cyclic.is_empty());
+    }
+
+    #[test]
+    fn flips_pick_one_item_by_weight() {
+        let m = morph_model();
+        // two items, the first covers weights up to 0.5 and the second the rest, each at its own affect
+        let offsets = m.eval_morphs(&weights(&[("flip", 0.3)]));
+        assert_eq!(offsets.weights, vec![0.0, 1.0, 0.0, 0.0]);
+        let offsets = m.eval_morphs(&weights(&[("flip", 0.8)]));
+        assert_eq!(offsets.weights, vec![1.0, 1.0, 0.0, 0.0]);
+        let offsets = m.eval_morphs(&weights(&[("flip", 0.0)]));
+        assert_eq!(offsets.weights, vec![0.0; 4]);
+    }
+
+    #[test]
+    fn cycles_are_reported_and_cut() {
+        let mut m = morph_model();
+        // a group referring to itself and a flip and a group referring to each other
+        m.morphs.push(morph("self", group(&[4, 0])));
+        m.morphs[2].data = group(&[3]);
+        let offsets = m.eval_morphs(&weights(&[("self", 1.0)]));
+        assert_eq!(offsets.cyclic, BTreeSet::from([4]));
+        assert_eq!(offsets.weights[0], 1.0);
+
+        let offsets = m.eval_morphs(&weights(&[("group", 1.0)]));
+        assert_eq!(offsets.cyclic, BTreeSet::from([2]));
+        assert_eq!(offsets.weights, vec![0.0; 5]);
+    }
+
+    #[test]
+    fn material_morphs_multiply_and_add() {
+        let mut m = mat_model();
+        let add = MorphMatItem { blend_mode: BlendMode::Add, diffuse: Vec4::splat(0.2), edge_size: 2.0, ..mat_item(0) };
+        let mul = MorphMatItem { diffuse: Vec4::splat(0.5), specularity: 3.0, ..mat_item(0) };
+        m.morphs[0].data = Morph::MorphMat(vec![mul, add]);
+        let offsets = m.eval_morphs(&weights(&[("m", 0.5)]));
+        let morph = offsets.mats[0];
+        assert_eq!(morph.mul.diffuse, Vec4::splat(0.75));
+        assert_eq!(morph.mul.specularity, 2.0);
+        assert_eq!(morph.add.diffuse, Vec4::splat(0.1));
+        assert_eq!(morph.add.edge_size, 1.0);
+        assert_eq!(offsets.mats[1], MatMorph::default());
+
+        let mut mat = Mat { diffuse: Vec4::ONE, specular: Vec4::splat(2.0), edge_scale: 1.0, ..m.mats[0].clone() };
+        morph.apply(&mut mat);
+        assert_eq!(mat.diffuse, Vec4::splat(0.85));
+        // the add item also adds half of its unit specular
+        assert_eq!(mat.specular, vec4(2.5, 2.5, 2.5, 4.5));
+        assert_eq!(mat.edge_scale, 2.0);
+    }
+
+    #[test]
+    fn all_materials_item_applies_to_every_material() {
+        let mut m = mat_model();
+        let all = MorphMatItem { diffuse: Vec4::splat(0.5), ..mat_item(u32::MAX) };
+        let one = MorphMatItem { diffuse: Vec4::splat(0.5), ..mat_item(2) };
+        m.morphs[0].data = Morph::MorphMat(vec![all, one]);
+        let offsets = m.eval_morphs(&weights(&[("m", 1.0)]));
+        let diffuse: Vec<f32> = offsets.mats.iter().map(|m| m.mul.diffuse.x).collect();
+        assert_eq!(diffuse, vec![0.5, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn uv_morph_category_picks_the_channel() {
+        let mut m = morph_model();
+        m.morphs[1].category = 4;
+        let offsets = m.eval_morphs(&weights(&[("uv", 1.0)]));
+        assert_eq!(offsets.uv[2], Vec4::ZERO);
+        assert_eq!(offsets.appendix_uvs[0][2], Vec4::ONE);
+
+        // the model has a single appendix uv, a morph of the second one does nothing
+        m.morphs[1].category = 5;
+        let offsets = m.eval_morphs(&weights(&[("uv", 1.0)]));
+        assert!(offsets.uv.iter().chain(&offsets.appendix_uvs[0]).all(|uv| *uv == Vec4::ZERO));
+
+        m.morphs[1].category = 3;
+        let offsets = m.eval_morphs(&weights(&[("uv", 1.0)]));
+        assert_eq!(offsets.uv[2], Vec4::ONE);
+        assert_eq!(offsets.appendix_uvs[0][2], Vec4::ZERO);
+    }
+}
@@ -0,0 +1,203 @@
+#![allow(dead_code)]
+
+use glam::*;
+
+use crate::deform::DeformedMesh;
+use crate::format::motion::Motion;
+use crate::format::pmx::{Bone, MorphInfo, Pmx};
+use crate::morph::MatMorph;
+use crate::skeleton::Skeleton;
+
+/// Frames per second of MMD motions
+pub const FPS: f32 = 30.0;
+
+/// Plays a motion on a model, evaluating interpolation, FK, IK, morphs and skinning on the CPU
+pub struct Playback {
+    pub motion: Option<Motion>,
+    pub frame: f32,
+    pub playing: bool,
+    /// the model flipped back to MMD's left handed space, where motions apply
+    model: Option<(Pmx, Skeleton)>,
+    /// set when the pose has to be evaluated again
+    dirty: bool,
+}
+
+/// Pose of the model at the current frame, in the app's right handed space
+pub struct PlaybackPose {
+    pub mesh: DeformedMesh,
+    pub mats: Vec<MatMorph>,
+}
+
+impl Playback {
+    pub fn new() -> Playback {
+        Playback {
+            motion: None,
+            frame: 0.0,
+            playing: false,
+            model: None,
+            dirty: true,
+        }
+    }
+
+    /// Takes a model as loaded in the app, which is flipped to the right handed space
+    pub fn set_model(&mut self, pmx: &Pmx) {
+        let mut pmx = pmx.clone();
+        pmx.right_hand();
+        let skeleton = Skeleton::new(&pmx);
+        self.model = Some((pmx, skeleton));
+        self.dirty = true;
+    }
+
+    /// Replaces one bone after an edit in the app, without copying the rest of the model
+    pub fn set_bone(&mut self, index: usize, bone: &Bone) {
+        let Some((pmx, skeleton)) = self.model.as_mut() else {
+            return;
+        };
+        if let Some(b) = pmx.bones.get_mut(index) {
+            *b = bone.clone();
+            b.right_hand();
+            *skeleton = Skeleton::new(pmx);
+            self.dirty = true;
+        }
+    }
+
+    /// Replaces one morph after an edit in the app, without copying the rest of the model
+    pub fn set_morph(&mut self, index: usize, morph: &MorphInfo) {
+        let Some((pmx, _)) = self.model.as_mut() else {
+            return;
+        };
+        if let Some(m) = pmx.morphs.get_mut(index) {
+            *m = morph.clone();
+            m.right_hand();
+            // morphs are only weighted by a motion, the rest pose stays the same
+            self.dirty |= self.motion.is_some();
+        }
+    }
+
+    pub fn set_motion(&mut self, motion: Option<Motion>) {
+        self.motion = motion;
+        self.frame = 0.0;
+        self.playing = false;
+        self.dirty = true;
+    }
+
+    pub fn max_frame(&self) -> u32 {
+        self.motion.as_ref().map_or(0, |m| m.max_frame())
+    }
+
+    pub fn seek(&mut self, frame: f32) {
+        self.frame = frame.clamp(0.0, self.max_frame() as f32);
+        self.dirty = true;
+    }
+
+    /// Moves the playhead by `dt` seconds while playing, looping at the end of the motion.
+    /// A motion holding a single frame has nowhere to move.
+    pub fn advance(&mut self, dt: f32) {
+        let max_frame = self.max_frame() as f32;
+        if !self.playing || max_frame == 0.0 {
+            return;
+        }
+        self.frame += dt * FPS;
+        if self.frame > max_frame {
+            self.frame %= max_frame;
+        }
+        self.dirty = true;
+    }
+
+    /// Evaluates the pose when the frame, model or motion changed since the last call.
+    /// Without a motion the rest pose is returned once.
+    pub fn update(&mut self) -> Option<PlaybackPose> {
+        if !self.dirty {
+            return None;
+        }
+        let (pmx, skeleton) = self.model.as_mut()?;
+        self.dirty = false;
+
+        let weights = self.motion.as_ref().map(|m| m.sample_morphs(self.frame)).unwrap_or_default();
+        let morphs = pmx.eval_morphs(&weights);
+        match &self.motion {
+            Some(motion) => skeleton.set_motion_pose(pmx, motion, self.frame),
+            None => skeleton.reset_pose(),
+        }
+        skeleton.apply_bone_morphs(&morphs);
+        skeleton.evaluate(pmx);
+        let mut mesh = pmx.deform(&skeleton.skinning_matrices(pmx), &morphs);
+        for (pos, nrm) in mesh.pos.iter_mut().zip(&mut mesh.nrm) {
+            pos.z *= -1.0;
+            nrm.z *= -1.0;
+        }
+        Some(PlaybackPose { mesh, mats: morphs.mats })
+    }
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::motion::MorphKeyframe;
+    use crate::format::pmx_fixtures::*;
+
+    /// The skeleton sample playing a motion that bends it from frame 0 to `len`
+    fn playback(len: u32) -> Playback {
+        let mut motion = Motion::new();
+        let frames = if len > 0 { vec![0, len] } else { vec![0] };
+        motion.morph_keyframes.insert("bend".into(), frames.iter().map(|&frame| MorphKeyframe { frame, weight: frame as f32 }).collect());
+        let mut p = Playback::new();
+        p.set_model(&skeleton_model());
+        p.set_motion(Some(motion));
+        p.playing = true;
+        p
+    }
+
+    #[test]
+    fn advance_loops_at_the_end() {
+        let mut p = playback(10);
+        p.advance(0.25);
+        assert!((p.frame - 7.5).abs() < 1e-4);
+        p.advance(0.25);
+        assert!((p.frame - 5.0).abs() < 1e-4);
+        p.playing = false;
+        p.advance(0.25);
+        assert!((p.frame - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn advance_without_length_does_nothing() {
+        let mut p = playback(0);
+        assert!(p.update().is_some());
+        p.advance(0.25);
+        assert_eq!(p.frame, 0.0);
+        assert!(p.update().is_none());
+    }
+
+    #[test]
+    fn seek_clamps_to_the_motion() {
+        let mut p = playback(10);
+        p.seek(-3.0);
+        assert_eq!(p.frame, 0.0);
+        p.seek(25.0);
+        assert_eq!(p.frame, 10.0);
+        p.seek(4.5);
+        assert_eq!(p.frame, 4.5);
+    }
+
+    #[test]
+    fn update_only_reports_changes() {
+        let mut p = playback(10);
+        let m = skeleton_model();
+        assert!(p.update().is_some());
+        assert!(p.update().is_none());
+        p.set_morph(0, &m.morphs[0]);
+        assert!(p.update().is_some());
+        p.set_bone(2, &m.bones[2]);
+        assert!(p.update().is_some());
+        p.seek(3.0);
+        assert!(p.update().is_some());
+        assert!(p.update().is_none());
+    }
+}
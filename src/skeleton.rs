@@ -0,0 +1,397 @@
+#![allow(dead_code)]
+
+use glam::*;
+
+use crate::format::motion::Motion;
+use crate::format::pmx::*;
+
+/// Global transforms of the bones of a `Pmx` evaluated from local translations and rotations.
+/// Translations are offsets from the rest position and rotations are relative to the parent, as in VMD.
+pub struct Skeleton {
+    /// bone indexes in deform order, each stage sorted by layer then index
+    pub order: Vec<usize>,
+    /// number of bones at the start of `order` that deform before physics
+    pub before_physics: usize,
+    pub trans: Vec<Vec3>,
+    pub rot: Vec<Quat>,
+    /// rotation added by IK, applied before the bone's own rotation
+    pub ik_rot: Vec<Quat>,
+    pub inherit_trans: Vec<Vec3>,
+    pub inherit_rot: Vec<Quat>,
+    pub local: Vec<Mat4>,
+    pub global: Vec<Mat4>,
+    /// whether each IK of `Pmx::iks` is solved during evaluation
+    pub ik_enabled: Vec<bool>,
+    /// Treat the rotations of bones with a local axis as given in that axis frame, as editors show them
+    pub local_axis_space: bool,
+    /// bones whose parent or inherit parent is each bone
+    dependents: Vec<Vec<usize>>,
+    /// position of every bone in `order`
+    rank: Vec<usize>,
+    /// indexes into `Pmx::iks` of the IKs of every bone
+    bone_iks: Vec<Vec<usize>>,
+    /// scratch buffers of `update_subtree`
+    subtree: Vec<usize>,
+    in_subtree: Vec<bool>,
+}
+
+fn bone_index(index: Option<usize>, len: usize) -> Option<usize> {
+    index.filter(|i| *i < len)
+}
+
+/// The part of `q` that rotates around `axis`
+fn twist(q: Quat, axis: Vec3) -> Quat {
+    let axis = axis.normalize_or_zero();
+    let v = axis * q.xyz().dot(axis);
+    let twist = Quat::from_xyzw(v.x, v.y, v.z, q.w);
+    if twist.length_squared() > 0.0 {
+        twist.normalize()
+    } else {
+        Quat::IDENTITY
+    }
+}
+
+/// Rotation from the model frame to the frame of a local axis given as its x and z directions
+fn local_axis_basis(x: Vec3, z: Vec3) -> Quat {
+    let x = x.normalize_or_zero();
+    let y = z.cross(x).normalize_or_zero();
+    let z = x.cross(y);
+    if y == Vec3::ZERO {
+        return Quat::IDENTITY;
+    }
+    Quat::from_mat3(&Mat3::from_cols(x, y, z))
+}
+
+impl Skeleton {
+    pub fn new(pmx: &Pmx) -> Skeleton {
+        let n = pmx.bones.len();
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by_key(|i| {
+            let bone = &pmx.bones[*i];
+            (bone.bone_flags.contains(BoneFlags::PHYSICS_AFTER_DEFORM), bone.layer, *i)
+        });
+        let before_physics = order.iter()
+            .filter(|i| !pmx.bones[**i].bone_flags.contains(BoneFlags::PHYSICS_AFTER_DEFORM))
+            .count();
+        let mut rank = vec![0; n];
+        for (k, i) in order.iter().enumerate() {
+            rank[*i] = k;
+        }
+        let mut dependents = vec![Vec::new(); n];
+        for (i, bone) in pmx.bones.iter().enumerate() {
+            let parent = bone_index(bone.parent_index, n);
+            let inherit = bone_index(bone.inherit.and_then(|(p, _)| usize::try_from(p).ok()), n);
+            for p in [parent, inherit].into_iter().flatten() {
+                if p != i && !dependents[p].contains(&i) {
+                    dependents[p].push(i);
+                }
+            }
+        }
+        let mut bone_iks = vec![Vec::new(); n];
+        for (k, ik) in pmx.iks.iter().enumerate() {
+            if let Some(i) = bone_index(usize::try_from(ik.bone).ok(), n) {
+                bone_iks[i].push(k);
+            }
+        }
+        let mut skeleton = Skeleton {
+            order,
+            before_physics,
+            trans: vec![Vec3::ZERO; n],
+            rot: vec![Quat::IDENTITY; n],
+            ik_rot: vec![Quat::IDENTITY; n],
+            inherit_trans: vec![Vec3::ZERO; n],
+            inherit_rot: vec![Quat::IDENTITY; n],
+            local: vec![Mat4::IDENTITY; n],
+            global: vec![Mat4::IDENTITY; n],
+            ik_enabled: vec![true; pmx.iks.len()],
+            local_axis_space: false,
+            dependents,
+            rank,
+            bone_iks,
+            subtree: Vec::new(),
+            in_subtree: vec![false; n],
+        };
+        skeleton.reset_globals(pmx);
+        skeleton
+    }
+
+    pub fn reset_pose(&mut self) {
+        self.trans.fill(Vec3::ZERO);
+        self.rot.fill(Quat::IDENTITY);
+        self.ik_rot.fill(Quat::IDENTITY);
+        self.ik_enabled.fill(true);
+    }
+
+    /// Sets the local pose of every bone from the track of the same name, bones without a track are reset.
+    /// IKs are switched on or off by the motion's IK keyframes.
+    pub fn set_motion_pose(&mut self, pmx: &Pmx, motion: &Motion, frame: f32) {
+        for (i, bone) in pmx.bones.iter().enumerate() {
+            let (trans, rot) = motion.sample_bone(&bone.name, frame).unwrap_or((Vec3::ZERO, Quat::IDENTITY));
+            self.trans[i] = trans;
+            self.rot[i] = rot;
+        }
+        self.ik_rot.fill(Quat::IDENTITY);
+        for (enabled, ik) in self.ik_enabled.iter_mut().zip(&pmx.iks) {
+            *enabled = usize::try_from(ik.bone).ok()
+                .and_then(|i| pmx.bones.get(i))
+                .map_or(true, |bone| motion.sample_ik(&bone.name, frame));
+        }
+    }
+
+    fn reset_globals(&mut self, pmx: &Pmx) {
+        for (i, bone) in pmx.bones.iter().enumerate() {
+            self.global[i] = Mat4::from_translation(bone.pos);
+        }
+    }
+
+    /// Evaluates both deform stages, see `evaluate_stage`
+    pub fn evaluate(&mut self, pmx: &Pmx) {
+        self.reset_globals(pmx);
+        self.evaluate_stage(pmx, false);
+        self.evaluate_stage(pmx, true);
+    }
+
+    /// Updates the bones of one stage in deform order. A bone whose parent deforms later
+    /// sees the parent as it was when it was updated, as in MMD.
+    /// Inherited transforms and enabled IKs are then applied in the same order on top of the updated stage,
+    /// an inheriting bone is only updated again when what it inherits has changed since.
+    pub fn evaluate_stage(&mut self, pmx: &Pmx, after_physics: bool) {
+        let range = if after_physics {
+            self.before_physics..self.order.len()
+        } else {
+            0..self.before_physics
+        };
+        for k in range.clone() {
+            self.update_bone(pmx, self.order[k]);
+        }
+        for k in range {
+            let i = self.order[k];
+            if pmx.bones[i].inherit.is_some() && self.inherited(pmx, i) != (self.inherit_rot[i], self.inherit_trans[i]) {
+                self.update_subtree(pmx, i);
+            }
+            for j in 0..self.bone_iks[i].len() {
+                let ik = self.bone_iks[i][j];
+                if self.ik_enabled[ik] {
+                    self.solve_ik(pmx, &pmx.iks[ik]);
+                }
+            }
+        }
+    }
+
+    /// Rotation and translation bone `i` inherits from the current pose
+    fn inherited(&self, pmx: &Pmx, i: usize) -> (Quat, Vec3) {
+        let bone = &pmx.bones[i];
+        let mut inherited = (Quat::IDENTITY, Vec3::ZERO);
+        if let Some((p, ratio)) = bone.inherit {
+            if let Some(p) = bone_index(usize::try_from(p).ok(), pmx.bones.len()).filter(|p| *p != i) {
+                // the inherit parent passes on its whole rotation including what it inherits itself
+                if bone.bone_flags.contains(BoneFlags::INHERIT_ROTATION) {
+                    let rot = self.ik_rot[p] * self.rot[p] * self.inherit_rot[p];
+                    inherited.0 = Quat::IDENTITY.slerp(rot, ratio);
+                }
+                if bone.bone_flags.contains(BoneFlags::INHERIT_TRANSLATION) {
+                    inherited.1 = (self.trans[p] + self.inherit_trans[p]) * ratio;
+                }
+            }
+        }
+        inherited
+    }
+
+    /// Recomputes the local and global transform of bone `i` from the current pose
+    pub fn update_bone(&mut self, pmx: &Pmx, i: usize) {
+        let n = pmx.bones.len();
+        let bone = &pmx.bones[i];
+
+        (self.inherit_rot[i], self.inherit_trans[i]) = self.inherited(pmx, i);
+
+        let mut rot = self.rot[i];
+        if self.local_axis_space && bone.bone_flags.contains(BoneFlags::LOCAL_AXIS) {
+            if let Some((x, z)) = bone.local_axis {
+                let basis = local_axis_basis(x, z);
+                rot = basis * rot * basis.inverse();
+            }
+        }
+        if bone.bone_flags.contains(BoneFlags::FIXED_AXIS) {
+            if let Some(axis) = bone.fixed_axis {
+                rot = twist(rot, axis);
+            }
+        }
+        let rot = self.ik_rot[i] * rot * self.inherit_rot[i];
+        let trans = self.trans[i] + self.inherit_trans[i];
+
+        let parent = bone_index(bone.parent_index, n).filter(|p| *p != i);
+        let parent_pos = parent.map_or(Vec3::ZERO, |p| pmx.bones[p].pos);
+        self.local[i] = Mat4::from_rotation_translation(rot, bone.pos - parent_pos + trans);
+        self.global[i] = parent.map_or(Mat4::IDENTITY, |p| self.global[p]) * self.local[i];
+    }
+
+    /// Updates bone `i` and then every descendant in deform order, used after changing a single bone.
+    /// Past the direct dependents of `i` a bone only follows a parent that deforms before it.
+    pub fn update_subtree(&mut self, pmx: &Pmx, i: usize) {
+        self.update_bone(pmx, i);
+        let mut subtree = std::mem::take(&mut self.subtree);
+        subtree.clear();
+        self.in_subtree[i] = true;
+        subtree.push(i);
+        let mut k = 0;
+        while k < subtree.len() {
+            let b = subtree[k];
+            for d in &self.dependents[b] {
+                if !self.in_subtree[*d] && (b == i || self.rank[*d] > self.rank[b]) {
+                    self.in_subtree[*d] = true;
+                    subtree.push(*d);
+                }
+            }
+            k += 1;
+        }
+        let rank = &self.rank;
+        subtree[1..].sort_unstable_by_key(|b| rank[*b]);
+        for b in &subtree {
+            self.in_subtree[*b] = false;
+        }
+        for b in &subtree[1..] {
+            self.update_bone(pmx, *b);
+        }
+        self.subtree = subtree;
+    }
+
+    /// Current world position of bone `i`
+    pub fn position(&self, i: usize) -> Vec3 {
+        self.global[i].w_axis.truncate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+    use crate::format::pmx_fixtures::bone;
+
+    /// root at the origin, arm one up, hand two up
+    fn chain() -> Pmx {
+        let mut m = Pmx::new();
+        m.bones.push(bone("root", None, Vec3::ZERO));
+        m.bones.push(bone("arm", Some(0), Vec3::Y));
+        m.bones.push(bone("hand", Some(1), vec3(0.0, 2.0, 0.0)));
+        m
+    }
+
+    fn assert_global(s: &Skeleton, i: usize, rot: Quat, pos: Vec3) {
+        let expected = Mat4::from_rotation_translation(rot, pos);
+        assert!(s.global[i].abs_diff_eq(expected, 1e-5), "bone {}: {:?} != {:?}", i, s.global[i], expected);
+    }
+
+    #[test]
+    fn rest_pose_is_at_the_bone_positions() {
+        let m = chain();
+        let mut s = Skeleton::new(&m);
+        s.evaluate(&m);
+        for (i, b) in m.bones.iter().enumerate() {
+            assert_global(&s, i, Quat::IDENTITY, b.pos);
+        }
+    }
+
+    #[test]
+    fn parent_chain() {
+        let m = chain();
+        let mut s = Skeleton::new(&m);
+        let quarter = Quat::from_rotation_z(FRAC_PI_2);
+        s.trans[0] = Vec3::X;
+        s.rot[1] = quarter;
+        s.evaluate(&m);
+        assert_global(&s, 0, Quat::IDENTITY, Vec3::X);
+        assert_global(&s, 1, quarter, vec3(1.0, 1.0, 0.0));
+        // the hand is carried around the arm, one unit along -x
+        assert_global(&s, 2, quarter, vec3(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn later_layers_and_after_physics_deform_last() {
+        let mut m = chain();
+        m.bones[1].layer = 1;
+        m.bones[2].parent_index = Some(0);
+        m.bones[0].bone_flags |= BoneFlags::PHYSICS_AFTER_DEFORM;
+        let mut s = Skeleton::new(&m);
+        assert_eq!(s.order, vec![2, 1, 0]);
+        assert_eq!(s.before_physics, 2);
+
+        // the hand and the arm deform before the root, so they see it still at rest
+        s.rot[0] = Quat::from_rotation_z(FRAC_PI_2);
+        s.evaluate(&m);
+        assert_global(&s, 0, Quat::from_rotation_z(FRAC_PI_2), Vec3::ZERO);
+        assert_global(&s, 1, Quat::IDENTITY, Vec3::Y);
+        assert_global(&s, 2, Quat::IDENTITY, vec3(0.0, 2.0, 0.0));
+
+        // with the root back in the first stage the arm follows it
+        m.bones[0].bone_flags.remove(BoneFlags::PHYSICS_AFTER_DEFORM);
+        let mut s = Skeleton::new(&m);
+        assert_eq!(s.order, vec![0, 2, 1]);
+        s.rot[0] = Quat::from_rotation_z(FRAC_PI_2);
+        s.evaluate(&m);
+        assert_global(&s, 1, Quat::from_rotation_z(FRAC_PI_2), -Vec3::X);
+        assert_global(&s, 2, Quat::from_rotation_z(FRAC_PI_2), vec3(-2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn inherit_ratio() {
+        let mut m = chain();
+        m.bones[2].parent_index = Some(0);
+        m.bones[2].inherit = Some((1, 0.5));
+        m.bones[2].bone_flags |= BoneFlags::INHERIT_ROTATION | BoneFlags::INHERIT_TRANSLATION;
+        let mut s = Skeleton::new(&m);
+        s.rot[1] = Quat::from_rotation_y(FRAC_PI_2);
+        s.trans[1] = vec3(0.0, 0.0, 2.0);
+        s.evaluate(&m);
+        assert_global(&s, 2, Quat::from_rotation_y(FRAC_PI_2 * 0.5), vec3(0.0, 2.0, 1.0));
+    }
+
+    #[test]
+    fn fixed_axis_keeps_the_twist() {
+        let mut m = chain();
+        m.bones[1].bone_flags |= BoneFlags::FIXED_AXIS;
+        m.bones[1].fixed_axis = Some(Vec3::Y * 2.0);
+        let mut s = Skeleton::new(&m);
+        s.rot[1] = Quat::from_rotation_x(FRAC_PI_2) * Quat::from_rotation_y(0.5);
+        s.evaluate(&m);
+        assert_global(&s, 1, Quat::from_rotation_y(0.5), Vec3::Y);
+        s.rot[1] = Quat::from_rotation_z(0.5);
+        s.evaluate(&m);
+        assert_global(&s, 1, Quat::IDENTITY, Vec3::Y);
+    }
+
+    #[test]
+    fn local_axis_space() {
+        let mut m = chain();
+        // local x along the model y axis
+        m.bones[1].bone_flags |= BoneFlags::LOCAL_AXIS;
+        m.bones[1].local_axis = Some((Vec3::Y, Vec3::Z));
+        let mut s = Skeleton::new(&m);
+        s.rot[1] = Quat::from_rotation_x(FRAC_PI_2);
+        s.evaluate(&m);
+        assert_global(&s, 1, Quat::from_rotation_x(FRAC_PI_2), Vec3::Y);
+        s.local_axis_space = true;
+        s.evaluate(&m);
+        assert_global(&s, 1, Quat::from_rotation_y(FRAC_PI_2), Vec3::Y);
+        assert_global(&s, 2, Quat::from_rotation_y(FRAC_PI_2), vec3(0.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn update_subtree_follows_parents_and_inherits() {
+        let mut m = chain();
+        m.bones.push(bone("other", Some(0), Vec3::X));
+        m.bones[3].inherit = Some((1, 1.0));
+        m.bones[3].bone_flags |= BoneFlags::INHERIT_ROTATION;
+        let mut s = Skeleton::new(&m);
+        s.evaluate(&m);
+        s.rot[1] = Quat::from_rotation_z(FRAC_PI_2);
+        s.update_subtree(&m, 1);
+        assert_global(&s, 2, Quat::from_rotation_z(FRAC_PI_2), -Vec3::X + Vec3::Y);
+        assert_global(&s, 3, Quat::from_rotation_z(FRAC_PI_2), Vec3::X);
+        // nothing stays marked for the next call
+        s.rot[1] = Quat::IDENTITY;
+        s.update_subtree(&m, 1);
+        assert_global(&s, 2, Quat::IDENTITY, vec3(0.0, 2.0, 0.0));
+        assert_global(&s, 3, Quat::IDENTITY, Vec3::X);
+    }
+}